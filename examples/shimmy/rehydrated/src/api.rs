@@ -9,11 +9,11 @@ use axum::{
     response::IntoResponse,
     Json,
 };
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
-use crate::engine::{GenOptions, InferenceEngine, ModelSpec};
+use crate::engine::{GenOptions, InferenceEngine};
 use crate::server::AppState;
 use crate::templates::detect_template_family;
 
@@ -112,8 +112,8 @@ pub async fn generate_handler<E: InferenceEngine + Send + Sync>(
         max_tokens: req.max_tokens,
         temperature: req.temperature,
         top_p: req.top_p.unwrap_or(0.95),
-        top_k: req.top_k.unwrap_or(40),
-        stop_sequences: req.stop.unwrap_or_default(),
+        top_k: req.top_k.unwrap_or(40) as i32,
+        stop_tokens: req.stop.unwrap_or_default(),
         ..Default::default()
     };
     
@@ -193,8 +193,8 @@ pub async fn chat_handler<E: InferenceEngine + Send + Sync>(
         max_tokens: req.max_tokens,
         temperature: req.temperature,
         top_p: req.top_p.unwrap_or(0.95),
-        top_k: req.top_k.unwrap_or(40),
-        stop_sequences: template.stop_tokens(),
+        top_k: req.top_k.unwrap_or(40) as i32,
+        stop_tokens: template.stop_tokens(),
         ..Default::default()
     };
     
@@ -305,25 +305,22 @@ async fn handle_ws_stream<E: InferenceEngine + Send + Sync>(
         ..Default::default()
     };
     
-    // Stream tokens via callback
-    let socket = Arc::new(Mutex::new(socket));
-    let socket_clone = socket.clone();
-    
-    let callback = Box::new(move |token: &str| {
-        let socket = socket_clone.clone();
-        let token = token.to_string();
-        tokio::spawn(async move {
-            let chunk = StreamChunk {
-                text: token,
+    // Stream tokens as they are generated
+    let mut tokens = model.generate_stream(&req.prompt, options);
+    while let Some(event) = tokens.next().await {
+        let msg = match event {
+            Ok(event) => serde_json::to_string(&StreamChunk {
+                text: event.text,
                 done: false,
-            };
-            let msg = serde_json::to_string(&chunk).unwrap();
-            let _ = socket.lock().await.send(axum::extract::ws::Message::Text(msg)).await;
-        });
-    });
-    
-    // Generate with streaming
-    let _ = model.generate_stream(&req.prompt, options, callback).await;
+            })
+            .unwrap(),
+            Err(e) => serde_json::json!({ "error": e.to_string() }).to_string(),
+        };
+        if socket.send(axum::extract::ws::Message::Text(msg)).await.is_err() {
+            // Client went away; dropping the stream stops generation
+            return;
+        }
+    }
     
     // Send done signal
     let done_chunk = StreamChunk {
@@ -331,5 +328,5 @@ async fn handle_ws_stream<E: InferenceEngine + Send + Sync>(
         done: true,
     };
     let done_msg = serde_json::to_string(&done_chunk).unwrap();
-    let _ = socket.lock().await.send(axum::extract::ws::Message::Text(done_msg)).await;
+    let _ = socket.send(axum::extract::ws::Message::Text(done_msg)).await;
 }
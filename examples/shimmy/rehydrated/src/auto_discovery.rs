@@ -187,11 +187,9 @@ fn parse_filename(filename: &str) -> (String, Option<String>, Option<String>) {
         }
         
         // Check for parameter count (7B, 8B, 13B, etc.)
-        if upper.ends_with('B') && upper.len() <= 4 {
-            if upper[..upper.len()-1].chars().all(|c| c.is_numeric() || c == '.') {
-                param_count = Some(upper);
-                continue;
-            }
+        if upper.ends_with('B') && upper.len() <= 4 && upper[..upper.len()-1].chars().all(|c| c.is_numeric() || c == '.') {
+            param_count = Some(upper);
+            continue;
         }
         
         name_parts.push(part);
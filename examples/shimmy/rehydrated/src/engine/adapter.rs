@@ -4,7 +4,7 @@
 //! 
 //! Routes model loading to the appropriate backend based on file type.
 
-use super::{llama::LlamaEngine, GenOptions, InferenceEngine, LoadedModel, ModelSpec};
+use super::{llama::LlamaEngine, InferenceEngine, LoadedModel, ModelSpec};
 use async_trait::async_trait;
 
 // ═══════════════════════════════════════════════════════════════════
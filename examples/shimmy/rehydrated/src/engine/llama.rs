@@ -6,8 +6,9 @@
 //! FFI bindings to llama.cpp. For rehydration demonstration, we provide
 //! a mock that returns placeholder text.
 
-use super::{GenOptions, InferenceEngine, LoadedModel, ModelSpec};
+use super::{GenOptions, InferenceEngine, LoadedModel, ModelSpec, TokenEvent, TokenStream};
 use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
 use std::sync::Mutex;
 
 // ═══════════════════════════════════════════════════════════════════
@@ -16,20 +17,17 @@ use std::sync::Mutex;
 // ═══════════════════════════════════════════════════════════════════
 
 /// Determines which GPU acceleration to use for inference.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum GpuBackend {
+    /// Resolved to a concrete backend when the engine is built.
+    Auto,
+    #[default]
     Cpu,
     Cuda,
     Vulkan,
     OpenCL,
     Metal,
-}
-
-impl Default for GpuBackend {
-    fn default() -> Self {
-        // Auto-detect would check: CUDA > Vulkan > OpenCL > CPU
-        GpuBackend::Cpu
-    }
+    Mlx,
 }
 
 // ═══════════════════════════════════════════════════════════════════
@@ -62,6 +60,7 @@ impl MoeConfig {
 // ═══════════════════════════════════════════════════════════════════
 
 /// The primary inference engine using llama.cpp bindings.
+#[derive(Clone)]
 pub struct LlamaEngine {
     backend: GpuBackend,
     moe_config: MoeConfig,
@@ -75,14 +74,10 @@ impl LlamaEngine {
         }
     }
 
-    pub fn new_with_backend(backend: Option<&str>) -> Self {
+    pub fn new_with_backend(backend: GpuBackend) -> Self {
         let backend = match backend {
-            Some("cpu") => GpuBackend::Cpu,
-            Some("cuda") => GpuBackend::Cuda,
-            Some("vulkan") => GpuBackend::Vulkan,
-            Some("opencl") => GpuBackend::OpenCL,
-            Some("metal") => GpuBackend::Metal,
-            _ => GpuBackend::default(), // "auto"
+            GpuBackend::Auto => GpuBackend::default(),
+            chosen => chosen,
         };
         Self {
             backend,
@@ -90,7 +85,7 @@ impl LlamaEngine {
         }
     }
 
-    pub fn new_with_moe(backend: Option<&str>, moe_config: MoeConfig) -> Self {
+    pub fn new_with_moe(backend: GpuBackend, moe_config: MoeConfig) -> Self {
         let mut engine = Self::new_with_backend(backend);
         engine.moe_config = moe_config;
         engine
@@ -98,11 +93,13 @@ impl LlamaEngine {
 
     pub fn get_backend_info(&self) -> String {
         match self.backend {
+            GpuBackend::Auto => "Auto".to_string(),
             GpuBackend::Cpu => "CPU".to_string(),
             GpuBackend::Cuda => "CUDA".to_string(),
             GpuBackend::Vulkan => "Vulkan".to_string(),
             GpuBackend::OpenCL => "OpenCL".to_string(),
             GpuBackend::Metal => "Metal".to_string(),
+            GpuBackend::Mlx => "MLX".to_string(),
         }
     }
 }
@@ -143,33 +140,37 @@ struct LlamaLoaded {
 
 #[async_trait]
 impl LoadedModel for LlamaLoaded {
-    async fn generate(
-        &self,
-        prompt: &str,
-        opts: GenOptions,
-        on_token: Option<Box<dyn Fn(String) + Send + Sync>>,
-    ) -> anyhow::Result<String> {
+    fn generate_stream<'a>(&'a self, prompt: &str, opts: GenOptions) -> TokenStream<'a> {
         // STUB: Real implementation would:
         // 1. tokenize prompt via llama_tokenize
         // 2. llama_decode for prefill
         // 3. sampling loop with temperature, top_p, top_k, repeat_penalty
         // 4. check stop conditions
-        // 5. if on_token: callback with each token
+        // 5. yield a TokenEvent per sampled token
         
+        let head: String = prompt.chars().take(50).collect();
         let response = format!(
             "[STUB] Model '{}' would generate {} tokens from prompt: {}",
             self.model_name,
             opts.max_tokens,
-            &prompt[..prompt.len().min(50)]
+            head
         );
         
-        // Simulate streaming if callback provided
-        if let Some(callback) = on_token {
-            for word in response.split_whitespace() {
-                callback(format!("{} ", word));
-            }
-        }
-        
-        Ok(response)
+        // Simulate streaming one word per token
+        let words: Vec<String> = response.split_whitespace().map(|w| format!("{} ", w)).collect();
+        let last = words.len().saturating_sub(1);
+        let events = words.into_iter().enumerate().map(move |(i, text)| {
+            Ok(TokenEvent {
+                text,
+                finish_reason: (i == last).then(|| "stop".to_string()),
+                ..Default::default()
+            })
+        });
+        stream::iter(events).boxed()
+    }
+
+    fn count_tokens(&self, text: &str) -> Option<usize> {
+        // The stub's tokens are whitespace-separated words.
+        Some(text.split_whitespace().count())
     }
 }
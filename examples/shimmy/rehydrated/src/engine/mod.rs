@@ -5,7 +5,7 @@
 //! This module defines the core abstraction layer for inference engines.
 
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
+use futures::stream::{BoxStream, StreamExt};
 use std::path::PathBuf;
 
 pub mod adapter;
@@ -90,6 +90,22 @@ pub trait InferenceEngine: Send + Sync {
     async fn load(&self, spec: &ModelSpec) -> anyhow::Result<Box<dyn LoadedModel>>;
 }
 
+// ═══════════════════════════════════════════════════════════════════
+// TokenEvent - Streamed Generation Output
+// ═══════════════════════════════════════════════════════════════════
+
+/// One generated token. The last event of a stream carries `finish_reason`.
+#[derive(Debug, Clone, Default)]
+pub struct TokenEvent {
+    pub text: String,
+    pub token_id: Option<u32>,
+    pub logprob: Option<f32>,
+    pub finish_reason: Option<String>,
+}
+
+/// The tokens of one generation, in order.
+pub type TokenStream<'a> = BoxStream<'a, anyhow::Result<TokenEvent>>;
+
 // ═══════════════════════════════════════════════════════════════════
 // LoadedModel Trait
 // From engine.spell: @LoadedModel
@@ -98,17 +114,39 @@ pub trait InferenceEngine: Send + Sync {
 /// A model that has been loaded into memory and is ready to generate.
 /// 
 /// Invariant from spell: must be thread-safe (Send + Sync)
-/// Invariant from spell: on_token callback enables streaming
 #[async_trait]
 pub trait LoadedModel: Send + Sync {
-    /// Generates text from a prompt.
-    /// 
-    /// If `on_token` is Some, streams each token via the callback.
-    /// Returns the full generated text.
-    async fn generate(
+    /// Streams the tokens generated for a prompt.
+    fn generate_stream<'a>(&'a self, prompt: &str, opts: GenOptions) -> TokenStream<'a>;
+
+    /// Tokens `text` encodes to with this model's tokenizer, or `None` when
+    /// the backend cannot tell without generating.
+    fn count_tokens(&self, _text: &str) -> Option<usize> {
+        None
+    }
+
+    /// Generates text from a prompt, returning the full completion.
+    async fn generate(&self, prompt: &str, opts: GenOptions) -> anyhow::Result<String> {
+        self.generate_with_callback(prompt, opts, None).await
+    }
+
+    /// Callback form of [`LoadedModel::generate_stream`]: `on_token` receives
+    /// each piece as it is generated, and the full completion is returned.
+    async fn generate_with_callback(
         &self,
         prompt: &str,
         opts: GenOptions,
         on_token: Option<Box<dyn Fn(String) + Send + Sync>>,
-    ) -> anyhow::Result<String>;
+    ) -> anyhow::Result<String> {
+        let mut text = String::new();
+        let mut tokens = self.generate_stream(prompt, opts);
+        while let Some(event) = tokens.next().await {
+            let event = event?;
+            if let Some(callback) = &on_token {
+                callback(event.text.clone());
+            }
+            text.push_str(&event.text);
+        }
+        Ok(text)
+    }
 }
@@ -9,7 +9,7 @@
 //!
 //! ## Architecture
 //!
-//! ```
+//! ```text
 //!                    ┌─────────────────┐
 //!                    │       CLI       │
 //!                    │    (clap.rs)    │
@@ -9,8 +9,8 @@ use clap::Parser;
 use std::sync::Arc;
 
 use shimmy_rehydrated::{
-    cli::{Cli, Command, GpuBackendArg},
-    engine::{adapter::InferenceEngineAdapter, llama::{LlamaEngine, GpuBackend, MoeConfig}},
+    cli::{Cli, Command},
+    engine::{llama::{LlamaEngine, GpuBackend, MoeConfig}, InferenceEngine},
     model_registry::Registry,
     server::{run, ServerConfig, AppState},
     auto_discovery::ModelAutoDiscovery,
@@ -36,8 +36,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             
             let engine = if let Some(moe_layers) = args.moe_cpu_offload {
                 let moe = MoeConfig::from_cli(
-                    Some(moe_layers),
-                    args.threads,
+                    false,
+                    Some(moe_layers.max(0) as usize),
                 );
                 LlamaEngine::new_with_moe(gpu_backend, moe)
             } else {
@@ -86,7 +86,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 max_tokens: args.max_tokens,
                 temperature: args.temperature,
                 top_p: args.top_p.unwrap_or(0.95),
-                top_k: args.top_k.unwrap_or(40),
+                top_k: args.top_k.unwrap_or(40) as i32,
                 ..Default::default()
             };
             
@@ -147,7 +147,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 let options = shimmy_rehydrated::GenOptions {
                     max_tokens: 512,
                     temperature: args.temperature,
-                    stop_sequences: template.stop_tokens(),
+                    stop_tokens: template.stop_tokens(),
                     ..Default::default()
                 };
                 
@@ -9,9 +9,9 @@ use axum::{
     response::{sse::Event, IntoResponse, Sse},
     Json,
 };
-use futures::stream::{self, Stream};
+use futures::stream::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::{convert::Infallible, pin::Pin, sync::Arc, time::SystemTime};
+use std::{convert::Infallible, sync::Arc, time::SystemTime};
 use tokio::sync::mpsc;
 use uuid::Uuid;
 
@@ -59,7 +59,9 @@ pub struct ChatCompletionResponse {
     pub created: u64,
     pub model: String,
     pub choices: Vec<Choice>,
-    pub usage: Usage,
+    /// Omitted when the backend cannot count prompt tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
 }
 
 #[derive(Debug, Serialize)]
@@ -201,7 +203,7 @@ async fn non_stream_chat_completions<E: InferenceEngine + Send + Sync>(
         max_tokens: req.max_tokens.unwrap_or(256),
         temperature: req.temperature.unwrap_or(0.7),
         top_p: req.top_p.unwrap_or(0.95),
-        stop_sequences: req.stop.unwrap_or_else(|| template.stop_tokens()),
+        stop_tokens: req.stop.unwrap_or_else(|| template.stop_tokens()),
         ..Default::default()
     };
     
@@ -218,8 +220,28 @@ async fn non_stream_chat_completions<E: InferenceEngine + Send + Sync>(
         }
     };
     
-    match model.generate(&prompt, options).await {
-        Ok(text) => {
+    let mut tokens = model.generate_stream(&prompt, options);
+    let mut text = String::new();
+    let mut completion_tokens = 0;
+    let mut finish_reason = None;
+    let mut result = Ok(());
+    while let Some(event) = tokens.next().await {
+        match event {
+            Ok(event) => {
+                text.push_str(&event.text);
+                completion_tokens += 1;
+                finish_reason = event.finish_reason.or(finish_reason);
+            }
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    drop(tokens);
+
+    match result {
+        Ok(()) => {
             let response = ChatCompletionResponse {
                 id,
                 object: "chat.completion".to_string(),
@@ -231,13 +253,13 @@ async fn non_stream_chat_completions<E: InferenceEngine + Send + Sync>(
                         role: "assistant".to_string(),
                         content: text.clone(),
                     },
-                    finish_reason: "stop".to_string(),
+                    finish_reason: finish_reason.unwrap_or_else(|| "stop".to_string()),
                 }],
-                usage: Usage {
-                    prompt_tokens: estimate_tokens(&prompt),
-                    completion_tokens: estimate_tokens(&text),
-                    total_tokens: estimate_tokens(&prompt) + estimate_tokens(&text),
-                },
+                usage: model.count_tokens(&prompt).map(|prompt_tokens| Usage {
+                    prompt_tokens,
+                    completion_tokens,
+                    total_tokens: prompt_tokens + completion_tokens,
+                }),
             };
             Json(response).into_response()
         }
@@ -289,7 +311,7 @@ async fn stream_chat_completions<E: InferenceEngine + Send + Sync + 'static>(
             max_tokens: req_clone.max_tokens.unwrap_or(256),
             temperature: req_clone.temperature.unwrap_or(0.7),
             top_p: req_clone.top_p.unwrap_or(0.95),
-            stop_sequences: req_clone.stop.unwrap_or_else(|| template.stop_tokens()),
+            stop_tokens: req_clone.stop.unwrap_or_else(|| template.stop_tokens()),
             ..Default::default()
         };
         
@@ -299,17 +321,13 @@ async fn stream_chat_completions<E: InferenceEngine + Send + Sync + 'static>(
             Err(_) => return,
         };
         
-        // Generate with callback
-        let tx_clone = tx.clone();
-        let callback = Box::new(move |token: &str| {
-            let tx = tx_clone.clone();
-            let token = token.to_string();
-            tokio::spawn(async move {
-                let _ = tx.send(token).await;
-            });
-        });
-        
-        let _ = model.generate_stream(&prompt, options, callback).await;
+        // Forward tokens in order; stop generating once the client is gone
+        let mut tokens = model.generate_stream(&prompt, options);
+        while let Some(Ok(event)) = tokens.next().await {
+            if tx.send(event.text).await.is_err() {
+                break;
+            }
+        }
         // Signal end by dropping tx
     });
     
@@ -422,8 +440,3 @@ fn format_openai_messages(model: &str, messages: &[OpenAIMessage]) -> String {
     
     template.render(system, &history, user_input)
 }
-
-/// Simple token estimation (~4 chars per token)
-fn estimate_tokens(text: &str) -> usize {
-    (text.len() + 3) / 4
-}
@@ -5,7 +5,6 @@
 //! Unified HTTP server with health checks, CORS, and graceful shutdown.
 
 use axum::{
-    extract::State,
     http::{header, Method},
     response::IntoResponse,
     routing::{get, post},
@@ -209,7 +208,7 @@ impl<E: InferenceEngine> ServerBuilder<E> {
     
     pub fn build(self) -> Result<(Arc<AppState<E>>, ServerConfig), &'static str> {
         let engine = self.engine.ok_or("Engine is required")?;
-        let registry = self.registry.unwrap_or_else(Registry::new);
+        let registry = self.registry.unwrap_or_default();
         
         let state = Arc::new(AppState::new(engine, registry));
         Ok((state, self.config))
@@ -7,13 +7,22 @@ use axum::{
 	Json,
 };
 use serde::{Deserialize, Serialize};
+use futures::{SinkExt, Stream, StreamExt};
 use serde_json::{json, Value};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-	engine::{GenOptions, InferenceEngine},
+	context_window::{ContextLimit, ContextReport, ContextStrategy},
+	engine::{
+		collect_completion, grammar::Grammar, sampling::Mirostat, FinishReason, GenOptions, LoadedModel,
+		SpeculativeStats, TokenLogprobs,
+	},
 	model_registry::ModelSpec,
+	openai_compat::StopTokens,
+	error::Error,
+	scheduler::Ticket,
 	server::AppState,
 	templates::{detect_template_family, TemplateFamily},
 };
@@ -25,10 +34,115 @@ pub struct GenerateRequest {
 	pub messages: Option<Vec<ChatMessage>>,
 	pub system: Option<String>,
 	pub max_tokens: Option<usize>,
+	#[serde(flatten)]
+	pub sampling: SamplingParams,
+	/// Extra stop sequences on top of the template's own.
+	pub stop: Option<StopTokens>,
+	pub stream: Option<bool>,
+	/// Queue priority (higher runs sooner) when the server uses priority ordering.
+	pub priority: Option<i32>,
+	/// `false` skips prompt-prefix cache reuse and storage for this request.
+	pub cache_prompt: Option<bool>,
+	/// GBNF grammar the output must match.
+	pub grammar: Option<String>,
+	/// Report each generated token's log-probability.
+	pub logprobs: Option<bool>,
+	/// Alternatives to report per token (0-20); requires `logprobs`.
+	pub top_logprobs: Option<usize>,
+	/// LoRA adapter(s) to decode with; omitted uses the model's default.
+	pub adapter: Option<AdapterSelection>,
+	/// How to fit `messages` that overflow the context window; omitted uses
+	/// the server's `--context-strategy`.
+	pub context_strategy: Option<ContextStrategy>,
+}
+
+/// A request's choice of LoRA adapters: one name, several names at full
+/// weight, or names mapped to blend weights. An empty list selects the
+/// bare base model.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum AdapterSelection {
+	One(String),
+	Many(Vec<String>),
+	Weighted(std::collections::BTreeMap<String, f32>),
+}
+
+impl AdapterSelection {
+	pub fn into_vec(self) -> Vec<(String, f32)> {
+		match self {
+			AdapterSelection::One(name) => vec![(name, 1.0)],
+			AdapterSelection::Many(names) => names.into_iter().map(|n| (n, 1.0)).collect(),
+			AdapterSelection::Weighted(weights) => weights.into_iter().collect(),
+		}
+	}
+}
+
+/// Sampler settings accepted by both the HTTP and WebSocket generate requests;
+/// anything left out keeps the `GenOptions` default.
+#[derive(Debug, Default, Deserialize)]
+pub struct SamplingParams {
 	pub temperature: Option<f32>,
 	pub top_p: Option<f32>,
 	pub top_k: Option<i32>,
-	pub stream: Option<bool>,
+	pub min_p: Option<f32>,
+	pub typical_p: Option<f32>,
+	pub repeat_penalty: Option<f32>,
+	pub repeat_last_n: Option<usize>,
+	pub frequency_penalty: Option<f32>,
+	pub presence_penalty: Option<f32>,
+	/// 0 disables mirostat, 2 selects mirostat v2 (v1 is not supported).
+	pub mirostat: Option<u8>,
+	pub mirostat_tau: Option<f32>,
+	pub mirostat_eta: Option<f32>,
+	pub seed: Option<u64>,
+}
+
+impl SamplingParams {
+	pub fn apply(&self, opts: &mut GenOptions) -> Result<(), String> {
+		if let Some(v) = self.temperature {
+			opts.temperature = v;
+		}
+		if let Some(v) = self.top_p {
+			opts.top_p = v;
+		}
+		if let Some(v) = self.top_k {
+			opts.top_k = v;
+		}
+		if let Some(v) = self.min_p {
+			opts.min_p = v;
+		}
+		if let Some(v) = self.typical_p {
+			opts.typical_p = v;
+		}
+		if let Some(v) = self.repeat_penalty {
+			opts.repeat_penalty = v;
+		}
+		if let Some(v) = self.repeat_last_n {
+			opts.repeat_last_n = v;
+		}
+		if let Some(v) = self.frequency_penalty {
+			opts.frequency_penalty = v;
+		}
+		if let Some(v) = self.presence_penalty {
+			opts.presence_penalty = v;
+		}
+		if self.seed.is_some() {
+			opts.seed = self.seed;
+		}
+
+		match self.mirostat.unwrap_or(0) {
+			0 => opts.mirostat = None,
+			2 => {
+				let defaults = Mirostat::default();
+				opts.mirostat = Some(Mirostat {
+					tau: self.mirostat_tau.unwrap_or(defaults.tau),
+					eta: self.mirostat_eta.unwrap_or(defaults.eta),
+				});
+			}
+			other => return Err(format!("unsupported mirostat mode {} (use 0 or 2)", other)),
+		}
+		Ok(())
+	}
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -40,6 +154,20 @@ pub struct ChatMessage {
 #[derive(Debug, Serialize)]
 pub struct GenerateResponse {
 	pub response: String,
+	pub finish_reason: FinishReason,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub stop_sequence: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub prompt_tokens: Option<usize>,
+	pub completion_tokens: usize,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub logprobs: Option<Vec<TokenLogprobs>>,
+	/// Draft acceptance for this request, when the model has a draft.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub speculative: Option<SpeculativeStats>,
+	/// How `messages` were fitted to the context window.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub context: Option<ContextReport>,
 }
 
 #[derive(Debug, Serialize)]
@@ -57,35 +185,70 @@ pub struct ModelInfo {
 	pub is_discovered: bool,
 }
 
-#[derive(Debug)]
-enum ApiError {
-	ModelNotFound(String),
-	GenerationFailed(String),
-	InvalidRequest(String),
+/// Parses a request's GBNF grammar, refusing backends that cannot enforce it.
+pub(crate) fn compile_grammar(src: Option<&str>, model: &dyn LoadedModel) -> Result<Option<Arc<Grammar>>, String> {
+	let Some(src) = src else {
+		return Ok(None);
+	};
+	let grammar = Grammar::parse(src).map_err(|e| format!("invalid grammar: {}", e))?;
+	if !model.supports_grammar() {
+		return Err("this model's backend does not support grammar-constrained decoding".into());
+	}
+	Ok(Some(Arc::new(grammar)))
 }
 
-impl ApiError {
-	fn status(&self) -> StatusCode {
-		match self {
-			ApiError::ModelNotFound(_) => StatusCode::NOT_FOUND,
-			ApiError::GenerationFailed(_) => StatusCode::BAD_GATEWAY,
-			ApiError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
-		}
+/// Turns a request's `adapter` field into `GenOptions::adapters`, checking
+/// every name against the adapters resident on `model`.
+pub(crate) fn adapter_options(
+	selection: Option<AdapterSelection>,
+	model: &dyn LoadedModel,
+) -> Result<Option<Vec<(String, f32)>>, String> {
+	let Some(selection) = selection else {
+		return Ok(None);
+	};
+	let adapters = selection.into_vec();
+	if adapters.is_empty() {
+		return Ok(Some(adapters));
 	}
-
-	fn message(&self) -> String {
-		match self {
-			ApiError::ModelNotFound(m) => m.clone(),
-			ApiError::GenerationFailed(m) => m.clone(),
-			ApiError::InvalidRequest(m) => m.clone(),
+	if !model.supports_adapters() {
+		return Err("this model's backend does not support LoRA adapters".into());
+	}
+	let loaded = model.adapters();
+	for (name, weight) in &adapters {
+		if !weight.is_finite() {
+			return Err(format!("adapter {} has a non-finite weight", name));
+		}
+		if !loaded.iter().any(|a| &a.name == name) {
+			let names: Vec<&str> = loaded.iter().map(|a| a.name.as_str()).collect();
+			return Err(format!("adapter {} is not loaded (available: {})", name, names.join(", ")));
 		}
 	}
+	Ok(Some(adapters))
 }
 
-impl IntoResponse for ApiError {
-	fn into_response(self) -> axum::response::Response {
-		(self.status(), Json(json!({ "error": self.message() }))).into_response()
+/// Most alternatives a request may ask for per token, as in the OpenAI API.
+const MAX_TOP_LOGPROBS: usize = 20;
+
+/// Turns a request's `logprobs`/`top_logprobs` pair into
+/// `GenOptions::logprobs`.
+pub(crate) fn logprob_options(
+	logprobs: Option<bool>,
+	top_logprobs: Option<usize>,
+	model: &dyn LoadedModel,
+) -> Result<Option<usize>, String> {
+	if top_logprobs.is_some_and(|n| n > MAX_TOP_LOGPROBS) {
+		return Err(format!("top_logprobs must be between 0 and {}", MAX_TOP_LOGPROBS));
+	}
+	if !logprobs.unwrap_or(false) {
+		if top_logprobs.is_some() {
+			return Err("top_logprobs requires logprobs to be true".into());
+		}
+		return Ok(None);
+	}
+	if !model.supports_logprobs() {
+		return Err("this model's backend does not report token log-probabilities".into());
 	}
+	Ok(Some(top_logprobs.unwrap_or(0)))
 }
 
 pub async fn generate(State(state): State<Arc<AppState>>, Json(req): Json<GenerateRequest>) -> impl IntoResponse {
@@ -95,89 +258,182 @@ pub async fn generate(State(state): State<Arc<AppState>>, Json(req): Json<Genera
 	}
 }
 
-async fn generate_inner(state: Arc<AppState>, req: GenerateRequest) -> Result<axum::response::Response, ApiError> {
+async fn generate_inner(state: Arc<AppState>, req: GenerateRequest) -> Result<axum::response::Response, Error> {
 	let spec = {
 		let reg = state.registry.read().await;
-		reg.to_spec(&req.model)
-			.ok_or_else(|| ApiError::ModelNotFound(format!("Model not found: {}", req.model)))?
+		reg.to_spec(&req.model).ok_or_else(|| Error::model_not_found(&req.model))?
 	};
 
-	let loaded = state
-		.engine
-		.load(&spec)
-		.await
-		.map_err(|e| ApiError::GenerationFailed(e.to_string()))?;
-
-	let (prompt, family) = build_prompt(&spec, &req)?;
+	// Queue before loading so a full queue is refused without touching the pool.
+	let ticket = state
+		.scheduler
+		.enqueue(&spec.name, req.priority.unwrap_or(0))?;
+	let loaded = state.load_model(&spec).await?;
 
 	let mut opts = GenOptions::default();
 	if let Some(v) = req.max_tokens {
 		opts.max_tokens = v;
 	}
-	if let Some(v) = req.temperature {
-		opts.temperature = v;
-	}
-	if let Some(v) = req.top_p {
-		opts.top_p = v;
-	}
-	if let Some(v) = req.top_k {
-		opts.top_k = v;
-	}
+	let count = |prompt: &str| loaded.count_prompt_tokens(prompt);
+	let limit = ContextLimit {
+		context_length: context_length(&spec, loaded.as_ref()),
+		max_tokens: opts.max_tokens,
+		count_tokens: &count,
+	};
+	let strategy = req.context_strategy.unwrap_or(state.context_strategy);
+	let (prompt, family, context) = build_prompt(&spec, &req, &limit, strategy)?;
+	req.sampling.apply(&mut opts).map_err(Error::invalid_request)?;
+	opts.grammar =
+		compile_grammar(req.grammar.as_deref(), loaded.as_ref()).map_err(|e| Error::invalid_param("grammar", e))?;
+	opts.logprobs = logprob_options(req.logprobs, req.top_logprobs, loaded.as_ref())
+		.map_err(|e| Error::invalid_param("logprobs", e))?;
+	let want_logprobs = opts.logprobs.is_some();
+	opts.adapters = adapter_options(req.adapter, loaded.as_ref()).map_err(|e| Error::invalid_param("adapter", e))?;
+	opts.cache_prompt = req.cache_prompt.unwrap_or(true);
 	let stream = req.stream.unwrap_or(false);
 	opts.stream = stream;
 	opts.stop_tokens = family.stop_tokens();
+	opts.stop_tokens.extend(req.stop.map(StopTokens::into_vec).unwrap_or_default());
+
+	let tracker = state.observability.track_generation();
 
 	if stream {
 		let (tx, rx) = mpsc::unbounded_channel::<Result<Event, std::convert::Infallible>>();
-		let prompt_clone = prompt.clone();
-		let tx_tokens = tx.clone();
+		let stream = cancel_on_drop(UnboundedReceiverStream::new(rx), opts.cancel.clone());
+		let cancel = opts.cancel.clone();
 		tokio::spawn(async move {
-			let on_token = move |tok: String| {
-				let _ = tx_tokens.send(Ok(Event::default().data(tok)));
+			let queued = ticket.wait(|position| {
+				let _ = tx.send(Ok(Event::default().event("queued").data(json!({"position": position}).to_string())));
+			});
+			let _permit = tokio::select! {
+				permit = queued => match permit {
+					Ok(permit) => permit,
+					Err(e) => {
+						let data = Error::from(e).native_body().to_string();
+						let _ = tx.send(Ok(Event::default().event("error").data(data)));
+						return;
+					}
+				},
+				_ = cancel.cancelled() => return,
 			};
+			if let Some(report) = &context {
+				let data = serde_json::to_string(report).unwrap_or_default();
+				let _ = tx.send(Ok(Event::default().event("context").data(data)));
+			}
 
-			let _ = loaded
-				.generate(&prompt_clone, opts, Some(Box::new(on_token)))
-				.await;
+			let mut finish = Some(FinishReason::Stop);
+			let mut stop_sequence = None;
+			let mut speculative = None;
+			let mut tokens = state.scheduler.generate(&spec.name, &loaded, &prompt, opts);
+			while let Some(event) = tokens.next().await {
+				match event {
+					Ok(ev) => {
+						if let Some(reason) = ev.finish_reason {
+							finish = Some(reason);
+							stop_sequence = ev.stop_sequence;
+						}
+						if ev.speculative.is_some() {
+							speculative = ev.speculative;
+						}
+						if let Some(lp) = &ev.logprobs {
+							let data = serde_json::to_string(lp).unwrap_or_default();
+							let _ = tx.send(Ok(Event::default().event("logprobs").data(data)));
+						}
+						if !ev.text.is_empty() && tx.send(Ok(Event::default().data(ev.text))).is_err() {
+							cancel.cancel();
+						}
+					}
+					Err(e) => {
+						let data = Error::from(e).native_body().to_string();
+						let _ = tx.send(Ok(Event::default().event("error").data(data)));
+						finish = None;
+						break;
+					}
+				}
+			}
+			if let Some(stop) = stop_sequence {
+				let _ = tx.send(Ok(Event::default().event("stop").data(stop)));
+			}
+			if let Some(stats) = speculative {
+				let data = serde_json::to_string(&stats).unwrap_or_default();
+				let _ = tx.send(Ok(Event::default().event("speculative").data(data)));
+			}
 			let _ = tx.send(Ok(Event::default().data("[DONE]")));
+			match finish {
+				Some(reason) => tracker.finish(reason),
+				None => tracker.fail(),
+			}
 		});
 
-		let stream = UnboundedReceiverStream::new(rx);
 		let sse = Sse::new(stream).keep_alive(axum::response::sse::KeepAlive::default());
 		return Ok(sse.into_response());
 	}
 
-	let response = loaded
-		.generate(&prompt, opts, None)
-		.await
-		.map_err(|e| ApiError::GenerationFailed(e.to_string()))?;
+	let _permit = ticket.wait(|_| {}).await?;
+	let completion = match collect_completion(state.scheduler.generate(&spec.name, &loaded, &prompt, opts)).await {
+		Ok(c) => c,
+		Err(e) => {
+			tracker.fail();
+			return Err(e.into());
+		}
+	};
+	tracker.finish(completion.finish_reason);
+
+	Ok(Json(GenerateResponse {
+		response: completion.text,
+		finish_reason: completion.finish_reason,
+		stop_sequence: completion.stop_sequence,
+		prompt_tokens: loaded.count_prompt_tokens(&prompt),
+		completion_tokens: completion.completion_tokens,
+		logprobs: want_logprobs.then_some(completion.logprobs),
+		speculative: completion.speculative,
+		context,
+	})
+	.into_response())
+}
 
-	Ok(Json(GenerateResponse { response }).into_response())
+/// Wraps an SSE body so that dropping it (the client went away) trips `cancel`.
+pub(crate) fn cancel_on_drop<S: Stream>(stream: S, cancel: CancellationToken) -> impl Stream<Item = S::Item> {
+	let guard = cancel.drop_guard();
+	stream.map(move |item| {
+		let _ = &guard;
+		item
+	})
 }
 
-fn build_prompt(spec: &ModelSpec, req: &GenerateRequest) -> Result<(String, TemplateFamily), ApiError> {
+fn build_prompt(
+	spec: &ModelSpec,
+	req: &GenerateRequest,
+	limit: &ContextLimit,
+	strategy: ContextStrategy,
+) -> Result<(String, TemplateFamily, Option<ContextReport>), Error> {
+	let fam = template_from_spec(spec);
 	if let Some(p) = &req.prompt {
-		let fam = template_from_spec(spec);
-		return Ok((p.clone(), fam));
+		limit.check(p)?;
+		return Ok((p.clone(), fam, None));
 	}
 
 	let messages = req
 		.messages
 		.as_ref()
-		.ok_or_else(|| ApiError::InvalidRequest("Either prompt or messages must be provided".into()))?;
+		.ok_or_else(|| Error::invalid_request("Either prompt or messages must be provided"))?;
 
 	let (system, history, last_user) = split_messages(req.system.as_deref(), messages);
-	let fam = template_from_spec(spec);
-	let prompt = fam.render(system.as_deref(), &history, last_user.as_deref());
-	Ok((prompt, fam))
+	let (prompt, report) = limit.fit_chat(&fam, system.as_deref(), history, last_user.as_deref(), strategy)?;
+	Ok((prompt, fam, report))
 }
 
-fn template_from_spec(spec: &ModelSpec) -> TemplateFamily {
-	match spec.template.as_deref() {
-		Some("chatml") => TemplateFamily::ChatML,
-		Some("llama3") | Some("llama-3") => TemplateFamily::Llama3,
-		_ => detect_template_family(&spec.name),
-	}
+/// The window a request's prompt and completion share: the model's
+/// configured `ctx_len`, else whatever the backend loaded with.
+pub(crate) fn context_length(spec: &ModelSpec, model: &dyn LoadedModel) -> Option<usize> {
+	spec.ctx_len.or_else(|| model.context_length())
+}
+
+pub(crate) fn template_from_spec(spec: &ModelSpec) -> TemplateFamily {
+	spec.template
+		.as_deref()
+		.and_then(TemplateFamily::from_name)
+		.unwrap_or_else(|| detect_template_family(&spec.name))
 }
 
 pub(crate) fn split_messages(
@@ -269,21 +525,136 @@ pub async fn load_model(State(state): State<Arc<AppState>>, Path(name): Path<Str
 		let reg = state.registry.read().await;
 		match reg.to_spec(&name) {
 			Some(s) => s,
-			None => return ApiError::ModelNotFound(format!("Model not found: {}", name)).into_response(),
+			None => return Error::model_not_found(&name).into_response(),
+		}
+	};
+	let loaded = match state.load_model(&spec).await {
+		Ok(m) => m,
+		Err(e) => return Error::from(e).into_response(),
+	};
+	let status = state.pool.status(&name);
+	// Shows whether the context had to shrink to fit.
+	let memory = loaded.memory_plan();
+	Json(json!({"ok": true, "model": name, "loaded": true, "pool": status, "memory": memory})).into_response()
+}
+
+pub async fn unload_model(State(state): State<Arc<AppState>>, Path(name): Path<String>) -> impl IntoResponse {
+	let unloaded = state.pool.unload(&name);
+	state.scheduler.forget(&name);
+	Json(json!({"model": name, "unloaded": unloaded}))
+}
+
+pub async fn model_status(State(state): State<Arc<AppState>>, Path(name): Path<String>) -> impl IntoResponse {
+	let spec = state.registry.read().await.to_spec(&name);
+	match state.pool.status(&name) {
+		Some(status) => Json(json!({
+			"model": name,
+			"status": "loaded",
+			"loaded": true,
+			"pool": status,
+			"queue": state.scheduler.stats(&name),
+			"prefix_cache": state.pool.prefix_cache_stats().remove(&name),
+			"speculative": state.pool.speculative_stats().remove(&name),
+			"memory": state.pool.get(&name).and_then(|m| m.memory_plan()),
+		}))
+		.into_response(),
+		None => {
+			let Some(spec) = spec else {
+				return Error::model_not_found(&name).into_response();
+			};
+			// What a load would take, against the memory free right now.
+			let memory = state.engine.plan_memory(&state.spec_with_slots(&spec));
+			Json(json!({"model": name, "status": "not_loaded", "loaded": false, "memory": memory})).into_response()
 		}
+	}
+}
+
+pub async fn list_adapters(State(state): State<Arc<AppState>>, Path(name): Path<String>) -> impl IntoResponse {
+	let Some(spec) = state.registry.read().await.to_spec(&name) else {
+		return Error::model_not_found(&name).into_response();
+	};
+	if let Some(loaded) = state.pool.get(&name) {
+		return Json(json!({"model": name, "loaded": true, "adapters": loaded.adapters()})).into_response();
+	}
+	// Not resident: report what will be loaded with it.
+	let mut adapters: Vec<Value> = vec![];
+	if let Some(path) = &spec.lora_path {
+		let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("adapter");
+		adapters.push(json!({"name": stem, "path": path, "default": true}));
+	}
+	for (adapter, path) in &spec.adapters {
+		adapters.push(json!({"name": adapter, "path": path, "default": false}));
+	}
+	Json(json!({"model": name, "loaded": false, "adapters": adapters})).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoadAdapterRequest {
+	pub path: String,
+	/// Defaults to the file name without extension.
+	pub name: Option<String>,
+}
+
+/// Loads (or hot-swaps) an adapter onto a model, loading the base model
+/// first if it is not resident. The adapter is remembered and restored if
+/// the model is later evicted and reloaded.
+pub async fn load_adapter(
+	State(state): State<Arc<AppState>>,
+	Path(name): Path<String>,
+	Json(req): Json<LoadAdapterRequest>,
+) -> impl IntoResponse {
+	let Some(spec) = state.registry.read().await.to_spec(&name) else {
+		return Error::model_not_found(&name).into_response();
 	};
-	match state.engine.load(&spec).await {
-		Ok(_) => Json(json!({"ok": true})).into_response(),
-		Err(e) => ApiError::GenerationFailed(e.to_string()).into_response(),
+	let path = std::path::PathBuf::from(&req.path);
+	if !adapter_path_allowed(&state, &spec, &path) {
+		let message = format!("{} is neither an adapter of {} nor inside an adapter directory", req.path, name);
+		return Error::invalid_param("path", message).into_response();
 	}
+	let adapter = match req.name {
+		Some(adapter) => adapter,
+		None => path.file_stem().and_then(|s| s.to_str()).unwrap_or("adapter").to_string(),
+	};
+	let loaded = match state.load_model(&spec).await {
+		Ok(m) => m,
+		Err(e) => return Error::from(e).into_response(),
+	};
+	let (task_name, task_path) = (adapter.clone(), path.clone());
+	let info = match tokio::task::spawn_blocking(move || loaded.load_adapter(&task_name, &task_path)).await {
+		Ok(Ok(info)) => info,
+		Ok(Err(e)) => return Error::invalid_param("path", e.to_string()).into_response(),
+		Err(e) => return Error::from(e).into_response(),
+	};
+	state.registry.write().await.add_adapter(&name, &adapter, path);
+	Json(json!({"ok": true, "model": name, "adapter": info})).into_response()
 }
 
-pub async fn unload_model(_state: State<Arc<AppState>>, Path(name): Path<String>) -> impl IntoResponse {
-	Json(json!({"model": name, "unloaded": false}))
+/// Whether the adapter API may read `path`: a file already registered as an
+/// adapter of the model, or one inside a directory given with
+/// `--adapter-dir`. Both sides are canonicalized, so neither `..` nor a
+/// symlink leads outside them.
+fn adapter_path_allowed(state: &AppState, spec: &ModelSpec, path: &std::path::Path) -> bool {
+	let Ok(path) = path.canonicalize() else {
+		return false;
+	};
+	let registered = spec.lora_path.iter().chain(spec.adapters.values());
+	registered.filter_map(|p| p.canonicalize().ok()).any(|p| p == path)
+		|| state.adapter_dirs.iter().filter_map(|d| d.canonicalize().ok()).any(|d| path.starts_with(d))
 }
 
-pub async fn model_status(_state: State<Arc<AppState>>, Path(name): Path<String>) -> impl IntoResponse {
-	Json(json!({"model": name, "status": "unknown", "loaded": false}))
+pub async fn unload_adapter(
+	State(state): State<Arc<AppState>>,
+	Path((name, adapter)): Path<(String, String)>,
+) -> impl IntoResponse {
+	// A model that is not resident has no adapters loaded, whatever the
+	// registry would restore with it.
+	let unloaded = state.pool.get(&name).is_some_and(|m| m.unload_adapter(&adapter));
+	if !unloaded {
+		return Error::AdapterNotFound(format!("Adapter {} is not loaded on {}", adapter, name)).into_response();
+	}
+	// Otherwise the next reload would bring it back.
+	state.registry.write().await.remove_adapter(&name, &adapter);
+	Json(json!({"model": name, "adapter": adapter, "unloaded": true})).into_response()
 }
 
 pub async fn ws_generate(State(state): State<Arc<AppState>>, ws: WebSocketUpgrade) -> impl IntoResponse {
@@ -295,9 +666,64 @@ struct WsGenerateRequest {
 	model: String,
 	prompt: String,
 	max_tokens: Option<usize>,
-	temperature: Option<f32>,
-	top_p: Option<f32>,
-	top_k: Option<i32>,
+	#[serde(flatten)]
+	sampling: SamplingParams,
+	stop: Option<StopTokens>,
+	priority: Option<i32>,
+	cache_prompt: Option<bool>,
+	grammar: Option<String>,
+	logprobs: Option<bool>,
+	top_logprobs: Option<usize>,
+	adapter: Option<AdapterSelection>,
+}
+
+/// A WebSocket generation whose request has been checked and queued.
+struct WsJob {
+	prompt: String,
+	spec: ModelSpec,
+	loaded: Arc<dyn LoadedModel>,
+	opts: GenOptions,
+	ticket: Ticket,
+}
+
+async fn prepare_ws_job(state: &AppState, text: &str) -> Result<WsJob, Error> {
+	let req: WsGenerateRequest =
+		serde_json::from_str(text).map_err(|e| Error::invalid_request(format!("invalid request: {}", e)))?;
+	let spec = {
+		let reg = state.registry.read().await;
+		reg.to_spec(&req.model).ok_or_else(|| Error::model_not_found(&req.model))?
+	};
+	let ticket = state.scheduler.enqueue(&spec.name, req.priority.unwrap_or(0))?;
+	let loaded = state.load_model(&spec).await?;
+
+	let mut opts = GenOptions::default();
+	if let Some(v) = req.max_tokens {
+		opts.max_tokens = v;
+	}
+	req.sampling.apply(&mut opts).map_err(Error::invalid_request)?;
+	opts.grammar =
+		compile_grammar(req.grammar.as_deref(), loaded.as_ref()).map_err(|e| Error::invalid_param("grammar", e))?;
+	opts.logprobs = logprob_options(req.logprobs, req.top_logprobs, loaded.as_ref())
+		.map_err(|e| Error::invalid_param("logprobs", e))?;
+	opts.adapters = adapter_options(req.adapter, loaded.as_ref()).map_err(|e| Error::invalid_param("adapter", e))?;
+	let count = |prompt: &str| loaded.count_prompt_tokens(prompt);
+	let limit = ContextLimit {
+		context_length: context_length(&spec, loaded.as_ref()),
+		max_tokens: opts.max_tokens,
+		count_tokens: &count,
+	};
+	limit.check(&req.prompt)?;
+	opts.cache_prompt = req.cache_prompt.unwrap_or(true);
+	opts.stop_tokens = template_from_spec(&spec).stop_tokens();
+	opts.stop_tokens.extend(req.stop.map(StopTokens::into_vec).unwrap_or_default());
+
+	Ok(WsJob {
+		prompt: req.prompt,
+		spec,
+		loaded,
+		opts,
+		ticket,
+	})
 }
 
 async fn handle_ws_generate(state: Arc<AppState>, mut socket: axum::extract::ws::WebSocket) {
@@ -308,72 +734,109 @@ async fn handle_ws_generate(state: Arc<AppState>, mut socket: axum::extract::ws:
 		return;
 	};
 
-	let req: WsGenerateRequest = match serde_json::from_str(&text) {
-		Ok(r) => r,
-		Err(_) => {
-			let _ = socket
-				.send(Message::Text("{\"error\":\"invalid_request\"}".into()))
-				.await;
+	let WsJob {
+		prompt,
+		spec,
+		loaded,
+		opts,
+		ticket,
+	} = match prepare_ws_job(&state, &text).await {
+		Ok(job) => job,
+		Err(e) => {
+			let _ = socket.send(Message::Text(e.ws_frame())).await;
 			let _ = socket.send(Message::Close(None)).await;
 			return;
 		}
 	};
 
-	let spec = {
-		let reg = state.registry.read().await;
-		reg.to_spec(&req.model)
-	};
-	let Some(spec) = spec else {
-		let _ = socket
-			.send(Message::Text(format!("{{\"error\":\"model_not_found\",\"model\":\"{}\"}}", req.model)))
-			.await;
-		let _ = socket.send(Message::Close(None)).await;
-		return;
-	};
+	// Watch the read half so a closed socket cancels generation even while
+	// no token is being written.
+	let (mut sender, mut receiver) = socket.split();
+	let cancel = opts.cancel.clone();
+	let watcher = tokio::spawn({
+		let cancel = cancel.clone();
+		async move {
+			while let Some(msg) = receiver.next().await {
+				if matches!(msg, Ok(Message::Close(_)) | Err(_)) {
+					break;
+				}
+			}
+			cancel.cancel();
+		}
+	});
 
-	let loaded = match state.engine.load(&spec).await {
-		Ok(m) => m,
-		Err(e) => {
-			let _ = socket
-				.send(Message::Text(format!("{{\"error\":\"load_failed\",\"message\":\"{}\"}}", e)))
-				.await;
-			let _ = socket.send(Message::Close(None)).await;
-			return;
+	let tracker = state.observability.track_generation();
+	let (position_tx, mut positions) = mpsc::unbounded_channel();
+	let queued = ticket.wait(move |position| {
+		let _ = position_tx.send(position);
+	});
+	tokio::pin!(queued);
+	let _permit = loop {
+		tokio::select! {
+			permit = &mut queued => match permit {
+				Ok(permit) => break permit,
+				Err(e) => {
+					watcher.abort();
+					let _ = sender.send(Message::Text(Error::from(e).ws_frame())).await;
+					let _ = sender.send(Message::Close(None)).await;
+					return;
+				}
+			},
+			Some(position) = positions.recv() => {
+				let msg = json!({"queued": true, "position": position}).to_string();
+				let _ = sender.send(Message::Text(msg)).await;
+			}
+			_ = cancel.cancelled() => {
+				watcher.abort();
+				return;
+			}
 		}
 	};
 
-	let mut opts = GenOptions::default();
-	if let Some(v) = req.max_tokens {
-		opts.max_tokens = v;
-	}
-	if let Some(v) = req.temperature {
-		opts.temperature = v;
-	}
-	if let Some(v) = req.top_p {
-		opts.top_p = v;
+	let mut finish = Some(FinishReason::Stop);
+	let mut stop_sequence = None;
+	let mut speculative = None;
+	let mut tokens = state.scheduler.generate(&spec.name, &loaded, &prompt, opts);
+	while let Some(event) = tokens.next().await {
+		match event {
+			Ok(ev) => {
+				if let Some(reason) = ev.finish_reason {
+					finish = Some(reason);
+					stop_sequence = ev.stop_sequence;
+				}
+				if ev.speculative.is_some() {
+					speculative = ev.speculative;
+				}
+				if let Some(lp) = &ev.logprobs {
+					let _ = sender.send(Message::Text(json!({"logprobs": lp}).to_string())).await;
+				}
+				if !ev.text.is_empty() && sender.send(Message::Text(ev.text)).await.is_err() {
+					cancel.cancel();
+				}
+			}
+			Err(e) => {
+				let _ = sender.send(Message::Text(Error::from(e).ws_frame())).await;
+				finish = None;
+				break;
+			}
+		}
 	}
-	if let Some(v) = req.top_k {
-		opts.top_k = v;
+	watcher.abort();
+	match finish {
+		Some(reason) => tracker.finish(reason),
+		None => tracker.fail(),
 	}
 
-	let (tx, mut rx) = mpsc::unbounded_channel::<String>();
-	let prompt = req.prompt.clone();
-	let tx_tokens = tx.clone();
-	let gen = tokio::spawn(async move {
-		let cb = move |t: String| {
-			let _ = tx_tokens.send(t);
-		};
-		let _ = loaded.generate(&prompt, opts, Some(Box::new(cb))).await;
-		let _ = tx.send("[DONE]".to_string());
-	});
-
-	while let Some(tok) = rx.recv().await {
-		let _ = socket.send(Message::Text(tok)).await;
+	let _ = sender.send(Message::Text("[DONE]".into())).await;
+	let mut done = json!({"done": true});
+	if let Some(stop) = stop_sequence {
+		done["stop_sequence"] = json!(stop);
 	}
-
-	let _ = gen.await;
-	let _ = socket.send(Message::Text("{\"done\": true}".into())).await;
-	let _ = socket.send(Message::Close(None)).await;
+	if let Some(stats) = speculative {
+		done["speculative"] = json!(stats);
+	}
+	let _ = sender.send(Message::Text(done.to_string())).await;
+	let _ = sender.send(Message::Close(None)).await;
 }
 
 pub async fn list_tools() -> impl IntoResponse {
@@ -393,3 +856,101 @@ pub async fn execute_workflow() -> impl IntoResponse {
 		Json(json!({"error": "workflow execution not implemented"})),
 	)
 }
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::{
+		engine::mock::{Script, ScriptedEngine},
+		scheduler::SchedulerConfig,
+		testing::{self, read_until, ws_close, ws_recv_text, ws_send_text},
+	};
+
+	/// A model that would talk for a good while if nobody stopped it.
+	fn slow_engine() -> ScriptedEngine {
+		ScriptedEngine::new().otherwise(Script::new(vec!["tok "; 200]).with_latency(Duration::from_millis(10)))
+	}
+
+	fn generations(metrics: &Value) -> (u64, u64, u64) {
+		let g = &metrics["generations"];
+		let count = |k: &str| g[k].as_u64().unwrap_or(0);
+		(count("completed"), count("cancelled"), count("failed"))
+	}
+
+	#[tokio::test]
+	async fn a_full_queue_answers_429_with_retry_after() {
+		let config = SchedulerConfig {
+			max_queue: 0,
+			..SchedulerConfig::default()
+		};
+		let state = AppState::new_with_limits(Box::new(slow_engine()), testing::registry(&["m"]), None, config);
+		let server = testing::serve(state).await;
+		let body = json!({"model": "m", "prompt": "hi", "stream": true, "max_tokens": 200});
+		let mut running = server.send("POST", "/api/generate", Some(&body)).await;
+		read_until(&mut running, "data: tok").await;
+
+		let refused = server.post("/api/generate", json!({"model": "m", "prompt": "hi"})).await;
+		assert_eq!(refused.status, 429);
+		assert_eq!(refused.header("retry-after"), Some("1"));
+		assert_eq!(refused.json()["code"], "overloaded");
+	}
+
+	#[tokio::test]
+	async fn unloading_an_adapter_the_model_never_loaded_is_not_found() {
+		let mut registry = testing::registry(&["m"]);
+		registry.add_adapter("m", "style", "/adapters/style.gguf".into());
+		let server = testing::serve(AppState::new(Box::new(ScriptedEngine::new()), registry)).await;
+
+		let resp = server.request("DELETE", "/api/models/m/adapters/style", None).await;
+		assert_eq!(resp.status, 404);
+		assert_eq!(resp.json()["code"], "adapter_not_found");
+		// Still registered, so it loads with the model next time.
+		let listed = server.request("GET", "/api/models/m/adapters", None).await.json();
+		assert_eq!(listed["adapters"][0]["name"], "style");
+
+		assert_eq!(server.post("/api/models/m/load", json!({})).await.status, 200);
+		let resp = server.request("DELETE", "/api/models/m/adapters/style", None).await;
+		assert_eq!(resp.status, 404);
+	}
+
+	#[tokio::test]
+	async fn dropping_an_sse_stream_cancels_its_generation() {
+		let engine = slow_engine();
+		let server = testing::serve(testing::state(engine.clone(), &["m"])).await;
+		let body = json!({"model": "m", "prompt": "hi", "stream": true, "max_tokens": 200});
+		let mut stream = server.send("POST", "/api/generate", Some(&body)).await;
+		read_until(&mut stream, "data: tok").await;
+		drop(stream);
+
+		let metrics = server.wait_for_metrics(|m| generations(m).1 == 1).await;
+		assert_eq!(generations(&metrics), (0, 1, 0));
+		assert!(engine.calls()[0].opts.cancel.is_cancelled());
+	}
+
+	#[tokio::test]
+	async fn closing_a_websocket_cancels_its_generation() {
+		let engine = slow_engine();
+		let server = testing::serve(testing::state(engine.clone(), &["m"])).await;
+		let mut socket = server.websocket("/ws/generate").await;
+		ws_send_text(&mut socket, &json!({"model": "m", "prompt": "hi", "max_tokens": 200}).to_string()).await;
+		assert_eq!(ws_recv_text(&mut socket).await.as_deref(), Some("tok "));
+		ws_close(&mut socket).await;
+
+		let metrics = server.wait_for_metrics(|m| generations(m).1 == 1).await;
+		assert_eq!(generations(&metrics), (0, 1, 0));
+		assert!(engine.calls()[0].opts.cancel.is_cancelled());
+	}
+
+	#[tokio::test]
+	async fn finished_generations_are_not_counted_as_cancelled() {
+		let engine = ScriptedEngine::new().otherwise(Script::from_text("all done"));
+		let server = testing::serve(testing::state(engine, &["m"])).await;
+		let res = server.post("/api/generate", json!({"model": "m", "prompt": "hi", "stream": true})).await;
+		assert_eq!(res.status, 200);
+		assert_eq!(res.events(), vec!["all ", "done", "[DONE]"]);
+		let metrics = server.wait_for_metrics(|m| generations(m).0 == 1).await;
+		assert_eq!(generations(&metrics), (1, 0, 0));
+	}
+}
@@ -8,6 +8,11 @@ use std::{
 use serde::{Deserialize, Serialize};
 use walkdir::WalkDir;
 
+use crate::{
+	engine::gguf::{format_parameter_count, GgufFile},
+	templates::detect_template_from_chat_template,
+};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DiscoveredModel {
 	pub name: String,
@@ -17,6 +22,12 @@ pub struct DiscoveredModel {
 	pub model_type: String,
 	pub parameter_count: Option<String>,
 	pub quantization: Option<String>,
+	#[serde(default)]
+	pub architecture: Option<String>,
+	#[serde(default)]
+	pub context_length: Option<u64>,
+	#[serde(default)]
+	pub template: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -24,6 +35,12 @@ pub struct ModelAutoDiscovery {
 	pub search_paths: Vec<PathBuf>,
 }
 
+impl Default for ModelAutoDiscovery {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
 impl ModelAutoDiscovery {
 	pub fn new() -> Self {
 		let mut search_paths = vec![];
@@ -109,7 +126,7 @@ pub fn scan_directory_with_depth(path: PathBuf, max_depth: usize) -> Vec<Discove
 		let size_bytes = entry.metadata().map(|m| m.len()).unwrap_or(0);
 		let lora_path = detect_lora_adapter(p);
 
-		models.push(DiscoveredModel {
+		let mut model = DiscoveredModel {
 			name,
 			path: p.to_path_buf(),
 			lora_path,
@@ -117,12 +134,44 @@ pub fn scan_directory_with_depth(path: PathBuf, max_depth: usize) -> Vec<Discove
 			model_type: model_type.to_string(),
 			parameter_count,
 			quantization,
-		});
+			architecture: None,
+			context_length: None,
+			template: None,
+		};
+		if model_type == "gguf" {
+			apply_gguf_metadata(&mut model);
+		}
+		models.push(model);
 	}
 
 	group_sharded_models(models)
 }
 
+/// Replaces filename guesses with values read from the GGUF header when the
+/// file parses; unreadable files keep the filename-derived fields.
+pub fn apply_gguf_metadata(model: &mut DiscoveredModel) {
+	if !GgufFile::has_magic(&model.path) {
+		return;
+	}
+	let Ok(gguf) = GgufFile::open(&model.path) else {
+		return;
+	};
+
+	let params = gguf.parameter_count();
+	if params > 0 {
+		model.parameter_count = Some(format_parameter_count(params));
+	}
+	if let Some(q) = gguf.quantization() {
+		model.quantization = Some(q);
+	}
+	model.architecture = gguf.architecture().map(str::to_string);
+	model.context_length = gguf.context_length();
+	model.template = gguf
+		.chat_template()
+		.and_then(detect_template_from_chat_template)
+		.map(|f| f.name().to_string());
+}
+
 pub fn parse_filename(filename: &str) -> (String, Option<String>, Option<String>) {
 	let stem = filename.rsplit_once('.').map(|(s, _)| s).unwrap_or(filename);
 
@@ -285,7 +334,7 @@ pub fn discover_ollama_models(ollama_path: PathBuf) -> Vec<DiscoveredModel> {
 			.to_string();
 
 		let size_bytes = fs::metadata(&blob_path).map(|m| m.len()).unwrap_or(0);
-		out.push(DiscoveredModel {
+		let mut model = DiscoveredModel {
 			name,
 			path: blob_path,
 			lora_path: None,
@@ -293,12 +342,20 @@ pub fn discover_ollama_models(ollama_path: PathBuf) -> Vec<DiscoveredModel> {
 			model_type: "bin".to_string(),
 			parameter_count: None,
 			quantization: None,
-		});
+			architecture: None,
+			context_length: None,
+			template: None,
+		};
+		// Ollama stores GGUF weights as extensionless blobs.
+		apply_gguf_metadata(&mut model);
+		out.push(model);
 	}
 
 	out
 }
 
+/// Drops image, audio and vision-tower weights that sit next to language
+/// models. Embedding models stay, since `/v1/embeddings` serves them.
 pub fn filter_llm_only(models: HashMap<String, DiscoveredModel>) -> HashMap<String, DiscoveredModel> {
 	let mut out = HashMap::new();
 	for (name, model) in models {
@@ -310,9 +367,7 @@ pub fn filter_llm_only(models: HashMap<String, DiscoveredModel>) -> HashMap<Stri
 			|| n.contains("stable-")
 			|| n.contains("whisper")
 			|| n.contains("vae")
-			|| n.contains("encoder")
-			|| n.contains("embedding")
-			|| n.contains("embed");
+			|| n.contains("encoder");
 		if !blocked {
 			out.insert(name, model);
 		}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
 
 #[derive(Debug, Parser)]
@@ -23,6 +25,14 @@ pub struct Cli {
 	/// Offload first N layers of experts to CPU
 	#[arg(long = "n-cpu-moe")]
 	pub n_cpu_moe: Option<usize>,
+
+	/// Append every generation to this JSONL cassette
+	#[arg(long, conflicts_with = "replay")]
+	pub record: Option<PathBuf>,
+
+	/// Serve generations from this JSONL cassette instead of loading weights
+	#[arg(long)]
+	pub replay: Option<PathBuf>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -32,6 +42,37 @@ pub enum Command {
 		bind: String,
 		#[arg(long)]
 		model_path: Option<String>,
+		/// Memory budget for resident models in MB (least-recently-used models are evicted)
+		#[arg(long = "max-model-memory-mb")]
+		max_model_memory_mb: Option<u64>,
+		/// Generations each model runs at once; backends that can batch decode them together
+		#[arg(long, default_value_t = 1)]
+		parallel: usize,
+		/// Per-model override of --parallel, as NAME=N (repeatable)
+		#[arg(long = "model-parallel")]
+		model_parallel: Vec<String>,
+		/// Context window in tokens for every model, instead of the one it was
+		/// trained with; a load that cannot fit it fails rather than shrinking it
+		#[arg(long = "ctx-len")]
+		ctx_len: Option<usize>,
+		/// Per-model override of --ctx-len, as NAME=N (repeatable)
+		#[arg(long = "model-ctx-len")]
+		model_ctx_len: Vec<String>,
+		/// Draft model for speculative decoding, as NAME=PATH (repeatable)
+		#[arg(long = "model-draft")]
+		model_draft: Vec<String>,
+		/// Requests that may wait per model before new ones get HTTP 429
+		#[arg(long = "max-queue", default_value_t = 32)]
+		max_queue: usize,
+		/// Queue ordering: fifo|priority
+		#[arg(long = "queue-policy", default_value = "fifo")]
+		queue_policy: String,
+		/// Chats too long for the context window: reject|drop_oldest|keep_ends
+		#[arg(long = "context-strategy", default_value = "reject")]
+		context_strategy: String,
+		/// Directory the adapter API may load LoRA files from (repeatable)
+		#[arg(long = "adapter-dir")]
+		adapter_dir: Vec<PathBuf>,
 	},
 	List {
 		#[arg(short, long)]
@@ -55,8 +96,15 @@ pub enum Command {
 		prompt: String,
 		#[arg(long, default_value_t = 64)]
 		max_tokens: usize,
+		#[arg(long)]
+		temperature: Option<f32>,
+		#[arg(long)]
+		seed: Option<u64>,
+	},
+	GpuInfo {
+		/// Also show where this model's MoE expert tensors would be placed
+		name: Option<String>,
 	},
-	GpuInfo,
 	Init {
 		template: String,
 		#[arg(long, default_value = ".")]
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+
+use crate::templates::TemplateFamily;
+
+/// What to do with a chat whose prompt leaves no room for `max_tokens`
+/// inside the model's context window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextStrategy {
+	/// Refuse the request with `context_length_exceeded`.
+	#[default]
+	Reject,
+	/// Drop user/assistant exchanges, oldest first, until the prompt fits.
+	DropOldest,
+	/// Keep the system prompt, the first exchange and the latest turns,
+	/// dropping exchanges from the middle.
+	KeepEnds,
+}
+
+impl ContextStrategy {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"reject" => Some(ContextStrategy::Reject),
+			"drop_oldest" => Some(ContextStrategy::DropOldest),
+			"keep_ends" => Some(ContextStrategy::KeepEnds),
+			_ => None,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			ContextStrategy::Reject => "reject",
+			ContextStrategy::DropOldest => "drop_oldest",
+			ContextStrategy::KeepEnds => "keep_ends",
+		}
+	}
+}
+
+/// How a chat prompt was fitted to the context window, returned with the
+/// response.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContextReport {
+	pub strategy: ContextStrategy,
+	pub context_length: usize,
+	pub prompt_tokens: usize,
+	/// User/assistant exchanges left out of the prompt.
+	pub dropped_turns: usize,
+}
+
+/// A prompt plus `max_tokens` that no strategy could fit.
+#[derive(Debug, Clone)]
+pub struct ContextExceeded {
+	pub context_length: usize,
+	pub prompt_tokens: usize,
+	pub max_tokens: usize,
+}
+
+impl ContextExceeded {
+	/// Worded as the OpenAI API words it, since clients match on it.
+	pub fn message(&self) -> String {
+		format!(
+			"This model's maximum context length is {} tokens. However, you requested {} tokens ({} in the messages, {} in the completion). Please reduce the length of the messages or completion.",
+			self.context_length,
+			self.prompt_tokens + self.max_tokens,
+			self.prompt_tokens,
+			self.max_tokens
+		)
+	}
+}
+
+/// The model's limits for one request. Without a context length or a
+/// tokenizer to count with, prompts pass through unchecked.
+pub struct ContextLimit<'a> {
+	pub context_length: Option<usize>,
+	pub max_tokens: usize,
+	pub count_tokens: &'a (dyn Fn(&str) -> Option<usize> + Sync),
+}
+
+impl ContextLimit<'_> {
+	/// Checks a raw prompt, which cannot be shortened.
+	pub fn check(&self, prompt: &str) -> Result<(), ContextExceeded> {
+		let Some(context_length) = self.context_length else {
+			return Ok(());
+		};
+		match (self.count_tokens)(prompt) {
+			Some(prompt_tokens) if prompt_tokens + self.max_tokens > context_length => Err(ContextExceeded {
+				context_length,
+				prompt_tokens,
+				max_tokens: self.max_tokens,
+			}),
+			_ => Ok(()),
+		}
+	}
+
+	/// Renders a chat, dropping history as `strategy` allows until the prompt
+	/// and `max_tokens` fit. The report is `None` when nothing was measured.
+	pub fn fit_chat(
+		&self,
+		family: &TemplateFamily,
+		system: Option<&str>,
+		mut history: Vec<(String, String)>,
+		last_user: Option<&str>,
+		strategy: ContextStrategy,
+	) -> Result<(String, Option<ContextReport>), ContextExceeded> {
+		let mut dropped = 0;
+		loop {
+			let prompt = family.render(system, &history, last_user);
+			let Some((context_length, prompt_tokens)) = self.context_length.zip((self.count_tokens)(&prompt)) else {
+				return Ok((prompt, None));
+			};
+			if prompt_tokens + self.max_tokens <= context_length {
+				let report = ContextReport {
+					strategy,
+					context_length,
+					prompt_tokens,
+					dropped_turns: dropped,
+				};
+				return Ok((prompt, Some(report)));
+			}
+			let droppable = match strategy {
+				ContextStrategy::Reject => None,
+				ContextStrategy::DropOldest => (!history.is_empty()).then_some(0),
+				ContextStrategy::KeepEnds => (history.len() > 1).then_some(1),
+			};
+			let Some(index) = droppable else {
+				return Err(ContextExceeded {
+					context_length,
+					prompt_tokens,
+					max_tokens: self.max_tokens,
+				});
+			};
+			history.remove(index);
+			dropped += 1;
+		}
+	}
+}
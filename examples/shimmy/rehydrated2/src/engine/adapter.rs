@@ -2,14 +2,17 @@ use std::path::Path;
 
 use async_trait::async_trait;
 
+#[cfg(feature = "cpu")]
+use crate::engine::cpu::CpuEngine;
 use crate::{
-	engine::{llama::LlamaEngine, EngineError, InferenceEngine, LoadedModel, Result},
+	engine::{llama::LlamaEngine, memory::MemoryPlan, stream_from_text, EngineError, GenOptions, InferenceEngine, LoadedModel, Result, TokenStream},
 	model_registry::ModelSpec,
 };
 
 #[derive(Debug, Clone, Copy)]
 pub enum BackendChoice {
 	Llama,
+	Cpu,
 	HuggingFace,
 	MLX,
 	SafeTensors,
@@ -22,6 +25,14 @@ pub struct InferenceEngineAdapter {
 	mlx_engine: Option<StubEngine>,
 	safetensors_engine: Option<StubEngine>,
 	candle_engine: Option<StubEngine>,
+	#[cfg(feature = "cpu")]
+	cpu_engine: Option<CpuEngine>,
+}
+
+impl Default for InferenceEngineAdapter {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
 impl InferenceEngineAdapter {
@@ -32,9 +43,18 @@ impl InferenceEngineAdapter {
 			mlx_engine: if cfg!(feature = "mlx") { Some(StubEngine::new("mlx")) } else { None },
 			safetensors_engine: Some(StubEngine::new("safetensors")),
 			candle_engine: if cfg!(feature = "candle") { Some(StubEngine::new("candle")) } else { None },
+			#[cfg(feature = "cpu")]
+			cpu_engine: Some(CpuEngine::new()),
 		}
 	}
 
+	fn has_cpu_engine(&self) -> bool {
+		#[cfg(feature = "cpu")]
+		return self.cpu_engine.is_some();
+		#[cfg(not(feature = "cpu"))]
+		false
+	}
+
 	pub fn select_backend(&self, spec: &ModelSpec) -> BackendChoice {
 		let ext = Path::new(&spec.base_path)
 			.extension()
@@ -43,6 +63,8 @@ impl InferenceEngineAdapter {
 			.to_ascii_lowercase();
 
 		match ext.as_str() {
+			// The CPU reference backend decodes for real, so it wins over the llama placeholder.
+			"gguf" if self.has_cpu_engine() => BackendChoice::Cpu,
 			"gguf" => BackendChoice::Llama,
 			"safetensors" => BackendChoice::SafeTensors,
 			"bin" => BackendChoice::HuggingFace,
@@ -70,6 +92,15 @@ impl InferenceEngine for InferenceEngineAdapter {
 				})?;
 				engine.load(spec).await
 			}
+			#[cfg(feature = "cpu")]
+			BackendChoice::Cpu => {
+				let engine = self.cpu_engine.as_ref().ok_or_else(|| {
+					EngineError::LoadFailed("CPU reference backend unavailable".into())
+				})?;
+				engine.load(spec).await
+			}
+			#[cfg(not(feature = "cpu"))]
+			BackendChoice::Cpu => Err(EngineError::LoadFailed("CPU reference backend requires cpu feature".into())),
 			BackendChoice::HuggingFace => {
 				let engine = self.huggingface_engine.as_ref().ok_or_else(|| {
 					EngineError::LoadFailed("PyTorch format requires huggingface feature".into())
@@ -96,6 +127,15 @@ impl InferenceEngine for InferenceEngineAdapter {
 			}
 		}
 	}
+
+	fn plan_memory(&self, spec: &ModelSpec) -> Option<MemoryPlan> {
+		match self.select_backend(spec) {
+			BackendChoice::Llama => self.llama_engine.as_ref()?.plan_memory(spec),
+			#[cfg(feature = "cpu")]
+			BackendChoice::Cpu => self.cpu_engine.as_ref()?.plan_memory(spec),
+			_ => None,
+		}
+	}
 }
 
 struct StubEngine {
@@ -128,18 +168,8 @@ struct StubLoaded {
 
 #[async_trait]
 impl LoadedModel for StubLoaded {
-	async fn generate(
-		&self,
-		prompt: &str,
-		opts: crate::engine::GenOptions,
-		on_token: Option<Box<dyn Fn(String) + Send>>,
-	) -> Result<String> {
+	fn generate_stream<'a>(&'a self, prompt: &str, opts: GenOptions) -> TokenStream<'a> {
 		let result = format!("[{}:{}] response: {}", self.model, self.engine, prompt);
-		if let Some(cb) = on_token {
-			for word in result.split_whitespace().take(opts.max_tokens) {
-				cb(format!("{} ", word));
-			}
-		}
-		Ok(result)
+		stream_from_text(&result, &opts)
 	}
 }
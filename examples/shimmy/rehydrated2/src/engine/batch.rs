@@ -0,0 +1,140 @@
+use std::{collections::HashMap, sync::mpsc as std_mpsc};
+
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::engine::{EngineError, GenOptions, Result, TokenEvent, TokenStream};
+
+/// Identifies one sequence inside a [`BatchDecoder`].
+pub type SeqId = u64;
+
+/// Decodes several sequences in lockstep. A sequence joins between steps,
+/// takes part in every following [`BatchDecoder::step`], and leaves when it
+/// finishes, fails, or is removed with [`BatchDecoder::leave`].
+pub trait BatchDecoder: Send {
+	/// Adds a sequence; its prompt is processed by the next step.
+	fn join(&mut self, prompt: &str, opts: GenOptions) -> Result<SeqId>;
+
+	/// Adds one sequence per entry of `choices`, all continuing the same
+	/// prompt. Decoders that can should prefill it once and fork the result;
+	/// by default every choice is joined separately.
+	fn join_many(&mut self, prompt: &str, choices: Vec<GenOptions>) -> Result<Vec<SeqId>> {
+		let mut ids = vec![];
+		for opts in choices {
+			match self.join(prompt, opts) {
+				Ok(id) => ids.push(id),
+				Err(e) => {
+					for id in ids {
+						self.leave(id);
+					}
+					return Err(e);
+				}
+			}
+		}
+		Ok(ids)
+	}
+
+	/// Removes a sequence and frees its slot.
+	fn leave(&mut self, seq: SeqId);
+
+	/// Sequences currently in the batch.
+	fn active(&self) -> usize;
+
+	/// Advances every active sequence by at least one token, returning each
+	/// sequence's events in order (several when a draft model guessed
+	/// right). A sequence whose last event is an error or carries a
+	/// `finish_reason` has already left the batch.
+	fn step(&mut self) -> Vec<(SeqId, Result<TokenEvent>)>;
+}
+
+type EventSender = mpsc::UnboundedSender<Result<TokenEvent>>;
+
+struct Join {
+	prompt: String,
+	choices: Vec<(GenOptions, EventSender)>,
+}
+
+/// Owns a [`BatchDecoder`] on a dedicated thread. Requests submitted while a
+/// batch is running join it at the next token boundary.
+pub struct BatchRunner {
+	joins: std_mpsc::Sender<Join>,
+}
+
+impl BatchRunner {
+	/// Starts the decode thread. It exits once the runner is dropped and the
+	/// last sequence has finished.
+	pub fn spawn(decoder: Box<dyn BatchDecoder>) -> std::io::Result<Self> {
+		let (joins, rx) = std_mpsc::channel();
+		std::thread::Builder::new()
+			.name("shimmy-batch".into())
+			.spawn(move || run(decoder, rx))?;
+		Ok(Self { joins })
+	}
+
+	pub fn submit(&self, prompt: &str, opts: GenOptions) -> TokenStream<'static> {
+		self.submit_many(prompt, vec![opts]).remove(0)
+	}
+
+	/// Submits several completions of one prompt, joined together through
+	/// [`BatchDecoder::join_many`]; returns one stream per entry of `choices`.
+	pub fn submit_many(&self, prompt: &str, choices: Vec<GenOptions>) -> Vec<TokenStream<'static>> {
+		let mut streams = vec![];
+		let mut senders = vec![];
+		for opts in choices {
+			let (tx, rx) = mpsc::unbounded_channel();
+			senders.push((opts, tx));
+			streams.push(UnboundedReceiverStream::new(rx).boxed());
+		}
+		let join = Join {
+			prompt: prompt.to_string(),
+			choices: senders,
+		};
+		if let Err(std_mpsc::SendError(join)) = self.joins.send(join) {
+			for (_, tx) in join.choices {
+				let _ = tx.send(Err(EngineError::GenerationFailed("batch decoder stopped".into())));
+			}
+		}
+		streams
+	}
+}
+
+fn run(mut decoder: Box<dyn BatchDecoder>, joins: std_mpsc::Receiver<Join>) {
+	let mut outputs = HashMap::new();
+	loop {
+		// Sleep while idle; otherwise pick up whatever arrived during the last step.
+		if decoder.active() == 0 {
+			match joins.recv() {
+				Ok(join) => admit(decoder.as_mut(), &mut outputs, join),
+				Err(_) => return,
+			}
+		}
+		while let Ok(join) = joins.try_recv() {
+			admit(decoder.as_mut(), &mut outputs, join);
+		}
+
+		for (seq, event) in decoder.step() {
+			let ended = !matches!(&event, Ok(e) if e.finish_reason.is_none());
+			let delivered = outputs.get(&seq).is_some_and(|tx| tx.send(event).is_ok());
+			if ended {
+				outputs.remove(&seq);
+			} else if !delivered {
+				// The consumer went away; free the slot for someone else.
+				decoder.leave(seq);
+				outputs.remove(&seq);
+			}
+		}
+	}
+}
+
+fn admit(decoder: &mut dyn BatchDecoder, outputs: &mut HashMap<SeqId, EventSender>, join: Join) {
+	let (choices, senders): (Vec<_>, Vec<_>) = join.choices.into_iter().unzip();
+	match decoder.join_many(&join.prompt, choices) {
+		Ok(seqs) => outputs.extend(seqs.into_iter().zip(senders)),
+		Err(e) => {
+			for tx in senders {
+				let _ = tx.send(Err(e.clone()));
+			}
+		}
+	}
+}
@@ -0,0 +1,492 @@
+use std::{
+	fs::{File, OpenOptions},
+	io::{BufRead, BufReader, Write},
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	engine::{
+		memory::MemoryPlan, prefix_cache::PrefixCacheStats, tokenizer::Tokenizer, AdapterInfo, EmbedOptions, Embedding,
+		EngineError, FinishReason, GenOptions, InferenceEngine, LoadedModel, Result, SpeculativeStats, TokenEvent,
+		TokenLogprobs, TokenStream,
+	},
+	model_registry::ModelSpec,
+};
+
+/// The parts of [`GenOptions`] that decide what a backend generates. Replay
+/// matches on these, so caching and streaming flags are left out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CassetteOptions {
+	pub max_tokens: usize,
+	pub temperature: f32,
+	pub top_p: f32,
+	pub top_k: i32,
+	pub min_p: f32,
+	pub typical_p: f32,
+	pub repeat_penalty: f32,
+	pub repeat_last_n: usize,
+	pub frequency_penalty: f32,
+	pub presence_penalty: f32,
+	/// `[tau, eta]`.
+	pub mirostat: Option<[f32; 2]>,
+	pub seed: Option<u64>,
+	pub stop_tokens: Vec<String>,
+	/// GBNF source.
+	pub grammar: Option<String>,
+	pub logprobs: Option<usize>,
+	pub adapters: Option<Vec<(String, f32)>>,
+}
+
+impl From<&GenOptions> for CassetteOptions {
+	fn from(opts: &GenOptions) -> Self {
+		Self {
+			max_tokens: opts.max_tokens,
+			temperature: opts.temperature,
+			top_p: opts.top_p,
+			top_k: opts.top_k,
+			min_p: opts.min_p,
+			typical_p: opts.typical_p,
+			repeat_penalty: opts.repeat_penalty,
+			repeat_last_n: opts.repeat_last_n,
+			frequency_penalty: opts.frequency_penalty,
+			presence_penalty: opts.presence_penalty,
+			mirostat: opts.mirostat.map(|m| [m.tau, m.eta]),
+			seed: opts.seed,
+			stop_tokens: opts.stop_tokens.clone(),
+			grammar: opts.grammar.as_ref().map(|g| g.source().to_string()),
+			logprobs: opts.logprobs,
+			adapters: opts.adapters.clone(),
+		}
+	}
+}
+
+impl CassetteOptions {
+	/// Names of the fields that differ from `other`.
+	fn diff(&self, other: &CassetteOptions) -> Vec<&'static str> {
+		let mut fields = vec![];
+		let mut check = |name, same: bool| {
+			if !same {
+				fields.push(name);
+			}
+		};
+		check("max_tokens", self.max_tokens == other.max_tokens);
+		check("temperature", self.temperature == other.temperature);
+		check("top_p", self.top_p == other.top_p);
+		check("top_k", self.top_k == other.top_k);
+		check("min_p", self.min_p == other.min_p);
+		check("typical_p", self.typical_p == other.typical_p);
+		check("repeat_penalty", self.repeat_penalty == other.repeat_penalty);
+		check("repeat_last_n", self.repeat_last_n == other.repeat_last_n);
+		check("frequency_penalty", self.frequency_penalty == other.frequency_penalty);
+		check("presence_penalty", self.presence_penalty == other.presence_penalty);
+		check("mirostat", self.mirostat == other.mirostat);
+		check("seed", self.seed == other.seed);
+		check("stop_tokens", self.stop_tokens == other.stop_tokens);
+		check("grammar", self.grammar == other.grammar);
+		check("logprobs", self.logprobs == other.logprobs);
+		check("adapters", self.adapters == other.adapters);
+		fields
+	}
+}
+
+/// One [`TokenEvent`] as recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CassetteEvent {
+	pub text: String,
+	pub token_id: u32,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub logprobs: Option<TokenLogprobs>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub finish_reason: Option<FinishReason>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub stop_sequence: Option<String>,
+}
+
+impl From<&TokenEvent> for CassetteEvent {
+	fn from(event: &TokenEvent) -> Self {
+		Self {
+			text: event.text.clone(),
+			token_id: event.token_id,
+			logprobs: event.logprobs.clone(),
+			finish_reason: event.finish_reason,
+			stop_sequence: event.stop_sequence.clone(),
+		}
+	}
+}
+
+impl From<&CassetteEvent> for TokenEvent {
+	fn from(event: &CassetteEvent) -> Self {
+		Self {
+			logprobs: event.logprobs.clone(),
+			finish_reason: event.finish_reason,
+			stop_sequence: event.stop_sequence.clone(),
+			..TokenEvent::piece(event.text.clone(), event.token_id)
+		}
+	}
+}
+
+/// One line of a cassette: a generation request and everything the backend
+/// streamed back for it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CassetteEntry {
+	pub model: String,
+	/// The model's configured template, so a replaying server renders chat
+	/// prompts exactly as the recording one did.
+	#[serde(default)]
+	pub template: Option<String>,
+	pub prompt: String,
+	#[serde(default)]
+	pub prompt_tokens: Option<usize>,
+	pub options: CassetteOptions,
+	pub events: Vec<CassetteEvent>,
+	/// Set when the stream ended in an error instead of a finish reason.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub error: Option<EngineError>,
+}
+
+/// Wraps a real backend and appends every generation it serves to a JSONL
+/// cassette for [`ReplayEngine`].
+///
+/// Recorded models report no batch decoder, so every request goes through
+/// [`LoadedModel::generate_stream`] where it can be captured. A generation
+/// is written once its stream ends; streams dropped early are not recorded.
+pub struct RecordingEngine {
+	inner: Box<dyn InferenceEngine>,
+	out: Arc<Mutex<File>>,
+}
+
+impl RecordingEngine {
+	/// Appends to `path`, creating it if needed.
+	pub fn new(inner: Box<dyn InferenceEngine>, path: impl AsRef<Path>) -> std::io::Result<Self> {
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
+		Ok(Self {
+			inner,
+			out: Arc::new(Mutex::new(file)),
+		})
+	}
+}
+
+#[async_trait]
+impl InferenceEngine for RecordingEngine {
+	async fn load(&self, spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+		let inner = self.inner.load(spec).await?;
+		Ok(Box::new(RecordingModel {
+			inner,
+			model: spec.name.clone(),
+			template: spec.template.clone(),
+			out: self.out.clone(),
+		}))
+	}
+
+	fn plan_memory(&self, spec: &ModelSpec) -> Option<MemoryPlan> {
+		self.inner.plan_memory(spec)
+	}
+}
+
+struct RecordingModel {
+	inner: Box<dyn LoadedModel>,
+	model: String,
+	template: Option<String>,
+	out: Arc<Mutex<File>>,
+}
+
+#[async_trait]
+impl LoadedModel for RecordingModel {
+	fn generate_stream<'a>(&'a self, prompt: &str, opts: GenOptions) -> TokenStream<'a> {
+		let mut entry = Some(CassetteEntry {
+			model: self.model.clone(),
+			template: self.template.clone(),
+			prompt: prompt.to_string(),
+			prompt_tokens: self.inner.count_prompt_tokens(prompt),
+			options: CassetteOptions::from(&opts),
+			events: vec![],
+			error: None,
+		});
+		let out = self.out.clone();
+		// A generation that could not be recorded fails in place of its final
+		// event, so a recording session never silently loses entries.
+		self.inner
+			.generate_stream(prompt, opts)
+			.map(move |event| {
+				let Some(current) = entry.as_mut() else {
+					return event;
+				};
+				match &event {
+					Ok(piece) => {
+						current.events.push(CassetteEvent::from(piece));
+						if piece.finish_reason.is_none() {
+							return event;
+						}
+					}
+					Err(e) => current.error = Some(e.clone()),
+				}
+				if let Some(done) = entry.take() {
+					write_entry(&out, &done)?;
+				}
+				event
+			})
+			.boxed()
+	}
+
+	fn tokenizer(&self) -> Option<&dyn Tokenizer> {
+		self.inner.tokenizer()
+	}
+
+	fn supports_grammar(&self) -> bool {
+		self.inner.supports_grammar()
+	}
+
+	fn supports_logprobs(&self) -> bool {
+		self.inner.supports_logprobs()
+	}
+
+	fn supports_adapters(&self) -> bool {
+		self.inner.supports_adapters()
+	}
+
+	fn adapters(&self) -> Vec<AdapterInfo> {
+		self.inner.adapters()
+	}
+
+	fn load_adapter(&self, name: &str, path: &Path) -> Result<AdapterInfo> {
+		self.inner.load_adapter(name, path)
+	}
+
+	fn unload_adapter(&self, name: &str) -> bool {
+		self.inner.unload_adapter(name)
+	}
+
+	fn context_length(&self) -> Option<usize> {
+		self.inner.context_length()
+	}
+
+	fn memory_plan(&self) -> Option<MemoryPlan> {
+		self.inner.memory_plan()
+	}
+
+	/// Embeddings pass straight through; cassettes only hold generations.
+	fn supports_embeddings(&self) -> bool {
+		self.inner.supports_embeddings()
+	}
+
+	async fn embed(&self, inputs: &[String], opts: EmbedOptions) -> Result<Vec<Embedding>> {
+		self.inner.embed(inputs, opts).await
+	}
+
+	fn speculative_stats(&self) -> Option<SpeculativeStats> {
+		self.inner.speculative_stats()
+	}
+
+	fn prefix_cache_stats(&self) -> Option<PrefixCacheStats> {
+		self.inner.prefix_cache_stats()
+	}
+
+	fn count_prompt_tokens(&self, prompt: &str) -> Option<usize> {
+		self.inner.count_prompt_tokens(prompt)
+	}
+}
+
+fn write_entry(out: &Mutex<File>, entry: &CassetteEntry) -> Result<()> {
+	let fail = |e: String| EngineError::GenerationFailed(format!("could not record cassette entry: {}", e));
+	let line = serde_json::to_string(entry).map_err(|e| fail(e.to_string()))?;
+	let mut file = out.lock().unwrap_or_else(|e| e.into_inner());
+	writeln!(file, "{}", line).and_then(|_| file.flush()).map_err(|e| fail(e.to_string()))
+}
+
+/// Serves generations from a cassette written by [`RecordingEngine`],
+/// without loading any weights. A request is answered by the first entry
+/// with the same model, prompt and [`CassetteOptions`]; anything else fails
+/// with an error naming what did not match.
+pub struct ReplayEngine {
+	path: PathBuf,
+	entries: Arc<Vec<CassetteEntry>>,
+}
+
+impl ReplayEngine {
+	pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+		let path = path.as_ref();
+		let fail = |msg: String| EngineError::LoadFailed(format!("cassette {}: {}", path.display(), msg));
+		let file = File::open(path).map_err(|e| fail(e.to_string()))?;
+		let mut entries = vec![];
+		for (n, line) in BufReader::new(file).lines().enumerate() {
+			let line = line.map_err(|e| fail(e.to_string()))?;
+			if line.trim().is_empty() {
+				continue;
+			}
+			let entry = serde_json::from_str(&line).map_err(|e| fail(format!("line {}: {}", n + 1, e)))?;
+			entries.push(entry);
+		}
+		Ok(Self {
+			path: path.to_path_buf(),
+			entries: Arc::new(entries),
+		})
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Every model the cassette has entries for, with its recorded template,
+	/// in order of first appearance.
+	pub fn models(&self) -> Vec<(String, Option<String>)> {
+		let mut models: Vec<(String, Option<String>)> = vec![];
+		for entry in self.entries.iter() {
+			if !models.iter().any(|(name, _)| *name == entry.model) {
+				models.push((entry.model.clone(), entry.template.clone()));
+			}
+		}
+		models
+	}
+}
+
+#[async_trait]
+impl InferenceEngine for ReplayEngine {
+	async fn load(&self, spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+		if !self.entries.iter().any(|e| e.model == spec.name) {
+			return Err(EngineError::ModelNotFound(format!("{} (not in cassette {})", spec.name, self.path.display())));
+		}
+		Ok(Box::new(ReplayModel {
+			model: spec.name.clone(),
+			entries: self.entries.clone(),
+		}))
+	}
+}
+
+struct ReplayModel {
+	model: String,
+	entries: Arc<Vec<CassetteEntry>>,
+}
+
+impl ReplayModel {
+	fn recorded(&self) -> impl Iterator<Item = &CassetteEntry> {
+		self.entries.iter().filter(|e| e.model == self.model)
+	}
+
+	fn unmatched(&self, prompt: &str, options: &CassetteOptions) -> EngineError {
+		let closest = self
+			.recorded()
+			.filter(|e| e.prompt == prompt)
+			.map(|e| e.options.diff(options))
+			.min_by_key(|fields| fields.len());
+		let reason = match closest {
+			Some(fields) => format!("the recorded request for this prompt differs in {}", fields.join(", ")),
+			None => "this prompt was never recorded".to_string(),
+		};
+		EngineError::GenerationFailed(format!(
+			"no cassette entry for model {} matches prompt {:?}: {}",
+			self.model, prompt, reason
+		))
+	}
+}
+
+#[async_trait]
+impl LoadedModel for ReplayModel {
+	fn generate_stream<'a>(&'a self, prompt: &str, opts: GenOptions) -> TokenStream<'a> {
+		let options = CassetteOptions::from(&opts);
+		let Some(entry) = self.recorded().find(|e| e.prompt == prompt && e.options == options) else {
+			let error = self.unmatched(prompt, &options);
+			return futures::stream::once(async move { Err(error) }).boxed();
+		};
+		let mut events: Vec<Result<TokenEvent>> = entry.events.iter().map(|e| Ok(TokenEvent::from(e))).collect();
+		if let Some(error) = &entry.error {
+			events.push(Err(error.clone()));
+		}
+		let cancel = opts.cancel;
+		futures::stream::iter(events)
+			.scan(false, move |cancelled, event| {
+				let event = if *cancelled {
+					None
+				} else if cancel.is_cancelled() {
+					*cancelled = true;
+					Some(Ok(TokenEvent::finish(FinishReason::Cancelled)))
+				} else {
+					Some(event)
+				};
+				futures::future::ready(event)
+			})
+			.boxed()
+	}
+
+	/// Claimed only when the cassette holds such requests, so a replaying
+	/// server refuses what it could not have recorded.
+	fn supports_grammar(&self) -> bool {
+		self.recorded().any(|e| e.options.grammar.is_some())
+	}
+
+	fn supports_logprobs(&self) -> bool {
+		self.recorded().any(|e| e.options.logprobs.is_some())
+	}
+
+	fn count_prompt_tokens(&self, prompt: &str) -> Option<usize> {
+		self.recorded().find(|e| e.prompt == prompt).and_then(|e| e.prompt_tokens)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Always answers "hi" and stops.
+	struct Canned;
+
+	#[async_trait]
+	impl InferenceEngine for Canned {
+		async fn load(&self, _spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+			Ok(Box::new(Canned))
+		}
+	}
+
+	#[async_trait]
+	impl LoadedModel for Canned {
+		fn generate_stream<'a>(&'a self, _prompt: &str, _opts: GenOptions) -> TokenStream<'a> {
+			let events = vec![Ok(TokenEvent::piece("hi", 3)), Ok(TokenEvent::finish(FinishReason::Stop))];
+			futures::stream::iter(events).boxed()
+		}
+	}
+
+	fn spec() -> ModelSpec {
+		ModelSpec {
+			name: "canned".into(),
+			base_path: PathBuf::new(),
+			lora_path: None,
+			template: None,
+			ctx_len: None,
+			n_threads: None,
+			draft_path: None,
+			adapters: Default::default(),
+			slots: 1,
+		}
+	}
+
+	async fn texts(engine: &dyn InferenceEngine) -> Vec<Result<String>> {
+		let model = engine.load(&spec()).await.unwrap();
+		model.generate_stream("hello", GenOptions::default()).map(|e| e.map(|e| e.text)).collect().await
+	}
+
+	#[tokio::test]
+	async fn recorded_generations_replay() {
+		let path = std::env::temp_dir().join(format!("shimmy-cassette-{}.jsonl", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let recorded = texts(&RecordingEngine::new(Box::new(Canned), &path).unwrap()).await;
+		let replayed = texts(&ReplayEngine::open(&path).unwrap()).await;
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(recorded.len(), 2);
+		assert_eq!(format!("{:?}", replayed), format!("{:?}", recorded));
+	}
+
+	#[tokio::test]
+	async fn failed_writes_fail_the_generation() {
+		// Every write to /dev/full fails with "no space left on device".
+		let Ok(engine) = RecordingEngine::new(Box::new(Canned), "/dev/full") else {
+			return;
+		};
+		let events = texts(&engine).await;
+		assert_eq!(events[0].as_deref().ok(), Some("hi"));
+		assert!(matches!(&events[1], Err(EngineError::GenerationFailed(m)) if m.contains("cassette")));
+	}
+}
@@ -0,0 +1,639 @@
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::engine::{
+	batch::{BatchDecoder, SeqId},
+	cpu::{
+		lora::{AdapterMix, AdapterRegistry},
+		model::{KvCache, LlamaModel},
+	},
+	grammar::GrammarMatcher,
+	prefix_cache::PrefixCache,
+	sampling::{self, Candidates, SamplerChain},
+	tokenizer::Tokenizer,
+	EngineError, FinishReason, GenOptions, Result, SpeculativeStats, TokenEvent, TokenLogprob, TokenLogprobs,
+};
+
+/// A smaller model sharing the target's vocabulary. Each decode step it
+/// guesses up to `tokens` tokens greedily; the target then runs them all in
+/// one pass and samples every position exactly as it would have without the
+/// draft, keeping guesses only while they agree with its own samples. Output
+/// is therefore identical to plain decoding, seed for seed.
+#[derive(Clone)]
+pub struct Draft {
+	pub model: Arc<LlamaModel>,
+	pub tokens: usize,
+	/// Totals across every sequence of the model.
+	pub stats: Arc<Mutex<SpeculativeStats>>,
+}
+
+enum Phase {
+	/// Prompt tokens not yet run through the model.
+	Prompt(Vec<u32>),
+	/// The last sampled token, still to be fed back in.
+	Decode(u32),
+}
+
+struct Sequence {
+	id: SeqId,
+	opts: GenOptions,
+	sampler: SamplerChain,
+	grammar: Option<GrammarMatcher>,
+	adapters: AdapterMix,
+	cache: KvCache,
+	/// Tokens whose keys/values are in `cache`.
+	fed: Vec<u32>,
+	phase: Phase,
+	hidden: Vec<f32>,
+	generated: usize,
+	/// Bytes of a UTF-8 character split across tokens.
+	pending: Vec<u8>,
+	/// Further choices for the same prompt, forked from this sequence once
+	/// its prefill is done.
+	followers: Vec<(SeqId, GenOptions)>,
+	/// The draft model's cache; may lag `fed`, and is caught up before each guess.
+	draft_cache: Option<KvCache>,
+	speculative: SpeculativeStats,
+}
+
+/// Lockstep decoder for the CPU backend. New sequences prefill their prompt
+/// on the step after they join; from then on every step feeds each
+/// sequence's last token through one shared [`LlamaModel::forward_batch`].
+pub struct CpuBatch {
+	model: Arc<LlamaModel>,
+	tokenizer: Arc<dyn Tokenizer>,
+	/// Decoded bytes of every token, for grammar checks.
+	pieces: Arc<Vec<Vec<u8>>>,
+	prefixes: Arc<Mutex<PrefixCache<KvCache>>>,
+	adapters: Arc<RwLock<AdapterRegistry>>,
+	n_ctx: usize,
+	draft: Option<Draft>,
+	seqs: Vec<Sequence>,
+	next_id: SeqId,
+}
+
+impl CpuBatch {
+	pub fn new(
+		model: Arc<LlamaModel>,
+		tokenizer: Arc<dyn Tokenizer>,
+		pieces: Arc<Vec<Vec<u8>>>,
+		prefixes: Arc<Mutex<PrefixCache<KvCache>>>,
+		adapters: Arc<RwLock<AdapterRegistry>>,
+		n_ctx: usize,
+		draft: Option<Draft>,
+	) -> Self {
+		Self {
+			model,
+			tokenizer,
+			pieces,
+			prefixes,
+			adapters,
+			n_ctx,
+			draft,
+			seqs: vec![],
+			next_id: 0,
+		}
+	}
+
+	fn prefixes(&self) -> std::sync::MutexGuard<'_, PrefixCache<KvCache>> {
+		self.prefixes.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	/// Offers a departing sequence's cache to later prompts.
+	fn retire(&self, seq: Sequence) {
+		if seq.opts.cache_prompt && !seq.fed.is_empty() {
+			let bytes = seq.cache.size_bytes();
+			self.prefixes().insert(seq.adapters.key(), seq.fed, seq.cache, bytes);
+		}
+	}
+
+	/// A copy of `lead` decoding independently under `opts`.
+	fn fork(&self, lead: &Sequence, id: SeqId, opts: GenOptions) -> Sequence {
+		let mut sampler = SamplerChain::from_options(&opts);
+		for &t in &lead.fed {
+			sampler.accept(t);
+		}
+		Sequence {
+			id,
+			sampler,
+			grammar: opts.grammar.clone().map(GrammarMatcher::new),
+			opts,
+			adapters: lead.adapters.clone(),
+			cache: lead.cache.clone(),
+			fed: lead.fed.clone(),
+			phase: Phase::Prompt(vec![]),
+			hidden: lead.hidden.clone(),
+			generated: 0,
+			pending: vec![],
+			followers: vec![],
+			draft_cache: lead.draft_cache.clone(),
+			speculative: SpeculativeStats::default(),
+		}
+	}
+
+	/// How many draft tokens `seq` can verify this step without running past
+	/// its token budget or the context window; 0 means decode normally.
+	fn draft_room(&self, seq: &Sequence) -> usize {
+		match (&self.draft, &seq.phase) {
+			(Some(draft), Phase::Decode(_)) => {
+				let budget = seq.opts.max_tokens.saturating_sub(seq.generated);
+				let context = self.n_ctx.saturating_sub(seq.cache.len());
+				draft.tokens.min(budget.saturating_sub(1)).min(context.saturating_sub(1))
+			}
+			_ => 0,
+		}
+	}
+
+	/// One draft-then-verify round, returning every token it settled, the
+	/// last possibly a finish.
+	fn speculate(&self, seq: &mut Sequence, draft: &Draft, k: usize) -> Result<Vec<TokenEvent>> {
+		let Phase::Decode(last) = seq.phase else {
+			return Ok(vec![]);
+		};
+		let draft_cache = seq.draft_cache.get_or_insert_with(|| draft.model.new_cache());
+		let behind: Vec<u32> = seq.fed[draft_cache.len().min(seq.fed.len())..].iter().copied().chain([last]).collect();
+		// The draft only guesses, so it runs without the target's adapters.
+		let plain = AdapterMix::default();
+		let mut hidden = draft.model.forward_tokens(&behind, draft_cache, &plain)?.pop().unwrap_or_default();
+		let mut guesses = Vec::with_capacity(k);
+		loop {
+			guesses.push(argmax(&draft.model.logits(&hidden)));
+			if guesses.len() == k {
+				break;
+			}
+			hidden = draft.model.forward(guesses[guesses.len() - 1], draft_cache, &plain)?;
+		}
+
+		let base = seq.cache.len();
+		let inputs: Vec<u32> = std::iter::once(last).chain(guesses.iter().copied()).collect();
+		let hidden = self.model.forward_tokens(&inputs, &mut seq.cache, &seq.adapters)?;
+		let hidden: Vec<&[f32]> = hidden.iter().map(Vec::as_slice).collect();
+		let mut events = vec![];
+		let mut accepted = 0;
+		for (i, logits) in self.model.logits_batch(&hidden).iter().enumerate() {
+			let event = self.sample(seq, logits)?;
+			let agreed = event.finish_reason.is_none() && matches!(seq.phase, Phase::Decode(t) if guesses.get(i) == Some(&t));
+			events.push(event);
+			if !agreed {
+				break;
+			}
+			accepted += 1;
+		}
+
+		// Keep the positions of `last` and the guesses the target agreed with.
+		seq.fed.extend(&inputs[..1 + accepted]);
+		seq.cache.truncate(base + 1 + accepted);
+		if let Some(cache) = &mut seq.draft_cache {
+			cache.truncate(seq.fed.len());
+		}
+		let round = SpeculativeStats {
+			drafted: k as u64,
+			accepted: accepted as u64,
+		};
+		seq.speculative.merge(&round);
+		draft.stats.lock().unwrap_or_else(|e| e.into_inner()).merge(&round);
+		for event in &mut events {
+			event.speculative = Some(seq.speculative);
+		}
+		Ok(events)
+	}
+
+	/// Runs a pending prompt through the model, stopping early if cancelled.
+	fn prefill(&self, seq: &mut Sequence, tokens: &[u32]) -> Result<Option<FinishReason>> {
+		for &t in tokens {
+			if seq.opts.cancel.is_cancelled() {
+				return Ok(Some(FinishReason::Cancelled));
+			}
+			seq.hidden = self.model.forward(t, &mut seq.cache, &seq.adapters)?;
+			seq.fed.push(t);
+			seq.sampler.accept(t);
+		}
+		Ok(None)
+	}
+
+	fn sample(&self, seq: &mut Sequence, logits: &[f32]) -> Result<TokenEvent> {
+		let vocab = self.tokenizer.vocab();
+		let next = match &seq.grammar {
+			Some(grammar) => {
+				if grammar.is_complete() && !grammar.can_continue() {
+					return Ok(TokenEvent::finish(FinishReason::Stop));
+				}
+				let mut candidates = Candidates::from_logits(logits);
+				grammar.constrain(&mut candidates, &self.pieces, |id| vocab.is_end_of_generation(id));
+				if candidates.is_empty() {
+					if grammar.is_complete() {
+						return Ok(TokenEvent::finish(FinishReason::Stop));
+					}
+					return Err(EngineError::GenerationFailed(
+						"no token in the vocabulary can continue the grammar".into(),
+					));
+				}
+				seq.sampler.sample_candidates(&mut candidates)
+			}
+			None => seq.sampler.sample(logits),
+		};
+		if vocab.is_end_of_generation(next) {
+			return Ok(TokenEvent::finish(FinishReason::Stop));
+		}
+		if let Some(grammar) = &mut seq.grammar {
+			grammar.accept(&self.pieces[next as usize]);
+		}
+		seq.pending.extend(self.tokenizer.decode_piece(next));
+		seq.phase = Phase::Decode(next);
+		seq.generated += 1;
+		let mut event = TokenEvent::piece(take_utf8(&mut seq.pending), next);
+		if let Some(top) = seq.opts.logprobs {
+			let (logprob, alternatives) = sampling::logprobs(logits, next, top);
+			event.logprobs = Some(TokenLogprobs {
+				sampled: self.token_logprob(next, logprob),
+				top_logprobs: alternatives.into_iter().map(|(id, lp)| self.token_logprob(id, lp)).collect(),
+			});
+		}
+		Ok(event)
+	}
+
+	fn token_logprob(&self, id: u32, logprob: f32) -> TokenLogprob {
+		let bytes = self.pieces.get(id as usize).cloned().unwrap_or_default();
+		// Control tokens decode to nothing, so they are named by their vocabulary entry.
+		let token = if bytes.is_empty() {
+			self.tokenizer.vocab().token(id).unwrap_or_default().to_string()
+		} else {
+			String::from_utf8_lossy(&bytes).into_owned()
+		};
+		TokenLogprob {
+			token,
+			logprob,
+			bytes,
+		}
+	}
+}
+
+impl BatchDecoder for CpuBatch {
+	fn join(&mut self, prompt: &str, opts: GenOptions) -> Result<SeqId> {
+		self.join_many(prompt, vec![opts]).map(|ids| ids[0])
+	}
+
+	fn join_many(&mut self, prompt: &str, choices: Vec<GenOptions>) -> Result<Vec<SeqId>> {
+		let mut choices = choices.into_iter();
+		let Some(opts) = choices.next() else {
+			return Ok(vec![]);
+		};
+		let tokens = self.tokenizer.encode(prompt, true);
+		if tokens.is_empty() {
+			return Err(EngineError::GenerationFailed("prompt produced no tokens".into()));
+		}
+		if tokens.len() >= self.n_ctx {
+			return Err(EngineError::GenerationFailed(format!(
+				"prompt is {} tokens, context window is {}",
+				tokens.len(),
+				self.n_ctx
+			)));
+		}
+
+		let adapters = self
+			.adapters
+			.read()
+			.unwrap_or_else(|e| e.into_inner())
+			.resolve(opts.adapters.as_deref())?;
+		let mut sampler = SamplerChain::from_options(&opts);
+		// At least one prompt token is always run so there is a hidden state to sample from.
+		let reused = if opts.cache_prompt {
+			self.prefixes().lookup(&adapters.key(), &tokens, tokens.len() - 1)
+		} else {
+			self.prefixes().record_skip();
+			None
+		};
+		let (fed, cache) = match reused {
+			Some((n, mut cache)) => {
+				cache.truncate(n);
+				(tokens[..n].to_vec(), cache)
+			}
+			None => (vec![], self.model.new_cache()),
+		};
+		for &t in &fed {
+			sampler.accept(t);
+		}
+
+		self.next_id += 1;
+		let id = self.next_id;
+		let followers: Vec<(SeqId, GenOptions)> = choices
+			.map(|opts| {
+				self.next_id += 1;
+				(self.next_id, opts)
+			})
+			.collect();
+		let ids = std::iter::once(id).chain(followers.iter().map(|f| f.0)).collect();
+		self.seqs.push(Sequence {
+			id,
+			sampler,
+			grammar: opts.grammar.clone().map(GrammarMatcher::new),
+			opts,
+			adapters,
+			cache,
+			phase: Phase::Prompt(tokens[fed.len()..].to_vec()),
+			fed,
+			hidden: vec![],
+			generated: 0,
+			pending: vec![],
+			followers,
+			draft_cache: None,
+			speculative: SpeculativeStats::default(),
+		});
+		Ok(ids)
+	}
+
+	fn leave(&mut self, seq: SeqId) {
+		for lead in &mut self.seqs {
+			lead.followers.retain(|(id, _)| *id != seq);
+		}
+		if let Some(i) = self.seqs.iter().position(|s| s.id == seq) {
+			let mut seq = self.seqs.remove(i);
+			// A lead still owing its followers a prefill hands the job to the first of them.
+			let mut followers = std::mem::take(&mut seq.followers).into_iter();
+			if let Some((id, opts)) = followers.next() {
+				let mut lead = self.fork(&seq, id, opts);
+				lead.phase = std::mem::replace(&mut seq.phase, Phase::Prompt(vec![]));
+				lead.followers = followers.collect();
+				self.seqs.push(lead);
+			}
+			self.retire(seq);
+		}
+	}
+
+	fn active(&self) -> usize {
+		self.seqs.iter().map(|s| 1 + s.followers.len()).sum()
+	}
+
+	fn step(&mut self) -> Vec<(SeqId, Result<TokenEvent>)> {
+		let mut ended: Vec<(Sequence, Result<TokenEvent>)> = vec![];
+		let mut seqs = vec![];
+		let mut events = vec![];
+
+		// Settle every sequence that ends before it needs the model, and
+		// prefill the ones that just joined.
+		for mut seq in std::mem::take(&mut self.seqs) {
+			let outcome = if seq.opts.cancel.is_cancelled() {
+				Ok(Some(FinishReason::Cancelled))
+			} else {
+				match std::mem::replace(&mut seq.phase, Phase::Prompt(vec![])) {
+					Phase::Prompt(tokens) => self.prefill(&mut seq, &tokens),
+					Phase::Decode(token) => {
+						seq.phase = Phase::Decode(token);
+						if seq.generated >= seq.opts.max_tokens || seq.cache.len() >= self.n_ctx {
+							Ok(Some(FinishReason::Length))
+						} else {
+							Ok(None)
+						}
+					}
+				}
+			};
+			// Followers share their lead's prefill, and its fate if that fails.
+			for (id, opts) in std::mem::take(&mut seq.followers) {
+				match &outcome {
+					Ok(None) => seqs.push(self.fork(&seq, id, opts)),
+					Ok(Some(reason)) => events.push((id, Ok(TokenEvent::finish(*reason)))),
+					Err(e) => events.push((id, Err(e.clone()))),
+				}
+			}
+			match outcome {
+				Ok(None) => seqs.push(seq),
+				Ok(Some(reason)) => ended.push((seq, Ok(TokenEvent::finish(reason)))),
+				Err(e) => ended.push((seq, Err(e))),
+			}
+		}
+
+		// Sequences with a draft to verify run their own multi-token pass.
+		let (speculating, mut seqs): (Vec<Sequence>, Vec<Sequence>) =
+			seqs.into_iter().partition(|seq| self.draft_room(seq) > 0);
+		if let Some(draft) = &self.draft {
+			for mut seq in speculating {
+				let k = self.draft_room(&seq);
+				match self.speculate(&mut seq, draft, k) {
+					Ok(mut round) => match round.pop() {
+						Some(last) if last.finish_reason.is_some() => {
+							events.extend(round.into_iter().map(|e| (seq.id, Ok(e))));
+							ended.push((seq, Ok(last)));
+						}
+						last => {
+							events.extend(round.into_iter().chain(last).map(|e| (seq.id, Ok(e))));
+							self.seqs.push(seq);
+						}
+					},
+					Err(e) => ended.push((seq, Err(e))),
+				}
+			}
+		}
+
+		// Sequences past their prompt share one forward pass.
+		let mut fed: Vec<(u32, &mut KvCache, &AdapterMix)> = vec![];
+		let mut fed_idx = vec![];
+		for (i, seq) in seqs.iter_mut().enumerate() {
+			if let Phase::Decode(token) = seq.phase {
+				fed.push((token, &mut seq.cache, &seq.adapters));
+				fed_idx.push(i);
+			}
+		}
+		if !fed.is_empty() {
+			match self.model.forward_batch(&mut fed) {
+				Ok(hidden) => {
+					for (i, h) in fed_idx.into_iter().zip(hidden) {
+						let seq = &mut seqs[i];
+						seq.hidden = h;
+						if let Phase::Decode(token) = seq.phase {
+							seq.fed.push(token);
+						}
+					}
+				}
+				Err(e) => {
+					// A bad forward pass poisons every sequence that took part in it.
+					let msg = e.to_string();
+					for &i in fed_idx.iter().rev() {
+						let seq = seqs.remove(i);
+						ended.push((seq, Err(EngineError::GenerationFailed(msg.clone()))));
+					}
+				}
+			}
+		}
+
+		// A sequence that has just prefilled may already be at its limit.
+		let (at_limit, mut seqs): (Vec<Sequence>, Vec<Sequence>) =
+			seqs.into_iter().partition(|seq| seq.generated >= seq.opts.max_tokens);
+		ended.extend(at_limit.into_iter().map(|seq| (seq, Ok(TokenEvent::finish(FinishReason::Length)))));
+
+		let hidden: Vec<&[f32]> = seqs.iter().map(|s| s.hidden.as_slice()).collect();
+		let logits = self.model.logits_batch(&hidden);
+		for (mut seq, logits) in seqs.drain(..).zip(&logits) {
+			match self.sample(&mut seq, logits) {
+				Ok(event) if event.finish_reason.is_none() => {
+					events.push((seq.id, Ok(event)));
+					self.seqs.push(seq);
+				}
+				event => ended.push((seq, event)),
+			}
+		}
+
+		for (seq, mut event) in ended {
+			let id = seq.id;
+			if let (Some(_), Ok(event)) = (&self.draft, &mut event) {
+				event.speculative = Some(seq.speculative);
+			}
+			// A failed forward pass may have left partial layers in the cache.
+			if event.is_ok() {
+				self.retire(seq);
+			}
+			events.push((id, event));
+		}
+		events
+	}
+}
+
+fn argmax(xs: &[f32]) -> u32 {
+	let mut best = 0;
+	for (i, x) in xs.iter().enumerate() {
+		if *x > xs[best] {
+			best = i;
+		}
+	}
+	best as u32
+}
+
+/// Removes and returns the longest valid UTF-8 prefix, keeping an incomplete
+/// trailing character buffered for the next token.
+fn take_utf8(pending: &mut Vec<u8>) -> String {
+	let valid = match std::str::from_utf8(pending) {
+		Ok(s) => s.len(),
+		Err(e) if e.error_len().is_none() => e.valid_up_to(),
+		// Invalid (not merely incomplete) bytes are emitted lossily.
+		Err(_) => pending.len(),
+	};
+	let text = String::from_utf8_lossy(&pending[..valid]).into_owned();
+	pending.drain(..valid);
+	text
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use futures::StreamExt;
+
+	use super::*;
+	use crate::engine::{
+		cpu::{
+			fixtures::{tiny_llama, TempModel},
+			CpuEngine,
+		},
+		gguf::GgmlType,
+		InferenceEngine, LoadedModel,
+	};
+
+	fn opts(seed: u64, temperature: f32) -> GenOptions {
+		GenOptions {
+			max_tokens: 8,
+			temperature,
+			seed: Some(seed),
+			..Default::default()
+		}
+	}
+
+	async fn load(file: &TempModel) -> Box<dyn LoadedModel> {
+		let spec = crate::model_registry::ModelSpec {
+			slots: 4,
+			..file.spec()
+		};
+		CpuEngine::new().load(&spec).await.unwrap()
+	}
+
+	async fn alone(model: &dyn LoadedModel, prompt: &str, opts: GenOptions) -> Vec<u32> {
+		let events: Vec<TokenEvent> = model.generate_stream(prompt, opts).map(|e| e.unwrap()).collect().await;
+		events.iter().filter(|e| e.finish_reason.is_none()).map(|e| e.token_id).collect()
+	}
+
+	/// Steps `decoder` until every sequence has finished, collecting tokens per sequence.
+	fn drain(decoder: &mut dyn BatchDecoder, out: &mut HashMap<SeqId, Vec<u32>>) {
+		while decoder.active() > 0 {
+			step(decoder, out);
+		}
+	}
+
+	fn step(decoder: &mut dyn BatchDecoder, out: &mut HashMap<SeqId, Vec<u32>>) {
+		for (seq, event) in decoder.step() {
+			let event = event.unwrap();
+			if event.finish_reason.is_none() {
+				out.entry(seq).or_default().push(event.token_id);
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn batched_sequences_match_sequential_ones() {
+		let file = TempModel::write(&tiny_llama(7, GgmlType::F32));
+		let model = load(&file).await;
+		let jobs = [
+			("hello world", opts(1, 0.0)),
+			("the cat sat on", opts(2, 0.9)),
+			("one two three", opts(3, 1.2)),
+		];
+
+		let mut decoder = model.batch_decoder().unwrap();
+		let mut out = HashMap::new();
+		let first = decoder.join(jobs[0].0, jobs[0].1.clone()).unwrap();
+		step(decoder.as_mut(), &mut out);
+		step(decoder.as_mut(), &mut out);
+		// Later joiners prefill while the first sequence is mid-decode.
+		let rest: Vec<SeqId> = jobs[1..].iter().map(|(p, o)| decoder.join(p, o.clone()).unwrap()).collect();
+		drain(decoder.as_mut(), &mut out);
+
+		for (id, (prompt, opts)) in std::iter::once(first).chain(rest).zip(jobs) {
+			assert!(!out[&id].is_empty());
+			assert_eq!(out[&id], alone(model.as_ref(), prompt, opts).await, "{:?}", prompt);
+		}
+	}
+
+	#[tokio::test]
+	async fn forked_choices_match_separate_runs() {
+		let file = TempModel::write(&tiny_llama(7, GgmlType::Q8_0));
+		let model = load(&file).await;
+		let choices: Vec<GenOptions> = (0..3).map(|i| opts(10 + i, 1.0)).collect();
+
+		let mut decoder = model.batch_decoder().unwrap();
+		let ids = decoder.join_many("the dog ran", choices.clone()).unwrap();
+		let mut out = HashMap::new();
+		drain(decoder.as_mut(), &mut out);
+
+		for (id, opts) in ids.into_iter().zip(choices) {
+			assert_eq!(out[&id], alone(model.as_ref(), "the dog ran", opts).await);
+		}
+	}
+
+	#[tokio::test]
+	async fn a_sequence_that_leaves_frees_its_slot() {
+		let file = TempModel::write(&tiny_llama(7, GgmlType::F32));
+		let model = load(&file).await;
+		let mut decoder = model.batch_decoder().unwrap();
+		let a = decoder.join("hello", opts(1, 0.0)).unwrap();
+		let b = decoder.join("world", opts(1, 0.0)).unwrap();
+		let mut out = HashMap::new();
+		step(decoder.as_mut(), &mut out);
+		decoder.leave(a);
+		assert_eq!(decoder.active(), 1);
+		drain(decoder.as_mut(), &mut out);
+		assert_eq!(out[&b], alone(model.as_ref(), "world", opts(1, 0.0)).await);
+	}
+
+	#[tokio::test]
+	async fn the_scheduler_forgets_runners_of_unloaded_models() {
+		let file = TempModel::write(&tiny_llama(7, GgmlType::F32));
+		let model: Arc<dyn LoadedModel> = load(&file).await.into();
+		let scheduler = crate::scheduler::Scheduler::new(crate::scheduler::SchedulerConfig {
+			parallel: 2,
+			..Default::default()
+		});
+		let batched = |s: &crate::scheduler::Scheduler| s.metrics()["batched_models"].clone();
+
+		let tokens: Vec<_> = scheduler.generate("tiny", &model, "hello", opts(1, 0.0)).collect().await;
+		assert!(!tokens.is_empty());
+		assert_eq!(batched(&scheduler), serde_json::json!(["tiny"]));
+		scheduler.forget("tiny");
+		assert_eq!(batched(&scheduler), serde_json::json!([]));
+	}
+}
@@ -0,0 +1,159 @@
+//! Tiny llama-architecture models for tests, built in memory with [`GgufBuilder`].
+
+use std::{
+	path::{Path, PathBuf},
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+	engine::{
+		cpu::tensor::{encode_f16, quantize_q4_0, quantize_q8_0},
+		gguf::{GgmlType, GgufBuilder, GgufFile, GgufValue, GgufValueType},
+	},
+	model_registry::ModelSpec,
+};
+
+pub const N_EMBD: u64 = 32;
+pub const N_HEAD: u64 = 4;
+pub const N_HEAD_KV: u64 = 2;
+pub const N_FF: u64 = 64;
+pub const N_LAYER: u64 = 2;
+pub const N_CTX: u32 = 64;
+
+/// SentencePiece pieces: the three specials, then words and single letters.
+pub const VOCAB: [&str; 32] = [
+	"<unk>", "<s>", "</s>", "▁a", "▁b", "▁c", "▁d", "▁e", "▁hello", "▁world", "▁the", "▁cat", "▁sat", "▁on", "▁mat",
+	"▁dog", "▁ran", "▁is", "▁big", "▁red", "▁blue", "▁one", "▁two", "▁three", "▁yes", "▁no", "<0x0A>", "▁", "e", "a",
+	"b", "c",
+];
+
+/// Uniform values in [-1, 1) from a fixed seed.
+struct Lcg(u64);
+
+impl Lcg {
+	fn next(&mut self) -> f32 {
+		self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+		((self.0 >> 33) as f32 / (1u64 << 31) as f32) * 2.0 - 1.0
+	}
+}
+
+/// A two-layer model with grouped-query attention whose weights follow from
+/// `seed`. Matrices are stored as `ggml_type`; norms are always f32.
+pub fn tiny_llama(seed: u64, ggml_type: GgmlType) -> GgufBuilder {
+	let n_vocab = VOCAB.len() as u64;
+	let kv_dim = N_EMBD / N_HEAD * N_HEAD_KV;
+	let tokens = VOCAB.iter().map(|t| GgufValue::String(t.to_string())).collect();
+	let mut builder = GgufBuilder::new()
+		.metadata("general.architecture", GgufValue::String("llama".into()))
+		.metadata("general.name", GgufValue::String("tiny-llama".into()))
+		.metadata("llama.context_length", GgufValue::U32(N_CTX))
+		.metadata("llama.embedding_length", GgufValue::U32(N_EMBD as u32))
+		.metadata("llama.block_count", GgufValue::U32(N_LAYER as u32))
+		.metadata("llama.feed_forward_length", GgufValue::U32(N_FF as u32))
+		.metadata("llama.attention.head_count", GgufValue::U32(N_HEAD as u32))
+		.metadata("llama.attention.head_count_kv", GgufValue::U32(N_HEAD_KV as u32))
+		.metadata("llama.attention.layer_norm_rms_epsilon", GgufValue::F32(1e-5))
+		.metadata("llama.rope.freq_base", GgufValue::F32(10000.0))
+		.metadata("tokenizer.ggml.model", GgufValue::String("llama".into()))
+		.metadata("tokenizer.ggml.tokens", GgufValue::Array(GgufValueType::String, tokens))
+		.metadata("tokenizer.ggml.bos_token_id", GgufValue::U32(1))
+		.metadata("tokenizer.ggml.eos_token_id", GgufValue::U32(2));
+
+	let mut rng = Lcg(seed);
+	let mut matrix = |builder: GgufBuilder, name: String, shape: [u64; 2], scale: f32| {
+		let values: Vec<f32> = (0..shape[0] * shape[1]).map(|_| rng.next() * scale).collect();
+		let data = match ggml_type {
+			GgmlType::F32 => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+			GgmlType::F16 => encode_f16(&values),
+			GgmlType::Q8_0 => quantize_q8_0(&values),
+			GgmlType::Q4_0 => quantize_q4_0(&values),
+			other => panic!("fixtures cannot encode {:?}", other),
+		};
+		builder.tensor(name, shape.to_vec(), ggml_type, data)
+	};
+	let norm = |builder: GgufBuilder, name: String| builder.tensor_f32(name, vec![N_EMBD], &[1.0; N_EMBD as usize]);
+
+	builder = matrix(builder, "token_embd.weight".into(), [N_EMBD, n_vocab], 1.0);
+	for l in 0..N_LAYER {
+		let name = |t: &str| format!("blk.{}.{}.weight", l, t);
+		builder = norm(builder, name("attn_norm"));
+		builder = matrix(builder, name("attn_q"), [N_EMBD, N_EMBD], 0.3);
+		builder = matrix(builder, name("attn_k"), [N_EMBD, kv_dim], 0.3);
+		builder = matrix(builder, name("attn_v"), [N_EMBD, kv_dim], 0.3);
+		builder = matrix(builder, name("attn_output"), [N_EMBD, N_EMBD], 0.3);
+		builder = norm(builder, name("ffn_norm"));
+		builder = matrix(builder, name("ffn_gate"), [N_EMBD, N_FF], 0.3);
+		builder = matrix(builder, name("ffn_up"), [N_EMBD, N_FF], 0.3);
+		builder = matrix(builder, name("ffn_down"), [N_FF, N_EMBD], 0.3);
+	}
+	builder = norm(builder, "output_norm.weight".into());
+	matrix(builder, "output.weight".into(), [N_EMBD, n_vocab], 0.5)
+}
+
+/// A rank-`rank` LoRA adapter for [`tiny_llama`] touching every layer's
+/// query and feed-forward up projections.
+pub fn tiny_lora(seed: u64, rank: u64) -> GgufBuilder {
+	let mut rng = Lcg(seed);
+	let mut matrix = |builder: GgufBuilder, name: String, shape: [u64; 2]| {
+		let values: Vec<f32> = (0..shape[0] * shape[1]).map(|_| rng.next()).collect();
+		builder.tensor_f32(name, shape.to_vec(), &values)
+	};
+	let mut builder = GgufBuilder::new()
+		.metadata("general.type", GgufValue::String("adapter".into()))
+		.metadata("adapter.type", GgufValue::String("lora".into()))
+		.metadata("adapter.lora.alpha", GgufValue::F32(rank as f32));
+	for l in 0..N_LAYER {
+		for (tensor, n_out) in [("attn_q", N_EMBD), ("ffn_up", N_FF)] {
+			let name = format!("blk.{}.{}.weight", l, tensor);
+			builder = matrix(builder, format!("{}.lora_a", name), [N_EMBD, rank]);
+			builder = matrix(builder, format!("{}.lora_b", name), [rank, n_out]);
+		}
+	}
+	builder
+}
+
+pub fn tiny_llama_gguf(seed: u64, ggml_type: GgmlType) -> GgufFile {
+	GgufFile::from_bytes(tiny_llama(seed, ggml_type).to_bytes()).expect("fixture parses")
+}
+
+/// A fixture written to a temporary file, removed again on drop.
+pub struct TempModel {
+	path: PathBuf,
+}
+
+impl TempModel {
+	pub fn write(builder: &GgufBuilder) -> Self {
+		static NEXT: AtomicUsize = AtomicUsize::new(0);
+		let path = std::env::temp_dir().join(format!(
+			"shimmy-fixture-{}-{}.gguf",
+			std::process::id(),
+			NEXT.fetch_add(1, Ordering::Relaxed)
+		));
+		builder.write(&path).expect("fixture written");
+		Self { path }
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	pub fn spec(&self) -> ModelSpec {
+		ModelSpec {
+			name: "tiny".into(),
+			base_path: self.path.clone(),
+			lora_path: None,
+			template: None,
+			ctx_len: Some(N_CTX as usize),
+			n_threads: Some(1),
+			draft_path: None,
+			adapters: Default::default(),
+			slots: 1,
+		}
+	}
+}
+
+impl Drop for TempModel {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_file(&self.path);
+	}
+}
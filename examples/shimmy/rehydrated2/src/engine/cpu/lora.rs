@@ -0,0 +1,289 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+};
+
+use crate::engine::{
+	cpu::{model::LlamaConfig, tensor::QMatrix},
+	gguf::GgufFile,
+	AdapterInfo, EngineError, Result,
+};
+
+/// Distinguishes every adapter ever loaded, so state computed under one is
+/// never mistaken for another's after a reload under the same name.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A weight matrix of a llama block that an adapter may adjust.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Target {
+	Q,
+	K,
+	V,
+	O,
+	Gate,
+	Up,
+	Down,
+}
+
+impl Target {
+	pub const ALL: [Target; 7] = [Target::Q, Target::K, Target::V, Target::O, Target::Gate, Target::Up, Target::Down];
+
+	fn tensor(self) -> &'static str {
+		match self {
+			Target::Q => "attn_q",
+			Target::K => "attn_k",
+			Target::V => "attn_v",
+			Target::O => "attn_output",
+			Target::Gate => "ffn_gate",
+			Target::Up => "ffn_up",
+			Target::Down => "ffn_down",
+		}
+	}
+
+	/// Output and input width of the base matrix.
+	fn shape(self, c: &LlamaConfig) -> (usize, usize) {
+		match self {
+			Target::Q | Target::O => (c.n_embd, c.n_embd),
+			Target::K | Target::V => (c.kv_dim(), c.n_embd),
+			Target::Gate | Target::Up => (c.n_ff, c.n_embd),
+			Target::Down => (c.n_embd, c.n_ff),
+		}
+	}
+}
+
+/// `B · A`, the low-rank update to one base matrix.
+struct LoraPair {
+	a: QMatrix,
+	b: QMatrix,
+}
+
+/// A LoRA adapter in llama.cpp's GGUF layout (`blk.N.<weight>.weight.lora_a`
+/// and `.lora_b`). It is applied next to the base weights at decode time,
+/// never merged into them, so any number can share one resident model.
+pub struct LoraAdapter {
+	pub id: u64,
+	pub name: String,
+	pub path: PathBuf,
+	pub rank: usize,
+	pub alpha: f32,
+	pairs: HashMap<(usize, Target), LoraPair>,
+}
+
+impl LoraAdapter {
+	pub fn load(name: &str, path: &Path, config: &LlamaConfig) -> Result<Self> {
+		let fail = |msg: String| EngineError::LoadFailed(format!("adapter {}: {}", name, msg));
+		let gguf = GgufFile::open(path).map_err(|e| fail(e.to_string()))?;
+		if gguf.get_str("general.type").is_some_and(|t| t != "adapter")
+			|| gguf.get_str("adapter.type").is_some_and(|t| t != "lora")
+		{
+			return Err(fail("not a LoRA adapter".into()));
+		}
+
+		let matrix = |tensor: &str| -> Result<Option<QMatrix>> {
+			let Some(info) = gguf.tensor(tensor) else {
+				return Ok(None);
+			};
+			let data = gguf.tensor_data(info).ok_or_else(|| fail(format!("tensor {} has no data", tensor)))?;
+			QMatrix::new(tensor, info.ggml_type, &info.shape, data).map(Some)
+		};
+		let mut pairs = HashMap::new();
+		let mut rank = None;
+		for layer in 0..config.n_layer {
+			for target in Target::ALL {
+				let base = format!("blk.{}.{}.weight", layer, target.tensor());
+				let (a, b) = match (matrix(&format!("{}.lora_a", base))?, matrix(&format!("{}.lora_b", base))?) {
+					(Some(a), Some(b)) => (a, b),
+					(None, None) => continue,
+					_ => return Err(fail(format!("{} has only one of lora_a and lora_b", base))),
+				};
+				let (n_out, n_in) = target.shape(config);
+				let r = *rank.get_or_insert(a.rows);
+				if a.rows != r || a.cols != n_in || b.rows != n_out || b.cols != r {
+					return Err(fail(format!(
+						"{} has lora_a [{}, {}] and lora_b [{}, {}], expected [{}, {}] and [{}, {}]",
+						base, a.cols, a.rows, b.cols, b.rows, n_in, r, r, n_out
+					)));
+				}
+				pairs.insert((layer, target), LoraPair { a, b });
+			}
+		}
+		let Some(rank) = rank.filter(|r| *r > 0) else {
+			return Err(fail("no LoRA tensors for this model's layers".into()));
+		};
+		let expected = pairs.len() * 2;
+		let found = gguf.tensors().iter().filter(|t| t.name.ends_with(".lora_a") || t.name.ends_with(".lora_b")).count();
+		if found != expected {
+			return Err(fail("adapts weights the CPU backend cannot (only attention and feed-forward blocks)".into()));
+		}
+
+		Ok(Self {
+			id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+			name: name.to_string(),
+			path: path.to_path_buf(),
+			rank,
+			alpha: gguf.get_f32("adapter.lora.alpha").unwrap_or(rank as f32),
+			pairs,
+		})
+	}
+
+	pub fn info(&self, default: bool) -> AdapterInfo {
+		AdapterInfo {
+			name: self.name.clone(),
+			path: self.path.clone(),
+			rank: self.rank,
+			alpha: self.alpha,
+			default,
+		}
+	}
+
+	/// Adds `weight * alpha / rank * B(A x)` to `out`.
+	fn apply(&self, layer: usize, target: Target, x: &[f32], out: &mut [f32], weight: f32) {
+		let Some(pair) = self.pairs.get(&(layer, target)) else {
+			return;
+		};
+		let mid = pair.a.matmul(&[x], 1).swap_remove(0);
+		let delta = pair.b.matmul(&[&mid], 1).swap_remove(0);
+		let scale = weight * self.alpha / self.rank as f32;
+		for (o, d) in out.iter_mut().zip(delta) {
+			*o += scale * d;
+		}
+	}
+}
+
+/// The adapters one sequence decodes with, each with its blend weight.
+#[derive(Clone, Default)]
+pub struct AdapterMix(Vec<(Arc<LoraAdapter>, f32)>);
+
+impl AdapterMix {
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	/// Names the mix for the prefix cache; KV state is only reusable under
+	/// the exact adapters it was computed with.
+	pub fn key(&self) -> String {
+		self.0.iter().map(|(a, w)| format!("{}*{}", a.id, w)).collect::<Vec<_>>().join(",")
+	}
+
+	pub fn apply(&self, layer: usize, target: Target, x: &[f32], out: &mut [f32]) {
+		for (adapter, weight) in &self.0 {
+			adapter.apply(layer, target, x, out, *weight);
+		}
+	}
+}
+
+/// The adapters resident on one model, by name.
+#[derive(Default)]
+pub struct AdapterRegistry {
+	loaded: BTreeMap<String, Arc<LoraAdapter>>,
+	/// Applied to requests that do not pick adapters themselves.
+	default: Option<String>,
+}
+
+impl AdapterRegistry {
+	/// Adds or replaces an adapter; requests already decoding keep the one
+	/// they started with.
+	pub fn insert(&mut self, adapter: LoraAdapter) -> AdapterInfo {
+		let info = adapter.info(self.default.as_deref() == Some(adapter.name.as_str()));
+		self.loaded.insert(adapter.name.clone(), Arc::new(adapter));
+		info
+	}
+
+	pub fn set_default(&mut self, name: &str) {
+		self.default = Some(name.to_string());
+	}
+
+	pub fn remove(&mut self, name: &str) -> bool {
+		self.loaded.remove(name).is_some()
+	}
+
+	pub fn list(&self) -> Vec<AdapterInfo> {
+		self.loaded
+			.values()
+			.map(|a| a.info(self.default.as_deref() == Some(a.name.as_str())))
+			.collect()
+	}
+
+	/// Looks up a request's adapter choice; `None` selects the default.
+	pub fn resolve(&self, choice: Option<&[(String, f32)]>) -> Result<AdapterMix> {
+		let Some(choice) = choice else {
+			let default = self.default.as_ref().and_then(|name| self.loaded.get(name));
+			return Ok(AdapterMix(default.map(|a| (a.clone(), 1.0)).into_iter().collect()));
+		};
+		choice
+			.iter()
+			.map(|(name, weight)| match self.loaded.get(name) {
+				Some(adapter) => Ok((adapter.clone(), *weight)),
+				None => Err(EngineError::GenerationFailed(format!("adapter {} is not loaded", name))),
+			})
+			.collect::<Result<_>>()
+			.map(AdapterMix)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::StreamExt;
+
+	use super::*;
+	use crate::engine::{
+		cpu::{
+			fixtures::{tiny_llama, tiny_lora, TempModel},
+			load_blocking, CpuLoaded,
+		},
+		gguf::GgmlType,
+		GenOptions, LoadedModel,
+	};
+
+	async fn greedy(model: &CpuLoaded, adapters: Option<Vec<(String, f32)>>) -> String {
+		let opts = GenOptions {
+			max_tokens: 8,
+			temperature: 0.0,
+			adapters,
+			..Default::default()
+		};
+		let events: Vec<_> = model.generate_stream("the cat sat on", opts).map(|e| e.unwrap()).collect().await;
+		events.into_iter().map(|e| e.text).collect()
+	}
+
+	#[tokio::test]
+	async fn an_unloaded_adapter_leaves_the_base_model_as_it_was() {
+		let base = TempModel::write(&tiny_llama(11, GgmlType::F32));
+		let lora = TempModel::write(&tiny_lora(5, 4));
+		let model = load_blocking(&base.spec()).unwrap();
+		let weights = model.model.clone();
+		let before = greedy(&model, None).await;
+
+		let info = model.load_adapter("style", lora.path()).unwrap();
+		assert_eq!((info.rank, info.alpha, info.default), (4, 4.0, false));
+		assert_eq!(model.adapters().len(), 1);
+		// Loaded but not asked for: requests still decode with the base weights.
+		assert_eq!(greedy(&model, None).await, before);
+		let adapted = greedy(&model, Some(vec![("style".into(), 1.0)])).await;
+		assert_ne!(adapted, before);
+
+		assert!(model.unload_adapter("style"));
+		assert!(!model.unload_adapter("style"));
+		assert!(model.adapters().is_empty());
+		assert_eq!(greedy(&model, None).await, before);
+		// Adapters sit next to the weights; nothing was merged into or copied from them.
+		assert!(Arc::ptr_eq(&weights, &model.model));
+	}
+
+	#[test]
+	fn files_that_do_not_fit_the_model_are_refused() {
+		let base = TempModel::write(&tiny_llama(11, GgmlType::F32));
+		let model = load_blocking(&base.spec()).unwrap();
+		// The fixture has two layers; an adapter for a tenth cannot apply.
+		let deeper = TempModel::write(&tiny_lora(5, 4).tensor_f32("blk.9.attn_q.weight.lora_a", vec![32, 4], &[0.0; 128]));
+		let err = model.load_adapter("deeper", deeper.path()).unwrap_err();
+		assert!(err.to_string().contains("cannot"), "{}", err);
+		let err = model.load_adapter("base", base.path()).unwrap_err();
+		assert!(err.to_string().contains("no LoRA tensors"), "{}", err);
+		assert!(model.adapters().is_empty());
+	}
+}
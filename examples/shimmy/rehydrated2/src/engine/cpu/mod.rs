@@ -0,0 +1,447 @@
+use std::{
+	path::Path,
+	sync::{Arc, Mutex, RwLock},
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::{
+	engine::{
+		batch::BatchDecoder,
+		gguf::{GgmlType, GgufFile},
+		memory::{MemoryEstimate, MemoryPlan, SystemMemory},
+		prefix_cache::{PrefixCache, PrefixCacheStats},
+		tokenizer::{self, Tokenizer},
+		AdapterInfo, EmbedOptions, Embedding, EngineError, GenOptions, InferenceEngine, LoadedModel, Pooling, Result,
+		SpeculativeStats, TokenEvent, TokenStream,
+	},
+	model_registry::ModelSpec,
+};
+
+pub mod batch;
+#[cfg(test)]
+pub(crate) mod fixtures;
+pub mod lora;
+pub mod model;
+pub mod tensor;
+
+use batch::{CpuBatch, Draft};
+use lora::{AdapterRegistry, LoraAdapter};
+use model::{KvCache, LlamaModel};
+
+/// KV state kept per model for prompt-prefix reuse.
+const PREFIX_CACHE_BYTES: usize = 256 << 20;
+
+/// Tokens a draft model guesses ahead per verification pass.
+const DRAFT_TOKENS: usize = 4;
+
+/// Pure-Rust reference backend for llama-architecture GGUF models. Slow, but
+/// needs neither llama.cpp nor a GPU.
+#[derive(Default)]
+pub struct CpuEngine;
+
+impl CpuEngine {
+	pub fn new() -> Self {
+		Self
+	}
+}
+
+#[async_trait]
+impl InferenceEngine for CpuEngine {
+	async fn load(&self, spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+		if !Path::new(&spec.base_path).exists() {
+			return Err(EngineError::ModelNotFound(spec.name.clone()));
+		}
+
+		// Copying weights out of the map is CPU- and IO-heavy; keep it off the runtime.
+		let spec = spec.clone();
+		let loaded = tokio::task::spawn_blocking(move || load_blocking(&spec))
+			.await
+			.map_err(|e| EngineError::LoadFailed(e.to_string()))??;
+
+		Ok(Box::new(loaded))
+	}
+
+	fn plan_memory(&self, spec: &ModelSpec) -> Option<MemoryPlan> {
+		let gguf = GgufFile::open(&spec.base_path).ok()?;
+		Some(plan_memory(&gguf, spec.ctx_len, spec.slots))
+	}
+}
+
+/// Reads the weights, tokenizer, draft and adapters `spec` names.
+fn load_blocking(spec: &ModelSpec) -> Result<CpuLoaded> {
+	let threads = spec
+		.n_threads
+		.map(|n| n.max(1) as usize)
+		.unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+	let gguf = GgufFile::open(&spec.base_path).map_err(|e| EngineError::LoadFailed(format!("{}: {}", spec.name, e)))?;
+	let memory = plan_memory(&gguf, spec.ctx_len, spec.slots);
+	// A downgraded context is reported through the plan, see `LoadedModel::memory_plan`.
+	memory.check()?;
+	let model = LlamaModel::load(&gguf, threads)?;
+	let tokenizer = tokenizer::from_gguf(&gguf)?;
+	let n_ctx = memory.estimate.context_length;
+	let pooling = gguf
+		.architecture()
+		.and_then(|arch| gguf.get_u32(&format!("{}.pooling_type", arch)))
+		.and_then(Pooling::from_gguf);
+	let pieces = (0..tokenizer.vocab().len() as u32).map(|id| tokenizer.decode_piece(id)).collect();
+	let draft = match &spec.draft_path {
+		Some(path) => Some(load_draft(path, tokenizer.as_ref(), threads)?),
+		None => None,
+	};
+	// The registered adapter is the default; ones added at runtime are restored alongside it.
+	// Discovery also pairs models with safetensors adapters, which only other backends read.
+	let mut adapters = AdapterRegistry::default();
+	if let Some(path) = spec.lora_path.as_ref().filter(|p| GgufFile::has_magic(p)) {
+		let name = adapter_name(path);
+		adapters.set_default(&name);
+		adapters.insert(LoraAdapter::load(&name, path, &model.config)?);
+	}
+	for (name, path) in &spec.adapters {
+		adapters.insert(LoraAdapter::load(name, path, &model.config)?);
+	}
+	Ok(CpuLoaded {
+		model: Arc::new(model),
+		tokenizer,
+		pieces: Arc::new(pieces),
+		prefixes: Arc::new(Mutex::new(PrefixCache::new(PREFIX_CACHE_BYTES))),
+		adapters: Arc::new(RwLock::new(adapters)),
+		n_ctx,
+		pooling,
+		draft,
+		memory,
+	})
+}
+
+/// Sizes a load with this backend's f32 KV cache and its prefix cache at
+/// capacity. A context the spec sets explicitly is refused, not shrunk.
+fn plan_memory(gguf: &GgufFile, ctx_len: Option<usize>, slots: usize) -> MemoryPlan {
+	let n_ctx = ctx_len.or_else(|| gguf.context_length().map(|n| n as usize)).unwrap_or(2048);
+	MemoryEstimate::from_gguf(gguf, n_ctx, slots, GgmlType::F32)
+		.with_cache_bytes(PREFIX_CACHE_BYTES as u64)
+		.admit(SystemMemory::read().map(|m| m.available_bytes), ctx_len.is_none())
+}
+
+pub struct CpuLoaded {
+	model: Arc<LlamaModel>,
+	tokenizer: Arc<dyn Tokenizer>,
+	pieces: Arc<Vec<Vec<u8>>>,
+	/// Shared by every batch of this model, so one request's prompt can
+	/// serve the next regardless of which path decoded it.
+	prefixes: Arc<Mutex<PrefixCache<KvCache>>>,
+	adapters: Arc<RwLock<AdapterRegistry>>,
+	n_ctx: usize,
+	/// Declared by embedding models; see [`EmbedOptions::pooling`].
+	pooling: Option<Pooling>,
+	draft: Option<Draft>,
+	memory: MemoryPlan,
+}
+
+impl CpuLoaded {
+	fn new_batch(&self) -> CpuBatch {
+		CpuBatch::new(
+			self.model.clone(),
+			self.tokenizer.clone(),
+			self.pieces.clone(),
+			self.prefixes.clone(),
+			self.adapters.clone(),
+			self.n_ctx,
+			self.draft.clone(),
+		)
+	}
+}
+
+#[async_trait]
+impl LoadedModel for CpuLoaded {
+	fn generate_stream<'a>(&'a self, prompt: &str, opts: GenOptions) -> TokenStream<'a> {
+		let (tx, rx) = mpsc::unbounded_channel();
+		let batch = self.new_batch();
+		let prompt = prompt.to_string();
+
+		tokio::task::spawn_blocking(move || decode(batch, &prompt, opts, &tx));
+		UnboundedReceiverStream::new(rx).boxed()
+	}
+
+	fn batch_decoder(&self) -> Option<Box<dyn BatchDecoder>> {
+		Some(Box::new(self.new_batch()))
+	}
+
+	fn tokenizer(&self) -> Option<&dyn Tokenizer> {
+		Some(self.tokenizer.as_ref())
+	}
+
+	fn supports_grammar(&self) -> bool {
+		true
+	}
+
+	fn supports_logprobs(&self) -> bool {
+		true
+	}
+
+	fn supports_adapters(&self) -> bool {
+		true
+	}
+
+	fn adapters(&self) -> Vec<AdapterInfo> {
+		self.adapters.read().unwrap_or_else(|e| e.into_inner()).list()
+	}
+
+	fn load_adapter(&self, name: &str, path: &Path) -> Result<AdapterInfo> {
+		// Loading happens outside the lock so decoding is never held up by it.
+		let adapter = LoraAdapter::load(name, path, &self.model.config)?;
+		Ok(self.adapters.write().unwrap_or_else(|e| e.into_inner()).insert(adapter))
+	}
+
+	fn unload_adapter(&self, name: &str) -> bool {
+		self.adapters.write().unwrap_or_else(|e| e.into_inner()).remove(name)
+	}
+
+	fn context_length(&self) -> Option<usize> {
+		Some(self.n_ctx)
+	}
+
+	fn memory_plan(&self) -> Option<MemoryPlan> {
+		Some(self.memory.clone())
+	}
+
+	fn supports_embeddings(&self) -> bool {
+		true
+	}
+
+	async fn embed(&self, inputs: &[String], opts: EmbedOptions) -> Result<Vec<Embedding>> {
+		let model = self.model.clone();
+		let tokenizer = self.tokenizer.clone();
+		let mix = self.adapters.read().unwrap_or_else(|e| e.into_inner()).resolve(None)?;
+		let pooling = opts.pooling.or(self.pooling).unwrap_or(Pooling::Mean);
+		let n_ctx = self.n_ctx;
+		let inputs = inputs.to_vec();
+		tokio::task::spawn_blocking(move || {
+			inputs
+				.iter()
+				.map(|input| {
+					let tokens = tokenizer.encode(input, true);
+					if tokens.len() > n_ctx {
+						return Err(EngineError::GenerationFailed(format!(
+							"input is {} tokens, more than the context window of {}",
+							tokens.len(),
+							n_ctx
+						)));
+					}
+					let hidden = model.forward_tokens(&tokens, &mut model.new_cache(), &mix)?;
+					Ok(Embedding {
+						vector: pooling.apply(&hidden, opts.normalize),
+						tokens: tokens.len(),
+					})
+				})
+				.collect()
+		})
+		.await
+		.map_err(|e| EngineError::GenerationFailed(e.to_string()))?
+	}
+
+	fn speculative_stats(&self) -> Option<SpeculativeStats> {
+		let draft = self.draft.as_ref()?;
+		Some(*draft.stats.lock().unwrap_or_else(|e| e.into_inner()))
+	}
+
+	fn prefix_cache_stats(&self) -> Option<PrefixCacheStats> {
+		Some(self.prefixes.lock().unwrap_or_else(|e| e.into_inner()).stats())
+	}
+}
+
+/// Registered adapters are known by their file name.
+fn adapter_name(path: &Path) -> String {
+	path.file_stem().and_then(|s| s.to_str()).unwrap_or("adapter").to_string()
+}
+
+/// Loads a draft model, which must tokenize exactly like the target so its
+/// guesses mean the same thing.
+fn load_draft(path: &Path, target: &dyn Tokenizer, threads: usize) -> Result<Draft> {
+	let fail = |msg: String| EngineError::LoadFailed(format!("draft model {}: {}", path.display(), msg));
+	let gguf = GgufFile::open(path).map_err(|e| fail(e.to_string()))?;
+	let model = LlamaModel::load(&gguf, threads).map_err(|e| fail(e.to_string()))?;
+	let tokenizer = tokenizer::from_gguf(&gguf).map_err(|e| fail(e.to_string()))?;
+	let (ours, theirs) = (target.vocab(), tokenizer.vocab());
+	let same_vocab = ours.len() == theirs.len()
+		&& model.config.n_vocab == ours.len()
+		&& (0..ours.len() as u32).all(|id| ours.token(id) == theirs.token(id));
+	if !same_vocab {
+		return Err(fail("vocabulary differs from the target model's".into()));
+	}
+	Ok(Draft {
+		model: Arc::new(model),
+		tokens: DRAFT_TOKENS,
+		stats: Arc::new(Mutex::new(SpeculativeStats::default())),
+	})
+}
+
+type EventSender = mpsc::UnboundedSender<Result<TokenEvent>>;
+
+/// Single-request decoding is a batch of one, so both paths sample alike.
+fn decode(mut batch: CpuBatch, prompt: &str, opts: GenOptions, tx: &EventSender) {
+	if let Err(e) = batch.join(prompt, opts) {
+		let _ = tx.send(Err(e));
+		return;
+	}
+	while batch.active() > 0 {
+		for (_, event) in batch.step() {
+			if tx.send(event).is_err() {
+				// Nobody is listening any more.
+				return;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::engine::{gguf::GgufValue, FinishReason};
+	use fixtures::{tiny_llama, TempModel};
+
+	async fn greedy(model: &dyn LoadedModel, prompt: &str, max_tokens: usize) -> Vec<TokenEvent> {
+		let opts = GenOptions {
+			max_tokens,
+			temperature: 0.0,
+			seed: Some(1),
+			..Default::default()
+		};
+		model.generate_stream(prompt, opts).map(|e| e.unwrap()).collect().await
+	}
+
+	async fn sampled(model: &dyn LoadedModel, temperature: f32) -> Vec<TokenEvent> {
+		let opts = GenOptions {
+			max_tokens: 12,
+			temperature,
+			seed: Some(5),
+			..Default::default()
+		};
+		model.generate_stream("the cat sat on", opts).map(|e| e.unwrap()).collect().await
+	}
+
+	#[tokio::test]
+	async fn loads_and_decodes_every_supported_encoding() {
+		for ty in [GgmlType::F32, GgmlType::F16, GgmlType::Q8_0, GgmlType::Q4_0] {
+			let file = TempModel::write(&tiny_llama(7, ty));
+			let model = CpuEngine::new().load(&file.spec()).await.unwrap();
+			assert_eq!(model.context_length(), Some(fixtures::N_CTX as usize));
+			let events = greedy(model.as_ref(), "hello world", 5).await;
+			let pieces = events.iter().filter(|e| e.finish_reason.is_none()).count();
+			assert!(pieces > 0 && pieces <= 5, "{:?}", ty);
+			let last = events.last().unwrap();
+			assert!(matches!(last.finish_reason, Some(FinishReason::Length | FinishReason::Stop)), "{:?}", ty);
+		}
+	}
+
+	#[tokio::test]
+	async fn greedy_decoding_is_deterministic() {
+		let file = TempModel::write(&tiny_llama(7, GgmlType::F32));
+		let model = CpuEngine::new().load(&file.spec()).await.unwrap();
+		let ids = |events: Vec<TokenEvent>| events.iter().map(|e| e.token_id).collect::<Vec<_>>();
+		let first = ids(greedy(model.as_ref(), "the cat sat", 8).await);
+		let second = ids(greedy(model.as_ref(), "the cat sat", 8).await);
+		assert_eq!(first, second);
+	}
+
+	#[tokio::test]
+	async fn speculative_decoding_matches_plain_decoding() {
+		let target = TempModel::write(&tiny_llama(7, GgmlType::F32));
+		let plain = CpuEngine::new().load(&target.spec()).await.unwrap();
+		// A quantized copy of the target mostly agrees with it; an unrelated
+		// model mostly does not. Either way only the speed may change.
+		for draft in [tiny_llama(7, GgmlType::Q8_0), tiny_llama(11, GgmlType::F32)] {
+			let draft = TempModel::write(&draft);
+			let spec = ModelSpec {
+				draft_path: Some(draft.path().to_path_buf()),
+				..target.spec()
+			};
+			let speculative = CpuEngine::new().load(&spec).await.unwrap();
+			for temperature in [0.0, 0.9] {
+				let expected = sampled(plain.as_ref(), temperature).await;
+				let actual = sampled(speculative.as_ref(), temperature).await;
+				let ids = |events: &[TokenEvent]| events.iter().map(|e| (e.token_id, e.finish_reason)).collect::<Vec<_>>();
+				assert_eq!(ids(&actual), ids(&expected), "temperature {}", temperature);
+				let text = |events: &[TokenEvent]| events.iter().map(|e| e.text.as_str()).collect::<String>();
+				assert_eq!(text(&actual), text(&expected));
+				assert!(actual.iter().any(|e| e.speculative.is_some()));
+			}
+			let stats = speculative.speculative_stats().unwrap();
+			assert!(stats.drafted > 0 && stats.accepted <= stats.drafted);
+			assert!(plain.speculative_stats().is_none());
+		}
+	}
+
+	#[tokio::test]
+	async fn memory_plan_counts_the_fixture_weights() {
+		let file = TempModel::write(&tiny_llama(7, GgmlType::Q8_0));
+		let plan = CpuEngine::new().plan_memory(&file.spec()).unwrap();
+		let gguf = GgufFile::open(file.path()).unwrap();
+		assert_eq!(plan.estimate.weights_bytes, gguf.tensor_data_size());
+		assert!(plan.estimate.kv_cache_bytes > 0);
+		assert_eq!(plan.estimate.context_length, fixtures::N_CTX as usize);
+	}
+
+	fn assert_close(actual: &[f32], expected: &[f32]) {
+		assert_eq!(actual.len(), expected.len());
+		for (a, e) in actual.iter().zip(expected) {
+			assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+		}
+	}
+
+	#[tokio::test]
+	async fn embeddings_pool_the_final_hidden_states() {
+		let file = TempModel::write(&tiny_llama(7, GgmlType::F32));
+		let model = load_blocking(&file.spec()).unwrap();
+		let text = "the cat sat on the mat";
+		let inputs = [text.to_string()];
+		let tokens = model.tokenizer.encode(text, true);
+		let mut cache = model.model.new_cache();
+		let hidden = model.model.forward_tokens(&tokens, &mut cache, &lora::AdapterMix::default()).unwrap();
+		let mean: Vec<f32> = (0..hidden[0].len())
+			.map(|i| hidden.iter().map(|h| h[i]).sum::<f32>() / hidden.len() as f32)
+			.collect();
+
+		let (first, last) = (hidden[0].clone(), hidden[hidden.len() - 1].clone());
+		for (pooling, pooled) in [(Pooling::Mean, mean), (Pooling::Cls, first), (Pooling::Last, last)] {
+			let embed = |normalize| {
+				let opts = EmbedOptions {
+					pooling: Some(pooling),
+					normalize,
+				};
+				model.embed(&inputs, opts)
+			};
+			let raw = embed(false).await.unwrap();
+			assert_eq!(raw[0].tokens, tokens.len());
+			assert_close(&raw[0].vector, &pooled);
+
+			let unit = embed(true).await.unwrap();
+			let norm = pooled.iter().map(|x| x * x).sum::<f32>().sqrt();
+			let scaled: Vec<f32> = pooled.iter().map(|x| x / norm).collect();
+			assert_close(&unit[0].vector, &scaled);
+			assert!((unit[0].vector.iter().map(|x| x * x).sum::<f32>() - 1.0).abs() < 1e-4, "{:?}", pooling);
+		}
+	}
+
+	#[tokio::test]
+	async fn embeddings_default_to_the_pooling_the_model_declares() {
+		let opts = || EmbedOptions {
+			pooling: None,
+			normalize: false,
+		};
+		let inputs = ["the cat sat".to_string()];
+		let plain = TempModel::write(&tiny_llama(7, GgmlType::F32));
+		let plain = load_blocking(&plain.spec()).unwrap();
+		let declared = tiny_llama(7, GgmlType::F32).metadata("llama.pooling_type", GgufValue::U32(3));
+		let declared = TempModel::write(&declared);
+		let declared = load_blocking(&declared.spec()).unwrap();
+
+		let mean = plain.embed(&inputs, EmbedOptions { pooling: Some(Pooling::Mean), ..opts() }).await.unwrap();
+		let last = plain.embed(&inputs, EmbedOptions { pooling: Some(Pooling::Last), ..opts() }).await.unwrap();
+		assert_eq!(plain.embed(&inputs, opts()).await.unwrap()[0].vector, mean[0].vector);
+		assert_eq!(declared.embed(&inputs, opts()).await.unwrap()[0].vector, last[0].vector);
+	}
+}
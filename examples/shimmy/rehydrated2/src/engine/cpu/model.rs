@@ -0,0 +1,541 @@
+use crate::engine::{
+	cpu::{
+		lora::{AdapterMix, Target},
+		tensor::{dot, QMatrix},
+	},
+	gguf::GgufFile,
+	EngineError, Result,
+};
+
+#[derive(Debug, Clone)]
+pub struct LlamaConfig {
+	pub n_vocab: usize,
+	pub n_embd: usize,
+	pub n_layer: usize,
+	pub n_head: usize,
+	pub n_head_kv: usize,
+	pub n_ff: usize,
+	pub n_ctx_train: usize,
+	pub n_rot: usize,
+	pub rms_eps: f32,
+	pub rope_freq_base: f32,
+}
+
+impl LlamaConfig {
+	pub fn from_gguf(gguf: &GgufFile) -> Result<Self> {
+		let arch = gguf.architecture().unwrap_or("unknown");
+		if arch != "llama" {
+			return Err(EngineError::LoadFailed(format!(
+				"CPU backend supports the llama architecture, model is {}",
+				arch
+			)));
+		}
+		let need = |key: &str| -> Result<usize> {
+			gguf.arch_u64(key)
+				.map(|v| v as usize)
+				.ok_or_else(|| EngineError::LoadFailed(format!("missing metadata llama.{}", key)))
+		};
+
+		let n_embd = need("embedding_length")?;
+		let n_head = need("attention.head_count")?;
+		let n_head_kv = gguf.arch_u64("attention.head_count_kv").map(|v| v as usize).unwrap_or(n_head);
+		if n_head == 0 || n_head_kv == 0 || !n_embd.is_multiple_of(n_head) || !n_head.is_multiple_of(n_head_kv) {
+			return Err(EngineError::LoadFailed(format!(
+				"invalid attention shape: n_embd={} n_head={} n_head_kv={}",
+				n_embd, n_head, n_head_kv
+			)));
+		}
+		let head_dim = n_embd / n_head;
+		let n_vocab = gguf
+			.tensor("token_embd.weight")
+			.and_then(|t| t.shape.get(1).copied())
+			.map(|v| v as usize)
+			.ok_or_else(|| EngineError::LoadFailed("missing tensor token_embd.weight".into()))?;
+
+		Ok(Self {
+			n_vocab,
+			n_embd,
+			n_layer: need("block_count")?,
+			n_head,
+			n_head_kv,
+			n_ff: need("feed_forward_length")?,
+			n_ctx_train: gguf.context_length().map(|v| v as usize).unwrap_or(2048),
+			n_rot: gguf.arch_u64("rope.dimension_count").map(|v| v as usize).unwrap_or(head_dim),
+			rms_eps: gguf.arch_f32("attention.layer_norm_rms_epsilon").unwrap_or(1e-5),
+			rope_freq_base: gguf.arch_f32("rope.freq_base").unwrap_or(10_000.0),
+		})
+	}
+
+	pub fn head_dim(&self) -> usize {
+		self.n_embd / self.n_head
+	}
+
+	pub fn kv_dim(&self) -> usize {
+		self.head_dim() * self.n_head_kv
+	}
+}
+
+struct Layer {
+	attn_norm: Vec<f32>,
+	wq: QMatrix,
+	wk: QMatrix,
+	wv: QMatrix,
+	wo: QMatrix,
+	ffn_norm: Vec<f32>,
+	w_gate: QMatrix,
+	w_up: QMatrix,
+	w_down: QMatrix,
+}
+
+pub struct LlamaModel {
+	pub config: LlamaConfig,
+	token_embd: QMatrix,
+	layers: Vec<Layer>,
+	output_norm: Vec<f32>,
+	output: QMatrix,
+	threads: usize,
+}
+
+/// Per-sequence attention state: keys and values for every processed position.
+#[derive(Clone)]
+pub struct KvCache {
+	k: Vec<Vec<f32>>,
+	v: Vec<Vec<f32>>,
+	kv_dim: usize,
+	len: usize,
+}
+
+impl KvCache {
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Drops every position at or after `len`.
+	pub fn truncate(&mut self, len: usize) {
+		if len >= self.len {
+			return;
+		}
+		for layer in self.k.iter_mut().chain(self.v.iter_mut()) {
+			layer.truncate(len * self.kv_dim);
+		}
+		self.len = len;
+	}
+
+	pub fn size_bytes(&self) -> usize {
+		self.k.iter().chain(self.v.iter()).map(|l| l.len() * 4).sum()
+	}
+}
+
+impl LlamaModel {
+	pub fn load(gguf: &GgufFile, threads: usize) -> Result<Self> {
+		let config = LlamaConfig::from_gguf(gguf)?;
+
+		let matrix = |name: &str| -> Result<QMatrix> {
+			let info = gguf
+				.tensor(name)
+				.ok_or_else(|| EngineError::LoadFailed(format!("missing tensor {}", name)))?;
+			let data = gguf
+				.tensor_data(info)
+				.ok_or_else(|| EngineError::LoadFailed(format!("tensor {} has no data", name)))?;
+			QMatrix::new(name, info.ggml_type, &info.shape, data)
+		};
+		let vector = |name: &str| -> Result<Vec<f32>> { Ok(matrix(name)?.to_f32()) };
+		let expect = |m: &QMatrix, name: &str, rows: usize, cols: usize| -> Result<()> {
+			if m.rows != rows || m.cols != cols {
+				return Err(EngineError::LoadFailed(format!(
+					"tensor {} has shape [{}, {}], expected [{}, {}]",
+					name, m.cols, m.rows, cols, rows
+				)));
+			}
+			Ok(())
+		};
+
+		let c = &config;
+		let token_embd = matrix("token_embd.weight")?;
+		expect(&token_embd, "token_embd.weight", c.n_vocab, c.n_embd)?;
+		let output = match gguf.tensor("output.weight") {
+			Some(_) => matrix("output.weight")?,
+			// Tied embeddings: reuse the input embedding as the LM head.
+			None => token_embd.clone(),
+		};
+		expect(&output, "output.weight", c.n_vocab, c.n_embd)?;
+
+		let mut layers = Vec::with_capacity(c.n_layer);
+		for i in 0..c.n_layer {
+			let name = |t: &str| format!("blk.{}.{}.weight", i, t);
+			let layer = Layer {
+				attn_norm: vector(&name("attn_norm"))?,
+				wq: matrix(&name("attn_q"))?,
+				wk: matrix(&name("attn_k"))?,
+				wv: matrix(&name("attn_v"))?,
+				wo: matrix(&name("attn_output"))?,
+				ffn_norm: vector(&name("ffn_norm"))?,
+				w_gate: matrix(&name("ffn_gate"))?,
+				w_up: matrix(&name("ffn_up"))?,
+				w_down: matrix(&name("ffn_down"))?,
+			};
+			expect(&layer.wq, &name("attn_q"), c.n_embd, c.n_embd)?;
+			expect(&layer.wk, &name("attn_k"), c.kv_dim(), c.n_embd)?;
+			expect(&layer.wv, &name("attn_v"), c.kv_dim(), c.n_embd)?;
+			expect(&layer.wo, &name("attn_output"), c.n_embd, c.n_embd)?;
+			expect(&layer.w_gate, &name("ffn_gate"), c.n_ff, c.n_embd)?;
+			expect(&layer.w_up, &name("ffn_up"), c.n_ff, c.n_embd)?;
+			expect(&layer.w_down, &name("ffn_down"), c.n_embd, c.n_ff)?;
+			layers.push(layer);
+		}
+
+		Ok(Self {
+			token_embd,
+			output_norm: vector("output_norm.weight")?,
+			output,
+			layers,
+			config,
+			threads: threads.max(1),
+		})
+	}
+
+	pub fn new_cache(&self) -> KvCache {
+		KvCache {
+			k: vec![Vec::new(); self.config.n_layer],
+			v: vec![Vec::new(); self.config.n_layer],
+			kv_dim: self.config.kv_dim(),
+			len: 0,
+		}
+	}
+
+	/// Runs one token through the network at position `cache.len()`, appending
+	/// its keys/values, and returns the final normalized hidden state.
+	pub fn forward(&self, token: u32, cache: &mut KvCache, adapters: &AdapterMix) -> Result<Vec<f32>> {
+		let mut hidden = self.forward_batch(&mut [(token, cache, adapters)])?;
+		Ok(hidden.swap_remove(0))
+	}
+
+	/// [`LlamaModel::forward`] for one token from each of several independent
+	/// sequences. Weight matrices are shared across the batch; attention only
+	/// ever looks at a sequence's own cache, and adapters only touch their
+	/// own sequence.
+	pub fn forward_batch(&self, batch: &mut [(u32, &mut KvCache, &AdapterMix)]) -> Result<Vec<Vec<f32>>> {
+		let mut tokens = Vec::with_capacity(batch.len());
+		let mut caches = Vec::with_capacity(batch.len());
+		let mut mixes = Vec::with_capacity(batch.len());
+		for (token, cache, adapters) in batch.iter_mut() {
+			tokens.push(*token);
+			caches.push(&mut **cache);
+			mixes.push(*adapters);
+		}
+		let owners: Vec<usize> = (0..tokens.len()).collect();
+		self.forward_core(&tokens, &owners, &mut caches, &mixes)
+	}
+
+	/// Runs consecutive tokens of one sequence in a single pass, returning the
+	/// hidden state after each. Results match feeding them one at a time.
+	pub fn forward_tokens(&self, tokens: &[u32], cache: &mut KvCache, adapters: &AdapterMix) -> Result<Vec<Vec<f32>>> {
+		self.forward_core(tokens, &vec![0; tokens.len()], &mut [cache], &[adapters])
+	}
+
+	/// Token `i` belongs to `caches[owners[i]]` and decodes with
+	/// `mixes[owners[i]]`; tokens sharing a cache must be in position order,
+	/// and each attends to the ones before it.
+	fn forward_core(
+		&self,
+		tokens: &[u32],
+		owners: &[usize],
+		caches: &mut [&mut KvCache],
+		mixes: &[&AdapterMix],
+	) -> Result<Vec<Vec<f32>>> {
+		let c = &self.config;
+		if let Some(token) = tokens.iter().find(|t| **t as usize >= c.n_vocab) {
+			return Err(EngineError::GenerationFailed(format!("token id {} out of range", token)));
+		}
+		let head_dim = c.head_dim();
+		let group = c.n_head / c.n_head_kv;
+		let scale = 1.0 / (head_dim as f32).sqrt();
+		let norm = |x: &[f32], weight: &[f32]| {
+			let mut out = vec![0.0f32; c.n_embd];
+			rms_norm(x, weight, c.rms_eps, &mut out);
+			out
+		};
+
+		let mut xs: Vec<Vec<f32>> = tokens
+			.iter()
+			.map(|token| {
+				let mut x = vec![0.0f32; c.n_embd];
+				self.token_embd.row(*token as usize, &mut x);
+				x
+			})
+			.collect();
+
+		let adapted = !mixes.iter().all(|m| m.is_empty());
+		let matmul = |w: &QMatrix, l: usize, target: Target, inputs: &[&[f32]]| {
+			let mut out = w.matmul(inputs, self.threads);
+			if adapted {
+				for (i, o) in out.iter_mut().enumerate() {
+					mixes[owners[i]].apply(l, target, inputs[i], o);
+				}
+			}
+			out
+		};
+
+		for (l, layer) in self.layers.iter().enumerate() {
+			let xb: Vec<Vec<f32>> = xs.iter().map(|x| norm(x, &layer.attn_norm)).collect();
+			let xb_refs: Vec<&[f32]> = xb.iter().map(Vec::as_slice).collect();
+			let mut qs = matmul(&layer.wq, l, Target::Q, &xb_refs);
+			let mut ks = matmul(&layer.wk, l, Target::K, &xb_refs);
+			let vs = matmul(&layer.wv, l, Target::V, &xb_refs);
+
+			let mut attns = Vec::with_capacity(tokens.len());
+			for (i, &owner) in owners.iter().enumerate() {
+				let cache = &mut *caches[owner];
+				let pos = cache.k[l].len() / c.kv_dim();
+				let (q, k) = (&mut qs[i], &mut ks[i]);
+				for h in 0..c.n_head {
+					rope(&mut q[h * head_dim..(h + 1) * head_dim], pos, c.n_rot, c.rope_freq_base);
+				}
+				for h in 0..c.n_head_kv {
+					rope(&mut k[h * head_dim..(h + 1) * head_dim], pos, c.n_rot, c.rope_freq_base);
+				}
+				cache.k[l].extend_from_slice(k);
+				cache.v[l].extend_from_slice(&vs[i]);
+
+				let keys = &cache.k[l];
+				let values = &cache.v[l];
+				let mut scores = vec![0.0f32; pos + 1];
+				let mut attn = vec![0.0f32; c.n_embd];
+				for h in 0..c.n_head {
+					let kv_off = (h / group) * head_dim;
+					let qh = &q[h * head_dim..(h + 1) * head_dim];
+					for (t, s) in scores.iter_mut().enumerate() {
+						let kt = &keys[t * c.kv_dim() + kv_off..t * c.kv_dim() + kv_off + head_dim];
+						*s = dot(qh, kt) * scale;
+					}
+					softmax(&mut scores);
+					let out = &mut attn[h * head_dim..(h + 1) * head_dim];
+					for (t, s) in scores.iter().enumerate() {
+						let vt = &values[t * c.kv_dim() + kv_off..t * c.kv_dim() + kv_off + head_dim];
+						for (o, val) in out.iter_mut().zip(vt) {
+							*o += s * val;
+						}
+					}
+				}
+				attns.push(attn);
+			}
+
+			let attn_refs: Vec<&[f32]> = attns.iter().map(Vec::as_slice).collect();
+			for (x, d) in xs.iter_mut().zip(matmul(&layer.wo, l, Target::O, &attn_refs)) {
+				add_assign(x, &d);
+			}
+
+			let xb: Vec<Vec<f32>> = xs.iter().map(|x| norm(x, &layer.ffn_norm)).collect();
+			let xb_refs: Vec<&[f32]> = xb.iter().map(Vec::as_slice).collect();
+			let mut gates = matmul(&layer.w_gate, l, Target::Gate, &xb_refs);
+			let ups = matmul(&layer.w_up, l, Target::Up, &xb_refs);
+			for (gate, up) in gates.iter_mut().zip(&ups) {
+				for (g, u) in gate.iter_mut().zip(up) {
+					*g = silu(*g) * u;
+				}
+			}
+			let gate_refs: Vec<&[f32]> = gates.iter().map(Vec::as_slice).collect();
+			for (x, d) in xs.iter_mut().zip(matmul(&layer.w_down, l, Target::Down, &gate_refs)) {
+				add_assign(x, &d);
+			}
+		}
+		for &owner in owners {
+			caches[owner].len += 1;
+		}
+
+		Ok(xs.iter().map(|x| norm(x, &self.output_norm)).collect())
+	}
+
+	pub fn logits(&self, hidden: &[f32]) -> Vec<f32> {
+		self.logits_batch(&[hidden]).swap_remove(0)
+	}
+
+	pub fn logits_batch(&self, hidden: &[&[f32]]) -> Vec<Vec<f32>> {
+		self.output.matmul(hidden, self.threads)
+	}
+}
+
+fn add_assign(x: &mut [f32], d: &[f32]) {
+	for (xi, di) in x.iter_mut().zip(d) {
+		*xi += di;
+	}
+}
+
+fn rms_norm(x: &[f32], weight: &[f32], eps: f32, out: &mut [f32]) {
+	let mean_sq = x.iter().map(|v| v * v).sum::<f32>() / x.len() as f32;
+	let inv = 1.0 / (mean_sq + eps).sqrt();
+	for ((o, xi), w) in out.iter_mut().zip(x).zip(weight) {
+		*o = xi * inv * w;
+	}
+}
+
+/// Rotates adjacent pairs (llama.cpp "normal" RoPE, which GGUF llama weights expect).
+fn rope(x: &mut [f32], pos: usize, n_rot: usize, base: f32) {
+	for i in (0..n_rot.min(x.len())).step_by(2) {
+		let theta = pos as f32 * base.powf(-(i as f32) / n_rot as f32);
+		let (sin, cos) = theta.sin_cos();
+		let (a, b) = (x[i], x[i + 1]);
+		x[i] = a * cos - b * sin;
+		x[i + 1] = a * sin + b * cos;
+	}
+}
+
+fn silu(x: f32) -> f32 {
+	x / (1.0 + (-x).exp())
+}
+
+pub fn softmax(x: &mut [f32]) {
+	let max = x.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+	let mut sum = 0.0;
+	for v in x.iter_mut() {
+		*v = (*v - max).exp();
+		sum += *v;
+	}
+	for v in x.iter_mut() {
+		*v /= sum;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::engine::{cpu::fixtures::tiny_llama_gguf, gguf::GgmlType};
+
+	fn weights(gguf: &GgufFile, name: &str) -> Vec<f32> {
+		let data = gguf.tensor_data(gguf.tensor(name).unwrap()).unwrap();
+		data.chunks_exact(4).map(|c| f32::from_le_bytes(c.try_into().unwrap())).collect()
+	}
+
+	/// `w` stored GGUF-style: `cols` inputs contiguous per output row.
+	fn matvec(w: &[f32], x: &[f32]) -> Vec<f32> {
+		w.chunks_exact(x.len()).map(|row| row.iter().zip(x).map(|(a, b)| a * b).sum()).collect()
+	}
+
+	fn rms(x: &[f32]) -> Vec<f32> {
+		let inv = 1.0 / (x.iter().map(|v| v * v).sum::<f32>() / x.len() as f32 + 1e-5).sqrt();
+		x.iter().map(|v| v * inv).collect()
+	}
+
+	fn assert_close(a: &[f32], b: &[f32], tol: f32) {
+		assert_eq!(a.len(), b.len());
+		for (i, (x, y)) in a.iter().zip(b).enumerate() {
+			assert!((x - y).abs() <= tol, "element {}: {} vs {}", i, x, y);
+		}
+	}
+
+	/// At position 0 RoPE is the identity and each head attends only to
+	/// itself, so attention reduces to `wo · v` and the whole pass can be
+	/// written out directly.
+	#[test]
+	fn first_token_matches_a_direct_computation() {
+		let gguf = tiny_llama_gguf(7, GgmlType::F32);
+		let model = LlamaModel::load(&gguf, 1).unwrap();
+		let c = &model.config;
+		let token = 8;
+
+		let embd = weights(&gguf, "token_embd.weight");
+		let mut x = embd[token * c.n_embd..(token + 1) * c.n_embd].to_vec();
+		for l in 0..c.n_layer {
+			let w = |t: &str| weights(&gguf, &format!("blk.{}.{}.weight", l, t));
+			let v = matvec(&w("attn_v"), &rms(&x));
+			let group = c.n_head / c.n_head_kv;
+			let heads: Vec<f32> = (0..c.n_head)
+				.flat_map(|h| {
+					let kv = h / group * c.head_dim();
+					v[kv..kv + c.head_dim()].to_vec()
+				})
+				.collect();
+			for (xi, d) in x.iter_mut().zip(matvec(&w("attn_output"), &heads)) {
+				*xi += d;
+			}
+			let n = rms(&x);
+			let act: Vec<f32> = matvec(&w("ffn_gate"), &n)
+				.iter()
+				.zip(matvec(&w("ffn_up"), &n))
+				.map(|(g, u)| g / (1.0 + (-g).exp()) * u)
+				.collect();
+			for (xi, d) in x.iter_mut().zip(matvec(&w("ffn_down"), &act)) {
+				*xi += d;
+			}
+		}
+		let expected_logits = matvec(&weights(&gguf, "output.weight"), &rms(&x));
+
+		let hidden = model.forward(token as u32, &mut model.new_cache(), &AdapterMix::default()).unwrap();
+		assert_close(&hidden, &rms(&x), 1e-4);
+		assert_close(&model.logits(&hidden), &expected_logits, 1e-4);
+	}
+
+	#[test]
+	fn prompt_pass_matches_token_by_token() {
+		let model = LlamaModel::load(&tiny_llama_gguf(7, GgmlType::F32), 1).unwrap();
+		let tokens = [1, 8, 9, 10, 11, 12];
+		let none = AdapterMix::default();
+
+		let mut cache = model.new_cache();
+		let stepwise: Vec<Vec<f32>> = tokens.iter().map(|t| model.forward(*t, &mut cache, &none).unwrap()).collect();
+		let mut prompt_cache = model.new_cache();
+		let at_once = model.forward_tokens(&tokens, &mut prompt_cache, &none).unwrap();
+
+		assert_eq!(cache.len(), tokens.len());
+		assert_eq!(prompt_cache.len(), tokens.len());
+		for (a, b) in stepwise.iter().zip(&at_once) {
+			assert_close(a, b, 1e-5);
+		}
+	}
+
+	#[test]
+	fn batched_sequences_match_separate_ones() {
+		let model = LlamaModel::load(&tiny_llama_gguf(7, GgmlType::F32), 1).unwrap();
+		let none = AdapterMix::default();
+		let (a, b) = ([1u32, 8, 9], [1u32, 15, 16]);
+
+		let (mut ca, mut cb) = (model.new_cache(), model.new_cache());
+		let mut batched = vec![];
+		for (ta, tb) in a.iter().zip(&b) {
+			batched.push(model.forward_batch(&mut [(*ta, &mut ca, &none), (*tb, &mut cb, &none)]).unwrap());
+		}
+		let alone = |tokens: &[u32]| {
+			let mut cache = model.new_cache();
+			tokens.iter().map(|t| model.forward(*t, &mut cache, &none).unwrap()).collect::<Vec<_>>()
+		};
+		for (step, (ha, hb)) in alone(&a).iter().zip(alone(&b)).enumerate() {
+			assert_close(&batched[step][0], ha, 1e-5);
+			assert_close(&batched[step][1], &hb, 1e-5);
+		}
+	}
+
+	#[test]
+	fn truncating_the_cache_rewinds_the_sequence() {
+		let model = LlamaModel::load(&tiny_llama_gguf(7, GgmlType::F32), 1).unwrap();
+		let none = AdapterMix::default();
+		let mut cache = model.new_cache();
+		model.forward_tokens(&[1, 8, 9], &mut cache, &none).unwrap();
+		let expected = model.forward(10, &mut cache.clone(), &none).unwrap();
+		model.forward(20, &mut cache, &none).unwrap();
+		cache.truncate(3);
+		assert_close(&model.forward(10, &mut cache, &none).unwrap(), &expected, 0.0);
+	}
+
+	#[test]
+	fn quantized_weights_track_f32() {
+		let f32_model = LlamaModel::load(&tiny_llama_gguf(7, GgmlType::F32), 1).unwrap();
+		let q8_model = LlamaModel::load(&tiny_llama_gguf(7, GgmlType::Q8_0), 1).unwrap();
+		let none = AdapterMix::default();
+		let logits = |m: &LlamaModel| {
+			let hidden = m.forward_tokens(&[1, 8, 9, 10], &mut m.new_cache(), &none).unwrap();
+			m.logits(hidden.last().unwrap())
+		};
+		assert_close(&logits(&q8_model), &logits(&f32_model), 0.05);
+	}
+
+	#[test]
+	fn rejects_out_of_range_tokens() {
+		let model = LlamaModel::load(&tiny_llama_gguf(7, GgmlType::F32), 1).unwrap();
+		let err = model.forward(999, &mut model.new_cache(), &AdapterMix::default());
+		assert!(matches!(err, Err(EngineError::GenerationFailed(_))));
+	}
+}
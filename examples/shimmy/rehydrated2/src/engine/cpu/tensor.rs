@@ -0,0 +1,326 @@
+use crate::engine::{gguf::GgmlType, EngineError, Result};
+
+const QK: usize = 32;
+
+pub fn f16_to_f32(h: u16) -> f32 {
+	let sign = ((h >> 15) as u32) << 31;
+	let exp = ((h >> 10) & 0x1f) as u32;
+	let mant = (h & 0x3ff) as u32;
+	let bits = match (exp, mant) {
+		(0, 0) => sign,
+		(0, _) => {
+			// Subnormal half: renormalize into an f32 exponent.
+			let mut e: u32 = 127 - 15 + 1;
+			let mut m = mant;
+			while m & 0x400 == 0 {
+				m <<= 1;
+				e -= 1;
+			}
+			sign | (e << 23) | ((m & 0x3ff) << 13)
+		}
+		(0x1f, _) => sign | 0x7f80_0000 | (mant << 13),
+		_ => sign | ((exp + 127 - 15) << 23) | (mant << 13),
+	};
+	f32::from_bits(bits)
+}
+
+/// Round-to-nearest-even conversion, used when writing F16/Q8_0/Q4_0 fixtures.
+pub fn f32_to_f16(v: f32) -> u16 {
+	let bits = v.to_bits();
+	let sign = ((bits >> 16) & 0x8000) as u16;
+	let exp = ((bits >> 23) & 0xff) as i32;
+	let mant = bits & 0x7f_ffff;
+
+	if exp == 0xff {
+		return sign | 0x7c00 | if mant != 0 { 0x200 } else { 0 };
+	}
+	let e = exp - 127 + 15;
+	if e >= 0x1f {
+		return sign | 0x7c00;
+	}
+	if e <= 0 {
+		if e < -10 {
+			return sign;
+		}
+		let m = mant | 0x80_0000;
+		let shift = (14 - e) as u32;
+		let half = 1u32 << (shift - 1);
+		let rounded = (m + half - 1 + ((m >> shift) & 1)) >> shift;
+		return sign | rounded as u16;
+	}
+	let rounded = mant + 0xfff + ((mant >> 13) & 1);
+	if rounded & 0x80_0000 != 0 {
+		// Mantissa overflow carries into the exponent.
+		let e = e + 1;
+		if e >= 0x1f {
+			return sign | 0x7c00;
+		}
+		return sign | ((e as u16) << 10);
+	}
+	sign | ((e as u16) << 10) | (rounded >> 13) as u16
+}
+
+pub fn supports(ty: GgmlType) -> bool {
+	matches!(ty, GgmlType::F32 | GgmlType::F16 | GgmlType::Q8_0 | GgmlType::Q4_0)
+}
+
+/// Decodes `out.len()` consecutive elements starting at the beginning of `data`.
+pub fn dequantize(ty: GgmlType, data: &[u8], out: &mut [f32]) {
+	match ty {
+		GgmlType::F32 => {
+			for (o, c) in out.iter_mut().zip(data.chunks_exact(4)) {
+				*o = f32::from_le_bytes([c[0], c[1], c[2], c[3]]);
+			}
+		}
+		GgmlType::F16 => {
+			for (o, c) in out.iter_mut().zip(data.chunks_exact(2)) {
+				*o = f16_to_f32(u16::from_le_bytes([c[0], c[1]]));
+			}
+		}
+		GgmlType::Q8_0 => {
+			for (block, o) in data.chunks_exact(2 + QK).zip(out.chunks_mut(QK)) {
+				let d = f16_to_f32(u16::from_le_bytes([block[0], block[1]]));
+				for (x, q) in o.iter_mut().zip(&block[2..]) {
+					*x = (*q as i8) as f32 * d;
+				}
+			}
+		}
+		GgmlType::Q4_0 => {
+			for (block, o) in data.chunks_exact(2 + QK / 2).zip(out.chunks_mut(QK)) {
+				let d = f16_to_f32(u16::from_le_bytes([block[0], block[1]]));
+				let qs = &block[2..];
+				for j in 0..QK / 2 {
+					o[j] = ((qs[j] & 0x0f) as i32 - 8) as f32 * d;
+					o[j + QK / 2] = ((qs[j] >> 4) as i32 - 8) as f32 * d;
+				}
+			}
+		}
+		_ => unreachable!("unsupported tensor type {:?} passed validation", ty),
+	}
+}
+
+pub fn encode_f16(values: &[f32]) -> Vec<u8> {
+	values.iter().flat_map(|v| f32_to_f16(*v).to_le_bytes()).collect()
+}
+
+pub fn quantize_q8_0(values: &[f32]) -> Vec<u8> {
+	let mut out = Vec::with_capacity(values.len() / QK * (2 + QK));
+	for block in values.chunks(QK) {
+		let amax = block.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+		let d = amax / 127.0;
+		let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+		out.extend_from_slice(&f32_to_f16(d).to_le_bytes());
+		for i in 0..QK {
+			let v = block.get(i).copied().unwrap_or(0.0);
+			out.push((v * id).round() as i8 as u8);
+		}
+	}
+	out
+}
+
+pub fn quantize_q4_0(values: &[f32]) -> Vec<u8> {
+	let mut out = Vec::with_capacity(values.len() / QK * (2 + QK / 2));
+	for block in values.chunks(QK) {
+		// Same scheme as ggml: the signed max maps to -8 so the full range is used.
+		let max = block.iter().fold(0.0f32, |m, v| if v.abs() > m.abs() { *v } else { m });
+		let d = max / -8.0;
+		let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+		out.extend_from_slice(&f32_to_f16(d).to_le_bytes());
+		let q = |i: usize| -> u8 {
+			let v = block.get(i).copied().unwrap_or(0.0);
+			((v * id + 8.5) as i32).clamp(0, 15) as u8
+		};
+		for j in 0..QK / 2 {
+			out.push(q(j) | (q(j + QK / 2) << 4));
+		}
+	}
+	out
+}
+
+/// A 2-D weight kept in its on-disk encoding; rows are dequantized on use.
+#[derive(Clone)]
+pub struct QMatrix {
+	pub ty: GgmlType,
+	pub rows: usize,
+	pub cols: usize,
+	data: Vec<u8>,
+	row_bytes: usize,
+}
+
+impl QMatrix {
+	pub fn new(name: &str, ty: GgmlType, shape: &[u64], data: &[u8]) -> Result<Self> {
+		if !supports(ty) {
+			return Err(EngineError::LoadFailed(format!(
+				"tensor {} has unsupported type {} (CPU backend supports F32, F16, Q8_0, Q4_0)",
+				name,
+				ty.name()
+			)));
+		}
+		let cols = shape.first().copied().unwrap_or(1) as usize;
+		let rows = shape.iter().skip(1).product::<u64>() as usize;
+		let (block, bytes) = ty.block_layout().unwrap_or((1, 4));
+		if !cols.is_multiple_of(block as usize) {
+			return Err(EngineError::LoadFailed(format!("tensor {} row length {} is not block aligned", name, cols)));
+		}
+		let row_bytes = cols / block as usize * bytes as usize;
+		if data.len() < row_bytes * rows {
+			return Err(EngineError::LoadFailed(format!("tensor {} is truncated", name)));
+		}
+		Ok(Self {
+			ty,
+			rows,
+			cols,
+			data: data[..row_bytes * rows].to_vec(),
+			row_bytes,
+		})
+	}
+
+	pub fn row(&self, r: usize, out: &mut [f32]) {
+		let start = r * self.row_bytes;
+		dequantize(self.ty, &self.data[start..start + self.row_bytes], &mut out[..self.cols]);
+	}
+
+	pub fn to_f32(&self) -> Vec<f32> {
+		let mut out = vec![0.0; self.rows * self.cols];
+		for (r, chunk) in out.chunks_mut(self.cols).enumerate() {
+			self.row(r, chunk);
+		}
+		out
+	}
+
+	/// `out[r] = dot(row r, x)`, split across up to `threads` workers.
+	pub fn matvec(&self, x: &[f32], out: &mut [f32], threads: usize) {
+		let mut result = self.matmul(&[x], threads);
+		out[..self.rows].copy_from_slice(&result.swap_remove(0));
+	}
+
+	/// [`QMatrix::matvec`] for several inputs at once. Each row is dequantized
+	/// a single time and dotted with every input, which is what makes batched
+	/// decoding cheaper than decoding sequences one by one.
+	pub fn matmul(&self, xs: &[&[f32]], threads: usize) -> Vec<Vec<f32>> {
+		let n = xs.len();
+		// Row-major: the results for row r are at [r * n, (r + 1) * n).
+		let mut by_row = vec![0.0f32; self.rows * n];
+		let work = |first_row: usize, out: &mut [f32]| {
+			let mut buf = vec![0.0f32; self.cols];
+			for (i, row_out) in out.chunks_mut(n).enumerate() {
+				self.row(first_row + i, &mut buf);
+				for (o, x) in row_out.iter_mut().zip(xs) {
+					*o = dot(&buf, x);
+				}
+			}
+		};
+
+		let threads = threads.max(1).min(self.rows);
+		if n == 0 {
+			return vec![];
+		} else if threads == 1 || self.rows * self.cols < 1 << 16 {
+			work(0, &mut by_row);
+		} else {
+			let chunk = self.rows.div_ceil(threads);
+			std::thread::scope(|s| {
+				for (i, part) in by_row.chunks_mut(chunk * n).enumerate() {
+					let work = &work;
+					s.spawn(move || work(i * chunk, part));
+				}
+			});
+		}
+		(0..n).map(|b| by_row.iter().skip(b).step_by(n).copied().collect()).collect()
+	}
+}
+
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+	a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn ramp(n: usize) -> Vec<f32> {
+		(0..n).map(|i| ((i * 37 % 101) as f32 - 50.0) / 25.0).collect()
+	}
+
+	fn decode(ty: GgmlType, data: &[u8], n: usize) -> Vec<f32> {
+		let mut out = vec![0.0; n];
+		dequantize(ty, data, &mut out);
+		out
+	}
+
+	fn max_error(a: &[f32], b: &[f32]) -> f32 {
+		a.iter().zip(b).map(|(x, y)| (x - y).abs()).fold(0.0, f32::max)
+	}
+
+	#[test]
+	fn f16_round_trips_exact_values_and_specials() {
+		for v in [0.0, -0.0, 1.0, -2.5, 65504.0, 6.1035156e-5, 5.9604645e-8] {
+			assert_eq!(f16_to_f32(f32_to_f16(v)).to_bits(), v.to_bits(), "{}", v);
+		}
+		assert_eq!(f16_to_f32(f32_to_f16(1e6)), f32::INFINITY);
+		assert_eq!(f16_to_f32(f32_to_f16(f32::NEG_INFINITY)), f32::NEG_INFINITY);
+		assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+		assert_eq!(f32_to_f16(1e-9), 0);
+		// 1 + 2^-11 is halfway between two halves and rounds to the even one.
+		assert_eq!(f32_to_f16(1.0 + 1.0 / 2048.0), 0x3c00);
+	}
+
+	#[test]
+	fn f16_encoding_is_within_half_precision() {
+		let values = ramp(64);
+		let decoded = decode(GgmlType::F16, &encode_f16(&values), values.len());
+		assert!(max_error(&values, &decoded) <= 2.0 / 1024.0);
+	}
+
+	#[test]
+	fn q8_0_round_trip_is_within_one_step() {
+		let values = ramp(96);
+		let data = quantize_q8_0(&values);
+		assert_eq!(data.len(), 3 * (2 + QK));
+		let decoded = decode(GgmlType::Q8_0, &data, values.len());
+		let amax = values.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+		assert!(max_error(&values, &decoded) <= amax / 127.0);
+	}
+
+	#[test]
+	fn q4_0_round_trip_is_within_one_step() {
+		let values = ramp(64);
+		let data = quantize_q4_0(&values);
+		assert_eq!(data.len(), 2 * (2 + QK / 2));
+		let decoded = decode(GgmlType::Q4_0, &data, values.len());
+		let amax = values.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+		assert!(max_error(&values, &decoded) <= amax / 8.0);
+	}
+
+	#[test]
+	fn zero_blocks_quantize_to_zero() {
+		let zeros = [0.0; QK];
+		assert_eq!(decode(GgmlType::Q8_0, &quantize_q8_0(&zeros), QK), zeros);
+		assert_eq!(decode(GgmlType::Q4_0, &quantize_q4_0(&zeros), QK), zeros);
+	}
+
+	#[test]
+	fn matmul_matches_rowwise_dots_across_threads() {
+		let (rows, cols) = (300, 256);
+		let values = ramp(rows * cols);
+		let m = QMatrix::new("w", GgmlType::Q8_0, &[cols as u64, rows as u64], &quantize_q8_0(&values)).unwrap();
+		let dense = m.to_f32();
+		let xs = [ramp(cols), ramp(cols + 3)[3..].to_vec()];
+		let inputs: Vec<&[f32]> = xs.iter().map(|x| x.as_slice()).collect();
+		let single = m.matmul(&inputs, 1);
+		let threaded = m.matmul(&inputs, 4);
+		for (b, x) in xs.iter().enumerate() {
+			let expected: Vec<f32> = dense.chunks(cols).map(|row| dot(row, x)).collect();
+			assert_eq!(single[b], expected);
+			assert_eq!(threaded[b], expected);
+		}
+	}
+
+	#[test]
+	fn rejects_unaligned_and_truncated_tensors() {
+		let unaligned = QMatrix::new("w", GgmlType::Q8_0, &[40, 1], &[0; 64]);
+		assert!(matches!(unaligned, Err(EngineError::LoadFailed(_))));
+		let truncated = QMatrix::new("w", GgmlType::F32, &[4, 2], &[0; 16]);
+		assert!(matches!(truncated, Err(EngineError::LoadFailed(_))));
+		assert!(QMatrix::new("w", GgmlType::F32, &[4, 2], &[0; 32]).is_ok());
+	}
+}
@@ -0,0 +1,1035 @@
+use std::{
+	collections::BTreeMap,
+	fs::File,
+	path::{Path, PathBuf},
+};
+
+use memmap2::Mmap;
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const DEFAULT_ALIGNMENT: u64 = 32;
+/// `GGML_MAX_DIMS`; no tensor ggml can load has more.
+const MAX_DIMS: u32 = 4;
+/// Arrays of arrays are rare in practice; the limit keeps a crafted file from
+/// recursing the parser off the stack.
+const MAX_ARRAY_DEPTH: usize = 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum GgufError {
+	#[error("io error: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("not a GGUF file (bad magic)")]
+	BadMagic,
+	#[error("unsupported GGUF version {0}")]
+	UnsupportedVersion(u32),
+	#[error("unexpected end of file at offset {0}")]
+	Truncated(usize),
+	#[error("invalid metadata value type {0}")]
+	InvalidValueType(u32),
+	#[error("invalid UTF-8 in string at offset {0}")]
+	InvalidUtf8(usize),
+	#[error("tensor {0} lies outside the data section")]
+	TensorOutOfBounds(String),
+	#[error("tensor {0} has {1} dimensions; at most 4 are supported")]
+	TooManyDimensions(String, u32),
+	#[error("tensor {0} is too large to address")]
+	TensorTooLarge(String),
+	#[error("metadata arrays nested deeper than {0} levels")]
+	NestingTooDeep(usize),
+	#[error("invalid alignment {0}")]
+	InvalidAlignment(u64),
+}
+
+pub type GgufResult<T> = std::result::Result<T, GgufError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GgmlType {
+	F32,
+	F16,
+	Q4_0,
+	Q4_1,
+	Q5_0,
+	Q5_1,
+	Q8_0,
+	Q8_1,
+	Q2K,
+	Q3K,
+	Q4K,
+	Q5K,
+	Q6K,
+	Q8K,
+	IQ2XXS,
+	IQ2XS,
+	IQ3XXS,
+	IQ1S,
+	IQ4NL,
+	IQ3S,
+	IQ2S,
+	IQ4XS,
+	I8,
+	I16,
+	I32,
+	I64,
+	F64,
+	IQ1M,
+	BF16,
+	TQ1_0,
+	TQ2_0,
+	Other(u32),
+}
+
+impl GgmlType {
+	pub fn from_u32(v: u32) -> Self {
+		match v {
+			0 => GgmlType::F32,
+			1 => GgmlType::F16,
+			2 => GgmlType::Q4_0,
+			3 => GgmlType::Q4_1,
+			6 => GgmlType::Q5_0,
+			7 => GgmlType::Q5_1,
+			8 => GgmlType::Q8_0,
+			9 => GgmlType::Q8_1,
+			10 => GgmlType::Q2K,
+			11 => GgmlType::Q3K,
+			12 => GgmlType::Q4K,
+			13 => GgmlType::Q5K,
+			14 => GgmlType::Q6K,
+			15 => GgmlType::Q8K,
+			16 => GgmlType::IQ2XXS,
+			17 => GgmlType::IQ2XS,
+			18 => GgmlType::IQ3XXS,
+			19 => GgmlType::IQ1S,
+			20 => GgmlType::IQ4NL,
+			21 => GgmlType::IQ3S,
+			22 => GgmlType::IQ2S,
+			23 => GgmlType::IQ4XS,
+			24 => GgmlType::I8,
+			25 => GgmlType::I16,
+			26 => GgmlType::I32,
+			27 => GgmlType::I64,
+			28 => GgmlType::F64,
+			29 => GgmlType::IQ1M,
+			30 => GgmlType::BF16,
+			34 => GgmlType::TQ1_0,
+			35 => GgmlType::TQ2_0,
+			other => GgmlType::Other(other),
+		}
+	}
+
+	pub fn to_u32(self) -> u32 {
+		match self {
+			GgmlType::F32 => 0,
+			GgmlType::F16 => 1,
+			GgmlType::Q4_0 => 2,
+			GgmlType::Q4_1 => 3,
+			GgmlType::Q5_0 => 6,
+			GgmlType::Q5_1 => 7,
+			GgmlType::Q8_0 => 8,
+			GgmlType::Q8_1 => 9,
+			GgmlType::Q2K => 10,
+			GgmlType::Q3K => 11,
+			GgmlType::Q4K => 12,
+			GgmlType::Q5K => 13,
+			GgmlType::Q6K => 14,
+			GgmlType::Q8K => 15,
+			GgmlType::IQ2XXS => 16,
+			GgmlType::IQ2XS => 17,
+			GgmlType::IQ3XXS => 18,
+			GgmlType::IQ1S => 19,
+			GgmlType::IQ4NL => 20,
+			GgmlType::IQ3S => 21,
+			GgmlType::IQ2S => 22,
+			GgmlType::IQ4XS => 23,
+			GgmlType::I8 => 24,
+			GgmlType::I16 => 25,
+			GgmlType::I32 => 26,
+			GgmlType::I64 => 27,
+			GgmlType::F64 => 28,
+			GgmlType::IQ1M => 29,
+			GgmlType::BF16 => 30,
+			GgmlType::TQ1_0 => 34,
+			GgmlType::TQ2_0 => 35,
+			GgmlType::Other(v) => v,
+		}
+	}
+
+	/// (elements per block, bytes per block); `None` for unknown types.
+	pub fn block_layout(self) -> Option<(u64, u64)> {
+		let layout = match self {
+			GgmlType::F32 => (1, 4),
+			GgmlType::F16 => (1, 2),
+			GgmlType::Q4_0 => (32, 18),
+			GgmlType::Q4_1 => (32, 20),
+			GgmlType::Q5_0 => (32, 22),
+			GgmlType::Q5_1 => (32, 24),
+			GgmlType::Q8_0 => (32, 34),
+			GgmlType::Q8_1 => (32, 36),
+			GgmlType::Q2K => (256, 84),
+			GgmlType::Q3K => (256, 110),
+			GgmlType::Q4K => (256, 144),
+			GgmlType::Q5K => (256, 176),
+			GgmlType::Q6K => (256, 210),
+			GgmlType::Q8K => (256, 292),
+			GgmlType::IQ2XXS => (256, 66),
+			GgmlType::IQ2XS => (256, 74),
+			GgmlType::IQ3XXS => (256, 98),
+			GgmlType::IQ1S => (256, 50),
+			GgmlType::IQ4NL => (32, 18),
+			GgmlType::IQ3S => (256, 110),
+			GgmlType::IQ2S => (256, 82),
+			GgmlType::IQ4XS => (256, 136),
+			GgmlType::I8 => (1, 1),
+			GgmlType::I16 => (1, 2),
+			GgmlType::I32 => (1, 4),
+			GgmlType::I64 => (1, 8),
+			GgmlType::F64 => (1, 8),
+			GgmlType::IQ1M => (256, 56),
+			GgmlType::BF16 => (1, 2),
+			GgmlType::TQ1_0 => (256, 54),
+			GgmlType::TQ2_0 => (256, 66),
+			GgmlType::Other(_) => return None,
+		};
+		Some(layout)
+	}
+
+	pub fn name(self) -> String {
+		match self {
+			GgmlType::Q2K => "Q2_K".into(),
+			GgmlType::Q3K => "Q3_K".into(),
+			GgmlType::Q4K => "Q4_K".into(),
+			GgmlType::Q5K => "Q5_K".into(),
+			GgmlType::Q6K => "Q6_K".into(),
+			GgmlType::Q8K => "Q8_K".into(),
+			GgmlType::IQ2XXS => "IQ2_XXS".into(),
+			GgmlType::IQ2XS => "IQ2_XS".into(),
+			GgmlType::IQ3XXS => "IQ3_XXS".into(),
+			GgmlType::IQ1S => "IQ1_S".into(),
+			GgmlType::IQ4NL => "IQ4_NL".into(),
+			GgmlType::IQ3S => "IQ3_S".into(),
+			GgmlType::IQ2S => "IQ2_S".into(),
+			GgmlType::IQ4XS => "IQ4_XS".into(),
+			GgmlType::IQ1M => "IQ1_M".into(),
+			GgmlType::Other(v) => format!("type{}", v),
+			other => format!("{:?}", other),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GgufValueType {
+	U8,
+	I8,
+	U16,
+	I16,
+	U32,
+	I32,
+	F32,
+	Bool,
+	String,
+	Array,
+	U64,
+	I64,
+	F64,
+}
+
+impl GgufValueType {
+	fn from_u32(v: u32) -> GgufResult<Self> {
+		Ok(match v {
+			0 => GgufValueType::U8,
+			1 => GgufValueType::I8,
+			2 => GgufValueType::U16,
+			3 => GgufValueType::I16,
+			4 => GgufValueType::U32,
+			5 => GgufValueType::I32,
+			6 => GgufValueType::F32,
+			7 => GgufValueType::Bool,
+			8 => GgufValueType::String,
+			9 => GgufValueType::Array,
+			10 => GgufValueType::U64,
+			11 => GgufValueType::I64,
+			12 => GgufValueType::F64,
+			other => return Err(GgufError::InvalidValueType(other)),
+		})
+	}
+
+	fn to_u32(self) -> u32 {
+		match self {
+			GgufValueType::U8 => 0,
+			GgufValueType::I8 => 1,
+			GgufValueType::U16 => 2,
+			GgufValueType::I16 => 3,
+			GgufValueType::U32 => 4,
+			GgufValueType::I32 => 5,
+			GgufValueType::F32 => 6,
+			GgufValueType::Bool => 7,
+			GgufValueType::String => 8,
+			GgufValueType::Array => 9,
+			GgufValueType::U64 => 10,
+			GgufValueType::I64 => 11,
+			GgufValueType::F64 => 12,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+	U8(u8),
+	I8(i8),
+	U16(u16),
+	I16(i16),
+	U32(u32),
+	I32(i32),
+	F32(f32),
+	Bool(bool),
+	String(String),
+	Array(GgufValueType, Vec<GgufValue>),
+	U64(u64),
+	I64(i64),
+	F64(f64),
+}
+
+impl GgufValue {
+	pub fn value_type(&self) -> GgufValueType {
+		match self {
+			GgufValue::U8(_) => GgufValueType::U8,
+			GgufValue::I8(_) => GgufValueType::I8,
+			GgufValue::U16(_) => GgufValueType::U16,
+			GgufValue::I16(_) => GgufValueType::I16,
+			GgufValue::U32(_) => GgufValueType::U32,
+			GgufValue::I32(_) => GgufValueType::I32,
+			GgufValue::F32(_) => GgufValueType::F32,
+			GgufValue::Bool(_) => GgufValueType::Bool,
+			GgufValue::String(_) => GgufValueType::String,
+			GgufValue::Array(..) => GgufValueType::Array,
+			GgufValue::U64(_) => GgufValueType::U64,
+			GgufValue::I64(_) => GgufValueType::I64,
+			GgufValue::F64(_) => GgufValueType::F64,
+		}
+	}
+
+	/// Any integer type that fits, so callers need not care how a writer stored it.
+	pub fn as_u64(&self) -> Option<u64> {
+		match *self {
+			GgufValue::U8(v) => Some(v as u64),
+			GgufValue::U16(v) => Some(v as u64),
+			GgufValue::U32(v) => Some(v as u64),
+			GgufValue::U64(v) => Some(v),
+			GgufValue::I8(v) => u64::try_from(v).ok(),
+			GgufValue::I16(v) => u64::try_from(v).ok(),
+			GgufValue::I32(v) => u64::try_from(v).ok(),
+			GgufValue::I64(v) => u64::try_from(v).ok(),
+			_ => None,
+		}
+	}
+
+	pub fn as_i64(&self) -> Option<i64> {
+		match *self {
+			GgufValue::I8(v) => Some(v as i64),
+			GgufValue::I16(v) => Some(v as i64),
+			GgufValue::I32(v) => Some(v as i64),
+			GgufValue::I64(v) => Some(v),
+			GgufValue::U8(v) => Some(v as i64),
+			GgufValue::U16(v) => Some(v as i64),
+			GgufValue::U32(v) => Some(v as i64),
+			GgufValue::U64(v) => i64::try_from(v).ok(),
+			_ => None,
+		}
+	}
+
+	pub fn as_f64(&self) -> Option<f64> {
+		match *self {
+			GgufValue::F32(v) => Some(v as f64),
+			GgufValue::F64(v) => Some(v),
+			_ => self.as_i64().map(|v| v as f64),
+		}
+	}
+
+	pub fn as_bool(&self) -> Option<bool> {
+		match *self {
+			GgufValue::Bool(v) => Some(v),
+			_ => None,
+		}
+	}
+
+	pub fn as_str(&self) -> Option<&str> {
+		match self {
+			GgufValue::String(s) => Some(s),
+			_ => None,
+		}
+	}
+
+	pub fn as_array(&self) -> Option<&[GgufValue]> {
+		match self {
+			GgufValue::Array(_, items) => Some(items),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct TensorInfo {
+	pub name: String,
+	/// Dimensions as stored, innermost (contiguous) first.
+	pub shape: Vec<u64>,
+	pub ggml_type: GgmlType,
+	/// Offset relative to the start of the tensor data section.
+	pub offset: u64,
+}
+
+impl TensorInfo {
+	/// Saturates rather than overflowing; parsed tensors never need to.
+	pub fn n_elements(&self) -> u64 {
+		self.checked_n_elements().unwrap_or(u64::MAX)
+	}
+
+	fn checked_n_elements(&self) -> Option<u64> {
+		self.shape.iter().try_fold(1u64, |n, &d| n.checked_mul(d))
+	}
+
+	/// `None` for types without a known block layout, or sizes past `u64`.
+	pub fn size_bytes(&self) -> Option<u64> {
+		let (block, bytes) = self.ggml_type.block_layout()?;
+		self.checked_n_elements()?.div_ceil(block).checked_mul(bytes)
+	}
+}
+
+enum Backing {
+	Mmap(Mmap),
+	Owned(Vec<u8>),
+}
+
+impl Backing {
+	fn bytes(&self) -> &[u8] {
+		match self {
+			Backing::Mmap(m) => m,
+			Backing::Owned(v) => v,
+		}
+	}
+}
+
+/// A parsed GGUF file. Metadata and tensor descriptors are decoded eagerly;
+/// tensor data stays in the memory map and is borrowed on demand.
+pub struct GgufFile {
+	path: Option<PathBuf>,
+	backing: Backing,
+	version: u32,
+	metadata: BTreeMap<String, GgufValue>,
+	tensors: Vec<TensorInfo>,
+	data_offset: u64,
+}
+
+impl std::fmt::Debug for GgufFile {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("GgufFile")
+			.field("path", &self.path)
+			.field("version", &self.version)
+			.field("metadata_keys", &self.metadata.len())
+			.field("tensors", &self.tensors.len())
+			.finish()
+	}
+}
+
+impl GgufFile {
+	pub fn open(path: impl AsRef<Path>) -> GgufResult<Self> {
+		let path = path.as_ref();
+		let file = File::open(path)?;
+		// SAFETY: the map is read-only and model files are not expected to be
+		// modified while loaded; this matches how llama.cpp maps weights.
+		let mmap = unsafe { Mmap::map(&file)? };
+		let mut gguf = Self::parse(Backing::Mmap(mmap))?;
+		gguf.path = Some(path.to_path_buf());
+		Ok(gguf)
+	}
+
+	pub fn from_bytes(bytes: Vec<u8>) -> GgufResult<Self> {
+		Self::parse(Backing::Owned(bytes))
+	}
+
+	/// Cheap check used by discovery before committing to a full parse.
+	pub fn has_magic(path: impl AsRef<Path>) -> bool {
+		use std::io::Read;
+		let mut magic = [0u8; 4];
+		File::open(path)
+			.and_then(|mut f| f.read_exact(&mut magic))
+			.map(|_| &magic == GGUF_MAGIC)
+			.unwrap_or(false)
+	}
+
+	fn parse(backing: Backing) -> GgufResult<Self> {
+		let bytes = backing.bytes();
+		let mut r = Reader { bytes, pos: 0, version: 3 };
+
+		if r.take(4)? != GGUF_MAGIC {
+			return Err(GgufError::BadMagic);
+		}
+		let version = r.u32()?;
+		if !(1..=3).contains(&version) {
+			return Err(GgufError::UnsupportedVersion(version));
+		}
+		r.version = version;
+
+		let tensor_count = r.count()?;
+		let kv_count = r.count()?;
+
+		let mut metadata = BTreeMap::new();
+		for _ in 0..kv_count {
+			let key = r.string()?;
+			let ty = GgufValueType::from_u32(r.u32()?)?;
+			let value = r.value(ty, 0)?;
+			metadata.insert(key, value);
+		}
+
+		let mut tensors = Vec::with_capacity(tensor_count.min(1 << 16) as usize);
+		for _ in 0..tensor_count {
+			let name = r.string()?;
+			let n_dims = r.u32()?;
+			if n_dims > MAX_DIMS {
+				return Err(GgufError::TooManyDimensions(name, n_dims));
+			}
+			let mut shape = Vec::with_capacity(n_dims as usize);
+			for _ in 0..n_dims {
+				shape.push(r.count()?);
+			}
+			let ggml_type = GgmlType::from_u32(r.u32()?);
+			let offset = r.u64()?;
+			let info = TensorInfo {
+				name,
+				shape,
+				ggml_type,
+				offset,
+			};
+			let known_layout = ggml_type.block_layout().is_some();
+			if info.checked_n_elements().is_none() || (known_layout && info.size_bytes().is_none()) {
+				return Err(GgufError::TensorTooLarge(info.name));
+			}
+			tensors.push(info);
+		}
+
+		let alignment = metadata
+			.get("general.alignment")
+			.and_then(GgufValue::as_u64)
+			.filter(|a| *a > 0)
+			.unwrap_or(DEFAULT_ALIGNMENT);
+		let data_offset = (r.pos as u64)
+			.checked_next_multiple_of(alignment)
+			.ok_or(GgufError::InvalidAlignment(alignment))?;
+
+		let data_len = (bytes.len() as u64).saturating_sub(data_offset);
+		for t in &tensors {
+			if let Some(size) = t.size_bytes() {
+				if t.offset.checked_add(size).is_none_or(|end| end > data_len) {
+					return Err(GgufError::TensorOutOfBounds(t.name.clone()));
+				}
+			}
+		}
+
+		Ok(Self {
+			path: None,
+			backing,
+			version,
+			metadata,
+			tensors,
+			data_offset,
+		})
+	}
+
+	pub fn path(&self) -> Option<&Path> {
+		self.path.as_deref()
+	}
+
+	pub fn version(&self) -> u32 {
+		self.version
+	}
+
+	pub fn metadata(&self) -> &BTreeMap<String, GgufValue> {
+		&self.metadata
+	}
+
+	pub fn get(&self, key: &str) -> Option<&GgufValue> {
+		self.metadata.get(key)
+	}
+
+	pub fn get_str(&self, key: &str) -> Option<&str> {
+		self.get(key)?.as_str()
+	}
+
+	pub fn get_u64(&self, key: &str) -> Option<u64> {
+		self.get(key)?.as_u64()
+	}
+
+	pub fn get_u32(&self, key: &str) -> Option<u32> {
+		self.get_u64(key).and_then(|v| u32::try_from(v).ok())
+	}
+
+	pub fn get_f32(&self, key: &str) -> Option<f32> {
+		self.get(key)?.as_f64().map(|v| v as f32)
+	}
+
+	pub fn get_bool(&self, key: &str) -> Option<bool> {
+		self.get(key)?.as_bool()
+	}
+
+	pub fn get_array(&self, key: &str) -> Option<&[GgufValue]> {
+		self.get(key)?.as_array()
+	}
+
+	pub fn get_str_array(&self, key: &str) -> Option<Vec<&str>> {
+		self.get_array(key)?.iter().map(GgufValue::as_str).collect()
+	}
+
+	pub fn architecture(&self) -> Option<&str> {
+		self.get_str("general.architecture")
+	}
+
+	/// Reads an architecture-scoped key such as `llama.block_count`.
+	pub fn arch_u64(&self, suffix: &str) -> Option<u64> {
+		let arch = self.architecture()?;
+		self.get_u64(&format!("{}.{}", arch, suffix))
+	}
+
+	pub fn arch_f32(&self, suffix: &str) -> Option<f32> {
+		let arch = self.architecture()?;
+		self.get_f32(&format!("{}.{}", arch, suffix))
+	}
+
+	pub fn name(&self) -> Option<&str> {
+		self.get_str("general.name")
+	}
+
+	pub fn context_length(&self) -> Option<u64> {
+		self.arch_u64("context_length")
+	}
+
+	pub fn block_count(&self) -> Option<u64> {
+		self.arch_u64("block_count")
+	}
+
+	pub fn chat_template(&self) -> Option<&str> {
+		self.get_str("tokenizer.chat_template")
+	}
+
+	/// Quantization label derived from `general.file_type`, falling back to the
+	/// most common tensor type when the key is absent.
+	pub fn quantization(&self) -> Option<String> {
+		if let Some(ft) = self.get_u32("general.file_type") {
+			if let Some(name) = file_type_name(ft) {
+				return Some(name.to_string());
+			}
+		}
+		let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+		for t in &self.tensors {
+			let count = counts.entry(t.ggml_type.name()).or_default();
+			*count = count.saturating_add(t.n_elements());
+		}
+		counts.into_iter().max_by_key(|(_, n)| *n).map(|(name, _)| name)
+	}
+
+	pub fn parameter_count(&self) -> u64 {
+		self.tensors.iter().map(TensorInfo::n_elements).fold(0, u64::saturating_add)
+	}
+
+	pub fn tensors(&self) -> &[TensorInfo] {
+		&self.tensors
+	}
+
+	pub fn tensor(&self, name: &str) -> Option<&TensorInfo> {
+		self.tensors.iter().find(|t| t.name == name)
+	}
+
+	pub fn tensor_data_size(&self) -> u64 {
+		self.tensors.iter().filter_map(TensorInfo::size_bytes).fold(0, u64::saturating_add)
+	}
+
+	pub fn tensor_data(&self, info: &TensorInfo) -> Option<&[u8]> {
+		let size = info.size_bytes()?;
+		let start = usize::try_from(self.data_offset.checked_add(info.offset)?).ok()?;
+		let end = start.checked_add(usize::try_from(size).ok()?)?;
+		self.backing.bytes().get(start..end)
+	}
+}
+
+/// Formats a raw parameter count the way model cards do ("7B", "1.1B", "135M").
+pub fn format_parameter_count(n: u64) -> String {
+	let (value, suffix) = if n >= 1_000_000_000 {
+		(n as f64 / 1e9, "B")
+	} else if n >= 1_000_000 {
+		(n as f64 / 1e6, "M")
+	} else if n >= 1_000 {
+		(n as f64 / 1e3, "K")
+	} else {
+		return n.to_string();
+	};
+	let rounded = (value * 10.0).round() / 10.0;
+	if rounded.fract() == 0.0 {
+		format!("{}{}", rounded as u64, suffix)
+	} else {
+		format!("{:.1}{}", rounded, suffix)
+	}
+}
+
+/// Names for llama.cpp's `llama_ftype` values stored in `general.file_type`.
+pub fn file_type_name(ft: u32) -> Option<&'static str> {
+	Some(match ft {
+		0 => "F32",
+		1 => "F16",
+		2 => "Q4_0",
+		3 => "Q4_1",
+		7 => "Q8_0",
+		8 => "Q5_0",
+		9 => "Q5_1",
+		10 => "Q2_K",
+		11 => "Q3_K_S",
+		12 => "Q3_K_M",
+		13 => "Q3_K_L",
+		14 => "Q4_K_S",
+		15 => "Q4_K_M",
+		16 => "Q5_K_S",
+		17 => "Q5_K_M",
+		18 => "Q6_K",
+		19 => "IQ2_XXS",
+		20 => "IQ2_XS",
+		21 => "Q2_K_S",
+		22 => "IQ3_XS",
+		23 => "IQ3_XXS",
+		24 => "IQ1_S",
+		25 => "IQ4_NL",
+		26 => "IQ3_S",
+		27 => "IQ3_M",
+		28 => "IQ2_S",
+		29 => "IQ2_M",
+		30 => "IQ4_XS",
+		31 => "IQ1_M",
+		32 => "BF16",
+		36 => "TQ1_0",
+		37 => "TQ2_0",
+		_ => return None,
+	})
+}
+
+struct Reader<'a> {
+	bytes: &'a [u8],
+	pos: usize,
+	version: u32,
+}
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, n: usize) -> GgufResult<&'a [u8]> {
+		let end = self.pos.checked_add(n).ok_or(GgufError::Truncated(self.pos))?;
+		let slice = self.bytes.get(self.pos..end).ok_or(GgufError::Truncated(self.pos))?;
+		self.pos = end;
+		Ok(slice)
+	}
+
+	fn array<const N: usize>(&mut self) -> GgufResult<[u8; N]> {
+		let mut out = [0u8; N];
+		out.copy_from_slice(self.take(N)?);
+		Ok(out)
+	}
+
+	fn u32(&mut self) -> GgufResult<u32> {
+		Ok(u32::from_le_bytes(self.array()?))
+	}
+
+	fn u64(&mut self) -> GgufResult<u64> {
+		Ok(u64::from_le_bytes(self.array()?))
+	}
+
+	/// Counts and lengths were 32-bit in GGUF v1 and 64-bit afterwards.
+	fn count(&mut self) -> GgufResult<u64> {
+		if self.version == 1 {
+			self.u32().map(u64::from)
+		} else {
+			self.u64()
+		}
+	}
+
+	fn string(&mut self) -> GgufResult<String> {
+		let len = self.count()?;
+		let start = self.pos;
+		let len = usize::try_from(len).map_err(|_| GgufError::Truncated(start))?;
+		let raw = self.take(len)?;
+		String::from_utf8(raw.to_vec()).map_err(|_| GgufError::InvalidUtf8(start))
+	}
+
+	/// Reads one value; `depth` counts the arrays it is nested in.
+	fn value(&mut self, ty: GgufValueType, depth: usize) -> GgufResult<GgufValue> {
+		Ok(match ty {
+			GgufValueType::U8 => GgufValue::U8(self.array::<1>()?[0]),
+			GgufValueType::I8 => GgufValue::I8(i8::from_le_bytes(self.array()?)),
+			GgufValueType::U16 => GgufValue::U16(u16::from_le_bytes(self.array()?)),
+			GgufValueType::I16 => GgufValue::I16(i16::from_le_bytes(self.array()?)),
+			GgufValueType::U32 => GgufValue::U32(self.u32()?),
+			GgufValueType::I32 => GgufValue::I32(i32::from_le_bytes(self.array()?)),
+			GgufValueType::F32 => GgufValue::F32(f32::from_le_bytes(self.array()?)),
+			GgufValueType::Bool => GgufValue::Bool(self.array::<1>()?[0] != 0),
+			GgufValueType::String => GgufValue::String(self.string()?),
+			GgufValueType::U64 => GgufValue::U64(self.u64()?),
+			GgufValueType::I64 => GgufValue::I64(i64::from_le_bytes(self.array()?)),
+			GgufValueType::F64 => GgufValue::F64(f64::from_le_bytes(self.array()?)),
+			GgufValueType::Array => {
+				if depth >= MAX_ARRAY_DEPTH {
+					return Err(GgufError::NestingTooDeep(MAX_ARRAY_DEPTH));
+				}
+				let item_ty = GgufValueType::from_u32(self.u32()?)?;
+				let len = self.count()?;
+				// Every element occupies at least one byte, which bounds bogus lengths.
+				if len > (self.bytes.len() - self.pos) as u64 {
+					return Err(GgufError::Truncated(self.pos));
+				}
+				let mut items = Vec::with_capacity(len as usize);
+				for _ in 0..len {
+					items.push(self.value(item_ty, depth + 1)?);
+				}
+				GgufValue::Array(item_ty, items)
+			}
+		})
+	}
+}
+
+/// Writes GGUF v3 files. Intended for generating small fixtures and adapters;
+/// tensor payloads are supplied already encoded in their `GgmlType`.
+#[derive(Debug, Default, Clone)]
+pub struct GgufBuilder {
+	metadata: Vec<(String, GgufValue)>,
+	tensors: Vec<(String, Vec<u64>, GgmlType, Vec<u8>)>,
+}
+
+impl GgufBuilder {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn metadata(mut self, key: impl Into<String>, value: GgufValue) -> Self {
+		self.metadata.push((key.into(), value));
+		self
+	}
+
+	pub fn tensor(mut self, name: impl Into<String>, shape: Vec<u64>, ggml_type: GgmlType, data: Vec<u8>) -> Self {
+		self.tensors.push((name.into(), shape, ggml_type, data));
+		self
+	}
+
+	pub fn tensor_f32(self, name: impl Into<String>, shape: Vec<u64>, values: &[f32]) -> Self {
+		let data = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+		self.tensor(name, shape, GgmlType::F32, data)
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let alignment = self
+			.metadata
+			.iter()
+			.find(|(k, _)| k == "general.alignment")
+			.and_then(|(_, v)| v.as_u64())
+			.filter(|a| *a > 0)
+			.unwrap_or(DEFAULT_ALIGNMENT) as usize;
+
+		let mut out = Vec::new();
+		out.extend_from_slice(GGUF_MAGIC);
+		out.extend_from_slice(&3u32.to_le_bytes());
+		out.extend_from_slice(&(self.tensors.len() as u64).to_le_bytes());
+		out.extend_from_slice(&(self.metadata.len() as u64).to_le_bytes());
+
+		for (key, value) in &self.metadata {
+			write_string(&mut out, key);
+			out.extend_from_slice(&value.value_type().to_u32().to_le_bytes());
+			write_value(&mut out, value);
+		}
+
+		let mut offset = 0usize;
+		for (name, shape, ty, data) in &self.tensors {
+			write_string(&mut out, name);
+			out.extend_from_slice(&(shape.len() as u32).to_le_bytes());
+			for d in shape {
+				out.extend_from_slice(&d.to_le_bytes());
+			}
+			out.extend_from_slice(&ty.to_u32().to_le_bytes());
+			out.extend_from_slice(&(offset as u64).to_le_bytes());
+			offset = (offset + data.len()).div_ceil(alignment) * alignment;
+		}
+
+		for (_, _, _, data) in &self.tensors {
+			out.resize(out.len().div_ceil(alignment) * alignment, 0);
+			out.extend_from_slice(data);
+		}
+		out
+	}
+
+	pub fn write(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+		std::fs::write(path, self.to_bytes())
+	}
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+	out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+	out.extend_from_slice(s.as_bytes());
+}
+
+fn write_value(out: &mut Vec<u8>, value: &GgufValue) {
+	match value {
+		GgufValue::U8(v) => out.push(*v),
+		GgufValue::I8(v) => out.extend_from_slice(&v.to_le_bytes()),
+		GgufValue::U16(v) => out.extend_from_slice(&v.to_le_bytes()),
+		GgufValue::I16(v) => out.extend_from_slice(&v.to_le_bytes()),
+		GgufValue::U32(v) => out.extend_from_slice(&v.to_le_bytes()),
+		GgufValue::I32(v) => out.extend_from_slice(&v.to_le_bytes()),
+		GgufValue::F32(v) => out.extend_from_slice(&v.to_le_bytes()),
+		GgufValue::Bool(v) => out.push(*v as u8),
+		GgufValue::String(s) => write_string(out, s),
+		GgufValue::Array(ty, items) => {
+			out.extend_from_slice(&ty.to_u32().to_le_bytes());
+			out.extend_from_slice(&(items.len() as u64).to_le_bytes());
+			for item in items {
+				write_value(out, item);
+			}
+		}
+		GgufValue::U64(v) => out.extend_from_slice(&v.to_le_bytes()),
+		GgufValue::I64(v) => out.extend_from_slice(&v.to_le_bytes()),
+		GgufValue::F64(v) => out.extend_from_slice(&v.to_le_bytes()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn header(tensor_count: u64, kv_count: u64) -> Vec<u8> {
+		let mut out = GGUF_MAGIC.to_vec();
+		out.extend_from_slice(&3u32.to_le_bytes());
+		out.extend_from_slice(&tensor_count.to_le_bytes());
+		out.extend_from_slice(&kv_count.to_le_bytes());
+		out
+	}
+
+	/// A header declaring one tensor named "t" with the given dimensions.
+	fn one_tensor(shape: &[u64], ggml_type: GgmlType) -> Vec<u8> {
+		let mut out = header(1, 0);
+		write_string(&mut out, "t");
+		out.extend_from_slice(&(shape.len() as u32).to_le_bytes());
+		for d in shape {
+			out.extend_from_slice(&d.to_le_bytes());
+		}
+		out.extend_from_slice(&ggml_type.to_u32().to_le_bytes());
+		out.extend_from_slice(&0u64.to_le_bytes());
+		out
+	}
+
+	#[test]
+	fn builder_round_trips_metadata_and_tensors() {
+		let q8: Vec<u8> = (0..34).collect();
+		let bytes = GgufBuilder::new()
+			.metadata("general.architecture", GgufValue::String("llama".into()))
+			.metadata("llama.block_count", GgufValue::U32(2))
+			.metadata("llama.context_length", GgufValue::U64(4096))
+			.metadata("llama.rope.freq_base", GgufValue::F32(10000.0))
+			.metadata("general.flag", GgufValue::Bool(true))
+			.metadata(
+				"tokenizer.ggml.tokens",
+				GgufValue::Array(GgufValueType::String, vec![GgufValue::String("a".into()), GgufValue::String("b".into())]),
+			)
+			.tensor_f32("output_norm.weight", vec![3], &[1.0, -2.5, 0.125])
+			.tensor("blk.0.attn_q.weight", vec![32, 1], GgmlType::Q8_0, q8.clone())
+			.to_bytes();
+
+		let gguf = GgufFile::from_bytes(bytes).unwrap();
+		assert_eq!(gguf.version(), 3);
+		assert_eq!(gguf.architecture(), Some("llama"));
+		assert_eq!(gguf.block_count(), Some(2));
+		assert_eq!(gguf.context_length(), Some(4096));
+		assert_eq!(gguf.arch_f32("rope.freq_base"), Some(10000.0));
+		assert_eq!(gguf.get_bool("general.flag"), Some(true));
+		assert_eq!(gguf.get_str_array("tokenizer.ggml.tokens"), Some(vec!["a", "b"]));
+
+		let norm = gguf.tensor("output_norm.weight").unwrap();
+		assert_eq!(norm.shape, vec![3]);
+		let values: Vec<f32> = gguf
+			.tensor_data(norm)
+			.unwrap()
+			.chunks_exact(4)
+			.map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+			.collect();
+		assert_eq!(values, vec![1.0, -2.5, 0.125]);
+
+		let q = gguf.tensor("blk.0.attn_q.weight").unwrap();
+		assert_eq!(q.ggml_type, GgmlType::Q8_0);
+		assert_eq!(q.offset % DEFAULT_ALIGNMENT, 0);
+		assert_eq!(gguf.tensor_data(q).unwrap(), &q8[..]);
+		assert_eq!(gguf.parameter_count(), 35);
+		assert_eq!(gguf.tensor_data_size(), 12 + 34);
+	}
+
+	#[test]
+	fn honours_custom_alignment() {
+		let bytes = GgufBuilder::new()
+			.metadata("general.alignment", GgufValue::U32(64))
+			.tensor_f32("a", vec![1], &[1.0])
+			.tensor_f32("b", vec![1], &[2.0])
+			.to_bytes();
+		let gguf = GgufFile::from_bytes(bytes).unwrap();
+		assert_eq!(gguf.tensor("b").unwrap().offset, 64);
+		assert_eq!(gguf.tensor_data(gguf.tensor("b").unwrap()).unwrap(), &2.0f32.to_le_bytes());
+	}
+
+	#[test]
+	fn rejects_bad_magic_and_truncation() {
+		assert!(matches!(GgufFile::from_bytes(b"GGML\x03\0\0\0".to_vec()), Err(GgufError::BadMagic)));
+		let mut bytes = GgufBuilder::new().metadata("general.name", GgufValue::String("tiny".into())).to_bytes();
+		bytes.truncate(bytes.len() - 2);
+		assert!(matches!(GgufFile::from_bytes(bytes), Err(GgufError::Truncated(_))));
+	}
+
+	#[test]
+	fn rejects_tensors_past_the_data_section() {
+		let mut bytes = one_tensor(&[8], GgmlType::F32);
+		bytes.resize(bytes.len().div_ceil(32) * 32 + 16, 0);
+		assert!(matches!(GgufFile::from_bytes(bytes), Err(GgufError::TensorOutOfBounds(_))));
+	}
+
+	#[test]
+	fn rejects_more_dimensions_than_ggml_supports() {
+		let bytes = one_tensor(&[1, 1, 1, 1, 1], GgmlType::F32);
+		assert!(matches!(GgufFile::from_bytes(bytes), Err(GgufError::TooManyDimensions(_, 5))));
+
+		// A huge count must fail before anything is allocated for it.
+		let mut bytes = header(1, 0);
+		write_string(&mut bytes, "t");
+		bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+		assert!(matches!(GgufFile::from_bytes(bytes), Err(GgufError::TooManyDimensions(_, u32::MAX))));
+	}
+
+	#[test]
+	fn rejects_shapes_that_overflow() {
+		let bytes = one_tensor(&[u64::MAX, 2], GgmlType::F32);
+		assert!(matches!(GgufFile::from_bytes(bytes), Err(GgufError::TensorTooLarge(_))));
+		// The element count fits but the byte size does not.
+		let bytes = one_tensor(&[u64::MAX / 2], GgmlType::F32);
+		assert!(matches!(GgufFile::from_bytes(bytes), Err(GgufError::TensorTooLarge(_))));
+	}
+
+	#[test]
+	fn rejects_deeply_nested_arrays() {
+		let mut bytes = header(0, 1);
+		write_string(&mut bytes, "deep");
+		bytes.extend_from_slice(&GgufValueType::Array.to_u32().to_le_bytes());
+		for _ in 0..MAX_ARRAY_DEPTH + 1 {
+			bytes.extend_from_slice(&GgufValueType::Array.to_u32().to_le_bytes());
+			bytes.extend_from_slice(&1u64.to_le_bytes());
+		}
+		bytes.extend_from_slice(&[0; 64]);
+		assert!(matches!(GgufFile::from_bytes(bytes), Err(GgufError::NestingTooDeep(_))));
+	}
+
+	#[test]
+	fn open_reads_files_written_by_the_builder() {
+		let path = std::env::temp_dir().join(format!("shimmy-gguf-{}.gguf", std::process::id()));
+		GgufBuilder::new()
+			.metadata("general.architecture", GgufValue::String("llama".into()))
+			.tensor_f32("w", vec![2, 2], &[1.0, 2.0, 3.0, 4.0])
+			.write(&path)
+			.unwrap();
+		assert!(GgufFile::has_magic(&path));
+		let gguf = GgufFile::open(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(gguf.path(), Some(path.as_path()));
+		assert_eq!(gguf.tensor("w").unwrap().n_elements(), 4);
+	}
+}
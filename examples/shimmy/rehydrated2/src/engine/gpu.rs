@@ -0,0 +1,599 @@
+use std::{
+	fs,
+	path::{Path, PathBuf},
+};
+
+use crate::engine::llama::GpuBackend;
+
+/// Directories searched for driver and loader libraries, relative to the root.
+const LIBRARY_DIRS: &[&str] = &[
+	"/lib",
+	"/lib64",
+	"/usr/lib",
+	"/usr/lib64",
+	"/usr/lib/x86_64-linux-gnu",
+	"/usr/lib/aarch64-linux-gnu",
+	"/usr/local/lib",
+	"/usr/local/cuda/lib64",
+	"/usr/lib/wsl/lib",
+];
+
+const VULKAN_ICD_DIRS: &[&str] = &["/etc/vulkan/icd.d", "/usr/share/vulkan/icd.d", "/usr/local/share/vulkan/icd.d"];
+
+const OPENCL_VENDOR_DIR: &str = "/etc/OpenCL/vendors";
+
+/// Preferred first when more than one backend can drive a device.
+const PRIORITY: [GpuBackend; 3] = [GpuBackend::Cuda, GpuBackend::Vulkan, GpuBackend::OpenCL];
+
+/// Inspects the host for accelerators and the driver stacks that reach
+/// them. Paths are read under `root`, so a fake tree can stand in for `/`.
+#[derive(Debug, Clone)]
+pub struct GpuProbe {
+	root: PathBuf,
+	library_dirs: Vec<PathBuf>,
+	compiled: Vec<GpuBackend>,
+}
+
+impl Default for GpuProbe {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl GpuProbe {
+	/// Probes this machine, also searching `LD_LIBRARY_PATH`.
+	pub fn new() -> Self {
+		let mut probe = Self::with_root("/");
+		if let Some(paths) = std::env::var_os("LD_LIBRARY_PATH") {
+			probe.library_dirs.extend(std::env::split_paths(&paths).filter(|p| p.is_absolute()));
+		}
+		probe
+	}
+
+	pub fn with_root(root: impl Into<PathBuf>) -> Self {
+		Self {
+			root: root.into(),
+			library_dirs: LIBRARY_DIRS.iter().map(PathBuf::from).collect(),
+			compiled: compiled_backends(),
+		}
+	}
+
+	/// Overrides which backends count as built in, which otherwise follows
+	/// the cargo features.
+	pub fn with_compiled_backends(mut self, backends: &[GpuBackend]) -> Self {
+		self.compiled = backends.to_vec();
+		self
+	}
+
+	pub fn detect(&self) -> GpuReport {
+		let libraries = vec![
+			self.find_library(&["libcuda.so.1", "libcuda.so"]),
+			self.find_library(&["libvulkan.so.1", "libvulkan.so"]),
+			self.find_library(&["libOpenCL.so.1", "libOpenCL.so"]),
+		];
+		let vulkan_icds: Vec<DriverManifest> = VULKAN_ICD_DIRS
+			.iter()
+			.flat_map(|dir| self.list(dir))
+			.filter(|p| p.extension().is_some_and(|e| e == "json"))
+			.map(|p| self.vulkan_icd(p))
+			.collect();
+		let opencl_vendors: Vec<DriverManifest> = self
+			.list(OPENCL_VENDOR_DIR)
+			.into_iter()
+			.filter(|p| p.extension().is_some_and(|e| e == "icd"))
+			.map(|p| self.opencl_vendor(p))
+			.collect();
+
+		let stacks = Stacks {
+			libraries: &libraries,
+			vulkan_icds: &vulkan_icds,
+			opencl_vendors: &opencl_vendors,
+		};
+		let mut devices: Vec<DeviceReport> = self
+			.devices()
+			.into_iter()
+			.map(|device| self.evaluate(device, &stacks))
+			.collect();
+
+		let best = PRIORITY.iter().find_map(|&backend| devices.iter().position(|d| d.usable == Some(backend)));
+		let backend = match best {
+			Some(index) => {
+				let chosen = devices[index].usable.unwrap_or(GpuBackend::Cpu);
+				let chosen_name = devices[index].name.clone();
+				for (i, device) in devices.iter_mut().enumerate() {
+					if i == index {
+						device.chosen = true;
+						device.reason = format!("chosen: {}", device.reason);
+					} else if let Some(usable) = device.usable {
+						device.reason = format!("usable via {}, but {} was chosen", usable.as_str(), chosen_name);
+					}
+				}
+				chosen
+			}
+			None => GpuBackend::Cpu,
+		};
+		GpuReport {
+			backend,
+			devices,
+			libraries,
+			vulkan_icds,
+			opencl_vendors,
+		}
+	}
+
+	fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+		self.root.join(path.as_ref().strip_prefix("/").unwrap_or(path.as_ref()))
+	}
+
+	fn read(&self, path: impl AsRef<Path>) -> Option<String> {
+		fs::read_to_string(self.path(path)).ok()
+	}
+
+	/// Entries of `dir` as paths inside the root, sorted for stable reports.
+	fn list(&self, dir: &str) -> Vec<PathBuf> {
+		let Ok(entries) = fs::read_dir(self.path(dir)) else {
+			return vec![];
+		};
+		let mut paths: Vec<PathBuf> = entries.flatten().map(|e| Path::new(dir).join(e.file_name())).collect();
+		paths.sort();
+		paths
+	}
+
+	fn find_library(&self, names: &[&str]) -> Library {
+		let path = names
+			.iter()
+			.find_map(|name| self.library_dirs.iter().map(|dir| dir.join(name)).find(|p| self.path(p).exists()));
+		Library {
+			name: names[0].to_string(),
+			path,
+		}
+	}
+
+	/// Resolves a driver library named by a manifest the way the loaders do:
+	/// absolute paths as given, bare names through the library search path,
+	/// anything else relative to the manifest.
+	fn resolve_driver(&self, manifest: &Path, library: &str) -> Option<PathBuf> {
+		let library = Path::new(library);
+		let candidate = if library.is_absolute() {
+			library.to_path_buf()
+		} else if library.components().count() == 1 {
+			return self.find_library(&[library.to_str()?]).path;
+		} else {
+			manifest.parent()?.join(library)
+		};
+		self.path(&candidate).exists().then_some(candidate)
+	}
+
+	fn vulkan_icd(&self, manifest: PathBuf) -> DriverManifest {
+		let library = self
+			.read(&manifest)
+			.and_then(|text| serde_json::from_str::<serde_json::Value>(&text).ok())
+			.and_then(|json| json["ICD"]["library_path"].as_str().map(str::to_string));
+		let resolved = library.as_deref().and_then(|l| self.resolve_driver(&manifest, l));
+		DriverManifest {
+			manifest,
+			library,
+			resolved,
+		}
+	}
+
+	fn opencl_vendor(&self, manifest: PathBuf) -> DriverManifest {
+		let library = self
+			.read(&manifest)
+			.map(|text| text.trim().to_string())
+			.filter(|l| !l.is_empty());
+		let resolved = library.as_deref().and_then(|l| self.resolve_driver(&manifest, l));
+		DriverManifest {
+			manifest,
+			library,
+			resolved,
+		}
+	}
+
+	/// NVIDIA GPUs the proprietary driver lists, then DRM render nodes not
+	/// already covered by one of them.
+	fn devices(&self) -> Vec<Device> {
+		let mut devices = vec![];
+		for dir in self.list("/proc/driver/nvidia/gpus") {
+			let info = self.read(dir.join("information")).unwrap_or_default();
+			let model = info
+				.lines()
+				.find_map(|l| l.strip_prefix("Model:"))
+				.map(|m| m.trim().to_string())
+				.unwrap_or_else(|| "NVIDIA GPU".into());
+			devices.push(Device {
+				name: model,
+				vendor: Some(Vendor::Nvidia),
+				slot: dir.file_name().and_then(|n| n.to_str()).map(|s| s.to_ascii_lowercase()),
+				source: dir,
+				candidates: PRIORITY.to_vec(),
+			});
+		}
+		for node in self.list("/dev/dri") {
+			let Some(node_name) = node.file_name().and_then(|n| n.to_str()).filter(|n| n.starts_with("renderD")) else {
+				continue;
+			};
+			let sys = Path::new("/sys/class/drm").join(node_name).join("device");
+			let uevent = self.read(sys.join("uevent")).unwrap_or_default();
+			let field = |key: &str| {
+				uevent
+					.lines()
+					.find_map(|l| l.strip_prefix(key)?.strip_prefix('='))
+					.map(|v| v.trim().to_string())
+			};
+			let slot = field("PCI_SLOT_NAME").map(|s| s.to_ascii_lowercase());
+			if slot.is_some() && devices.iter().any(|d| d.slot == slot) {
+				continue;
+			}
+			let vendor_id = field("PCI_ID")
+				.and_then(|id| id.split(':').next().map(str::to_string))
+				.or_else(|| self.read(sys.join("vendor")).map(|v| v.trim().trim_start_matches("0x").to_string()));
+			let vendor = vendor_id.as_deref().and_then(Vendor::from_pci_id);
+			let driver = field("DRIVER");
+			let vendor_label = vendor.map(|v| v.name().to_string()).or(vendor_id.map(|id| format!("vendor {}", id)));
+			let mut name = node_name.to_string();
+			let details: Vec<String> = [vendor_label, driver.clone()].into_iter().flatten().collect();
+			if !details.is_empty() {
+				name = format!("{} ({})", name, details.join(", "));
+			}
+			let mut candidates = vec![GpuBackend::Vulkan, GpuBackend::OpenCL];
+			if driver.as_deref() == Some("nvidia") {
+				candidates.insert(0, GpuBackend::Cuda);
+			}
+			devices.push(Device {
+				name,
+				vendor,
+				slot,
+				source: node,
+				candidates,
+			});
+		}
+		devices
+	}
+
+	/// Picks the first backend in the device's list that is built in and
+	/// whose driver stack is installed, noting why the others were passed over.
+	fn evaluate(&self, device: Device, stacks: &Stacks) -> DeviceReport {
+		let mut skipped = vec![];
+		for &backend in &device.candidates {
+			let missing = if !self.compiled.contains(&backend) {
+				Some(format!("not built in (enable the {} feature)", feature_name(backend)))
+			} else {
+				stacks.missing(backend, device.vendor)
+			};
+			match missing {
+				None => {
+					let mut reason = format!("{} available", backend.as_str());
+					if !skipped.is_empty() {
+						reason = format!("{}; {}", reason, skipped.join("; "));
+					}
+					return DeviceReport {
+						name: device.name,
+						source: device.source,
+						usable: Some(backend),
+						chosen: false,
+						reason,
+					};
+				}
+				Some(why) => skipped.push(format!("{}: {}", backend.as_str(), why)),
+			}
+		}
+		DeviceReport {
+			name: device.name,
+			source: device.source,
+			usable: None,
+			chosen: false,
+			reason: skipped.join("; "),
+		}
+	}
+}
+
+struct Device {
+	name: String,
+	vendor: Option<Vendor>,
+	/// PCI address, used to match render nodes to driver entries.
+	slot: Option<String>,
+	source: PathBuf,
+	candidates: Vec<GpuBackend>,
+}
+
+struct Stacks<'a> {
+	libraries: &'a [Library],
+	vulkan_icds: &'a [DriverManifest],
+	opencl_vendors: &'a [DriverManifest],
+}
+
+impl Stacks<'_> {
+	fn has_library(&self, name: &str) -> bool {
+		self.libraries.iter().any(|l| l.name == name && l.path.is_some())
+	}
+
+	/// What stops `backend` from running on a device from `vendor`, if anything.
+	fn missing(&self, backend: GpuBackend, vendor: Option<Vendor>) -> Option<String> {
+		let (loader, drivers, kind) = match backend {
+			GpuBackend::Cuda => {
+				return (!self.has_library("libcuda.so.1")).then(|| "libcuda.so.1 not found".to_string());
+			}
+			GpuBackend::Vulkan => ("libvulkan.so.1", self.vulkan_icds, "Vulkan ICD manifest"),
+			GpuBackend::OpenCL => ("libOpenCL.so.1", self.opencl_vendors, "OpenCL vendor file"),
+			GpuBackend::Cpu | GpuBackend::Metal => return Some("not probed on this platform".into()),
+		};
+		if !self.has_library(loader) {
+			return Some(format!("{} not found", loader));
+		}
+		if drivers.is_empty() {
+			return Some(format!("no {}", kind));
+		}
+		let drivers: Vec<&DriverManifest> = drivers.iter().filter(|d| d.resolved.is_some()).collect();
+		if drivers.is_empty() {
+			return Some(format!("no {} names a driver library that exists", kind));
+		}
+		if !drivers.iter().any(|d| d.drives(vendor)) {
+			let vendor = vendor.map_or("this device", Vendor::name);
+			return Some(format!("no {} for {}", kind, vendor));
+		}
+		None
+	}
+}
+
+/// What [`GpuProbe::detect`] found and which backend it settled on.
+#[derive(Debug, Clone)]
+pub struct GpuReport {
+	/// [`GpuBackend::Cpu`] when no device is usable.
+	pub backend: GpuBackend,
+	pub devices: Vec<DeviceReport>,
+	pub libraries: Vec<Library>,
+	pub vulkan_icds: Vec<DriverManifest>,
+	pub opencl_vendors: Vec<DriverManifest>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceReport {
+	pub name: String,
+	/// Where the device was found, as a path under the probed root.
+	pub source: PathBuf,
+	/// The backend that could drive it, if any.
+	pub usable: Option<GpuBackend>,
+	pub chosen: bool,
+	pub reason: String,
+}
+
+/// A loader or driver library, and where it was found.
+#[derive(Debug, Clone)]
+pub struct Library {
+	pub name: String,
+	pub path: Option<PathBuf>,
+}
+
+/// A Vulkan ICD manifest or OpenCL vendor file and the driver it names.
+#[derive(Debug, Clone)]
+pub struct DriverManifest {
+	pub manifest: PathBuf,
+	pub library: Option<String>,
+	/// The named library, when it exists.
+	pub resolved: Option<PathBuf>,
+}
+
+fn compiled_backends() -> Vec<GpuBackend> {
+	let mut compiled = vec![];
+	if cfg!(feature = "llama-cuda") || cfg!(feature = "gpu") {
+		compiled.push(GpuBackend::Cuda);
+	}
+	if cfg!(feature = "llama-vulkan") || cfg!(feature = "gpu") {
+		compiled.push(GpuBackend::Vulkan);
+	}
+	if cfg!(feature = "llama-opencl") || cfg!(feature = "gpu") {
+		compiled.push(GpuBackend::OpenCL);
+	}
+	compiled
+}
+
+fn feature_name(backend: GpuBackend) -> &'static str {
+	match backend {
+		GpuBackend::Cuda => "llama-cuda",
+		GpuBackend::Vulkan => "llama-vulkan",
+		GpuBackend::OpenCL => "llama-opencl",
+		GpuBackend::Cpu | GpuBackend::Metal => "llama",
+	}
+}
+
+impl DriverManifest {
+	/// Guesses from the manifest and library names whether this driver runs
+	/// on `vendor`'s hardware. Drivers naming no vendor, like Mesa's
+	/// rusticl, are taken to drive anything; software rasterizers drive no GPU.
+	fn drives(&self, vendor: Option<Vendor>) -> bool {
+		let names = format!(
+			"{} {}",
+			self.manifest.file_name().and_then(|n| n.to_str()).unwrap_or(""),
+			self.library.as_deref().unwrap_or("")
+		)
+		.to_ascii_lowercase();
+		if SOFTWARE_DRIVERS.iter().any(|k| names.contains(k)) {
+			return false;
+		}
+		match Vendor::ALL.iter().find(|v| v.driver_keywords().iter().any(|k| names.contains(k))) {
+			Some(driver_vendor) => vendor.is_none_or(|v| v == *driver_vendor),
+			None => true,
+		}
+	}
+}
+
+/// Vulkan and OpenCL implementations that run on the CPU.
+const SOFTWARE_DRIVERS: &[&str] = &["lvp", "lavapipe", "swiftshader", "pocl"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Vendor {
+	Nvidia,
+	Amd,
+	Intel,
+	Arm,
+	Qualcomm,
+}
+
+impl Vendor {
+	const ALL: [Vendor; 5] = [Vendor::Nvidia, Vendor::Amd, Vendor::Intel, Vendor::Arm, Vendor::Qualcomm];
+
+	fn from_pci_id(id: &str) -> Option<Self> {
+		match id.to_ascii_lowercase().as_str() {
+			"10de" => Some(Vendor::Nvidia),
+			"1002" => Some(Vendor::Amd),
+			"8086" => Some(Vendor::Intel),
+			"13b5" => Some(Vendor::Arm),
+			"5143" => Some(Vendor::Qualcomm),
+			_ => None,
+		}
+	}
+
+	fn name(self) -> &'static str {
+		match self {
+			Vendor::Nvidia => "NVIDIA",
+			Vendor::Amd => "AMD",
+			Vendor::Intel => "Intel",
+			Vendor::Arm => "ARM",
+			Vendor::Qualcomm => "Qualcomm",
+		}
+	}
+
+	/// Fragments of the Vulkan ICD and OpenCL vendor file names each
+	/// vendor's drivers ship under.
+	fn driver_keywords(self) -> &'static [&'static str] {
+		match self {
+			Vendor::Nvidia => &["nvidia", "nouveau", "nvk"],
+			Vendor::Amd => &["radeon", "amd"],
+			Vendor::Intel => &["intel"],
+			Vendor::Arm => &["panfrost", "mali"],
+			Vendor::Qualcomm => &["freedreno", "adreno"],
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use super::*;
+
+	/// A scratch directory standing in for `/`, removed again on drop.
+	struct FakeRoot(PathBuf);
+
+	impl FakeRoot {
+		fn new() -> Self {
+			static NEXT: AtomicUsize = AtomicUsize::new(0);
+			let dir = std::env::temp_dir().join(format!(
+				"shimmy-gpu-root-{}-{}",
+				std::process::id(),
+				NEXT.fetch_add(1, Ordering::Relaxed)
+			));
+			fs::create_dir_all(&dir).unwrap();
+			Self(dir)
+		}
+
+		fn file(&self, path: &str, contents: &str) -> &Self {
+			let path = self.0.join(path.trim_start_matches('/'));
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(path, contents).unwrap();
+			self
+		}
+
+		fn detect(&self, compiled: &[GpuBackend]) -> GpuReport {
+			GpuProbe::with_root(&self.0).with_compiled_backends(compiled).detect()
+		}
+	}
+
+	impl Drop for FakeRoot {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	const ALL: [GpuBackend; 3] = [GpuBackend::Cuda, GpuBackend::Vulkan, GpuBackend::OpenCL];
+
+	/// An NVIDIA card as both the proprietary driver and DRM list it.
+	fn nvidia(root: &FakeRoot) {
+		root.file("/proc/driver/nvidia/gpus/0000:01:00.0/information", "Model: \t GeForce RTX 4090\n")
+			.file("/dev/dri/renderD128", "")
+			.file(
+				"/sys/class/drm/renderD128/device/uevent",
+				"DRIVER=nvidia\nPCI_ID=10DE:2684\nPCI_SLOT_NAME=0000:01:00.0\n",
+			);
+	}
+
+	#[test]
+	fn an_empty_machine_falls_back_to_the_cpu() {
+		let report = FakeRoot::new().detect(&ALL);
+		assert_eq!(report.backend, GpuBackend::Cpu);
+		assert!(report.devices.is_empty());
+		assert!(report.libraries.iter().all(|l| l.path.is_none()));
+	}
+
+	#[test]
+	fn nvidia_cards_prefer_cuda_and_are_listed_once() {
+		let root = FakeRoot::new();
+		nvidia(&root);
+		root.file("/usr/lib/x86_64-linux-gnu/libcuda.so.1", "");
+		let report = root.detect(&ALL);
+		assert_eq!(report.backend, GpuBackend::Cuda);
+		assert_eq!(report.devices.len(), 1, "the render node is the same card");
+		let device = &report.devices[0];
+		assert_eq!(device.name, "GeForce RTX 4090");
+		assert!(device.chosen && device.reason.starts_with("chosen: cuda available"), "{}", device.reason);
+		assert_eq!(report.libraries[0].path, Some(PathBuf::from("/usr/lib/x86_64-linux-gnu/libcuda.so.1")));
+	}
+
+	#[test]
+	fn backends_not_built_in_are_skipped_with_a_reason() {
+		let root = FakeRoot::new();
+		nvidia(&root);
+		root.file("/usr/lib/libcuda.so.1", "")
+			.file("/usr/lib/libvulkan.so.1", "")
+			.file("/usr/lib/libGLX_nvidia.so.0", "")
+			.file("/etc/vulkan/icd.d/nvidia_icd.json", r#"{"ICD": {"library_path": "libGLX_nvidia.so.0"}}"#);
+		let report = root.detect(&[GpuBackend::Vulkan]);
+		assert_eq!(report.backend, GpuBackend::Vulkan);
+		let reason = &report.devices[0].reason;
+		assert!(reason.contains("cuda: not built in (enable the llama-cuda feature)"), "{}", reason);
+		assert_eq!(report.vulkan_icds[0].resolved, Some(PathBuf::from("/usr/lib/libGLX_nvidia.so.0")));
+
+		assert_eq!(root.detect(&[]).backend, GpuBackend::Cpu);
+	}
+
+	#[test]
+	fn drivers_must_exist_and_match_the_vendor() {
+		let root = FakeRoot::new();
+		root.file("/dev/dri/renderD128", "")
+			.file("/sys/class/drm/renderD128/device/uevent", "DRIVER=amdgpu\nPCI_SLOT_NAME=0000:03:00.0\n")
+			.file("/sys/class/drm/renderD128/device/vendor", "0x1002\n")
+			.file("/usr/lib/libvulkan.so.1", "")
+			.file("/usr/lib/libvulkan_lvp.so", "")
+			.file("/usr/lib/libvulkan_intel.so", "")
+			.file("/usr/share/vulkan/icd.d/lvp_icd.x86_64.json", r#"{"ICD": {"library_path": "/usr/lib/libvulkan_lvp.so"}}"#)
+			.file("/usr/share/vulkan/icd.d/intel_icd.x86_64.json", r#"{"ICD": {"library_path": "/usr/lib/libvulkan_intel.so"}}"#)
+			.file("/usr/share/vulkan/icd.d/radeon_icd.x86_64.json", r#"{"ICD": {"library_path": "../../../lib/missing.so"}}"#);
+		let report = root.detect(&[GpuBackend::Vulkan]);
+		assert_eq!(report.backend, GpuBackend::Cpu);
+		assert_eq!(report.devices[0].name, "renderD128 (AMD, amdgpu)");
+		let reason = &report.devices[0].reason;
+		assert!(reason.contains("vulkan: no Vulkan ICD manifest for AMD"), "{}", reason);
+		let radeon = report.vulkan_icds.iter().find(|d| d.manifest.ends_with("radeon_icd.x86_64.json")).unwrap();
+		assert!(radeon.resolved.is_none());
+
+		// A relative path is taken from the manifest's directory.
+		root.file("/usr/lib/missing.so", "");
+		assert_eq!(root.detect(&[GpuBackend::Vulkan]).backend, GpuBackend::Vulkan);
+	}
+
+	#[test]
+	fn opencl_vendor_files_name_libraries_on_the_search_path() {
+		let root = FakeRoot::new();
+		root.file("/dev/dri/renderD129", "")
+			.file("/sys/class/drm/renderD129/device/uevent", "DRIVER=i915\nPCI_ID=8086:56A0\n")
+			.file("/usr/lib64/libOpenCL.so.1", "")
+			.file("/etc/OpenCL/vendors/intel.icd", "libigdrcl.so\n");
+		assert_eq!(root.detect(&ALL).backend, GpuBackend::Cpu);
+		root.file("/usr/local/lib/libigdrcl.so", "");
+		let report = root.detect(&ALL);
+		assert_eq!(report.backend, GpuBackend::OpenCL);
+		assert_eq!(report.opencl_vendors[0].resolved, Some(PathBuf::from("/usr/local/lib/libigdrcl.so")));
+		assert!(report.devices[0].reason.contains("vulkan: libvulkan.so.1 not found"));
+	}
+}
@@ -0,0 +1,628 @@
+use std::collections::{HashMap, HashSet};
+
+use regex::Regex;
+use serde_json::{Map, Value};
+
+/// Repetition bounds above this are left open rather than spelled out.
+const MAX_BOUNDED_REPEAT: u64 = 1000;
+
+/// Built-in rules, each with the rules its body refers to.
+const PRIMITIVES: &[(&str, &str, &[&str])] = &[
+	("ws", r#"| " " | "\n" [ \t]{0,20}"#, &[]),
+	("value", "object | array | string | number | boolean | null", &["object", "array", "string", "number", "boolean", "null"]),
+	(
+		"object",
+		r#""{" ws ( string ws ":" ws value ws ( "," ws string ws ":" ws value ws )* )? "}""#,
+		&["ws", "string", "value"],
+	),
+	("array", r#""[" ws ( value ws ( "," ws value ws )* )? "]""#, &["ws", "value"]),
+	("string", r#""\"" char* "\"""#, &["char"]),
+	("char", r#"[^"\\\x7F\x00-\x1F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} )"#, &[]),
+	("number", r#"integer ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )?"#, &["integer"]),
+	("integer", r#""-"? ( "0" | [1-9] [0-9]{0,15} )"#, &[]),
+	("boolean", r#""true" | "false""#, &[]),
+	("null", r#""null""#, &[]),
+	("date", r#"[0-9]{4} "-" ( "0" [1-9] | "1" [0-2] ) "-" ( "0" [1-9] | [12] [0-9] | "3" [01] )"#, &[]),
+	(
+		"time",
+		r#"( [01] [0-9] | "2" [0-3] ) ":" [0-5] [0-9] ":" [0-5] [0-9] ( "." [0-9]{1,6} )? ( "Z" | [+-] ( [01] [0-9] | "2" [0-3] ) ":" [0-5] [0-9] )"#,
+		&[],
+	),
+	("date-time", r#"date "T" time"#, &["date", "time"]),
+	("uuid", r#"[0-9a-fA-F]{8} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{12}"#, &[]),
+	("email", r#"[a-zA-Z0-9._%+-]+ "@" [a-zA-Z0-9-]+ ( "." [a-zA-Z0-9-]+ )+"#, &[]),
+];
+
+/// GBNF accepting any JSON object, for OpenAI's `json_object` mode.
+pub fn json_object_gbnf() -> String {
+	let mut c = Converter::new(&Value::Null);
+	let object = c.primitive("object");
+	c.finish(object)
+}
+
+/// Compiles a JSON Schema into GBNF. Structure (types, properties,
+/// `required`, items and their counts, enums, `$ref`, string formats and
+/// lengths, the sign of bounded numbers) is enforced while decoding; exact
+/// numeric ranges and `pattern` are left to [`validate`].
+pub fn schema_to_gbnf(schema: &Value) -> Result<String, String> {
+	let mut c = Converter::new(schema);
+	let root = c.visit(schema, "root")?;
+	Ok(c.finish(root))
+}
+
+struct Converter<'a> {
+	root: &'a Value,
+	/// Rule bodies in definition order; `None` while a `$ref` target is being built.
+	rules: Vec<(String, Option<String>)>,
+	taken: HashSet<String>,
+	refs: HashMap<String, String>,
+}
+
+impl<'a> Converter<'a> {
+	fn new(root: &'a Value) -> Self {
+		Self {
+			root,
+			rules: vec![],
+			taken: PRIMITIVES.iter().map(|p| p.0.to_string()).collect(),
+			refs: HashMap::new(),
+		}
+	}
+
+	fn finish(mut self, root: String) -> String {
+		if root != "root" {
+			self.rules.insert(0, ("root".into(), Some(root)));
+		}
+		self.rules
+			.into_iter()
+			.map(|(name, body)| format!("{} ::= {}\n", name, body.unwrap_or_default()))
+			.collect()
+	}
+
+	fn primitive(&mut self, name: &str) -> String {
+		if self.rules.iter().any(|(n, _)| n == name) {
+			return name.to_string();
+		}
+		let Some((_, body, deps)) = PRIMITIVES.iter().find(|p| p.0 == name) else {
+			return name.to_string();
+		};
+		self.rules.push((name.to_string(), Some(body.to_string())));
+		for dep in *deps {
+			self.primitive(dep);
+		}
+		name.to_string()
+	}
+
+	fn reserve(&mut self, hint: &str) -> String {
+		let base: String = hint
+			.chars()
+			.map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' })
+			.collect();
+		let mut name = base.clone();
+		let mut n = 1;
+		while self.taken.contains(&name) {
+			n += 1;
+			name = format!("{}{}", base, n);
+		}
+		self.taken.insert(name.clone());
+		self.rules.push((name.clone(), None));
+		name
+	}
+
+	fn rule(&mut self, hint: &str, body: String) -> String {
+		let name = self.reserve(hint);
+		self.define(&name, body);
+		name
+	}
+
+	fn define(&mut self, name: &str, body: String) {
+		if let Some(rule) = self.rules.iter_mut().find(|(n, _)| n == name) {
+			rule.1 = Some(body);
+		}
+	}
+
+	/// Returns an expression (a rule name or literal) matching `schema`.
+	fn visit(&mut self, schema: &Value, hint: &str) -> Result<String, String> {
+		let obj = match schema {
+			Value::Bool(true) => return Ok(self.primitive("value")),
+			Value::Bool(false) => return Err(format!("{}: schema `false` matches nothing", hint)),
+			Value::Object(obj) => obj,
+			_ => return Err(format!("{}: schema must be an object", hint)),
+		};
+
+		if let Some(reference) = obj.get("$ref").and_then(Value::as_str) {
+			return self.visit_ref(reference);
+		}
+		if let Some(value) = obj.get("const") {
+			return Ok(json_literal(value));
+		}
+		if let Some(values) = obj.get("enum").and_then(Value::as_array) {
+			if values.is_empty() {
+				return Err(format!("{}: enum has no values", hint));
+			}
+			let body = values.iter().map(json_literal).collect::<Vec<_>>().join(" | ");
+			return Ok(self.rule(hint, body));
+		}
+		for key in ["anyOf", "oneOf"] {
+			if let Some(options) = obj.get(key).and_then(Value::as_array) {
+				let mut alts = vec![];
+				for (i, option) in options.iter().enumerate() {
+					alts.push(self.visit(option, &format!("{}-{}", hint, i))?);
+				}
+				return Ok(self.rule(hint, alts.join(" | ")));
+			}
+		}
+		if let Some(parts) = obj.get("allOf").and_then(Value::as_array) {
+			let merged = self.merge_all_of(parts, hint)?;
+			return self.visit(&merged, hint);
+		}
+
+		match obj.get("type") {
+			Some(Value::Array(types)) => {
+				let mut alts = vec![];
+				for ty in types {
+					let mut single = obj.clone();
+					single.insert("type".into(), ty.clone());
+					alts.push(self.visit(&Value::Object(single), &format!("{}-{}", hint, ty.as_str().unwrap_or("type")))?);
+				}
+				Ok(self.rule(hint, alts.join(" | ")))
+			}
+			Some(Value::String(ty)) => self.visit_type(ty, obj, hint),
+			Some(_) => Err(format!("{}: type must be a string or an array of strings", hint)),
+			None if obj.contains_key("properties") => self.visit_type("object", obj, hint),
+			None if obj.contains_key("items") => self.visit_type("array", obj, hint),
+			None => Ok(self.primitive("value")),
+		}
+	}
+
+	fn visit_type(&mut self, ty: &str, obj: &Map<String, Value>, hint: &str) -> Result<String, String> {
+		match ty {
+			"object" => self.visit_object(obj, hint),
+			"array" => {
+				let item = match obj.get("items") {
+					Some(items) => self.visit(items, &format!("{}-item", hint))?,
+					None => self.primitive("value"),
+				};
+				let min = obj.get("minItems").and_then(Value::as_u64).unwrap_or(0);
+				let max = obj.get("maxItems").and_then(Value::as_u64);
+				self.primitive("ws");
+				let body = match max {
+					Some(0) => r#""[" ws "]""#.to_string(),
+					_ => {
+						let rest = format!(r#"( ws "," ws {} ){}"#, item, repeat_bounds(min.saturating_sub(1), max.map(|m| m - 1)));
+						if min == 0 {
+							format!(r#""[" ws ( {} {} )? ws "]""#, item, rest)
+						} else {
+							format!(r#""[" ws {} {} ws "]""#, item, rest)
+						}
+					}
+				};
+				Ok(self.rule(hint, body))
+			}
+			"string" => {
+				if let Some(format) = obj.get("format").and_then(Value::as_str) {
+					if matches!(format, "date" | "time" | "date-time" | "uuid" | "email") {
+						let inner = self.primitive(format);
+						return Ok(self.rule(hint, format!(r#""\"" {} "\"""#, inner)));
+					}
+				}
+				let min = obj.get("minLength").and_then(Value::as_u64);
+				let max = obj.get("maxLength").and_then(Value::as_u64);
+				if min.is_none() && max.is_none() {
+					return Ok(self.primitive("string"));
+				}
+				let ch = self.primitive("char");
+				Ok(self.rule(hint, format!(r#""\"" {}{} "\"""#, ch, repeat_bounds(min.unwrap_or(0), max))))
+			}
+			"number" | "integer" => {
+				let lower = ["minimum", "exclusiveMinimum"].iter().filter_map(|k| obj.get(*k).and_then(Value::as_f64)).reduce(f64::max);
+				if lower.is_none_or(|m| m < 0.0) {
+					return Ok(self.primitive(ty));
+				}
+				// A non-negative lower bound rules out the sign.
+				let digits = r#"( "0" | [1-9] [0-9]{0,15} )"#;
+				let body = match ty {
+					"integer" => digits.to_string(),
+					_ => format!(r#"{} ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )?"#, digits),
+				};
+				Ok(self.rule(hint, body))
+			}
+			"boolean" => Ok(self.primitive("boolean")),
+			"null" => Ok(self.primitive("null")),
+			other => Err(format!("{}: unsupported type {:?}", hint, other)),
+		}
+	}
+
+	fn visit_object(&mut self, obj: &Map<String, Value>, hint: &str) -> Result<String, String> {
+		let empty = Map::new();
+		let properties = obj.get("properties").and_then(Value::as_object).unwrap_or(&empty);
+		let required: Vec<&str> = obj
+			.get("required")
+			.and_then(Value::as_array)
+			.map(|r| r.iter().filter_map(Value::as_str).collect())
+			.unwrap_or_default();
+		self.primitive("ws");
+
+		// Required members come first, in `required` order; the rest follow
+		// in key order. Required keys without a schema may hold any value.
+		let mut members = vec![];
+		for key in &required {
+			let rule = match properties.get(*key) {
+				Some(prop) => self.visit(prop, &format!("{}-{}", hint, key))?,
+				None => self.primitive("value"),
+			};
+			members.push((*key, rule));
+		}
+		for (key, prop) in properties.iter().filter(|(k, _)| !required.contains(&k.as_str())) {
+			let rule = self.visit(prop, &format!("{}-{}", hint, key))?;
+			members.push((key.as_str(), rule));
+		}
+
+		if members.is_empty() {
+			let body = match obj.get("additionalProperties") {
+				Some(Value::Bool(false)) => r#""{" ws "}""#.to_string(),
+				Some(extra @ Value::Object(_)) => {
+					let value = self.visit(extra, &format!("{}-value", hint))?;
+					let string = self.primitive("string");
+					let kv = format!(r#"{} ws ":" ws {}"#, string, value);
+					format!(r#""{{" ws ( {} ( ws "," ws {} )* )? ws "}}""#, kv, kv)
+				}
+				_ => return Ok(self.primitive("object")),
+			};
+			return Ok(self.rule(hint, body));
+		}
+
+		let kv = |key: &str, rule: &str| format!(r#"{} ws ":" ws {}"#, json_literal(&Value::String(key.into())), rule);
+		let (req, opt): (Vec<_>, Vec<_>) = members.iter().partition(|(k, _)| required.contains(k));
+		let optional_tail = |from: usize| {
+			opt[from..]
+				.iter()
+				.map(|(k, r)| format!(r#"( ws "," ws {} )?"#, kv(k, r)))
+				.collect::<Vec<_>>()
+				.join(" ")
+		};
+		let inner = if req.is_empty() {
+			// No member is guaranteed, so the first one present takes no comma.
+			let alts: Vec<String> = opt
+				.iter()
+				.enumerate()
+				.map(|(i, (k, r))| format!("{} {}", kv(k, r), optional_tail(i + 1)))
+				.collect();
+			format!("( {} )?", alts.join(" | "))
+		} else {
+			let required_part = req.iter().map(|(k, r)| kv(k, r)).collect::<Vec<_>>().join(r#" ws "," ws "#);
+			format!("{} {}", required_part, optional_tail(0))
+		};
+		Ok(self.rule(hint, format!(r#""{{" ws {} ws "}}""#, inner)))
+	}
+
+	fn visit_ref(&mut self, reference: &str) -> Result<String, String> {
+		if let Some(name) = self.refs.get(reference) {
+			return Ok(name.clone());
+		}
+		let target = resolve_ref(self.root, reference)?.clone();
+		let hint = reference.rsplit('/').next().unwrap_or("ref").to_string();
+		// Registered before visiting so recursive schemas refer back to it.
+		let name = self.reserve(&hint);
+		self.refs.insert(reference.to_string(), name.clone());
+		let body = self.visit(&target, &format!("{}-body", name))?;
+		self.define(&name, body);
+		Ok(name)
+	}
+
+	/// Folds `allOf` object schemas into one; anything else is unsupported.
+	fn merge_all_of(&self, parts: &[Value], hint: &str) -> Result<Value, String> {
+		let mut properties = Map::new();
+		let mut required = vec![];
+		for part in parts {
+			let part = match part.get("$ref").and_then(Value::as_str) {
+				Some(r) => resolve_ref(self.root, r)?,
+				None => part,
+			};
+			let is_object = part.get("type").is_none_or(|t| t == "object");
+			if !is_object {
+				return Err(format!("{}: allOf is only supported for object schemas", hint));
+			}
+			if let Some(props) = part.get("properties").and_then(Value::as_object) {
+				properties.extend(props.clone());
+			}
+			if let Some(req) = part.get("required").and_then(Value::as_array) {
+				required.extend(req.iter().cloned());
+			}
+		}
+		Ok(serde_json::json!({"type": "object", "properties": properties, "required": required}))
+	}
+}
+
+fn repeat_bounds(min: u64, max: Option<u64>) -> String {
+	match max {
+		Some(max) if max <= MAX_BOUNDED_REPEAT => format!("{{{},{}}}", min.min(max), max),
+		_ if min == 0 => "*".into(),
+		_ => format!("{{{},}}", min.min(MAX_BOUNDED_REPEAT)),
+	}
+}
+
+/// A GBNF string literal matching `value` serialized as compact JSON.
+fn json_literal(value: &Value) -> String {
+	let json = value.to_string();
+	let mut out = String::from("\"");
+	for c in json.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => out.push_str(&format!("\\x{:02X}", c as u32)),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+/// Resolves a local JSON pointer reference such as `#/$defs/Item`.
+fn resolve_ref<'v>(root: &'v Value, reference: &str) -> Result<&'v Value, String> {
+	let pointer = reference
+		.strip_prefix('#')
+		.ok_or_else(|| format!("only local $ref is supported, got {:?}", reference))?;
+	let pointer = pointer.replace("~1", "/").replace("~0", "~");
+	root.pointer(&pointer).ok_or_else(|| format!("unresolved $ref {:?}", reference))
+}
+
+/// Checks `instance` against `schema`, naming the first offending location
+/// (`$.items[2].name`) in the error.
+pub fn validate(schema: &Value, instance: &Value) -> Result<(), String> {
+	check(schema, schema, instance, "$")
+}
+
+fn check(root: &Value, schema: &Value, v: &Value, path: &str) -> Result<(), String> {
+	let obj = match schema {
+		Value::Bool(true) => return Ok(()),
+		Value::Bool(false) => return Err(format!("{}: no value is allowed here", path)),
+		Value::Object(obj) => obj,
+		_ => return Ok(()),
+	};
+
+	if let Some(reference) = obj.get("$ref").and_then(Value::as_str) {
+		return check(root, resolve_ref(root, reference)?, v, path);
+	}
+	if let Some(expected) = obj.get("const") {
+		if expected != v {
+			return Err(format!("{}: expected {}", path, expected));
+		}
+	}
+	if let Some(values) = obj.get("enum").and_then(Value::as_array) {
+		if !values.contains(v) {
+			return Err(format!("{}: {} is not one of the allowed values", path, v));
+		}
+	}
+	if let Some(parts) = obj.get("allOf").and_then(Value::as_array) {
+		for part in parts {
+			check(root, part, v, path)?;
+		}
+	}
+	if let Some(options) = obj.get("anyOf").and_then(Value::as_array) {
+		if !options.iter().any(|o| check(root, o, v, path).is_ok()) {
+			return Err(format!("{}: matches none of anyOf", path));
+		}
+	}
+	if let Some(options) = obj.get("oneOf").and_then(Value::as_array) {
+		let matching = options.iter().filter(|o| check(root, o, v, path).is_ok()).count();
+		if matching != 1 {
+			return Err(format!("{}: matches {} of oneOf, expected exactly 1", path, matching));
+		}
+	}
+	match obj.get("type") {
+		Some(Value::String(ty)) if !has_type(v, ty) => return Err(format!("{}: expected {}", path, ty)),
+		Some(Value::Array(types)) if !types.iter().filter_map(Value::as_str).any(|t| has_type(v, t)) => {
+			return Err(format!("{}: expected one of {}", path, Value::Array(types.clone())));
+		}
+		_ => {}
+	}
+
+	match v {
+		Value::Object(map) => {
+			if let Some(required) = obj.get("required").and_then(Value::as_array) {
+				for key in required.iter().filter_map(Value::as_str) {
+					if !map.contains_key(key) {
+						return Err(format!("{}: missing required property {:?}", path, key));
+					}
+				}
+			}
+			let properties = obj.get("properties").and_then(Value::as_object);
+			for (key, value) in map {
+				let child = format!("{}.{}", path, key);
+				match properties.and_then(|p| p.get(key)) {
+					Some(prop) => check(root, prop, value, &child)?,
+					None => match obj.get("additionalProperties") {
+						Some(Value::Bool(false)) => return Err(format!("{}: unexpected property {:?}", path, key)),
+						Some(extra) => check(root, extra, value, &child)?,
+						None => {}
+					},
+				}
+			}
+		}
+		Value::Array(items) => {
+			let count = items.len() as u64;
+			if let Some(min) = obj.get("minItems").and_then(Value::as_u64).filter(|m| count < *m) {
+				return Err(format!("{}: expected at least {} items, got {}", path, min, count));
+			}
+			if let Some(max) = obj.get("maxItems").and_then(Value::as_u64).filter(|m| count > *m) {
+				return Err(format!("{}: expected at most {} items, got {}", path, max, count));
+			}
+			if let Some(item) = obj.get("items") {
+				for (i, value) in items.iter().enumerate() {
+					check(root, item, value, &format!("{}[{}]", path, i))?;
+				}
+			}
+		}
+		Value::String(s) => {
+			let len = s.chars().count() as u64;
+			if let Some(min) = obj.get("minLength").and_then(Value::as_u64).filter(|m| len < *m) {
+				return Err(format!("{}: shorter than {} characters", path, min));
+			}
+			if let Some(max) = obj.get("maxLength").and_then(Value::as_u64).filter(|m| len > *m) {
+				return Err(format!("{}: longer than {} characters", path, max));
+			}
+			if let Some(pattern) = obj.get("pattern").and_then(Value::as_str) {
+				let re = Regex::new(pattern).map_err(|e| format!("{}: invalid pattern: {}", path, e))?;
+				if !re.is_match(s) {
+					return Err(format!("{}: does not match pattern {:?}", path, pattern));
+				}
+			}
+			if let Some(format) = obj.get("format").and_then(Value::as_str) {
+				if !matches_format(s, format) {
+					return Err(format!("{}: not a valid {}", path, format));
+				}
+			}
+		}
+		Value::Number(n) => {
+			let x = n.as_f64().unwrap_or(0.0);
+			let bound = |key: &str| obj.get(key).and_then(Value::as_f64);
+			if bound("minimum").is_some_and(|m| x < m) || bound("exclusiveMinimum").is_some_and(|m| x <= m) {
+				return Err(format!("{}: {} is below the minimum", path, n));
+			}
+			if bound("maximum").is_some_and(|m| x > m) || bound("exclusiveMaximum").is_some_and(|m| x >= m) {
+				return Err(format!("{}: {} is above the maximum", path, n));
+			}
+		}
+		_ => {}
+	}
+	Ok(())
+}
+
+fn has_type(v: &Value, ty: &str) -> bool {
+	match ty {
+		"object" => v.is_object(),
+		"array" => v.is_array(),
+		"string" => v.is_string(),
+		"number" => v.is_number(),
+		"integer" => v.is_i64() || v.is_u64() || v.as_f64().is_some_and(|f| f.fract() == 0.0),
+		"boolean" => v.is_boolean(),
+		"null" => v.is_null(),
+		_ => true,
+	}
+}
+
+/// Formats the grammar can generate; others are accepted as plain strings.
+fn matches_format(s: &str, format: &str) -> bool {
+	let pattern = match format {
+		"date" => r"^\d{4}-(0[1-9]|1[0-2])-(0[1-9]|[12]\d|3[01])$",
+		"time" => r"^([01]\d|2[0-3]):[0-5]\d:[0-5]\d(\.\d{1,6})?(Z|[+-]([01]\d|2[0-3]):[0-5]\d)$",
+		"date-time" => {
+			r"^\d{4}-(0[1-9]|1[0-2])-(0[1-9]|[12]\d|3[01])T([01]\d|2[0-3]):[0-5]\d:[0-5]\d(\.\d{1,6})?(Z|[+-]([01]\d|2[0-3]):[0-5]\d)$"
+		}
+		"uuid" => r"^[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$",
+		"email" => r"^[a-zA-Z0-9._%+-]+@[a-zA-Z0-9-]+(\.[a-zA-Z0-9-]+)+$",
+		_ => return true,
+	};
+	Regex::new(pattern).map(|re| re.is_match(s)).unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use serde_json::json;
+
+	use super::*;
+	use crate::engine::grammar::{Grammar, GrammarMatcher};
+
+	/// Whether the grammar compiled from `schema` accepts `text` in full.
+	fn accepts(schema: &Value, text: &str) -> bool {
+		let gbnf = schema_to_gbnf(schema).unwrap();
+		let grammar = Grammar::parse(&gbnf).unwrap_or_else(|e| panic!("{}\n{}", e, gbnf));
+		let mut m = GrammarMatcher::new(Arc::new(grammar));
+		m.accept(text.as_bytes()) && m.is_complete()
+	}
+
+	#[test]
+	fn objects_need_required_members_in_order() {
+		let schema = json!({
+			"type": "object",
+			"properties": {
+				"age": {"type": "integer", "minimum": 0},
+				"name": {"type": "string"},
+				"tags": {"type": "array", "items": {"type": "string"}, "maxItems": 2}
+			},
+			"required": ["name", "age"]
+		});
+		assert!(accepts(&schema, r#"{"name": "Ada", "age": 36}"#));
+		assert!(accepts(&schema, r#"{"name":"Ada","age":0,"tags":["a","b"]}"#));
+		assert!(!accepts(&schema, r#"{"age": 36, "name": "Ada"}"#), "required members come in order");
+		assert!(!accepts(&schema, r#"{"name": "Ada"}"#));
+		assert!(!accepts(&schema, r#"{"name": "Ada", "age": -1}"#), "a non-negative minimum rules out the sign");
+		assert!(!accepts(&schema, r#"{"name": "Ada", "age": 1, "tags": ["a","b","c"]}"#));
+	}
+
+	#[test]
+	fn optional_members_may_all_be_missing() {
+		let schema = json!({"properties": {"a": {"type": "boolean"}, "b": {"type": "null"}}});
+		assert!(accepts(&schema, "{}"));
+		assert!(accepts(&schema, r#"{"b": null}"#));
+		assert!(accepts(&schema, r#"{"a": true, "b": null}"#));
+		assert!(!accepts(&schema, r#"{"b": null, "a": true}"#));
+		assert!(!accepts(&schema, r#"{, "b": null}"#));
+	}
+
+	#[test]
+	fn enums_consts_unions_and_formats() {
+		assert!(accepts(&json!({"enum": ["red", 1, null]}), "1"));
+		assert!(!accepts(&json!({"enum": ["red", 1, null]}), r#""blue""#));
+		assert!(accepts(&json!({"const": {"k": [1]}}), r#"{"k":[1]}"#));
+		let union = json!({"anyOf": [{"type": "integer"}, {"type": "string", "maxLength": 2}]});
+		assert!(accepts(&union, "-42"));
+		assert!(accepts(&union, r#""ok""#));
+		assert!(!accepts(&union, r#""long""#));
+		assert!(accepts(&json!({"type": ["number", "null"]}), "1.5e3"));
+		let date = json!({"type": "string", "format": "date"});
+		assert!(accepts(&date, r#""2024-02-29""#));
+		assert!(!accepts(&date, r#""2024-13-01""#));
+	}
+
+	#[test]
+	fn refs_resolve_recursively() {
+		let schema = json!({
+			"$ref": "#/$defs/node",
+			"$defs": {
+				"node": {
+					"type": "object",
+					"properties": {"children": {"type": "array", "items": {"$ref": "#/$defs/node"}}},
+					"required": ["children"]
+				}
+			}
+		});
+		assert!(accepts(&schema, r#"{"children": [{"children": []}, {"children": [{"children": []}]}]}"#));
+		assert!(!accepts(&schema, r#"{"children": [{}]}"#));
+	}
+
+	#[test]
+	fn json_object_mode_accepts_any_object() {
+		let grammar = Grammar::parse(&json_object_gbnf()).unwrap();
+		let mut m = GrammarMatcher::new(Arc::new(grammar));
+		assert!(m.accept(br#"{"a": [1, {"b": "c\n"}], "d": false}"#) && m.is_complete());
+		assert!(!GrammarMatcher::new(Arc::new(Grammar::parse(&json_object_gbnf()).unwrap())).accept(b"[]"));
+	}
+
+	#[test]
+	fn unsupported_schemas_are_errors() {
+		assert!(schema_to_gbnf(&json!(false)).is_err());
+		assert!(schema_to_gbnf(&json!({"enum": []})).is_err());
+		assert!(schema_to_gbnf(&json!({"type": "tuple"})).is_err());
+		assert!(schema_to_gbnf(&json!({"$ref": "http://example.com/s.json"})).is_err());
+		assert!(schema_to_gbnf(&json!({"$ref": "#/$defs/missing"})).is_err());
+	}
+
+	#[test]
+	fn validate_names_the_offending_location() {
+		let schema = json!({
+			"type": "object",
+			"properties": {"items": {"type": "array", "items": {"type": "integer", "maximum": 10}}},
+			"required": ["items"]
+		});
+		assert!(validate(&schema, &json!({"items": [1, 10]})).is_ok());
+		let err = validate(&schema, &json!({"items": [1, 11]})).unwrap_err();
+		assert!(err.contains("$.items[1]"), "{}", err);
+		assert!(validate(&schema, &json!({})).is_err());
+	}
+}
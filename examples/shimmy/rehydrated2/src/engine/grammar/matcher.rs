@@ -0,0 +1,253 @@
+use std::sync::Arc;
+
+use crate::engine::{
+	grammar::{Element, Grammar},
+	sampling::Candidates,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Pos {
+	rule: usize,
+	alt: usize,
+	idx: usize,
+}
+
+/// Positions in the grammar, innermost last. Every stack in a matcher's
+/// state is either empty (the input so far is a complete match) or has a
+/// character element on top.
+type Stack = Vec<Pos>;
+
+/// Tracks how much of a [`Grammar`] generated text has consumed, as the set
+/// of every parse still possible (a pushdown automaton run on all branches
+/// at once).
+#[derive(Clone)]
+pub struct GrammarMatcher {
+	grammar: Arc<Grammar>,
+	stacks: Vec<Stack>,
+	/// Leading bytes of a character split across tokens.
+	partial: Vec<u8>,
+}
+
+impl GrammarMatcher {
+	pub fn new(grammar: Arc<Grammar>) -> Self {
+		let mut stacks = vec![];
+		for alt in 0..grammar.rules[grammar.root].len() {
+			expand(&grammar, vec![Pos { rule: grammar.root, alt, idx: 0 }], &mut stacks);
+		}
+		dedup(&mut stacks);
+		Self {
+			grammar,
+			stacks,
+			partial: vec![],
+		}
+	}
+
+	/// The text so far is a full match, so generation may stop here.
+	pub fn is_complete(&self) -> bool {
+		self.partial.is_empty() && self.stacks.iter().any(Vec::is_empty)
+	}
+
+	/// Some character can still extend the match.
+	pub fn can_continue(&self) -> bool {
+		self.stacks.iter().any(|s| !s.is_empty())
+	}
+
+	/// Whether `bytes` could come next. A trailing incomplete UTF-8 sequence
+	/// is allowed as long as the grammar still expects more text.
+	pub fn allows(&self, bytes: &[u8]) -> bool {
+		self.advance(bytes).is_some()
+	}
+
+	/// Consumes `bytes`, returning `false` (and changing nothing) if the
+	/// grammar does not allow them.
+	pub fn accept(&mut self, bytes: &[u8]) -> bool {
+		match self.advance(bytes) {
+			Some((stacks, partial)) => {
+				self.stacks = stacks;
+				self.partial = partial;
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Drops candidates whose text the grammar rejects. Tokens for which
+	/// `is_end` holds survive only when the grammar is complete; tokens with
+	/// no text never do, since they could repeat forever.
+	pub fn constrain(&self, candidates: &mut Candidates, pieces: &[Vec<u8>], is_end: impl Fn(u32) -> bool) {
+		let complete = self.is_complete();
+		candidates.items.retain(|c| {
+			if is_end(c.id) {
+				return complete;
+			}
+			match pieces.get(c.id as usize) {
+				Some(piece) if !piece.is_empty() => self.allows(piece),
+				_ => false,
+			}
+		});
+	}
+
+	fn advance(&self, bytes: &[u8]) -> Option<(Vec<Stack>, Vec<u8>)> {
+		let mut buf = self.partial.clone();
+		buf.extend_from_slice(bytes);
+		let (text, rest) = match std::str::from_utf8(&buf) {
+			Ok(s) => (s, &[][..]),
+			Err(e) if e.error_len().is_none() => {
+				let (valid, rest) = buf.split_at(e.valid_up_to());
+				(std::str::from_utf8(valid).unwrap_or_default(), rest)
+			}
+			Err(_) => return None,
+		};
+
+		let mut chars = text.chars();
+		let mut stacks = match chars.next() {
+			Some(c) => step(&self.grammar, &self.stacks, c),
+			None => self.stacks.clone(),
+		};
+		for c in chars {
+			if stacks.is_empty() {
+				return None;
+			}
+			stacks = step(&self.grammar, &stacks, c);
+		}
+		let expecting_more = stacks.iter().any(|s| !s.is_empty());
+		if stacks.is_empty() || (!rest.is_empty() && !expecting_more) {
+			return None;
+		}
+		Some((stacks, rest.to_vec()))
+	}
+}
+
+/// Advances every stack whose top accepts `c`.
+fn step(grammar: &Grammar, stacks: &[Stack], c: char) -> Vec<Stack> {
+	let mut out = vec![];
+	for stack in stacks {
+		let Some(top) = stack.last() else { continue };
+		if !grammar.rules[top.rule][top.alt][top.idx].matches(c) {
+			continue;
+		}
+		let mut next = stack.clone();
+		next.pop();
+		if top.idx + 1 < grammar.rules[top.rule][top.alt].len() {
+			next.push(Pos { idx: top.idx + 1, ..*top });
+		}
+		expand(grammar, next, &mut out);
+	}
+	dedup(&mut out);
+	out
+}
+
+/// Descends into rule references until the top of `stack` is a character
+/// element, pushing one stack per alternative taken. Finished positions are
+/// popped rather than kept, so repetition does not grow the stack.
+fn expand(grammar: &Grammar, mut stack: Stack, out: &mut Vec<Stack>) {
+	let Some(top) = stack.last().copied() else {
+		out.push(stack);
+		return;
+	};
+	let alt = &grammar.rules[top.rule][top.alt];
+	match alt.get(top.idx) {
+		None => {
+			stack.pop();
+			expand(grammar, stack, out);
+		}
+		Some(Element::Char { .. }) => out.push(stack),
+		Some(Element::Rule(r)) => {
+			stack.pop();
+			if top.idx + 1 < alt.len() {
+				stack.push(Pos { idx: top.idx + 1, ..top });
+			}
+			for a in 0..grammar.rules[*r].len() {
+				let mut branch = stack.clone();
+				branch.push(Pos { rule: *r, alt: a, idx: 0 });
+				expand(grammar, branch, out);
+			}
+		}
+	}
+}
+
+fn dedup(stacks: &mut Vec<Stack>) {
+	stacks.sort_unstable();
+	stacks.dedup();
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn matcher(src: &str) -> GrammarMatcher {
+		GrammarMatcher::new(Arc::new(Grammar::parse(src).unwrap()))
+	}
+
+	/// Whether `pieces`, fed one at a time, form a complete match.
+	fn matches(src: &str, pieces: &[&str]) -> bool {
+		let mut m = matcher(src);
+		pieces.iter().all(|p| m.accept(p.as_bytes())) && m.is_complete()
+	}
+
+	#[test]
+	fn accepts_and_rejects_whole_strings() {
+		let yes_no = r#"root ::= "yes" | "no""#;
+		assert!(matches(yes_no, &["yes"]));
+		assert!(matches(yes_no, &["n", "o"]));
+		assert!(!matches(yes_no, &["ye"]), "a prefix is not complete");
+		assert!(!matches(yes_no, &["yes", "!"]));
+		assert!(!matches(yes_no, &["maybe"]));
+	}
+
+	#[test]
+	fn repetitions_classes_and_nested_rules() {
+		let src = r#"
+			root ::= item ("," ws item){0,2}
+			item ::= [a-z]+ | num
+			num  ::= "-"? [0-9]+
+			ws   ::= " "?
+		"#;
+		assert!(matches(src, &["abc"]));
+		assert!(matches(src, &["ab,", " -12", ",x"]));
+		assert!(!matches(src, &["a,b,c,d"]), "at most three items");
+		assert!(!matches(src, &["A"]));
+		assert!(!matches(src, &["a,"]));
+
+		let any = matcher(r#"root ::= [^"]* "\"""#);
+		assert!(any.allows("anything at all".as_bytes()));
+		assert!(!any.allows("a\"b".as_bytes()));
+	}
+
+	#[test]
+	fn failed_accepts_leave_the_state_alone() {
+		let mut m = matcher(r#"root ::= "ab" "c"*"#);
+		assert!(m.accept(b"a"));
+		assert!(!m.accept(b"x"));
+		assert!(m.accept(b"b"));
+		assert!(m.is_complete() && m.can_continue());
+		assert!(m.accept(b"cc"));
+	}
+
+	#[test]
+	fn characters_may_split_across_tokens() {
+		let mut m = matcher(r#"root ::= "é" [a-z]"#);
+		let e = "é".as_bytes();
+		assert!(m.accept(&e[..1]));
+		assert!(!m.is_complete());
+		assert!(!m.allows(b"x"), "the partial character must be finished first");
+		assert!(m.accept(&e[1..]));
+		assert!(m.accept(b"z") && m.is_complete());
+		assert!(!matcher(r#"root ::= "a""#).allows(&[e[0], b'a']), "invalid UTF-8 is refused");
+	}
+
+	#[test]
+	fn constrain_keeps_only_allowed_tokens() {
+		let pieces: Vec<Vec<u8>> = ["a", "b", "ab", "", "</s>"].iter().map(|p| p.as_bytes().to_vec()).collect();
+		let mut m = matcher(r#"root ::= "a" "b"?"#);
+		let ids = |m: &GrammarMatcher| {
+			let mut candidates = Candidates::from_logits(&[0.0; 5]);
+			m.constrain(&mut candidates, &pieces, |id| id == 4);
+			candidates.items.iter().map(|c| c.id).collect::<Vec<_>>()
+		};
+		assert_eq!(ids(&m), vec![0, 2]);
+		m.accept(b"a");
+		// Complete now: the end token is allowed, and so is the optional "b".
+		assert_eq!(ids(&m), vec![1, 4]);
+	}
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+
+pub mod json_schema;
+mod matcher;
+mod parser;
+
+pub use matcher::GrammarMatcher;
+
+/// A parse failure, located 1-based in the grammar source.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("line {line}, column {column}: {message}")]
+pub struct GrammarError {
+	pub line: usize,
+	pub column: usize,
+	pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Element {
+	/// One character inside any of the inclusive ranges, or outside all of
+	/// them when `negated`. `.` is an empty negated set.
+	Char { ranges: Vec<(char, char)>, negated: bool },
+	Rule(usize),
+}
+
+impl Element {
+	pub(crate) fn matches(&self, c: char) -> bool {
+		match self {
+			Element::Char { ranges, negated } => ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated,
+			Element::Rule(_) => false,
+		}
+	}
+}
+
+/// A GBNF grammar (the llama.cpp dialect) compiled to plain alternatives:
+/// strings become one element per character, and groups and repetitions
+/// become generated helper rules.
+#[derive(Clone)]
+pub struct Grammar {
+	/// `rules[r][alt]` is one alternative's sequence of elements.
+	pub(crate) rules: Vec<Vec<Vec<Element>>>,
+	names: Vec<String>,
+	pub(crate) root: usize,
+	source: String,
+}
+
+impl Grammar {
+	/// Parses GBNF source. The grammar must define `root`.
+	pub fn parse(src: &str) -> Result<Self, GrammarError> {
+		parser::Parser::new(src).parse()
+	}
+
+	pub fn rule_names(&self) -> &[String] {
+		&self.names
+	}
+
+	/// The GBNF text this grammar was parsed from.
+	pub fn source(&self) -> &str {
+		&self.source
+	}
+}
+
+impl fmt::Debug for Grammar {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Grammar")
+			.field("rules", &self.rules.len())
+			.field("root", &self.names[self.root])
+			.finish()
+	}
+}
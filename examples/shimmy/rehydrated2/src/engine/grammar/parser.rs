@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+
+use crate::engine::grammar::{Element, Grammar, GrammarError};
+
+/// Bounded so `x{1000000}` cannot expand into millions of rules.
+const MAX_REPEAT: usize = 1000;
+
+type Alternatives = Vec<Vec<Element>>;
+
+pub(super) struct Parser {
+	chars: Vec<char>,
+	pos: usize,
+	names: Vec<String>,
+	ids: HashMap<String, usize>,
+	rules: Vec<Option<Alternatives>>,
+	/// Where each rule was first referenced, then where it was defined.
+	located_at: Vec<usize>,
+	/// For generated helper rules, the user rule they were written in.
+	helper_of: HashMap<usize, String>,
+}
+
+impl Parser {
+	pub(super) fn new(src: &str) -> Self {
+		Self {
+			chars: src.chars().collect(),
+			pos: 0,
+			names: vec![],
+			ids: HashMap::new(),
+			rules: vec![],
+			located_at: vec![],
+			helper_of: HashMap::new(),
+		}
+	}
+
+	pub(super) fn parse(mut self) -> Result<Grammar, GrammarError> {
+		self.skip_space(true);
+		while self.pos < self.chars.len() {
+			self.parse_rule()?;
+			self.skip_space(true);
+		}
+
+		let root = *self.ids.get("root").ok_or_else(|| self.error(0, "grammar does not define a root rule"))?;
+		if let Some(id) = self.rules.iter().position(Option::is_none) {
+			return Err(self.error(self.located_at[id], format!("undefined rule {}", self.names[id])));
+		}
+		let rules: Vec<Alternatives> = self.rules.iter().cloned().map(Option::unwrap_or_default).collect();
+		if let Some(id) = left_recursive_rule(&rules) {
+			let what = match self.helper_of.get(&id) {
+				Some(parent) => format!("a group or repetition in rule {}", parent),
+				None => format!("rule {}", self.names[id]),
+			};
+			return Err(self.error(
+				self.located_at[id],
+				format!("{} is left-recursive (it can reach itself without consuming input)", what),
+			));
+		}
+		Ok(Grammar {
+			rules,
+			names: self.names,
+			root,
+			source: self.chars.iter().collect(),
+		})
+	}
+
+	fn error(&self, at: usize, message: impl Into<String>) -> GrammarError {
+		let before = &self.chars[..at.min(self.chars.len())];
+		let line = 1 + before.iter().filter(|c| **c == '\n').count();
+		let column = 1 + before.iter().rev().take_while(|c| **c != '\n').count();
+		GrammarError {
+			line,
+			column,
+			message: message.into(),
+		}
+	}
+
+	fn peek(&self) -> Option<char> {
+		self.chars.get(self.pos).copied()
+	}
+
+	/// Skips blanks and `#` comments, and line breaks when `newline_ok`.
+	fn skip_space(&mut self, newline_ok: bool) {
+		while let Some(c) = self.peek() {
+			match c {
+				' ' | '\t' => self.pos += 1,
+				'\r' | '\n' if newline_ok => self.pos += 1,
+				'#' => {
+					while !matches!(self.peek(), None | Some('\n')) {
+						self.pos += 1;
+					}
+				}
+				_ => break,
+			}
+		}
+	}
+
+	fn rule_id(&mut self, name: &str, at: usize) -> usize {
+		if let Some(id) = self.ids.get(name) {
+			return *id;
+		}
+		let id = self.names.len();
+		self.names.push(name.to_string());
+		self.ids.insert(name.to_string(), id);
+		self.rules.push(None);
+		self.located_at.push(at);
+		id
+	}
+
+	/// Defines a generated helper rule named after the rule it came from.
+	fn helper_rule(&mut self, parent: &str, alts: Alternatives, at: usize) -> usize {
+		let mut n = self.names.len();
+		while self.ids.contains_key(&format!("{}_{}", parent, n)) {
+			n += 1;
+		}
+		let id = self.rule_id(&format!("{}_{}", parent, n), at);
+		self.rules[id] = Some(alts);
+		self.helper_of.insert(id, parent.to_string());
+		id
+	}
+
+	fn parse_name(&mut self) -> Option<String> {
+		let start = self.pos;
+		while self.peek().is_some_and(is_name_char) {
+			self.pos += 1;
+		}
+		(self.pos > start).then(|| self.chars[start..self.pos].iter().collect())
+	}
+
+	fn parse_rule(&mut self) -> Result<(), GrammarError> {
+		let start = self.pos;
+		let name = self.parse_name().ok_or_else(|| self.error(start, "expected rule name"))?;
+		self.skip_space(false);
+		if !self.chars[self.pos..].starts_with(&[':', ':', '=']) {
+			return Err(self.error(self.pos, "expected ::="));
+		}
+		self.pos += 3;
+		self.skip_space(true);
+
+		let id = self.rule_id(&name, start);
+		if self.rules[id].is_some() {
+			return Err(self.error(start, format!("rule {} is defined more than once", name)));
+		}
+		self.located_at[id] = start;
+		let alts = self.parse_alternates(&name, false)?;
+		match self.peek() {
+			None | Some('\r' | '\n') => {}
+			Some(c) => return Err(self.error(self.pos, format!("unexpected {:?}", c))),
+		}
+		self.rules[id] = Some(alts);
+		Ok(())
+	}
+
+	fn parse_alternates(&mut self, rule: &str, nested: bool) -> Result<Alternatives, GrammarError> {
+		let mut alts = vec![self.parse_sequence(rule, nested)?];
+		while self.peek() == Some('|') {
+			self.pos += 1;
+			self.skip_space(true);
+			alts.push(self.parse_sequence(rule, nested)?);
+		}
+		Ok(alts)
+	}
+
+	fn parse_sequence(&mut self, rule: &str, nested: bool) -> Result<Vec<Element>, GrammarError> {
+		let mut seq = vec![];
+		// Index in `seq` where the most recent item starts; repetition applies to it.
+		let mut last: Option<usize> = None;
+		loop {
+			let at = self.pos;
+			match self.peek() {
+				Some('"') => {
+					last = Some(seq.len());
+					self.pos += 1;
+					loop {
+						match self.peek() {
+							None => return Err(self.error(at, "unterminated string literal")),
+							Some('"') => {
+								self.pos += 1;
+								break;
+							}
+							Some(_) => {
+								let c = self.parse_char()?;
+								seq.push(Element::Char {
+									ranges: vec![(c, c)],
+									negated: false,
+								});
+							}
+						}
+					}
+				}
+				Some('[') => {
+					last = Some(seq.len());
+					seq.push(self.parse_class()?);
+				}
+				Some('.') => {
+					last = Some(seq.len());
+					self.pos += 1;
+					seq.push(Element::Char {
+						ranges: vec![],
+						negated: true,
+					});
+				}
+				Some('(') => {
+					last = Some(seq.len());
+					self.pos += 1;
+					self.skip_space(true);
+					let alts = self.parse_alternates(rule, true)?;
+					if self.peek() != Some(')') {
+						return Err(self.error(self.pos, "expected )"));
+					}
+					self.pos += 1;
+					seq.push(Element::Rule(self.helper_rule(rule, alts, at)));
+				}
+				Some(c) if is_name_char(c) => {
+					last = Some(seq.len());
+					let name = self.parse_name().unwrap_or_default();
+					seq.push(Element::Rule(self.rule_id(&name, at)));
+				}
+				Some(op @ ('*' | '+' | '?' | '{')) => {
+					let start = last.ok_or_else(|| self.error(at, format!("nothing to repeat before {:?}", op)))?;
+					self.pos += 1;
+					let (min, max) = match op {
+						'*' => (0, None),
+						'+' => (1, None),
+						'?' => (0, Some(1)),
+						_ => self.parse_braces(at)?,
+					};
+					self.repeat(&mut seq, start, min, max, rule, at);
+				}
+				_ => break,
+			}
+			self.skip_space(nested);
+		}
+		Ok(seq)
+	}
+
+	/// Parses the rest of `{m}`, `{m,}` or `{m,n}` after the opening brace.
+	fn parse_braces(&mut self, at: usize) -> Result<(usize, Option<usize>), GrammarError> {
+		self.skip_space(true);
+		let min = self.parse_number().ok_or_else(|| self.error(self.pos, "expected number"))?;
+		self.skip_space(true);
+		let max = if self.peek() == Some(',') {
+			self.pos += 1;
+			self.skip_space(true);
+			self.parse_number()
+		} else {
+			Some(min)
+		};
+		self.skip_space(true);
+		if self.peek() != Some('}') {
+			return Err(self.error(self.pos, "expected }"));
+		}
+		self.pos += 1;
+		if max.is_some_and(|max| max < min) {
+			return Err(self.error(at, "repetition maximum is below its minimum"));
+		}
+		if min.max(max.unwrap_or(0)) > MAX_REPEAT {
+			return Err(self.error(at, format!("repetition count above {}", MAX_REPEAT)));
+		}
+		Ok((min, max))
+	}
+
+	fn parse_number(&mut self) -> Option<usize> {
+		let start = self.pos;
+		while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+			self.pos += 1;
+		}
+		self.chars[start..self.pos].iter().collect::<String>().parse().ok()
+	}
+
+	/// Replaces `seq[start..]` with `min` copies followed by helper rules for
+	/// the optional rest: `x*` becomes `r ::= x r |` and `x{0,2}` becomes
+	/// `r2 ::= x r1 |`, `r1 ::= x |`.
+	fn repeat(&mut self, seq: &mut Vec<Element>, start: usize, min: usize, max: Option<usize>, rule: &str, at: usize) {
+		let item = seq.split_off(start);
+		for _ in 0..min {
+			seq.extend(item.iter().cloned());
+		}
+		match max {
+			None => {
+				let id = self.helper_rule(rule, vec![], at);
+				let mut again = item;
+				again.push(Element::Rule(id));
+				self.rules[id] = Some(vec![again, vec![]]);
+				seq.push(Element::Rule(id));
+			}
+			Some(max) => {
+				let mut tail = None;
+				for _ in min..max {
+					let mut first = item.clone();
+					first.extend(tail.map(Element::Rule));
+					tail = Some(self.helper_rule(rule, vec![first, vec![]], at));
+				}
+				seq.extend(tail.map(Element::Rule));
+			}
+		}
+	}
+
+	fn parse_class(&mut self) -> Result<Element, GrammarError> {
+		let at = self.pos;
+		self.pos += 1;
+		let negated = self.peek() == Some('^');
+		if negated {
+			self.pos += 1;
+		}
+		let mut ranges = vec![];
+		loop {
+			match self.peek() {
+				None => return Err(self.error(at, "unterminated character class")),
+				Some(']') => {
+					self.pos += 1;
+					break;
+				}
+				Some(_) => {
+					let lo = self.parse_char()?;
+					let mut hi = lo;
+					if self.peek() == Some('-') && !matches!(self.chars.get(self.pos + 1), None | Some(']')) {
+						self.pos += 1;
+						let range_end = self.pos;
+						hi = self.parse_char()?;
+						if hi < lo {
+							return Err(self.error(range_end, format!("invalid range {:?}-{:?}", lo, hi)));
+						}
+					}
+					ranges.push((lo, hi));
+				}
+			}
+		}
+		Ok(Element::Char { ranges, negated })
+	}
+
+	/// One literal character, resolving backslash escapes.
+	fn parse_char(&mut self) -> Result<char, GrammarError> {
+		let at = self.pos;
+		let c = self.peek().ok_or_else(|| self.error(at, "unexpected end of grammar"))?;
+		self.pos += 1;
+		if c != '\\' {
+			return Ok(c);
+		}
+		let e = self.peek().ok_or_else(|| self.error(at, "unfinished escape"))?;
+		self.pos += 1;
+		let hex_digits = match e {
+			'n' => return Ok('\n'),
+			'r' => return Ok('\r'),
+			't' => return Ok('\t'),
+			'\\' | '"' | '[' | ']' | '-' | '^' => return Ok(e),
+			'x' => 2,
+			'u' => 4,
+			'U' => 8,
+			_ => return Err(self.error(at, format!("unknown escape \\{}", e))),
+		};
+		let digits: String = self.chars[self.pos..].iter().take(hex_digits).collect();
+		let value = (digits.len() == hex_digits)
+			.then(|| u32::from_str_radix(&digits, 16).ok())
+			.flatten()
+			.and_then(char::from_u32)
+			.ok_or_else(|| self.error(at, format!("invalid \\{} escape", e)))?;
+		self.pos += hex_digits;
+		Ok(value)
+	}
+}
+
+fn is_name_char(c: char) -> bool {
+	c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// A rule that can reach itself through leading, possibly empty, elements.
+/// The matcher expands rules eagerly, so such a rule would never terminate.
+fn left_recursive_rule(rules: &[Alternatives]) -> Option<usize> {
+	let mut nullable = vec![false; rules.len()];
+	let mut changed = true;
+	while changed {
+		changed = false;
+		for (r, alts) in rules.iter().enumerate() {
+			if !nullable[r] && alts.iter().any(|alt| alt.iter().all(|e| matches!(e, Element::Rule(x) if nullable[*x]))) {
+				nullable[r] = true;
+				changed = true;
+			}
+		}
+	}
+
+	let leading: Vec<Vec<usize>> = rules
+		.iter()
+		.map(|alts| {
+			let mut out = vec![];
+			for alt in alts {
+				for e in alt {
+					match e {
+						Element::Rule(x) => {
+							out.push(*x);
+							if !nullable[*x] {
+								break;
+							}
+						}
+						Element::Char { .. } => break,
+					}
+				}
+			}
+			out
+		})
+		.collect();
+
+	// 0 = unvisited, 1 = on the current path, 2 = done.
+	fn visit(r: usize, leading: &[Vec<usize>], state: &mut [u8]) -> Option<usize> {
+		state[r] = 1;
+		for &next in &leading[r] {
+			match state[next] {
+				1 => return Some(next),
+				0 => {
+					if let Some(found) = visit(next, leading, state) {
+						return Some(found);
+					}
+				}
+				_ => {}
+			}
+		}
+		state[r] = 2;
+		None
+	}
+	let mut state = vec![0u8; rules.len()];
+	(0..rules.len()).find_map(|r| if state[r] == 0 { visit(r, &leading, &mut state) } else { None })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn error(src: &str) -> GrammarError {
+		Grammar::parse(src).unwrap_err()
+	}
+
+	#[test]
+	fn parses_comments_escapes_and_multiline_alternatives() {
+		let g = Grammar::parse(
+			"# greeting\nroot ::= ( \"hi\\n\" | [\\x41-\\x43]\n  | sub ) # trailing\nsub ::= \"\\u00e9\"\n",
+		)
+		.unwrap();
+		assert_eq!(g.rule_names()[..2], ["root".to_string(), "sub".to_string()]);
+		// The group becomes a helper rule holding the three alternatives.
+		let Element::Rule(group) = g.rules[g.root][0][0] else {
+			panic!("expected the group's helper rule")
+		};
+		let alts = &g.rules[group];
+		assert_eq!(alts.len(), 3);
+		assert_eq!(alts[0].len(), 3, "one element per character");
+		assert!(alts[0][2].matches('\n'));
+		assert!(alts[1][0].matches('B'));
+		assert!(!alts[1][0].matches('D'));
+		assert!(g.rules[1][0][0].matches('é'));
+	}
+
+	#[test]
+	fn reports_errors_with_their_location() {
+		assert_eq!(error("item ::= \"a\"").message, "grammar does not define a root rule");
+		let undefined = error("item ::= \"a\"\nroot ::= item missing");
+		assert_eq!((undefined.line, undefined.column), (2, 15));
+		assert_eq!(undefined.message, "undefined rule missing");
+		assert_eq!(error("root ::= \"a").message, "unterminated string literal");
+		assert_eq!(error("root ::= [a-").message, "unterminated character class");
+		assert_eq!(error("root ::= [z-a]").message, "invalid range 'z'-'a'");
+		assert_eq!(error("root ::= * \"a\"").message, "nothing to repeat before '*'");
+		assert_eq!(error("root ::= \"a\"{3,1}").message, "repetition maximum is below its minimum");
+		assert_eq!(error("root ::= \"a\"{5000}").message, format!("repetition count above {}", MAX_REPEAT));
+		assert_eq!(error("root ::= \"a\"\nroot ::= \"b\"").message, "rule root is defined more than once");
+		assert_eq!(error("root ::= \"\\q\"").message, "unknown escape \\q");
+	}
+
+	#[test]
+	fn rejects_left_recursion() {
+		assert!(error("root ::= root \"a\" | \"b\"").message.contains("rule root is left-recursive"));
+		assert!(error("root ::= \"x\" (\"a\"?)*").message.contains("a group or repetition in rule root"));
+		assert!(Grammar::parse("root ::= \"a\" root | \"b\"").is_ok());
+	}
+}
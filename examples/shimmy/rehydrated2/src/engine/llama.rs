@@ -1,15 +1,20 @@
-use std::{path::Path, sync::Mutex};
+use std::{path::Path, sync::Arc};
 
 use async_trait::async_trait;
 
-type BoxTokenCb = Option<Box<dyn Fn(String) + Send>>;
-
 use crate::{
-	engine::{EngineError, GenOptions, InferenceEngine, LoadedModel, Result},
+	engine::{
+		gguf::{GgmlType, GgufFile, TensorInfo},
+		gpu::GpuProbe,
+		memory::{MemoryEstimate, MemoryPlan, SystemMemory},
+		stream_from_text,
+		tokenizer::{self, Tokenizer},
+		EngineError, GenOptions, InferenceEngine, LoadedModel, Result, TokenStream,
+	},
 	model_registry::ModelSpec,
 };
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GpuBackend {
 	Cpu,
 	Cuda,
@@ -18,38 +23,131 @@ pub enum GpuBackend {
 	Metal,
 }
 
-#[derive(Debug, Clone)]
+impl GpuBackend {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			GpuBackend::Cpu => "cpu",
+			GpuBackend::Cuda => "cuda",
+			GpuBackend::Vulkan => "vulkan",
+			GpuBackend::OpenCL => "opencl",
+			GpuBackend::Metal => "metal",
+		}
+	}
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct MoeConfig {
 	pub enabled: bool,
 	pub offload_all: bool,
 	pub n_layers_cpu: Option<usize>,
 }
 
-impl Default for MoeConfig {
-	fn default() -> Self {
-		Self {
-			enabled: false,
-			offload_all: false,
-			n_layers_cpu: None,
+impl MoeConfig {
+	pub fn from_cli(cpu_moe: bool, n_cpu_moe: Option<usize>) -> Self {
+		MoeConfig {
+			enabled: cpu_moe || n_cpu_moe.is_some(),
+			offload_all: cpu_moe,
+			n_layers_cpu: n_cpu_moe,
 		}
 	}
+
+	/// Checks the settings against the model and lays its expert tensors out
+	/// layer by layer. Dense models without MoE settings have no plan.
+	pub fn plan(&self, gguf: &GgufFile) -> std::result::Result<Option<MoePlacement>, MoeError> {
+		let expert_count = gguf.arch_u64("expert_count").unwrap_or(0);
+		if expert_count < 2 {
+			if self.enabled {
+				return Err(MoeError::DenseModel);
+			}
+			return Ok(None);
+		}
+		let block_count = gguf.block_count().ok_or(MoeError::MissingBlockCount)? as usize;
+		let cpu_layers = match (self.offload_all, self.n_layers_cpu) {
+			(true, _) => block_count,
+			(false, Some(n)) if n > block_count => {
+				return Err(MoeError::TooManyLayers {
+					requested: n,
+					block_count,
+				});
+			}
+			(false, Some(n)) => n,
+			(false, None) => 0,
+		};
+		let layers = (0..block_count)
+			.map(|layer| {
+				let prefix = format!("blk.{}.", layer);
+				let tensors: Vec<&TensorInfo> = gguf
+					.tensors()
+					.iter()
+					.filter(|t| t.name.starts_with(&prefix) && t.name.contains("_exps"))
+					.collect();
+				LayerPlacement {
+					layer,
+					on_cpu: layer < cpu_layers,
+					tensors: tensors.iter().map(|t| t.name.clone()).collect(),
+					bytes: tensors.iter().filter_map(|t| t.size_bytes()).sum(),
+				}
+			})
+			.collect();
+		Ok(Some(MoePlacement {
+			expert_count,
+			expert_used_count: gguf.arch_u64("expert_used_count"),
+			layers,
+		}))
+	}
 }
 
-impl MoeConfig {
-	pub fn from_cli(cpu_moe: bool, n_cpu_moe: Option<usize>) -> Self {
-		let mut cfg = MoeConfig::default();
-		cfg.enabled = cpu_moe || n_cpu_moe.is_some();
-		cfg.offload_all = cpu_moe;
-		cfg.n_layers_cpu = n_cpu_moe;
-		cfg
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum MoeError {
+	#[error("--cpu-moe and --n-cpu-moe apply only to mixture-of-experts models; this one is dense (no expert_count)")]
+	DenseModel,
+	#[error("--n-cpu-moe {requested} is more than the model's {block_count} layers")]
+	TooManyLayers { requested: usize, block_count: usize },
+	#[error("mixture-of-experts model has no block_count")]
+	MissingBlockCount,
+}
+
+/// Where a mixture-of-experts model's expert tensors live, layer by layer.
+/// Everything else in the model follows the GPU backend.
+#[derive(Debug, Clone)]
+pub struct MoePlacement {
+	pub expert_count: u64,
+	/// Experts routed to per token.
+	pub expert_used_count: Option<u64>,
+	pub layers: Vec<LayerPlacement>,
+}
+
+impl MoePlacement {
+	pub fn cpu_layers(&self) -> usize {
+		self.layers.iter().filter(|l| l.on_cpu).count()
+	}
+
+	pub fn cpu_bytes(&self) -> u64 {
+		self.layers.iter().filter(|l| l.on_cpu).map(|l| l.bytes).sum()
 	}
 }
 
+#[derive(Debug, Clone)]
+pub struct LayerPlacement {
+	pub layer: usize,
+	/// Whether this layer's experts are kept in system memory.
+	pub on_cpu: bool,
+	/// The layer's expert tensors, such as `blk.0.ffn_up_exps.weight`.
+	pub tensors: Vec<String>,
+	pub bytes: u64,
+}
+
 pub struct LlamaEngine {
 	backend: GpuBackend,
 	moe_config: MoeConfig,
 }
 
+impl Default for LlamaEngine {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
 impl LlamaEngine {
 	pub fn new() -> Self {
 		Self {
@@ -98,36 +196,61 @@ impl InferenceEngine for LlamaEngine {
 			return Err(EngineError::ModelNotFound(spec.name.clone()));
 		}
 
+		let gguf = GgufFile::open(&spec.base_path)
+			.map_err(|e| EngineError::LoadFailed(format!("{}: {}", spec.name, e)))?;
+		let moe = self
+			.moe_config
+			.plan(&gguf)
+			.map_err(|e| EngineError::LoadFailed(format!("{}: {}", spec.name, e)))?;
+		let memory = plan_memory(&gguf, spec);
+		memory.check()?;
+
 		// Placeholder for real llama.cpp loading. We keep structure to match the spell.
 		let loaded = LlamaLoaded {
-			_guard: Mutex::new(()),
-			n_ctx: spec.ctx_len.unwrap_or(4096),
-			n_threads: spec.n_threads.unwrap_or(8),
+			n_ctx: memory.estimate.context_length,
+			_n_threads: spec.n_threads.unwrap_or(8),
 			model_name: spec.name.clone(),
 			backend: self.backend,
-			moe_enabled: self.moe_config.enabled,
+			moe,
+			// Usage counts stay exact even though decoding is still a placeholder.
+			tokenizer: tokenizer::from_gguf(&gguf).ok(),
+			memory,
 		};
 		Ok(Box::new(loaded))
 	}
+
+	fn plan_memory(&self, spec: &ModelSpec) -> Option<MemoryPlan> {
+		let gguf = GgufFile::open(&spec.base_path).ok()?;
+		Some(plan_memory(&gguf, spec))
+	}
+}
+
+/// llama.cpp caches keys and values as f16 by default.
+fn plan_memory(gguf: &GgufFile, spec: &ModelSpec) -> MemoryPlan {
+	let n_ctx = spec
+		.ctx_len
+		.or_else(|| gguf.context_length().map(|n| n as usize))
+		.unwrap_or(4096);
+	MemoryEstimate::from_gguf(gguf, n_ctx, spec.slots, GgmlType::F16)
+		.admit(SystemMemory::read().map(|m| m.available_bytes), spec.ctx_len.is_none())
 }
 
 pub struct LlamaLoaded {
-	_guard: Mutex<()>,
 	n_ctx: usize,
-	n_threads: i32,
+	_n_threads: i32,
 	model_name: String,
 	backend: GpuBackend,
-	moe_enabled: bool,
+	moe: Option<MoePlacement>,
+	tokenizer: Option<Arc<dyn Tokenizer>>,
+	memory: MemoryPlan,
 }
 
 #[async_trait]
 impl LoadedModel for LlamaLoaded {
-	async fn generate(&self, prompt: &str, opts: GenOptions, on_token: BoxTokenCb) -> Result<String> {
-		let _lock = self._guard.lock().map_err(|_| EngineError::GenerationFailed("mutex poisoned".into()))?;
-
+	fn generate_stream<'a>(&'a self, prompt: &str, opts: GenOptions) -> TokenStream<'a> {
 		// Minimal deterministic-ish placeholder text.
-		let mut completion = format!("[{}:{}] ", self.model_name, backend_tag(self.backend));
-		if self.moe_enabled {
+		let mut completion = format!("[{}:{}] ", self.model_name, self.backend.as_str());
+		if self.moe.as_ref().is_some_and(|m| m.cpu_layers() > 0) {
 			completion.push_str("(moe) ");
 		}
 		completion.push_str("response: ");
@@ -136,48 +259,24 @@ impl LoadedModel for LlamaLoaded {
 		let take = prompt.chars().take(200).collect::<String>();
 		completion.push_str(&take);
 
-		// Apply stop tokens by truncation if any match.
-		for stop in &opts.stop_tokens {
-			if stop.is_empty() {
-				continue;
-			}
-			if let Some(idx) = completion.find(stop) {
-				completion.truncate(idx);
-				break;
-			}
-		}
+		stream_from_text(&completion, &opts)
+	}
 
-		// Simulate token streaming.
-		if let Some(cb) = on_token {
-			for word in completion.split_whitespace().take(opts.max_tokens) {
-				cb(format!("{} ", word));
-			}
-		}
+	fn tokenizer(&self) -> Option<&dyn Tokenizer> {
+		self.tokenizer.as_deref()
+	}
 
-		Ok(completion)
+	fn context_length(&self) -> Option<usize> {
+		Some(self.n_ctx)
 	}
-}
 
-fn backend_tag(b: GpuBackend) -> &'static str {
-	match b {
-		GpuBackend::Cpu => "cpu",
-		GpuBackend::Cuda => "cuda",
-		GpuBackend::Vulkan => "vulkan",
-		GpuBackend::OpenCL => "opencl",
-		GpuBackend::Metal => "metal",
+	fn memory_plan(&self) -> Option<MemoryPlan> {
+		Some(self.memory.clone())
 	}
 }
 
+/// The best backend that is both built in and backed by a device and driver
+/// stack on this machine; see [`GpuProbe`] for the full report.
 fn detect_gpu_backend() -> GpuBackend {
-	// Spell describes probing external tools; keep simple + deterministic.
-	if cfg!(feature = "llama-cuda") || cfg!(feature = "gpu") {
-		return GpuBackend::Cuda;
-	}
-	if cfg!(feature = "llama-vulkan") || cfg!(feature = "gpu") {
-		return GpuBackend::Vulkan;
-	}
-	if cfg!(feature = "llama-opencl") || cfg!(feature = "gpu") {
-		return GpuBackend::OpenCL;
-	}
-	GpuBackend::Cpu
+	GpuProbe::new().detect().backend
 }
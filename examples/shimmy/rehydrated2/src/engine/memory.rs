@@ -0,0 +1,351 @@
+use serde::{Serialize, Serializer};
+
+use crate::engine::{
+	gguf::{GgmlType, GgufFile},
+	EngineError, Result,
+};
+
+/// Shortest context a load is downgraded to before it is refused outright.
+pub const MIN_DOWNGRADED_CONTEXT: usize = 512;
+
+/// Memory a model needs once loaded: its weights, a KV cache per batch slot,
+/// and per-slot scratch for activations and logits.
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryEstimate {
+	pub weights_bytes: u64,
+	pub kv_cache_bytes: u64,
+	pub scratch_bytes: u64,
+	/// Backend caches that grow up to a fixed cap, such as reusable prompt prefixes.
+	pub cache_bytes: u64,
+	pub total_bytes: u64,
+	pub context_length: usize,
+	pub slots: usize,
+	#[serde(serialize_with = "serialize_ggml_type")]
+	pub kv_type: GgmlType,
+	#[serde(skip)]
+	shape: Shape,
+}
+
+/// The hyperparameters the estimate scales with; zero when the GGUF does
+/// not declare them, which leaves only the weights counted.
+#[derive(Debug, Clone, Copy, Default)]
+struct Shape {
+	n_layer: u64,
+	/// Key plus value elements one layer stores per position.
+	kv_elements: u64,
+	n_vocab: u64,
+	n_embd: u64,
+	n_ff: u64,
+	n_head: u64,
+}
+
+impl Shape {
+	fn from_gguf(gguf: &GgufFile) -> Self {
+		let arch = |key: &str| gguf.arch_u64(key).unwrap_or(0);
+		let n_embd = arch("embedding_length");
+		let n_head = arch("attention.head_count");
+		let n_head_kv = gguf.arch_u64("attention.head_count_kv").unwrap_or(n_head);
+		let head_dim = n_embd.checked_div(n_head).unwrap_or(0);
+		let key_length = gguf.arch_u64("attention.key_length").unwrap_or(head_dim);
+		let value_length = gguf.arch_u64("attention.value_length").unwrap_or(head_dim);
+		let n_vocab = gguf
+			.tensor("token_embd.weight")
+			.and_then(|t| t.shape.get(1).copied())
+			.or_else(|| gguf.get_array("tokenizer.ggml.tokens").map(|t| t.len() as u64))
+			.unwrap_or(0);
+		Self {
+			n_layer: arch("block_count"),
+			kv_elements: n_head_kv.saturating_mul(key_length.saturating_add(value_length)),
+			n_vocab,
+			n_embd,
+			n_ff: arch("feed_forward_length"),
+			n_head,
+		}
+	}
+}
+
+impl MemoryEstimate {
+	/// Estimates `gguf` loaded with `context_length` positions for each of
+	/// `slots` concurrent sequences, caching keys and values as `kv_type`.
+	pub fn from_gguf(gguf: &GgufFile, context_length: usize, slots: usize, kv_type: GgmlType) -> Self {
+		Self {
+			weights_bytes: gguf.tensor_data_size(),
+			kv_cache_bytes: 0,
+			scratch_bytes: 0,
+			cache_bytes: 0,
+			total_bytes: 0,
+			context_length,
+			slots: slots.max(1),
+			kv_type,
+			shape: Shape::from_gguf(gguf),
+		}
+		.with_context_length(context_length)
+	}
+
+	pub fn with_cache_bytes(mut self, bytes: u64) -> Self {
+		self.cache_bytes = bytes;
+		self.total_bytes = sum(&[self.weights_bytes, self.kv_cache_bytes, self.scratch_bytes, self.cache_bytes]);
+		self
+	}
+
+	/// The same model and slots at a different context length.
+	pub fn with_context_length(&self, context_length: usize) -> Self {
+		let s = self.shape;
+		let ctx = context_length as u64;
+		let slots = self.slots as u64;
+		let (block, block_bytes) = self.kv_type.block_layout().unwrap_or((1, 4));
+		let kv_per_layer = ctx.saturating_mul(s.kv_elements).div_ceil(block);
+		let kv_cache_bytes = product(&[slots, s.n_layer, kv_per_layer, block_bytes]);
+		// f32 logits, residual stream, feed-forward activations and one row of attention scores per head.
+		let activations = sum(&[s.n_vocab, product(&[4, s.n_embd]), product(&[2, s.n_ff]), product(&[s.n_head, ctx])]);
+		let scratch_bytes = product(&[slots, 4, activations]);
+		Self {
+			kv_cache_bytes,
+			scratch_bytes,
+			context_length,
+			..self.clone()
+		}
+		.with_cache_bytes(self.cache_bytes)
+	}
+
+	/// Checks the estimate against `available_bytes`, halving the context
+	/// down to [`MIN_DOWNGRADED_CONTEXT`] when `allow_downgrade` is set. An
+	/// unknown amount of available memory admits everything.
+	pub fn admit(self, available_bytes: Option<u64>, allow_downgrade: bool) -> MemoryPlan {
+		let requested_context_length = self.context_length;
+		let mut estimate = self;
+		if let Some(available) = available_bytes {
+			while allow_downgrade && estimate.total_bytes > available && estimate.context_length > MIN_DOWNGRADED_CONTEXT {
+				estimate = estimate.with_context_length((estimate.context_length / 2).max(MIN_DOWNGRADED_CONTEXT));
+			}
+		}
+		MemoryPlan {
+			fits: available_bytes.is_none_or(|available| estimate.total_bytes <= available),
+			estimate,
+			requested_context_length,
+			available_bytes,
+		}
+	}
+}
+
+// A GGUF header can claim any shape; an absurd one saturates to a total
+// no system has free rather than wrapping into one that fits.
+fn product(factors: &[u64]) -> u64 {
+	factors.iter().fold(1, |acc, &f| acc.saturating_mul(f))
+}
+
+fn sum(terms: &[u64]) -> u64 {
+	terms.iter().fold(0, |acc, &t| acc.saturating_add(t))
+}
+
+fn serialize_ggml_type<S: Serializer>(t: &GgmlType, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+	serializer.serialize_str(&t.name())
+}
+
+/// A [`MemoryEstimate`] settled against the memory the system has free.
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryPlan {
+	#[serde(flatten)]
+	pub estimate: MemoryEstimate,
+	/// The context asked for; larger than `context_length` when the load was downgraded.
+	pub requested_context_length: usize,
+	pub available_bytes: Option<u64>,
+	pub fits: bool,
+}
+
+impl MemoryPlan {
+	pub fn downgraded(&self) -> bool {
+		self.estimate.context_length < self.requested_context_length
+	}
+
+	/// Refuses plans that do not fit, before any weights are read.
+	pub fn check(&self) -> Result<()> {
+		match self.available_bytes {
+			Some(available_bytes) if !self.fits => Err(EngineError::OutOfMemory {
+				required_bytes: self.estimate.total_bytes,
+				available_bytes,
+			}),
+			_ => Ok(()),
+		}
+	}
+}
+
+/// Totals from `/proc/meminfo`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SystemMemory {
+	pub total_bytes: u64,
+	/// What the kernel expects can be allocated without swapping.
+	pub available_bytes: u64,
+}
+
+impl SystemMemory {
+	/// `None` off Linux, or wherever `/proc/meminfo` cannot be read.
+	pub fn read() -> Option<Self> {
+		Self::parse(&std::fs::read_to_string("/proc/meminfo").ok()?)
+	}
+
+	pub fn parse(meminfo: &str) -> Option<Self> {
+		let field = |name: &str| {
+			meminfo.lines().find_map(|line| {
+				let rest = line.strip_prefix(name)?.strip_prefix(':')?;
+				let kb: u64 = rest.trim().trim_end_matches("kB").trim().parse().ok()?;
+				kb.checked_mul(1024)
+			})
+		};
+		let total_bytes = field("MemTotal")?;
+		// Kernels before 3.14 lack MemAvailable.
+		let free_and_cached = || Some(field("MemFree")?.saturating_add(field("Cached").unwrap_or(0)));
+		let available_bytes = field("MemAvailable").or_else(free_and_cached)?;
+		Some(Self {
+			total_bytes,
+			available_bytes,
+		})
+	}
+}
+
+/// Formats a byte count in binary units ("3.8 GiB", "512 MiB").
+pub fn format_bytes(n: u64) -> String {
+	const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+	if n < 1024 {
+		return format!("{} B", n);
+	}
+	let mut value = n as f64 / 1024.0;
+	let mut unit = 0;
+	while value >= 1024.0 && unit + 1 < UNITS.len() {
+		value /= 1024.0;
+		unit += 1;
+	}
+	if value >= 100.0 {
+		format!("{:.0} {}", value, UNITS[unit])
+	} else {
+		format!("{:.1} {}", value, UNITS[unit])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::engine::gguf::{GgufBuilder, GgufValue};
+
+	/// Two layers of 64-wide embeddings and four heads: 1 KiB of f32 keys
+	/// and values per position, and 2048 + 16 bytes per position of scratch.
+	fn small(block_count: GgufValue) -> GgufFile {
+		let builder = GgufBuilder::new()
+			.metadata("general.architecture", GgufValue::String("llama".into()))
+			.metadata("llama.block_count", block_count)
+			.metadata("llama.embedding_length", GgufValue::U32(64))
+			.metadata("llama.attention.head_count", GgufValue::U32(4))
+			.metadata("llama.feed_forward_length", GgufValue::U32(128));
+		GgufFile::from_bytes(builder.to_bytes()).unwrap()
+	}
+
+	fn estimate(context_length: usize) -> MemoryEstimate {
+		MemoryEstimate::from_gguf(&small(GgufValue::U32(2)), context_length, 1, GgmlType::F32)
+	}
+
+	#[test]
+	fn estimate_scales_with_context_and_slots() {
+		let e = estimate(4096);
+		assert_eq!(e.kv_cache_bytes, 4096 * 1024);
+		assert_eq!(e.scratch_bytes, 2048 + 16 * 4096);
+		assert_eq!(e.total_bytes, 4_261_888);
+		let halved = e.with_context_length(2048);
+		assert_eq!(halved.kv_cache_bytes, 2048 * 1024);
+		assert_eq!(halved.with_cache_bytes(100).total_bytes, 2048 * 1024 + 2048 + 16 * 2048 + 100);
+		let two = MemoryEstimate::from_gguf(&small(GgufValue::U32(2)), 4096, 2, GgmlType::F32);
+		assert_eq!(two.total_bytes, 2 * e.total_bytes);
+	}
+
+	#[test]
+	fn admit_halves_the_context_until_it_fits() {
+		let plan = estimate(4096).admit(Some(1536 * 1024), true);
+		assert!(plan.fits);
+		assert!(plan.downgraded());
+		assert_eq!(plan.estimate.context_length, 1024);
+		assert_eq!(plan.requested_context_length, 4096);
+		assert!(plan.check().is_ok());
+	}
+
+	#[test]
+	fn admit_stops_at_the_minimum_context_and_check_refuses() {
+		let plan = estimate(4096).admit(Some(100_000), true);
+		assert!(!plan.fits);
+		assert_eq!(plan.estimate.context_length, MIN_DOWNGRADED_CONTEXT);
+		match plan.check() {
+			Err(EngineError::OutOfMemory {
+				required_bytes,
+				available_bytes,
+			}) => {
+				assert_eq!(required_bytes, 512 * 1024 + 2048 + 16 * 512);
+				assert_eq!(available_bytes, 100_000);
+			}
+			other => panic!("expected OutOfMemory, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn admit_without_downgrade_keeps_the_requested_context() {
+		let plan = estimate(4096).admit(Some(1536 * 1024), false);
+		assert!(!plan.fits);
+		assert!(!plan.downgraded());
+		assert!(matches!(plan.check(), Err(EngineError::OutOfMemory { .. })));
+	}
+
+	#[test]
+	fn unknown_memory_admits_everything() {
+		let plan = estimate(1 << 20).admit(None, true);
+		assert!(plan.fits);
+		assert!(!plan.downgraded());
+		assert!(plan.check().is_ok());
+	}
+
+	#[test]
+	fn absurd_headers_saturate_and_are_refused() {
+		let gguf = small(GgufValue::U64(u64::MAX));
+		let estimate = MemoryEstimate::from_gguf(&gguf, usize::MAX, usize::MAX, GgmlType::F32);
+		let plan = estimate.admit(Some(u64::MAX - 1), true);
+		assert_eq!(plan.estimate.kv_cache_bytes, u64::MAX);
+		assert_eq!(plan.estimate.total_bytes, u64::MAX);
+		assert!(!plan.fits);
+		assert!(plan.check().is_err());
+	}
+
+	#[test]
+	fn parses_meminfo() {
+		let meminfo = "MemTotal:       16384 kB\n\
+			MemFree:         1024 kB\n\
+			MemAvailable:    8192 kB\n\
+			Cached:          2048 kB\n";
+		let memory = SystemMemory::parse(meminfo).unwrap();
+		assert_eq!(memory.total_bytes, 16384 * 1024);
+		assert_eq!(memory.available_bytes, 8192 * 1024);
+	}
+
+	#[test]
+	fn falls_back_to_free_plus_cached_without_mem_available() {
+		let meminfo = "MemTotal: 16384 kB\nMemFree: 1024 kB\nSwapCached: 4096 kB\nCached: 2048 kB\n";
+		assert_eq!(SystemMemory::parse(meminfo).unwrap().available_bytes, 3072 * 1024);
+		let uncached = "MemTotal: 16384 kB\nMemFree: 1024 kB\n";
+		assert_eq!(SystemMemory::parse(uncached).unwrap().available_bytes, 1024 * 1024);
+	}
+
+	#[test]
+	fn rejects_incomplete_or_overflowing_meminfo() {
+		assert!(SystemMemory::parse("").is_none());
+		assert!(SystemMemory::parse("MemFree: 1024 kB\nMemAvailable: 1024 kB\n").is_none());
+		assert!(SystemMemory::parse("MemTotal: 16384 kB\n").is_none());
+		assert!(SystemMemory::parse("MemTotal: lots kB\nMemAvailable: 1024 kB\n").is_none());
+		assert!(SystemMemory::parse(&format!("MemTotal: {} kB\nMemAvailable: 1 kB\n", u64::MAX)).is_none());
+	}
+
+	#[test]
+	fn formats_bytes_in_binary_units() {
+		assert_eq!(format_bytes(0), "0 B");
+		assert_eq!(format_bytes(1023), "1023 B");
+		assert_eq!(format_bytes(1024), "1.0 KiB");
+		assert_eq!(format_bytes(1536), "1.5 KiB");
+		assert_eq!(format_bytes(512 * 1024 * 1024), "512 MiB");
+		assert_eq!(format_bytes(4_080_218_931), "3.8 GiB");
+		assert_eq!(format_bytes(2048 << 40), "2048 TiB");
+	}
+}
@@ -0,0 +1,369 @@
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use regex::Regex;
+
+use crate::{
+	engine::{EngineError, FinishReason, GenOptions, InferenceEngine, LoadedModel, Result, TokenEvent, TokenStream},
+	model_registry::ModelSpec,
+};
+
+/// What a [`ScriptedEngine`] produces for a matching request.
+#[derive(Debug, Clone)]
+pub struct Script {
+	tokens: Vec<String>,
+	latency: Duration,
+	finish: FinishReason,
+	/// Fail with this error once this many tokens have been sent.
+	fail_after: Option<(usize, EngineError)>,
+}
+
+impl Script {
+	/// Streams `tokens` one event each, then finishes with `stop`.
+	pub fn new<S: Into<String>>(tokens: impl IntoIterator<Item = S>) -> Self {
+		Self {
+			tokens: tokens.into_iter().map(Into::into).collect(),
+			latency: Duration::ZERO,
+			finish: FinishReason::Stop,
+			fail_after: None,
+		}
+	}
+
+	/// Splits `text` into whitespace-delimited tokens, as
+	/// [`crate::engine::stream_from_text`] does.
+	pub fn from_text(text: &str) -> Self {
+		Self::new(text.split_inclusive(char::is_whitespace))
+	}
+
+	/// Waits this long before each token.
+	pub fn with_latency(mut self, latency: Duration) -> Self {
+		self.latency = latency;
+		self
+	}
+
+	/// Reported when the script runs out of tokens. Running into
+	/// `GenOptions::max_tokens` first still reports `length`.
+	pub fn with_finish(mut self, finish: FinishReason) -> Self {
+		self.finish = finish;
+		self
+	}
+
+	/// Ends the stream with `error` after `tokens` tokens, or in place of the
+	/// finish event when the script has fewer, unless `GenOptions::max_tokens`
+	/// ends it sooner.
+	pub fn fail_after(mut self, tokens: usize, error: EngineError) -> Self {
+		self.fail_after = Some((tokens, error));
+		self
+	}
+
+	fn stream(self, opts: &GenOptions) -> TokenStream<'static> {
+		let cancel = opts.cancel.clone();
+		let max_tokens = opts.max_tokens;
+		let state = (self, 0usize, false);
+		futures::stream::unfold(state, move |(script, sent, done)| {
+			let cancel = cancel.clone();
+			async move {
+				if done {
+					return None;
+				}
+				if !script.latency.is_zero() {
+					tokio::select! {
+						_ = tokio::time::sleep(script.latency) => {}
+						_ = cancel.cancelled() => {}
+					}
+				}
+				let event = if cancel.is_cancelled() {
+					Ok(TokenEvent::finish(FinishReason::Cancelled))
+				} else if let Some((_, error)) = script.fail_after.as_ref().filter(|(n, _)| (*n).min(script.tokens.len()) == sent) {
+					Err(error.clone())
+				} else if sent >= max_tokens {
+					Ok(TokenEvent::finish(FinishReason::Length))
+				} else if let Some(token) = script.tokens.get(sent) {
+					let event = TokenEvent::piece(token.clone(), sent as u32);
+					return Some((Ok(event), (script, sent + 1, false)));
+				} else {
+					Ok(TokenEvent::finish(script.finish))
+				};
+				Some((event, (script, sent, true)))
+			}
+		})
+		.boxed()
+	}
+}
+
+/// A generation a [`ScriptedEngine`] model was asked for.
+#[derive(Debug, Clone)]
+pub struct RecordedCall {
+	pub model: String,
+	pub prompt: String,
+	pub opts: GenOptions,
+}
+
+struct Rule {
+	model: Option<String>,
+	prompt: Option<Regex>,
+	script: Script,
+}
+
+#[derive(Default)]
+struct Shared {
+	rules: Vec<Rule>,
+	fallback: Option<Script>,
+	load_failures: HashMap<String, EngineError>,
+	loads: Vec<String>,
+	calls: Vec<RecordedCall>,
+}
+
+/// An [`InferenceEngine`] for tests that answers from scripts instead of
+/// weights. Rules are tried in the order they were added; the first whose
+/// model and prompt pattern both match supplies the script. Clones share
+/// state, so keep one to inspect [`ScriptedEngine::calls`] after handing
+/// another to the server.
+///
+/// Models load whether or not their `base_path` exists.
+#[derive(Clone, Default)]
+pub struct ScriptedEngine {
+	shared: Arc<Mutex<Shared>>,
+}
+
+impl ScriptedEngine {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Answers every prompt sent to `model` with `script`.
+	pub fn on_model(self, model: &str, script: Script) -> Self {
+		self.rule(Some(model), None, script)
+	}
+
+	/// Answers prompts matching the regex `pattern`, for any model.
+	pub fn on_prompt(self, pattern: &str, script: Script) -> Self {
+		self.rule(None, Some(pattern), script)
+	}
+
+	/// Answers prompts to `model` that match the regex `pattern`.
+	pub fn on(self, model: &str, pattern: &str, script: Script) -> Self {
+		self.rule(Some(model), Some(pattern), script)
+	}
+
+	/// Used when no rule matches; without it such requests fail.
+	pub fn otherwise(self, script: Script) -> Self {
+		self.lock().fallback = Some(script);
+		self
+	}
+
+	/// Makes loading `model` fail with `error`.
+	pub fn fail_load(self, model: &str, error: EngineError) -> Self {
+		self.lock().load_failures.insert(model.to_string(), error);
+		self
+	}
+
+	/// Every generation requested so far, oldest first.
+	pub fn calls(&self) -> Vec<RecordedCall> {
+		self.lock().calls.clone()
+	}
+
+	/// Names of the models loaded so far, including failed attempts.
+	pub fn loads(&self) -> Vec<String> {
+		self.lock().loads.clone()
+	}
+
+	fn rule(self, model: Option<&str>, pattern: Option<&str>, script: Script) -> Self {
+		let prompt = pattern.map(|p| Regex::new(p).unwrap_or_else(|e| panic!("invalid prompt pattern {:?}: {}", p, e)));
+		self.lock().rules.push(Rule {
+			model: model.map(str::to_string),
+			prompt,
+			script,
+		});
+		self
+	}
+
+	fn lock(&self) -> std::sync::MutexGuard<'_, Shared> {
+		self.shared.lock().unwrap_or_else(|e| e.into_inner())
+	}
+}
+
+#[async_trait]
+impl InferenceEngine for ScriptedEngine {
+	async fn load(&self, spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+		let mut shared = self.lock();
+		shared.loads.push(spec.name.clone());
+		if let Some(error) = shared.load_failures.get(&spec.name) {
+			return Err(error.clone());
+		}
+		Ok(Box::new(ScriptedModel {
+			name: spec.name.clone(),
+			engine: self.clone(),
+		}))
+	}
+}
+
+struct ScriptedModel {
+	name: String,
+	engine: ScriptedEngine,
+}
+
+#[async_trait]
+impl LoadedModel for ScriptedModel {
+	fn generate_stream<'a>(&'a self, prompt: &str, opts: GenOptions) -> TokenStream<'a> {
+		let mut shared = self.engine.lock();
+		shared.calls.push(RecordedCall {
+			model: self.name.clone(),
+			prompt: prompt.to_string(),
+			opts: opts.clone(),
+		});
+		let script = shared
+			.rules
+			.iter()
+			.find(|r| {
+				r.model.as_ref().is_none_or(|m| *m == self.name) && r.prompt.as_ref().is_none_or(|p| p.is_match(prompt))
+			})
+			.map(|r| r.script.clone())
+			.or_else(|| shared.fallback.clone());
+		match script {
+			Some(script) => script.stream(&opts),
+			None => {
+				let error = EngineError::GenerationFailed(format!(
+					"no script for model {} matches prompt {:?}",
+					self.name, prompt
+				));
+				futures::stream::once(async move { Err(error) }).boxed()
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+
+	use super::*;
+
+	fn spec(name: &str) -> ModelSpec {
+		ModelSpec {
+			name: name.into(),
+			base_path: format!("/nonexistent/{}.gguf", name).into(),
+			lora_path: None,
+			template: None,
+			ctx_len: None,
+			n_threads: None,
+			draft_path: None,
+			adapters: Default::default(),
+			slots: 1,
+		}
+	}
+
+	async fn run(engine: &ScriptedEngine, model: &str, prompt: &str, opts: GenOptions) -> Vec<Result<TokenEvent>> {
+		let loaded = engine.load(&spec(model)).await.unwrap();
+		loaded.generate_stream(prompt, opts).collect().await
+	}
+
+	async fn text(engine: &ScriptedEngine, model: &str, prompt: &str) -> String {
+		let events = run(engine, model, prompt, GenOptions::default()).await;
+		events.into_iter().map(|e| e.unwrap().text).collect()
+	}
+
+	#[tokio::test]
+	async fn the_first_matching_rule_answers() {
+		let engine = ScriptedEngine::new()
+			.on("a", "^hello", Script::from_text("a greeting"))
+			.on_model("a", Script::from_text("anything to a"))
+			.on_prompt("hello", Script::from_text("hello to anyone"))
+			.otherwise(Script::from_text("fallback"));
+		assert_eq!(text(&engine, "a", "hello there").await, "a greeting");
+		// The pattern is a regex, not a prefix: this one needs "hello" at the start.
+		assert_eq!(text(&engine, "a", "well hello").await, "anything to a");
+		assert_eq!(text(&engine, "b", "well hello").await, "hello to anyone");
+		assert_eq!(text(&engine, "b", "bye").await, "fallback");
+
+		let engine = ScriptedEngine::new()
+			.on_prompt("hello", Script::from_text("prompt rule"))
+			.on_model("a", Script::from_text("model rule"));
+		assert_eq!(text(&engine, "a", "hello").await, "prompt rule");
+	}
+
+	#[tokio::test]
+	async fn requests_no_rule_matches_fail() {
+		let engine = ScriptedEngine::new().on_model("a", Script::from_text("hi"));
+		let events = run(&engine, "b", "hello", GenOptions::default()).await;
+		assert!(matches!(events.as_slice(), [Err(EngineError::GenerationFailed(_))]));
+	}
+
+	#[tokio::test]
+	async fn scripts_finish_as_configured_or_at_max_tokens() {
+		let engine = ScriptedEngine::new().otherwise(Script::new(["a", "b", "c"]).with_finish(FinishReason::Length));
+		let events = run(&engine, "m", "", GenOptions::default()).await;
+		let finish: Vec<_> = events.iter().map(|e| e.as_ref().unwrap().finish_reason).collect();
+		assert_eq!(finish, vec![None, None, None, Some(FinishReason::Length)]);
+
+		let engine = ScriptedEngine::new().otherwise(Script::new(["a", "b", "c"]));
+		let opts = GenOptions {
+			max_tokens: 2,
+			..Default::default()
+		};
+		let events = run(&engine, "m", "", opts).await;
+		assert_eq!(events.len(), 3);
+		assert_eq!(events[2].as_ref().unwrap().finish_reason, Some(FinishReason::Length));
+	}
+
+	#[tokio::test]
+	async fn fail_after_fires_mid_script_or_at_its_end() {
+		let error = EngineError::GenerationFailed("boom".into());
+		for (after, sent) in [(1, 1), (2, 2), (10, 2)] {
+			let engine = ScriptedEngine::new().otherwise(Script::new(["a", "b"]).fail_after(after, error.clone()));
+			let events = run(&engine, "m", "", GenOptions::default()).await;
+			assert_eq!(events.len(), sent + 1, "fail_after({})", after);
+			assert!(events[..sent].iter().all(|e| e.is_ok()));
+			assert!(matches!(events[sent], Err(EngineError::GenerationFailed(_))));
+		}
+	}
+
+	#[tokio::test]
+	async fn cancelling_cuts_a_token_wait_short() {
+		let engine = ScriptedEngine::new().otherwise(Script::new(["slow"]).with_latency(Duration::from_secs(60)));
+		let opts = GenOptions::default();
+		let cancel = opts.cancel.clone();
+		tokio::spawn(async move {
+			tokio::time::sleep(Duration::from_millis(20)).await;
+			cancel.cancel();
+		});
+		let started = Instant::now();
+		let events = run(&engine, "m", "", opts).await;
+		assert!(started.elapsed() < Duration::from_secs(5));
+		assert_eq!(events.len(), 1);
+		assert_eq!(events[0].as_ref().unwrap().finish_reason, Some(FinishReason::Cancelled));
+	}
+
+	#[tokio::test]
+	async fn failed_loads_are_recorded_too() {
+		let engine = ScriptedEngine::new().fail_load("broken", EngineError::LoadFailed("bad file".into()));
+		assert!(matches!(engine.load(&spec("broken")).await, Err(EngineError::LoadFailed(_))));
+		assert!(engine.load(&spec("fine")).await.is_ok());
+		assert_eq!(engine.loads(), vec!["broken", "fine"]);
+	}
+
+	#[tokio::test]
+	async fn calls_record_the_model_prompt_and_options() {
+		let engine = ScriptedEngine::new().otherwise(Script::from_text("ok"));
+		let opts = GenOptions {
+			max_tokens: 7,
+			temperature: 0.25,
+			stop_tokens: vec!["END".into()],
+			..Default::default()
+		};
+		run(&engine, "m", "first", opts).await;
+		run(&engine, "n", "second", GenOptions::default()).await;
+
+		let calls = engine.calls();
+		assert_eq!(calls.len(), 2);
+		assert_eq!((calls[0].model.as_str(), calls[0].prompt.as_str()), ("m", "first"));
+		assert_eq!((calls[0].opts.max_tokens, calls[0].opts.temperature), (7, 0.25));
+		assert_eq!(calls[0].opts.stop_tokens, vec!["END"]);
+		assert_eq!((calls[1].model.as_str(), calls[1].prompt.as_str()), ("n", "second"));
+	}
+}
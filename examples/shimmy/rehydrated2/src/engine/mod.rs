@@ -1,6 +1,21 @@
+use std::{
+	path::{Path, PathBuf},
+	sync::Arc,
+	task::Poll,
+};
+
 use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt};
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use tokio_util::sync::CancellationToken;
 
-use crate::model_registry::ModelSpec;
+use crate::{
+	engine::{
+		batch::BatchDecoder, grammar::Grammar, memory::MemoryPlan, prefix_cache::PrefixCacheStats, sampling::Mirostat,
+		tokenizer::Tokenizer,
+	},
+	model_registry::ModelSpec,
+};
 
 #[derive(Debug, Clone)]
 pub struct GenOptions {
@@ -8,10 +23,31 @@ pub struct GenOptions {
 	pub temperature: f32,
 	pub top_p: f32,
 	pub top_k: i32,
+	pub min_p: f32,
+	pub typical_p: f32,
 	pub repeat_penalty: f32,
+	/// How many recent tokens the repeat, frequency and presence penalties look at.
+	pub repeat_last_n: usize,
+	pub frequency_penalty: f32,
+	pub presence_penalty: f32,
+	/// When set, replaces top-k/top-p/min-p/typical-p with mirostat v2.
+	pub mirostat: Option<Mirostat>,
 	pub seed: Option<u64>,
 	pub stream: bool,
 	pub stop_tokens: Vec<String>,
+	/// Lets the backend reuse cached state for a matching prompt prefix and
+	/// keep this request's state for later ones.
+	pub cache_prompt: bool,
+	/// Restricts output to text the grammar accepts.
+	pub grammar: Option<Arc<Grammar>>,
+	/// Reports each sampled token's log-probability along with this many of
+	/// the most likely alternatives.
+	pub logprobs: Option<usize>,
+	/// LoRA adapters to decode with, by name and blend weight; `None` uses
+	/// the model's default adapter, if it has one.
+	pub adapters: Option<Vec<(String, f32)>>,
+	/// Tripped by the caller to abandon generation; backends check it between tokens.
+	pub cancel: CancellationToken,
 }
 
 impl Default for GenOptions {
@@ -21,12 +57,244 @@ impl Default for GenOptions {
 			temperature: 0.7,
 			top_p: 0.9,
 			top_k: 40,
+			min_p: 0.05,
+			typical_p: 1.0,
 			repeat_penalty: 1.1,
+			repeat_last_n: 64,
+			frequency_penalty: 0.0,
+			presence_penalty: 0.0,
+			mirostat: None,
 			seed: None,
 			stream: false,
 			stop_tokens: vec![],
+			cache_prompt: true,
+			grammar: None,
+			logprobs: None,
+			adapters: None,
+			cancel: CancellationToken::new(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+	Stop,
+	Length,
+	Cancelled,
+}
+
+impl FinishReason {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			FinishReason::Stop => "stop",
+			FinishReason::Length => "length",
+			FinishReason::Cancelled => "cancelled",
+		}
+	}
+}
+
+/// A token's log-probability under the model's raw distribution, before
+/// penalties, temperature or truncation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenLogprob {
+	pub token: String,
+	pub logprob: f32,
+	pub bytes: Vec<u8>,
+}
+
+/// The sampled token's [`TokenLogprob`] and the most likely alternatives at
+/// its position, best first.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenLogprobs {
+	#[serde(flatten)]
+	pub sampled: TokenLogprob,
+	pub top_logprobs: Vec<TokenLogprob>,
+}
+
+/// One decoded piece of a completion. The last event of a stream carries
+/// `finish_reason`; its `text` may be empty.
+#[derive(Debug, Clone)]
+pub struct TokenEvent {
+	pub text: String,
+	pub token_id: u32,
+	/// Set when `GenOptions::logprobs` asked for it and the backend supports it.
+	pub logprobs: Option<TokenLogprobs>,
+	pub finish_reason: Option<FinishReason>,
+	/// On the final event, the stop sequence that ended generation.
+	pub stop_sequence: Option<String>,
+	/// Running draft-model totals for this sequence, from backends decoding
+	/// speculatively.
+	pub speculative: Option<SpeculativeStats>,
+}
+
+impl TokenEvent {
+	pub fn piece(text: impl Into<String>, token_id: u32) -> Self {
+		Self {
+			text: text.into(),
+			token_id,
+			logprobs: None,
+			finish_reason: None,
+			stop_sequence: None,
+			speculative: None,
+		}
+	}
+
+	pub fn finish(reason: FinishReason) -> Self {
+		Self {
+			text: String::new(),
+			token_id: 0,
+			logprobs: None,
+			finish_reason: Some(reason),
+			stop_sequence: None,
+			speculative: None,
+		}
+	}
+}
+
+pub type TokenStream<'a> = BoxStream<'a, Result<TokenEvent>>;
+
+/// How per-token hidden states are reduced to one embedding vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pooling {
+	Mean,
+	/// The first token's state, BOS for llama-style vocabularies.
+	Cls,
+	Last,
+}
+
+impl Pooling {
+	/// Reads llama.cpp's `<arch>.pooling_type` metadata value.
+	pub fn from_gguf(value: u32) -> Option<Self> {
+		match value {
+			1 => Some(Pooling::Mean),
+			2 => Some(Pooling::Cls),
+			3 => Some(Pooling::Last),
+			_ => None,
+		}
+	}
+
+	/// Pools `hidden`, one state per input token, optionally scaling the
+	/// result to unit length.
+	pub fn apply(self, hidden: &[Vec<f32>], normalize: bool) -> Vec<f32> {
+		let mut out = match self {
+			Pooling::Mean => {
+				let mut sum = vec![0.0f32; hidden.first().map_or(0, Vec::len)];
+				for h in hidden {
+					for (s, x) in sum.iter_mut().zip(h) {
+						*s += x;
+					}
+				}
+				let n = hidden.len().max(1) as f32;
+				sum.iter().map(|s| s / n).collect()
+			}
+			Pooling::Cls => hidden.first().cloned().unwrap_or_default(),
+			Pooling::Last => hidden.last().cloned().unwrap_or_default(),
+		};
+		if normalize {
+			let norm = out.iter().map(|x| x * x).sum::<f32>().sqrt();
+			if norm > 0.0 {
+				out.iter_mut().for_each(|x| *x /= norm);
+			}
+		}
+		out
+	}
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EmbedOptions {
+	/// `None` uses the pooling the model declares, or mean pooling.
+	pub pooling: Option<Pooling>,
+	pub normalize: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Embedding {
+	pub vector: Vec<f32>,
+	/// Input length in tokens, BOS included.
+	pub tokens: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Completion {
+	pub text: String,
+	pub completion_tokens: usize,
+	pub finish_reason: FinishReason,
+	pub stop_sequence: Option<String>,
+	/// One entry per generated token, when logprobs were requested.
+	pub logprobs: Vec<TokenLogprobs>,
+	pub speculative: Option<SpeculativeStats>,
+}
+
+/// How many tokens a draft model proposed and how many of those the target
+/// model went on to produce itself.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpeculativeStats {
+	pub drafted: u64,
+	pub accepted: u64,
+}
+
+impl SpeculativeStats {
+	pub fn merge(&mut self, other: &SpeculativeStats) {
+		self.drafted += other.drafted;
+		self.accepted += other.accepted;
+	}
+
+	pub fn acceptance_rate(&self) -> Option<f64> {
+		(self.drafted > 0).then(|| self.accepted as f64 / self.drafted as f64)
+	}
+}
+
+impl Serialize for SpeculativeStats {
+	fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+		let mut s = serializer.serialize_struct("SpeculativeStats", 3)?;
+		s.serialize_field("drafted", &self.drafted)?;
+		s.serialize_field("accepted", &self.accepted)?;
+		s.serialize_field("acceptance_rate", &self.acceptance_rate())?;
+		s.end()
+	}
+}
+
+/// A LoRA adapter resident on a loaded model.
+#[derive(Debug, Clone, Serialize)]
+pub struct AdapterInfo {
+	pub name: String,
+	pub path: PathBuf,
+	pub rank: usize,
+	pub alpha: f32,
+	/// Applied when a request names no adapters.
+	pub default: bool,
+}
+
+/// Drains a token stream into a [`Completion`].
+pub async fn collect_completion(mut stream: TokenStream<'_>) -> Result<Completion> {
+	let mut completion = Completion {
+		text: String::new(),
+		completion_tokens: 0,
+		finish_reason: FinishReason::Stop,
+		stop_sequence: None,
+		logprobs: vec![],
+		speculative: None,
+	};
+	while let Some(event) = stream.next().await {
+		let event = event?;
+		completion.text.push_str(&event.text);
+		completion.logprobs.extend(event.logprobs);
+		// Running totals; a synthesized final event may not carry them.
+		if event.speculative.is_some() {
+			completion.speculative = event.speculative;
+		}
+		match event.finish_reason {
+			Some(reason) => {
+				completion.finish_reason = reason;
+				completion.stop_sequence = event.stop_sequence;
+			}
+			// One event per sampled token, even when it decodes to no text yet.
+			None => completion.completion_tokens += 1,
 		}
 	}
+	Ok(completion)
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -38,14 +306,19 @@ pub enum ModelBackend {
 	SafeTensors,
 }
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, Clone, thiserror::Error, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "message", rename_all = "snake_case")]
 pub enum EngineError {
 	#[error("model not found: {0}")]
 	ModelNotFound(String),
 	#[error("model load failed: {0}")]
 	LoadFailed(String),
-	#[error("out of memory")]
-	OutOfMemory,
+	#[error(
+		"out of memory: needs {}, {} available",
+		memory::format_bytes(*.required_bytes),
+		memory::format_bytes(*.available_bytes)
+	)]
+	OutOfMemory { required_bytes: u64, available_bytes: u64 },
 	#[error("generation failed: {0}")]
 	GenerationFailed(String),
 }
@@ -55,17 +328,174 @@ pub type Result<T> = std::result::Result<T, EngineError>;
 #[async_trait]
 pub trait InferenceEngine: Send + Sync {
 	async fn load(&self, spec: &ModelSpec) -> Result<Box<dyn LoadedModel>>;
+
+	/// What loading `spec` would take against the memory free right now,
+	/// for backends that can size a model before loading it.
+	fn plan_memory(&self, _spec: &ModelSpec) -> Option<MemoryPlan> {
+		None
+	}
 }
 
 #[async_trait]
 pub trait LoadedModel: Send + Sync {
+	/// Streams the completion for `prompt`. Load-time style failures surface as
+	/// the first item of the stream.
+	fn generate_stream<'a>(&'a self, prompt: &str, opts: GenOptions) -> TokenStream<'a>;
+
+	/// [`LoadedModel::generate_stream`] with `opts.stop_tokens` enforced across
+	/// token boundaries. Servers stream from this, not the raw backend stream.
+	fn generate_until_stop<'a>(&'a self, prompt: &str, opts: GenOptions) -> TokenStream<'a> {
+		let stops = opts.stop_tokens.clone();
+		stop::apply_stops(self.generate_stream(prompt, opts), stops)
+	}
+
+	/// The vocabulary the model decodes with, when the backend exposes one.
+	fn tokenizer(&self) -> Option<&dyn Tokenizer> {
+		None
+	}
+
+	/// A decoder that runs this model's sequences in a shared batch, for
+	/// backends that support it.
+	fn batch_decoder(&self) -> Option<Box<dyn BatchDecoder>> {
+		None
+	}
+
+	/// Whether the backend honors `GenOptions::grammar`; callers should
+	/// refuse grammar requests for models where it does not.
+	fn supports_grammar(&self) -> bool {
+		false
+	}
+
+	/// Whether the backend fills `TokenEvent::logprobs` when asked.
+	fn supports_logprobs(&self) -> bool {
+		false
+	}
+
+	/// Whether the backend can load LoRA adapters and honor `GenOptions::adapters`.
+	fn supports_adapters(&self) -> bool {
+		false
+	}
+
+	fn adapters(&self) -> Vec<AdapterInfo> {
+		vec![]
+	}
+
+	/// Loads an adapter next to the resident base weights, replacing any
+	/// adapter of the same name.
+	fn load_adapter(&self, name: &str, _path: &Path) -> Result<AdapterInfo> {
+		Err(EngineError::LoadFailed(format!("adapter {}: this backend does not support LoRA adapters", name)))
+	}
+
+	/// Returns whether an adapter of that name was loaded.
+	fn unload_adapter(&self, _name: &str) -> bool {
+		false
+	}
+
+	/// Draft acceptance totals for models decoding speculatively.
+	fn speculative_stats(&self) -> Option<SpeculativeStats> {
+		None
+	}
+
+	/// Hit/miss counters for backends that reuse cached prompt prefixes.
+	fn prefix_cache_stats(&self) -> Option<PrefixCacheStats> {
+		None
+	}
+
+	/// Tokens the model attends over, prompt and completion together, when
+	/// the backend knows it.
+	fn context_length(&self) -> Option<usize> {
+		None
+	}
+
+	/// The memory plan the model was admitted under.
+	fn memory_plan(&self) -> Option<MemoryPlan> {
+		None
+	}
+
+	/// Whether the backend implements [`LoadedModel::embed`].
+	fn supports_embeddings(&self) -> bool {
+		false
+	}
+
+	/// Pools the model's final hidden states over each input, returning one
+	/// embedding per input in order.
+	async fn embed(&self, _inputs: &[String], _opts: EmbedOptions) -> Result<Vec<Embedding>> {
+		Err(EngineError::GenerationFailed("this backend does not compute embeddings".into()))
+	}
+
+	/// Exact prompt length as the model will see it, BOS included.
+	fn count_prompt_tokens(&self, prompt: &str) -> Option<usize> {
+		self.tokenizer().map(|t| t.encode(prompt, true).len())
+	}
+
+	/// Callback adapter over [`LoadedModel::generate_stream`]; returns the full completion.
 	async fn generate(
 		&self,
 		prompt: &str,
 		opts: GenOptions,
 		on_token: Option<Box<dyn Fn(String) + Send>>,
-	) -> Result<String>;
+	) -> Result<String> {
+		let mut stream = self.generate_until_stop(prompt, opts);
+		let mut completion = String::new();
+		while let Some(event) = stream.next().await {
+			let event = event?;
+			if event.text.is_empty() {
+				continue;
+			}
+			if let Some(cb) = &on_token {
+				cb(event.text.clone());
+			}
+			completion.push_str(&event.text);
+		}
+		Ok(completion)
+	}
+}
+
+/// Turns a finished completion into a token stream, one whitespace-delimited
+/// piece per event. Used by backends that do not decode incrementally.
+pub fn stream_from_text<'a>(text: &str, opts: &GenOptions) -> TokenStream<'a> {
+	let pieces: Vec<String> = text.split_inclusive(char::is_whitespace).map(str::to_string).collect();
+	let reason = if pieces.len() > opts.max_tokens {
+		FinishReason::Length
+	} else {
+		FinishReason::Stop
+	};
+	let cancel = opts.cancel.clone();
+	let mut pieces = pieces.into_iter().take(opts.max_tokens).enumerate();
+	let mut done = false;
+
+	futures::stream::poll_fn(move |_| {
+		if done {
+			return Poll::Ready(None);
+		}
+		if cancel.is_cancelled() {
+			done = true;
+			return Poll::Ready(Some(Ok(TokenEvent::finish(FinishReason::Cancelled))));
+		}
+		let event = match pieces.next() {
+			Some((i, piece)) => TokenEvent::piece(piece, i as u32),
+			None => {
+				done = true;
+				TokenEvent::finish(reason)
+			}
+		};
+		Poll::Ready(Some(Ok(event)))
+	})
+	.boxed()
 }
 
 pub mod adapter;
+pub mod batch;
+pub mod cassette;
+#[cfg(feature = "cpu")]
+pub mod cpu;
+pub mod gguf;
+pub mod gpu;
+pub mod grammar;
 pub mod llama;
+pub mod memory;
+pub mod mock;
+pub mod prefix_cache;
+pub mod sampling;
+pub mod stop;
+pub mod tokenizer;
@@ -0,0 +1,125 @@
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PrefixCacheStats {
+	/// Lookups that reused at least one token.
+	pub hits: u64,
+	pub misses: u64,
+	/// Requests that opted out with `cache_prompt: false`.
+	pub skipped: u64,
+	/// Prompt tokens that did not need prefilling thanks to a hit.
+	pub reused_tokens: u64,
+	pub entries: usize,
+	pub bytes: usize,
+}
+
+impl PrefixCacheStats {
+	pub fn merge(&mut self, other: &PrefixCacheStats) {
+		self.hits += other.hits;
+		self.misses += other.misses;
+		self.skipped += other.skipped;
+		self.reused_tokens += other.reused_tokens;
+		self.entries += other.entries;
+		self.bytes += other.bytes;
+	}
+}
+
+struct Entry<S> {
+	scope: String,
+	tokens: Vec<u32>,
+	state: S,
+	bytes: usize,
+	last_used: u64,
+}
+
+/// Backend state (typically a KV cache) for recently decoded token
+/// sequences, looked up by longest common prefix. Chat requests re-send the
+/// whole conversation, so the previous turn's state usually covers most of
+/// the next prompt. Entries only serve lookups in the scope they were stored
+/// under (e.g. the adapters the state was computed with). Least recently
+/// used entries go first once `max_bytes` is exceeded.
+pub struct PrefixCache<S> {
+	max_bytes: usize,
+	entries: Vec<Entry<S>>,
+	clock: u64,
+	stats: PrefixCacheStats,
+}
+
+impl<S: Clone> PrefixCache<S> {
+	pub fn new(max_bytes: usize) -> Self {
+		Self {
+			max_bytes,
+			entries: vec![],
+			clock: 0,
+			stats: PrefixCacheStats::default(),
+		}
+	}
+
+	/// Finds the entry sharing the longest prefix with `tokens`, capped at
+	/// `limit` tokens, and returns that length with a copy of its state. The
+	/// state still covers the entry's full sequence; callers trim it.
+	pub fn lookup(&mut self, scope: &str, tokens: &[u32], limit: usize) -> Option<(usize, S)> {
+		self.clock += 1;
+		let best = self
+			.entries
+			.iter_mut()
+			.filter(|e| e.scope == scope)
+			.map(|e| {
+				let shared = e.tokens.iter().zip(tokens).take_while(|(a, b)| a == b).count();
+				(shared.min(limit), e)
+			})
+			.filter(|(shared, _)| *shared > 0)
+			.max_by_key(|(shared, e)| (*shared, e.last_used));
+		match best {
+			Some((shared, entry)) => {
+				entry.last_used = self.clock;
+				self.stats.hits += 1;
+				self.stats.reused_tokens += shared as u64;
+				Some((shared, entry.state.clone()))
+			}
+			None => {
+				self.stats.misses += 1;
+				None
+			}
+		}
+	}
+
+	/// Stores the state reached after decoding `tokens`. Entries this one
+	/// extends are dropped, since every lookup they could serve it serves too.
+	pub fn insert(&mut self, scope: String, tokens: Vec<u32>, state: S, bytes: usize) {
+		if tokens.is_empty() || bytes > self.max_bytes {
+			return;
+		}
+		self.clock += 1;
+		self.entries.retain(|e| e.scope != scope || !tokens.starts_with(&e.tokens));
+		while !self.entries.is_empty() && self.bytes() + bytes > self.max_bytes {
+			let lru = self.entries.iter().enumerate().min_by_key(|(_, e)| e.last_used).map(|(i, _)| i);
+			if let Some(i) = lru {
+				self.entries.swap_remove(i);
+			}
+		}
+		self.entries.push(Entry {
+			scope,
+			tokens,
+			state,
+			bytes,
+			last_used: self.clock,
+		});
+	}
+
+	pub fn record_skip(&mut self) {
+		self.stats.skipped += 1;
+	}
+
+	pub fn stats(&self) -> PrefixCacheStats {
+		PrefixCacheStats {
+			entries: self.entries.len(),
+			bytes: self.bytes(),
+			..self.stats
+		}
+	}
+
+	fn bytes(&self) -> usize {
+		self.entries.iter().map(|e| e.bytes).sum()
+	}
+}
@@ -0,0 +1,620 @@
+use std::{
+	collections::{hash_map::RandomState, HashMap, HashSet},
+	hash::{BuildHasher, Hasher},
+};
+
+use crate::engine::GenOptions;
+
+/// Mirostat v2 targets a fixed surprise (`tau`, in bits) per token, adapting
+/// its truncation threshold at learning rate `eta`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mirostat {
+	pub tau: f32,
+	pub eta: f32,
+}
+
+impl Default for Mirostat {
+	fn default() -> Self {
+		Self { tau: 5.0, eta: 0.1 }
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+	pub id: u32,
+	pub logit: f32,
+	/// Only meaningful after `Candidates::softmax`.
+	pub p: f32,
+}
+
+/// The working set a sampler chain narrows down, one entry per surviving token.
+#[derive(Debug, Clone)]
+pub struct Candidates {
+	pub items: Vec<Candidate>,
+	sorted: bool,
+}
+
+impl Candidates {
+	pub fn from_logits(logits: &[f32]) -> Self {
+		Self {
+			items: logits
+				.iter()
+				.enumerate()
+				.map(|(i, l)| Candidate {
+					id: i as u32,
+					logit: *l,
+					p: 0.0,
+				})
+				.collect(),
+			sorted: false,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.items.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.items.is_empty()
+	}
+
+	/// Orders by descending logit; ties keep the lower token id first.
+	pub fn sort(&mut self) {
+		if !self.sorted {
+			self.items.sort_by(|a, b| b.logit.total_cmp(&a.logit).then(a.id.cmp(&b.id)));
+			self.sorted = true;
+		}
+	}
+
+	/// Sorts and fills `p` with the normalized distribution over the current set.
+	pub fn softmax(&mut self) {
+		self.sort();
+		let Some(max) = self.items.first().map(|c| c.logit) else {
+			return;
+		};
+		let mut sum = 0.0;
+		for c in &mut self.items {
+			c.p = (c.logit - max).exp();
+			sum += c.p;
+		}
+		for c in &mut self.items {
+			c.p /= sum;
+		}
+	}
+
+	pub fn truncate(&mut self, len: usize) {
+		self.items.truncate(len.max(1));
+	}
+
+	fn mark_unsorted(&mut self) {
+		self.sorted = false;
+	}
+}
+
+/// Log-softmax of `logits` at `token`, plus the `top` most likely tokens
+/// with theirs, best first.
+pub fn logprobs(logits: &[f32], token: u32, top: usize) -> (f32, Vec<(u32, f32)>) {
+	let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+	let log_sum = max + logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln();
+	let by_logit = |a: &u32, b: &u32| logits[*b as usize].total_cmp(&logits[*a as usize]).then(a.cmp(b));
+	let mut best: Vec<u32> = (0..logits.len() as u32).collect();
+	let top = top.min(best.len());
+	if top > 0 && top < best.len() {
+		best.select_nth_unstable_by(top - 1, by_logit);
+	}
+	best.truncate(top);
+	best.sort_by(by_logit);
+	let sampled = logits.get(token as usize).map_or(f32::NEG_INFINITY, |l| l - log_sum);
+	(sampled, best.into_iter().map(|id| (id, logits[id as usize] - log_sum)).collect())
+}
+
+/// One stage of the chain. Samplers may rewrite logits, drop candidates or
+/// reorder them, but must leave at least one candidate.
+pub trait Sampler: Send {
+	fn name(&self) -> &'static str;
+
+	/// `history` holds every token accepted so far, prompt included; its
+	/// last `generated` tokens are the ones this chain sampled.
+	fn apply(&mut self, candidates: &mut Candidates, history: &[u32], generated: usize);
+}
+
+/// Repetition penalty (llama.cpp style: divide positive logits, multiply
+/// negative ones) over the last `last_n` tokens, plus OpenAI-style frequency
+/// and presence penalties over the completion tokens among them.
+#[derive(Debug, Clone)]
+pub struct Penalties {
+	pub last_n: usize,
+	pub repeat: f32,
+	pub frequency: f32,
+	pub presence: f32,
+}
+
+impl Penalties {
+	pub fn is_noop(&self) -> bool {
+		self.last_n == 0 || (self.repeat == 1.0 && self.frequency == 0.0 && self.presence == 0.0)
+	}
+}
+
+impl Sampler for Penalties {
+	fn name(&self) -> &'static str {
+		"penalties"
+	}
+
+	fn apply(&mut self, candidates: &mut Candidates, history: &[u32], generated: usize) {
+		if self.is_noop() {
+			return;
+		}
+		let start = history.len().saturating_sub(self.last_n);
+		let completion_start = history.len().saturating_sub(generated).max(start);
+		let seen: HashSet<u32> = history[start..].iter().copied().collect();
+		let mut counts = HashMap::<u32, usize>::new();
+		for t in &history[completion_start..] {
+			*counts.entry(*t).or_default() += 1;
+		}
+		for c in &mut candidates.items {
+			if !seen.contains(&c.id) {
+				continue;
+			}
+			if c.logit > 0.0 {
+				c.logit /= self.repeat;
+			} else {
+				c.logit *= self.repeat;
+			}
+			if let Some(&n) = counts.get(&c.id) {
+				c.logit -= n as f32 * self.frequency + self.presence;
+			}
+		}
+		candidates.mark_unsorted();
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct TopK(pub usize);
+
+impl Sampler for TopK {
+	fn name(&self) -> &'static str {
+		"top_k"
+	}
+
+	fn apply(&mut self, candidates: &mut Candidates, _history: &[u32], _generated: usize) {
+		if self.0 == 0 || self.0 >= candidates.len() {
+			return;
+		}
+		candidates.sort();
+		candidates.truncate(self.0);
+	}
+}
+
+/// Nucleus sampling: keeps the smallest prefix whose mass reaches `p`.
+#[derive(Debug, Clone)]
+pub struct TopP {
+	pub p: f32,
+	pub min_keep: usize,
+}
+
+impl Sampler for TopP {
+	fn name(&self) -> &'static str {
+		"top_p"
+	}
+
+	fn apply(&mut self, candidates: &mut Candidates, _history: &[u32], _generated: usize) {
+		if self.p >= 1.0 {
+			return;
+		}
+		candidates.softmax();
+		let mut cum = 0.0;
+		let mut keep = candidates.len();
+		for (i, c) in candidates.items.iter().enumerate() {
+			cum += c.p;
+			if cum >= self.p && i + 1 >= self.min_keep {
+				keep = i + 1;
+				break;
+			}
+		}
+		candidates.truncate(keep);
+	}
+}
+
+/// Drops candidates less likely than `p` times the most likely one.
+#[derive(Debug, Clone)]
+pub struct MinP {
+	pub p: f32,
+	pub min_keep: usize,
+}
+
+impl Sampler for MinP {
+	fn name(&self) -> &'static str {
+		"min_p"
+	}
+
+	fn apply(&mut self, candidates: &mut Candidates, _history: &[u32], _generated: usize) {
+		if self.p <= 0.0 || candidates.is_empty() {
+			return;
+		}
+		candidates.softmax();
+		let threshold = candidates.items[0].p * self.p;
+		let keep = candidates.items.iter().take_while(|c| c.p >= threshold).count();
+		candidates.truncate(keep.max(self.min_keep));
+	}
+}
+
+/// Locally typical sampling: keeps tokens whose surprise is closest to the
+/// distribution's entropy until their mass reaches `p`.
+#[derive(Debug, Clone)]
+pub struct TypicalP {
+	pub p: f32,
+	pub min_keep: usize,
+}
+
+impl Sampler for TypicalP {
+	fn name(&self) -> &'static str {
+		"typical_p"
+	}
+
+	fn apply(&mut self, candidates: &mut Candidates, _history: &[u32], _generated: usize) {
+		if self.p >= 1.0 {
+			return;
+		}
+		candidates.softmax();
+		let entropy: f32 = candidates
+			.items
+			.iter()
+			.filter(|c| c.p > 0.0)
+			.map(|c| -c.p * c.p.ln())
+			.sum();
+		let deviation = |c: &Candidate| (-c.p.ln() - entropy).abs();
+		candidates.items.sort_by(|a, b| deviation(a).total_cmp(&deviation(b)));
+
+		let mut cum = 0.0;
+		let mut keep = candidates.len();
+		for (i, c) in candidates.items.iter().enumerate() {
+			cum += c.p;
+			if cum > self.p && i + 1 >= self.min_keep {
+				keep = i + 1;
+				break;
+			}
+		}
+		candidates.truncate(keep);
+		candidates.mark_unsorted();
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Temperature(pub f32);
+
+impl Sampler for Temperature {
+	fn name(&self) -> &'static str {
+		"temperature"
+	}
+
+	fn apply(&mut self, candidates: &mut Candidates, _history: &[u32], _generated: usize) {
+		if self.0 <= 0.0 || self.0 == 1.0 {
+			return;
+		}
+		for c in &mut candidates.items {
+			c.logit /= self.0;
+		}
+	}
+}
+
+/// How the chain picks a token from whatever candidates survive it.
+#[derive(Debug, Clone)]
+pub enum Selector {
+	Greedy,
+	Random,
+	/// `mu` is the current surprise ceiling, starting at `2 * tau`.
+	MirostatV2 { config: Mirostat, mu: f32 },
+}
+
+/// SplitMix64: tiny, seedable and identical on every platform, which is all
+/// reproducible sampling needs.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+	pub fn new(seed: Option<u64>) -> Self {
+		Self(seed.unwrap_or_else(|| RandomState::new().build_hasher().finish()))
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		let mut z = self.0;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		z ^ (z >> 31)
+	}
+
+	/// Uniform in `[0, 1)`.
+	pub fn next_f32(&mut self) -> f32 {
+		(self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+	}
+}
+
+/// An ordered list of samplers followed by a selector. Backends call
+/// `accept` for prompt tokens and `sample` once per generated token.
+pub struct SamplerChain {
+	samplers: Vec<Box<dyn Sampler>>,
+	selector: Selector,
+	rng: Rng,
+	history: Vec<u32>,
+	generated: usize,
+}
+
+impl SamplerChain {
+	pub fn new(selector: Selector, seed: Option<u64>) -> Self {
+		Self {
+			samplers: vec![],
+			selector,
+			rng: Rng::new(seed),
+			history: vec![],
+			generated: 0,
+		}
+	}
+
+	pub fn greedy() -> Self {
+		Self::new(Selector::Greedy, None)
+	}
+
+	pub fn with(mut self, sampler: impl Sampler + 'static) -> Self {
+		self.samplers.push(Box::new(sampler));
+		self
+	}
+
+	/// Builds the chain llama.cpp uses by default: penalties, then top-k,
+	/// typical-p, top-p, min-p and temperature before a random draw. A
+	/// non-positive temperature means greedy; mirostat replaces the
+	/// truncation samplers entirely.
+	pub fn from_options(opts: &GenOptions) -> Self {
+		let min_keep = 1;
+		let penalties = Penalties {
+			last_n: opts.repeat_last_n,
+			repeat: opts.repeat_penalty,
+			frequency: opts.frequency_penalty,
+			presence: opts.presence_penalty,
+		};
+
+		if opts.temperature <= 0.0 {
+			return Self::new(Selector::Greedy, opts.seed).with(penalties);
+		}
+		if let Some(config) = opts.mirostat {
+			let selector = Selector::MirostatV2 {
+				config,
+				mu: 2.0 * config.tau,
+			};
+			return Self::new(selector, opts.seed)
+				.with(penalties)
+				.with(Temperature(opts.temperature));
+		}
+		Self::new(Selector::Random, opts.seed)
+			.with(penalties)
+			.with(TopK(opts.top_k.max(0) as usize))
+			.with(TypicalP {
+				p: opts.typical_p,
+				min_keep,
+			})
+			.with(TopP { p: opts.top_p, min_keep })
+			.with(MinP { p: opts.min_p, min_keep })
+			.with(Temperature(opts.temperature))
+	}
+
+	pub fn names(&self) -> Vec<&'static str> {
+		self.samplers.iter().map(|s| s.name()).collect()
+	}
+
+	/// Records a prompt token: one the model has seen without it being
+	/// sampled here.
+	pub fn accept(&mut self, token: u32) {
+		self.history.push(token);
+	}
+
+	pub fn history(&self) -> &[u32] {
+		&self.history
+	}
+
+	/// Runs the chain over `logits`, picks a token and accepts it.
+	pub fn sample(&mut self, logits: &[f32]) -> u32 {
+		let mut candidates = Candidates::from_logits(logits);
+		self.sample_candidates(&mut candidates)
+	}
+
+	pub fn sample_candidates(&mut self, candidates: &mut Candidates) -> u32 {
+		if candidates.is_empty() {
+			return 0;
+		}
+		for s in &mut self.samplers {
+			s.apply(candidates, &self.history, self.generated);
+		}
+		let token = match &mut self.selector {
+			Selector::Greedy => {
+				candidates.sort();
+				candidates.items[0].id
+			}
+			Selector::Random => draw(candidates, &mut self.rng).id,
+			Selector::MirostatV2 { config, mu } => {
+				candidates.softmax();
+				let keep = candidates.items.iter().take_while(|c| -c.p.log2() <= *mu).count();
+				candidates.truncate(keep);
+				let chosen = draw(candidates, &mut self.rng);
+				*mu -= config.eta * (-chosen.p.log2() - config.tau);
+				chosen.id
+			}
+		};
+		self.history.push(token);
+		self.generated += 1;
+		token
+	}
+}
+
+/// Samples from the renormalized candidate distribution.
+fn draw(candidates: &mut Candidates, rng: &mut Rng) -> Candidate {
+	candidates.softmax();
+	let r = rng.next_f32();
+	let mut cum = 0.0;
+	for c in &candidates.items {
+		cum += c.p;
+		if r < cum {
+			return *c;
+		}
+	}
+	candidates.items[candidates.len() - 1]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const LOGITS: [f32; 6] = [1.0, 3.0, 2.0, -1.0, 3.0, 0.5];
+
+	fn surviving(sampler: impl Sampler, logits: &[f32]) -> Vec<u32> {
+		let mut candidates = Candidates::from_logits(logits);
+		let mut sampler = sampler;
+		sampler.apply(&mut candidates, &[], 0);
+		candidates.sort();
+		candidates.items.iter().map(|c| c.id).collect()
+	}
+
+	fn penalized(penalties: Penalties, history: &[u32], generated: usize) -> Vec<f32> {
+		let mut candidates = Candidates::from_logits(&[2.0, -2.0, 2.0, 2.0]);
+		let mut penalties = penalties;
+		penalties.apply(&mut candidates, history, generated);
+		candidates.items.iter().map(|c| c.logit).collect()
+	}
+
+	#[test]
+	fn greedy_takes_the_highest_logit_and_breaks_ties_by_id() {
+		let mut chain = SamplerChain::greedy();
+		assert_eq!(chain.sample(&LOGITS), 1);
+		assert_eq!(chain.history(), &[1]);
+	}
+
+	#[test]
+	fn truncation_samplers_keep_the_expected_tokens() {
+		assert_eq!(surviving(TopK(3), &LOGITS), vec![1, 4, 2]);
+		assert_eq!(surviving(TopK(0), &LOGITS).len(), LOGITS.len());
+		// exp(3) / sum covers ~36% each for tokens 1 and 4; adding token 2 passes 0.8.
+		assert_eq!(surviving(TopP { p: 0.8, min_keep: 1 }, &LOGITS), vec![1, 4, 2]);
+		assert_eq!(surviving(TopP { p: 0.1, min_keep: 2 }, &LOGITS), vec![1, 4]);
+		// Token 2 is e^-1 ≈ 0.37 as likely as the best; token 0 is e^-2 ≈ 0.14.
+		assert_eq!(surviving(MinP { p: 0.3, min_keep: 1 }, &LOGITS), vec![1, 4, 2]);
+		assert_eq!(surviving(TypicalP { p: 1.0, min_keep: 1 }, &LOGITS).len(), LOGITS.len());
+	}
+
+	#[test]
+	fn temperature_scales_logits() {
+		let mut candidates = Candidates::from_logits(&[2.0, -1.0]);
+		Temperature(0.5).apply(&mut candidates, &[], 0);
+		assert_eq!(candidates.items.iter().map(|c| c.logit).collect::<Vec<_>>(), vec![4.0, -2.0]);
+	}
+
+	#[test]
+	fn repeat_penalty_covers_the_prompt_but_frequency_and_presence_do_not() {
+		let penalties = Penalties {
+			last_n: 64,
+			repeat: 2.0,
+			frequency: 0.5,
+			presence: 0.25,
+		};
+		// Token 0 appears twice in the prompt only, token 1 in the prompt and
+		// once in the completion, token 2 twice in the completion.
+		let history = [0, 0, 1, 1, 2, 2];
+		assert_eq!(penalized(penalties, &history, 3), vec![1.0, -4.0 - 0.75, 1.0 - 1.25, 2.0]);
+	}
+
+	#[test]
+	fn penalties_only_look_at_the_last_n_tokens() {
+		let penalties = Penalties {
+			last_n: 2,
+			repeat: 2.0,
+			frequency: 1.0,
+			presence: 0.0,
+		};
+		// Token 2 was sampled twice, but only once inside the window.
+		assert_eq!(penalized(penalties, &[2, 0, 2], 3), vec![0.0, -2.0, 0.0, 2.0]);
+		assert!(Penalties {
+			last_n: 0,
+			repeat: 2.0,
+			frequency: 1.0,
+			presence: 1.0,
+		}
+		.is_noop());
+	}
+
+	#[test]
+	fn a_chain_penalizes_what_it_sampled_but_not_prompt_tokens() {
+		let opts = GenOptions {
+			temperature: 0.0,
+			repeat_penalty: 1.0,
+			presence_penalty: 5.0,
+			..Default::default()
+		};
+		let mut chain = SamplerChain::from_options(&opts);
+		chain.accept(1);
+		assert_eq!(chain.sample(&LOGITS), 1, "prompt tokens carry no presence penalty");
+		assert_eq!(chain.sample(&LOGITS), 4, "the sampled token does");
+		assert_eq!(chain.sample(&LOGITS), 2);
+	}
+
+	#[test]
+	fn seeded_chains_are_reproducible() {
+		let opts = GenOptions {
+			temperature: 1.0,
+			top_k: 0,
+			top_p: 1.0,
+			min_p: 0.0,
+			repeat_penalty: 1.0,
+			seed: Some(42),
+			..Default::default()
+		};
+		let run = |opts: &GenOptions| {
+			let mut chain = SamplerChain::from_options(opts);
+			(0..32).map(|_| chain.sample(&LOGITS)).collect::<Vec<_>>()
+		};
+		let first = run(&opts);
+		assert_eq!(first, run(&opts));
+		assert!(first.iter().collect::<HashSet<_>>().len() > 2, "a flat enough distribution varies: {:?}", first);
+		assert_ne!(first, run(&GenOptions { seed: Some(43), ..opts.clone() }));
+	}
+
+	#[test]
+	fn from_options_builds_the_default_chain() {
+		let random = SamplerChain::from_options(&GenOptions::default());
+		assert_eq!(random.names(), vec!["penalties", "top_k", "typical_p", "top_p", "min_p", "temperature"]);
+		let greedy = SamplerChain::from_options(&GenOptions {
+			temperature: 0.0,
+			..Default::default()
+		});
+		assert_eq!(greedy.names(), vec!["penalties"]);
+		let mirostat = SamplerChain::from_options(&GenOptions {
+			mirostat: Some(Mirostat::default()),
+			..Default::default()
+		});
+		assert_eq!(mirostat.names(), vec!["penalties", "temperature"]);
+	}
+
+	#[test]
+	fn mirostat_adapts_its_ceiling() {
+		let mut chain = SamplerChain::new(
+			Selector::MirostatV2 {
+				config: Mirostat { tau: 2.0, eta: 0.5 },
+				mu: 2.0,
+			},
+			Some(7),
+		);
+		let token = chain.sample(&LOGITS);
+		assert!(matches!(token, 1 | 4), "only tokens within 2 bits of surprise survive");
+		// The two survivors are equally likely, so the pick cost 1 bit, under tau.
+		let Selector::MirostatV2 { mu, .. } = chain.selector else {
+			unreachable!()
+		};
+		assert!((mu - 2.5).abs() < 1e-5, "{}", mu);
+	}
+
+	#[test]
+	fn logprobs_are_normalized_and_ranked() {
+		let (sampled, top) = logprobs(&LOGITS, 2, 3);
+		let log_sum = LOGITS.iter().map(|l| l.exp()).sum::<f32>().ln();
+		assert!((sampled - (2.0 - log_sum)).abs() < 1e-6);
+		assert_eq!(top.iter().map(|t| t.0).collect::<Vec<_>>(), vec![1, 4, 2]);
+		assert_eq!(logprobs(&LOGITS, 99, 0), (f32::NEG_INFINITY, vec![]));
+	}
+}
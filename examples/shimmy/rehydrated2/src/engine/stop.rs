@@ -0,0 +1,210 @@
+use futures::{stream, StreamExt};
+
+use crate::engine::{FinishReason, TokenEvent, TokenStream};
+
+/// Result of feeding one piece of text to a [`StopMatcher`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopScan {
+	/// Text that can no longer be part of a stop sequence and is safe to emit.
+	Continue(String),
+	/// A stop sequence completed; `text` is what preceded it.
+	Stopped { text: String, stop: String },
+}
+
+/// Incremental stop-sequence detection over streamed text. Anything that
+/// might still grow into a stop sequence is held back until it either
+/// completes one (and is dropped) or diverges (and is released).
+#[derive(Debug, Clone)]
+pub struct StopMatcher {
+	stops: Vec<String>,
+	held: String,
+}
+
+impl StopMatcher {
+	pub fn new(stops: impl IntoIterator<Item = String>) -> Self {
+		Self {
+			stops: stops.into_iter().filter(|s| !s.is_empty()).collect(),
+			held: String::new(),
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.stops.is_empty()
+	}
+
+	pub fn push(&mut self, text: &str) -> StopScan {
+		self.held.push_str(text);
+
+		// Earliest match wins; at the same position prefer the longer stop.
+		let found = self
+			.stops
+			.iter()
+			.filter_map(|s| self.held.find(s.as_str()).map(|at| (at, s)))
+			.min_by(|a, b| a.0.cmp(&b.0).then(b.1.len().cmp(&a.1.len())));
+		if let Some((at, stop)) = found {
+			let stop = stop.clone();
+			let mut text = std::mem::take(&mut self.held);
+			text.truncate(at);
+			return StopScan::Stopped { text, stop };
+		}
+
+		let keep = self.partial_suffix_len();
+		let text = self.held[..self.held.len() - keep].to_string();
+		self.held.drain(..self.held.len() - keep);
+		StopScan::Continue(text)
+	}
+
+	/// Releases held-back text once the stream ends without a match.
+	pub fn flush(&mut self) -> String {
+		std::mem::take(&mut self.held)
+	}
+
+	/// Length of the longest suffix of `held` that is a proper prefix of some stop.
+	fn partial_suffix_len(&self) -> usize {
+		self.held
+			.char_indices()
+			.map(|(i, _)| &self.held[i..])
+			.find(|suffix| self.stops.iter().any(|s| s.len() > suffix.len() && s.starts_with(suffix)))
+			.map(str::len)
+			.unwrap_or(0)
+	}
+}
+
+enum State<'a> {
+	Running(TokenStream<'a>, StopMatcher),
+	Finishing(TokenEvent),
+	Done,
+}
+
+/// Wraps a backend stream so `stops` are enforced across token boundaries.
+/// A completed stop sequence ends the stream with [`FinishReason::Stop`] and
+/// `stop_sequence` set; the inner stream is dropped, which backends treat as
+/// the consumer going away.
+pub fn apply_stops(inner: TokenStream<'_>, stops: Vec<String>) -> TokenStream<'_> {
+	let matcher = StopMatcher::new(stops);
+	if matcher.is_empty() {
+		return inner;
+	}
+
+	stream::unfold(State::Running(inner, matcher), |state| async move {
+		let (mut inner, mut matcher) = match state {
+			State::Running(inner, matcher) => (inner, matcher),
+			State::Finishing(event) => return Some((Ok(event), State::Done)),
+			State::Done => return None,
+		};
+		match inner.next().await {
+			Some(Ok(mut event)) if event.finish_reason.is_some() => {
+				event.text = matcher.flush() + &event.text;
+				Some((Ok(event), State::Done))
+			}
+			Some(Ok(mut event)) => match matcher.push(&event.text) {
+				StopScan::Continue(text) => {
+					event.text = text;
+					Some((Ok(event), State::Running(inner, matcher)))
+				}
+				StopScan::Stopped { text, stop } => {
+					event.text = text;
+					let mut finish = TokenEvent::finish(FinishReason::Stop);
+					finish.stop_sequence = Some(stop);
+					Some((Ok(event), State::Finishing(finish)))
+				}
+			},
+			Some(Err(e)) => Some((Err(e), State::Done)),
+			// Inner stream ended without a finish event; release what was held.
+			None => {
+				let mut finish = TokenEvent::finish(FinishReason::Stop);
+				finish.text = matcher.flush();
+				Some((Ok(finish), State::Done))
+			}
+		}
+	})
+	.boxed()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn matcher(stops: &[&str]) -> StopMatcher {
+		StopMatcher::new(stops.iter().map(|s| s.to_string()))
+	}
+
+	fn pieces(texts: &[&str], last: FinishReason) -> TokenStream<'static> {
+		let mut events: Vec<_> = texts.iter().enumerate().map(|(i, t)| Ok(TokenEvent::piece(*t, i as u32))).collect();
+		events.push(Ok(TokenEvent::finish(last)));
+		stream::iter(events).boxed()
+	}
+
+	async fn collect(stream: TokenStream<'_>) -> (String, TokenEvent) {
+		let events: Vec<TokenEvent> = stream.map(|e| e.unwrap()).collect().await;
+		let text = events.iter().map(|e| e.text.as_str()).collect();
+		(text, events.last().unwrap().clone())
+	}
+
+	#[test]
+	fn holds_back_a_stop_split_across_pieces() {
+		let mut m = matcher(&["</s>"]);
+		assert_eq!(m.push("Hello <"), StopScan::Continue("Hello ".into()));
+		assert_eq!(m.push("/"), StopScan::Continue(String::new()));
+		assert_eq!(
+			m.push("s> trailing"),
+			StopScan::Stopped {
+				text: String::new(),
+				stop: "</s>".into()
+			}
+		);
+	}
+
+	#[test]
+	fn releases_held_text_once_it_diverges() {
+		let mut m = matcher(&["\nUser:"]);
+		assert_eq!(m.push("a\nUs"), StopScan::Continue("a".into()));
+		assert_eq!(m.push("ually"), StopScan::Continue("\nUsually".into()));
+		assert_eq!(m.push("\n"), StopScan::Continue(String::new()));
+		assert_eq!(m.flush(), "\n");
+	}
+
+	#[test]
+	fn earliest_then_longest_stop_wins() {
+		let mut m = matcher(&["b", "ab", "abc"]);
+		assert_eq!(
+			m.push("xabcd"),
+			StopScan::Stopped {
+				text: "x".into(),
+				stop: "abc".into()
+			}
+		);
+		assert!(matcher(&["", ""]).is_empty());
+	}
+
+	#[test]
+	fn matches_inside_multibyte_text() {
+		let mut m = matcher(&["終わり"]);
+		assert_eq!(m.push("はい終"), StopScan::Continue("はい".into()));
+		assert_eq!(
+			m.push("わり"),
+			StopScan::Stopped {
+				text: String::new(),
+				stop: "終わり".into()
+			}
+		);
+	}
+
+	#[tokio::test]
+	async fn stream_ends_with_the_stop_sequence_named() {
+		let stream = apply_stops(pieces(&["The ", "answer", "\n\n", "Q", ":", " more"], FinishReason::Length), vec!["\n\nQ:".into()]);
+		let (text, last) = collect(stream).await;
+		assert_eq!(text, "The answer");
+		assert_eq!(last.finish_reason, Some(FinishReason::Stop));
+		assert_eq!(last.stop_sequence.as_deref(), Some("\n\nQ:"));
+	}
+
+	#[tokio::test]
+	async fn held_text_is_released_when_the_backend_finishes() {
+		let stream = apply_stops(pieces(&["one", " <", "|"], FinishReason::Length), vec!["<|end|>".into()]);
+		let (text, last) = collect(stream).await;
+		assert_eq!(text, "one <|");
+		assert_eq!(last.finish_reason, Some(FinishReason::Length));
+		assert_eq!(last.stop_sequence, None);
+	}
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+
+use crate::engine::{
+	gguf::GgufFile,
+	tokenizer::{char_symbols, merge_symbols, Fragment, TokenType, Tokenizer, Vocab},
+	EngineError, Result,
+};
+
+/// Word-splitting rules applied before merges, chosen by `tokenizer.ggml.pre`.
+/// Each mirrors the regex its model was trained with; letters, digits and
+/// whitespace use Rust's Unicode predicates in place of `\p{L}`, `\p{N}`, `\s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreTokenizer {
+	/// `'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+`
+	Gpt2,
+	/// Llama 3 / Qwen 2 style: case-insensitive contractions, letters may take
+	/// one leading non-letter, digits in groups of at most `max_digits`.
+	Llama3 { max_digits: usize },
+}
+
+impl PreTokenizer {
+	pub fn from_name(name: &str) -> Self {
+		match name {
+			"llama3" | "llama-bpe" | "llama-v3" | "smaug-bpe" | "dbrx" | "falcon3" => PreTokenizer::Llama3 { max_digits: 3 },
+			"qwen2" | "deepseek-r1-qwen" => PreTokenizer::Llama3 { max_digits: 1 },
+			_ => PreTokenizer::Gpt2,
+		}
+	}
+
+	/// Splits `text` into the words BPE merges never cross.
+	pub fn split<'a>(&self, text: &'a str) -> Vec<&'a str> {
+		let chars: Vec<(usize, char)> = text.char_indices().collect();
+		let byte_at = |i: usize| chars.get(i).map(|c| c.0).unwrap_or(text.len());
+		let mut out = vec![];
+		let mut i = 0;
+		while i < chars.len() {
+			let end = self.match_at(&chars, i).max(i + 1);
+			out.push(&text[byte_at(i)..byte_at(end)]);
+			i = end;
+		}
+		out
+	}
+
+	fn match_at(&self, chars: &[(usize, char)], i: usize) -> usize {
+		let at = |j: usize| chars.get(j).map(|c| c.1);
+		let is_letter = |j: usize| at(j).is_some_and(char::is_alphabetic);
+		let is_digit = |j: usize| at(j).is_some_and(char::is_numeric);
+		let is_space = |j: usize| at(j).is_some_and(char::is_whitespace);
+		let is_newline = |j: usize| matches!(at(j), Some('\r' | '\n'));
+		let is_other = |j: usize| at(j).is_some() && !is_space(j) && !is_letter(j) && !is_digit(j);
+		let run = |mut j: usize, pred: &dyn Fn(usize) -> bool| {
+			while pred(j) {
+				j += 1;
+			}
+			j
+		};
+
+		let case_insensitive = matches!(self, PreTokenizer::Llama3 { .. });
+		if at(i) == Some('\'') {
+			for suffix in ["s", "t", "re", "ve", "m", "ll", "d"] {
+				let matches = suffix.chars().enumerate().all(|(k, c)| match at(i + 1 + k) {
+					Some(x) if case_insensitive => x.to_ascii_lowercase() == c,
+					Some(x) => x == c,
+					None => false,
+				});
+				if matches {
+					return i + 1 + suffix.len();
+				}
+			}
+		}
+
+		match *self {
+			PreTokenizer::Gpt2 => {
+				let start = if at(i) == Some(' ') { i + 1 } else { i };
+				if is_letter(start) {
+					return run(start, &is_letter);
+				}
+				if is_digit(start) {
+					return run(start, &is_digit);
+				}
+				if is_other(start) {
+					return run(start, &is_other);
+				}
+			}
+			PreTokenizer::Llama3 { max_digits } => {
+				if is_letter(i) {
+					return run(i, &is_letter);
+				}
+				if !is_newline(i) && !is_digit(i) && is_letter(i + 1) {
+					return run(i + 1, &is_letter);
+				}
+				if is_digit(i) {
+					let mut j = i;
+					while j - i < max_digits && is_digit(j) {
+						j += 1;
+					}
+					return j;
+				}
+				let start = if at(i) == Some(' ') { i + 1 } else { i };
+				if is_other(start) {
+					let j = run(start, &is_other);
+					return run(j, &is_newline);
+				}
+				// \s*[\r\n]+ : whitespace up to and including its last newline.
+				let ws_end = run(i, &is_space);
+				if let Some(last_nl) = (i..ws_end).rev().find(|j| is_newline(*j)) {
+					return last_nl + 1;
+				}
+			}
+		}
+
+		// \s+(?!\S) then \s+ : a whitespace run leaves its last character to
+		// prefix the following word.
+		let ws_end = run(i, &is_space);
+		if ws_end > i {
+			if ws_end == chars.len() || ws_end - i == 1 {
+				return ws_end;
+			}
+			return ws_end - 1;
+		}
+		i + 1
+	}
+}
+
+/// GPT-2 style byte-level BPE (`tokenizer.ggml.model = "gpt2"`): bytes are
+/// mapped to printable characters, split into words, then merged by rank.
+pub struct BpeTokenizer {
+	vocab: Vocab,
+	/// Keyed by the merge line itself, `"left right"`; byte-level symbols
+	/// never contain a plain space, so the key is unambiguous.
+	ranks: HashMap<String, usize>,
+	pre: PreTokenizer,
+	byte_to_char: [char; 256],
+	char_to_byte: HashMap<char, u8>,
+}
+
+impl BpeTokenizer {
+	pub fn from_gguf(gguf: &GgufFile) -> Result<Self> {
+		let merges = gguf
+			.get_str_array("tokenizer.ggml.merges")
+			.ok_or_else(|| EngineError::LoadFailed("missing tokenizer.ggml.merges".into()))?;
+		let pre = PreTokenizer::from_name(gguf.get_str("tokenizer.ggml.pre").unwrap_or("default"));
+		Self::new(Vocab::from_gguf(gguf, false)?, &merges, pre)
+	}
+
+	pub fn new(vocab: Vocab, merges: &[&str], pre: PreTokenizer) -> Result<Self> {
+		let mut ranks = HashMap::with_capacity(merges.len());
+		for (rank, merge) in merges.iter().enumerate() {
+			if !merge.contains(' ') {
+				return Err(EngineError::LoadFailed(format!("malformed BPE merge {:?}", merge)));
+			}
+			ranks.entry(merge.to_string()).or_insert(rank);
+		}
+		let byte_to_char = byte_to_char();
+		let char_to_byte = byte_to_char.iter().enumerate().map(|(b, c)| (*c, b as u8)).collect();
+		Ok(Self {
+			vocab,
+			ranks,
+			pre,
+			byte_to_char,
+			char_to_byte,
+		})
+	}
+
+	fn encode_word(&self, word: &str, out: &mut Vec<u32>) {
+		let mapped: String = word.bytes().map(|b| self.byte_to_char[b as usize]).collect();
+		if let Some(id) = self.vocab.id(&mapped) {
+			out.push(id);
+			return;
+		}
+		let symbols = merge_symbols(&mapped, char_symbols(&mapped), |piece, split| {
+			let key = format!("{} {}", &piece[..split], &piece[split..]);
+			self.ranks.get(&key).map(|r| *r as f32)
+		});
+		for (start, len) in symbols {
+			let piece = &mapped[start..start + len];
+			match self.vocab.id(piece) {
+				Some(id) => out.push(id),
+				None => {
+					for c in piece.chars() {
+						match self.vocab.id(c.encode_utf8(&mut [0; 4])) {
+							Some(id) => out.push(id),
+							None => out.extend(self.vocab.unk),
+						}
+					}
+				}
+			}
+		}
+	}
+}
+
+impl Tokenizer for BpeTokenizer {
+	fn encode(&self, text: &str, add_special: bool) -> Vec<u32> {
+		let mut out = vec![];
+		for fragment in self.vocab.split_specials(text) {
+			match fragment {
+				Fragment::Token(id) => out.push(id),
+				Fragment::Text(t) => {
+					for word in self.pre.split(t) {
+						self.encode_word(word, &mut out);
+					}
+				}
+			}
+		}
+		if add_special {
+			out = self.vocab.add_special(out);
+		}
+		out
+	}
+
+	fn decode_piece(&self, token: u32) -> Vec<u8> {
+		let Some(piece) = self.vocab.token(token) else {
+			return vec![];
+		};
+		match self.vocab.token_type(token) {
+			TokenType::Control | TokenType::Unused => vec![],
+			TokenType::UserDefined => piece.as_bytes().to_vec(),
+			_ => piece
+				.chars()
+				.flat_map(|c| match self.char_to_byte.get(&c) {
+					Some(b) => vec![*b],
+					None => c.to_string().into_bytes(),
+				})
+				.collect(),
+		}
+	}
+
+	fn vocab(&self) -> &Vocab {
+		&self.vocab
+	}
+}
+
+/// GPT-2's reversible byte -> printable character table: printable Latin-1
+/// bytes map to themselves, the rest to code points from U+0100 upwards.
+fn byte_to_char() -> [char; 256] {
+	let printable = |b: u8| matches!(b, b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff);
+	let mut table = ['\0'; 256];
+	let mut next = 256u32;
+	for b in 0..=255u8 {
+		table[b as usize] = if printable(b) {
+			b as char
+		} else {
+			let c = char::from_u32(next).unwrap_or('\u{fffd}');
+			next += 1;
+			c
+		};
+	}
+	table
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use super::*;
+	use crate::engine::{
+		gguf::{GgufBuilder, GgufValue, GgufValueType},
+		tokenizer,
+	};
+
+	const EOS: &str = "<|endoftext|>";
+
+	/// Every byte's character, then `pieces`, then the end-of-text token.
+	fn gguf(pieces: &[&str], merges: &[&str], pre: &str) -> GgufFile {
+		let strings = |items: Vec<String>| {
+			GgufValue::Array(GgufValueType::String, items.into_iter().map(GgufValue::String).collect())
+		};
+		let mut tokens: Vec<String> = byte_to_char().iter().map(char::to_string).collect();
+		tokens.extend(pieces.iter().map(|p| p.to_string()));
+		tokens.push(EOS.into());
+		let eos = tokens.len() as u32 - 1;
+		let builder = GgufBuilder::new()
+			.metadata("tokenizer.ggml.model", GgufValue::String("gpt2".into()))
+			.metadata("tokenizer.ggml.pre", GgufValue::String(pre.into()))
+			.metadata("tokenizer.ggml.tokens", strings(tokens))
+			.metadata("tokenizer.ggml.merges", strings(merges.iter().map(|m| m.to_string()).collect()))
+			.metadata("tokenizer.ggml.eos_token_id", GgufValue::U32(eos));
+		GgufFile::from_bytes(builder.to_bytes()).unwrap()
+	}
+
+	fn hello_world() -> Arc<dyn Tokenizer> {
+		let pieces = ["he", "ll", "hell", "hello", "Ġw", "or", "Ġwor", "ld", "Ġworld"];
+		let merges = ["h e", "l l", "he ll", "hell o", "Ġ w", "o r", "Ġw or", "l d", "Ġwor ld"];
+		tokenizer::from_gguf(&gguf(&pieces, &merges, "gpt2")).unwrap()
+	}
+
+	fn pieces(t: &dyn Tokenizer, text: &str) -> Vec<String> {
+		t.encode(text, false).iter().map(|id| t.vocab().token(*id).unwrap().to_string()).collect()
+	}
+
+	#[test]
+	fn merges_build_whole_words() {
+		let t = hello_world();
+		assert_eq!(pieces(t.as_ref(), "hello world"), vec!["hello", "Ġworld"]);
+		assert_eq!(pieces(t.as_ref(), "held"), vec!["he", "ld"]);
+		assert_eq!(pieces(t.as_ref(), "Hello"), vec!["H", "e", "ll", "o"], "merges are case-sensitive");
+		assert_eq!(t.decode_piece(t.vocab().id("Ġworld").unwrap()), b" world");
+	}
+
+	#[test]
+	fn lower_ranked_merges_apply_first() {
+		let rank = |merges: &[&str]| {
+			let t = BpeTokenizer::from_gguf(&gguf(&["he", "el"], merges, "gpt2")).unwrap();
+			pieces(&t, "hel")
+		};
+		assert_eq!(rank(&["h e", "e l"]), vec!["he", "l"]);
+		assert_eq!(rank(&["e l", "h e"]), vec!["h", "el"]);
+	}
+
+	#[test]
+	fn any_text_round_trips_through_bytes() {
+		let t = hello_world();
+		for text in ["hello world", "héllo\twörld\r\n", "世界 🎉!", "\u{0}\u{7f}\u{a0}"] {
+			let ids = t.encode(text, false);
+			assert_eq!(t.decode(&ids), text);
+			assert_eq!(t.count(text), ids.len());
+		}
+		// A four-byte emoji is four byte tokens, each one partial UTF-8.
+		let ids = t.encode("🎉", false);
+		assert_eq!(ids.len(), 4);
+		assert!(std::str::from_utf8(&t.decode_piece(ids[0])).is_err());
+	}
+
+	#[test]
+	fn special_tokens_match_literally_and_decode_to_nothing() {
+		let t = hello_world();
+		let eos = t.vocab().eos.unwrap();
+		let ids = t.encode("hello<|endoftext|>hello", false);
+		assert_eq!(ids, vec![ids[0], eos, ids[0]]);
+		assert!(t.vocab().is_end_of_generation(eos));
+		assert!(t.decode_piece(eos).is_empty());
+	}
+
+	#[test]
+	fn pre_tokenizers_split_words_like_their_regexes() {
+		let gpt2 = PreTokenizer::from_name("gpt2");
+		assert_eq!(gpt2.split("Hello world's 123  x"), vec!["Hello", " world", "'s", " 123", " ", " x"]);
+		assert_eq!(gpt2.split("I'M ok?!"), vec!["I", "'", "M", " ok", "?!"]);
+
+		let llama3 = PreTokenizer::from_name("llama-bpe");
+		assert_eq!(llama3.split("I'M 12345\n\nfoo"), vec!["I", "'M", " ", "123", "45", "\n\n", "foo"]);
+		assert_eq!(llama3.split("(x) ..."), vec!["(x", ")", " ..."]);
+		let qwen2 = PreTokenizer::from_name("qwen2");
+		assert_eq!(qwen2.split("a 42"), vec!["a", " ", "4", "2"]);
+	}
+
+	#[test]
+	fn malformed_or_missing_merges_are_refused() {
+		assert!(BpeTokenizer::from_gguf(&gguf(&[], &["hello"], "gpt2")).is_err());
+		let no_merges = GgufFile::from_bytes(
+			GgufBuilder::new()
+				.metadata("tokenizer.ggml.model", GgufValue::String("gpt2".into()))
+				.metadata("tokenizer.ggml.tokens", GgufValue::Array(GgufValueType::String, vec![]))
+				.to_bytes(),
+		)
+		.unwrap();
+		assert!(tokenizer::from_gguf(&no_merges).is_err());
+	}
+}
@@ -0,0 +1,331 @@
+use std::{cmp::Ordering, collections::BinaryHeap, collections::HashMap, sync::Arc};
+
+use crate::engine::{gguf::GgufFile, EngineError, Result};
+
+pub mod bpe;
+pub mod spm;
+
+pub use bpe::BpeTokenizer;
+pub use spm::{SpmModel, SpmTokenizer};
+
+/// Text <-> token id conversion for one model vocabulary.
+pub trait Tokenizer: Send + Sync {
+	/// Tokenizes `text`. Control and user-defined tokens written literally in
+	/// the text (e.g. `<|im_start|>` from a chat template) map to their ids.
+	/// `add_special` adds BOS/EOS as the vocabulary requests.
+	fn encode(&self, text: &str, add_special: bool) -> Vec<u32>;
+
+	/// Raw bytes for one token. A multi-byte character may be split across
+	/// tokens, so callers streaming text must buffer incomplete UTF-8.
+	fn decode_piece(&self, token: u32) -> Vec<u8>;
+
+	fn vocab(&self) -> &Vocab;
+
+	fn decode(&self, tokens: &[u32]) -> String {
+		let bytes: Vec<u8> = tokens.iter().flat_map(|t| self.decode_piece(*t)).collect();
+		String::from_utf8_lossy(&bytes).into_owned()
+	}
+
+	fn count(&self, text: &str) -> usize {
+		self.encode(text, false).len()
+	}
+}
+
+/// Value of `tokenizer.ggml.token_type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenType {
+	Normal,
+	Unknown,
+	Control,
+	UserDefined,
+	Unused,
+	Byte,
+}
+
+impl TokenType {
+	fn from_i64(v: i64) -> Self {
+		match v {
+			2 => TokenType::Unknown,
+			3 => TokenType::Control,
+			4 => TokenType::UserDefined,
+			5 => TokenType::Unused,
+			6 => TokenType::Byte,
+			_ => TokenType::Normal,
+		}
+	}
+}
+
+/// The `tokenizer.ggml.*` token table shared by every tokenizer kind.
+#[derive(Debug, Clone)]
+pub struct Vocab {
+	tokens: Vec<String>,
+	types: Vec<TokenType>,
+	scores: Vec<f32>,
+	lookup: HashMap<String, u32>,
+	/// Control and user-defined tokens, longest first, for literal matching.
+	specials: Vec<(String, u32)>,
+	pub bos: Option<u32>,
+	pub eos: Option<u32>,
+	pub eot: Option<u32>,
+	pub unk: Option<u32>,
+	pub add_bos: bool,
+	pub add_eos: bool,
+}
+
+impl Vocab {
+	pub fn from_gguf(gguf: &GgufFile, add_bos_default: bool) -> Result<Self> {
+		let tokens: Vec<String> = gguf
+			.get_str_array("tokenizer.ggml.tokens")
+			.ok_or_else(|| EngineError::LoadFailed("missing tokenizer.ggml.tokens".into()))?
+			.into_iter()
+			.map(str::to_string)
+			.collect();
+		let n = tokens.len();
+		let id = |key: &str| gguf.get_u32(key).filter(|id| (*id as usize) < n);
+		let (bos, eos, eot, unk) = (
+			id("tokenizer.ggml.bos_token_id"),
+			id("tokenizer.ggml.eos_token_id"),
+			id("tokenizer.ggml.eot_token_id"),
+			id("tokenizer.ggml.unknown_token_id"),
+		);
+		let types: Vec<TokenType> = match gguf.get_array("tokenizer.ggml.token_type") {
+			Some(items) => items
+				.iter()
+				.map(|v| v.as_i64().map(TokenType::from_i64).unwrap_or(TokenType::Normal))
+				.collect(),
+			// Older conversions omit types; recover the ones decoding depends on.
+			None => tokens
+				.iter()
+				.enumerate()
+				.map(|(i, t)| match Some(i as u32) {
+					i if i == unk => TokenType::Unknown,
+					i if i == bos || i == eos || i == eot => TokenType::Control,
+					_ if is_byte_token(t) => TokenType::Byte,
+					_ => TokenType::Normal,
+				})
+				.collect(),
+		};
+		let scores: Vec<f32> = match gguf.get_array("tokenizer.ggml.scores") {
+			Some(items) => items.iter().map(|v| v.as_f64().unwrap_or(0.0) as f32).collect(),
+			None => vec![0.0; n],
+		};
+		if types.len() != n || scores.len() != n {
+			return Err(EngineError::LoadFailed(format!(
+				"tokenizer metadata mismatch: {} tokens, {} types, {} scores",
+				n,
+				types.len(),
+				scores.len()
+			)));
+		}
+		Ok(Self::new(tokens, types, scores)
+			.with_special_ids(bos, eos, eot, unk)
+			.with_add_special(
+				gguf.get_bool("tokenizer.ggml.add_bos_token").unwrap_or(add_bos_default),
+				gguf.get_bool("tokenizer.ggml.add_eos_token").unwrap_or(false),
+			))
+	}
+
+	pub fn new(tokens: Vec<String>, types: Vec<TokenType>, scores: Vec<f32>) -> Self {
+		let lookup = tokens.iter().enumerate().map(|(i, t)| (t.clone(), i as u32)).collect();
+		let mut specials: Vec<(String, u32)> = tokens
+			.iter()
+			.zip(&types)
+			.enumerate()
+			.filter(|(_, (t, ty))| matches!(ty, TokenType::Control | TokenType::UserDefined) && !t.is_empty())
+			.map(|(i, (t, _))| (t.clone(), i as u32))
+			.collect();
+		specials.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.1.cmp(&b.1)));
+		Self {
+			tokens,
+			types,
+			scores,
+			lookup,
+			specials,
+			bos: None,
+			eos: None,
+			eot: None,
+			unk: None,
+			add_bos: false,
+			add_eos: false,
+		}
+	}
+
+	pub fn with_special_ids(mut self, bos: Option<u32>, eos: Option<u32>, eot: Option<u32>, unk: Option<u32>) -> Self {
+		self.bos = bos;
+		self.eos = eos;
+		self.eot = eot;
+		self.unk = unk;
+		self
+	}
+
+	pub fn with_add_special(mut self, add_bos: bool, add_eos: bool) -> Self {
+		self.add_bos = add_bos;
+		self.add_eos = add_eos;
+		self
+	}
+
+	pub fn len(&self) -> usize {
+		self.tokens.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.tokens.is_empty()
+	}
+
+	pub fn token(&self, id: u32) -> Option<&str> {
+		self.tokens.get(id as usize).map(String::as_str)
+	}
+
+	pub fn token_type(&self, id: u32) -> TokenType {
+		self.types.get(id as usize).copied().unwrap_or(TokenType::Unused)
+	}
+
+	pub fn score(&self, id: u32) -> f32 {
+		self.scores.get(id as usize).copied().unwrap_or(0.0)
+	}
+
+	pub fn id(&self, token: &str) -> Option<u32> {
+		self.lookup.get(token).copied()
+	}
+
+	/// EOS, EOT, or any other token that should end a completion.
+	pub fn is_end_of_generation(&self, id: u32) -> bool {
+		Some(id) == self.eos || Some(id) == self.eot
+	}
+
+	/// Splits `text` into plain-text runs and literal special tokens.
+	pub(crate) fn split_specials<'a>(&self, text: &'a str) -> Vec<Fragment<'a>> {
+		let mut out = vec![];
+		let mut start = 0;
+		let mut i = 0;
+		while i < text.len() {
+			let rest = &text[i..];
+			if let Some((tok, id)) = self.specials.iter().find(|(t, _)| rest.starts_with(t.as_str())) {
+				if start < i {
+					out.push(Fragment::Text(&text[start..i]));
+				}
+				out.push(Fragment::Token(*id));
+				i += tok.len();
+				start = i;
+			} else {
+				i += rest.chars().next().map(char::len_utf8).unwrap_or(1);
+			}
+		}
+		if start < text.len() {
+			out.push(Fragment::Text(&text[start..]));
+		}
+		out
+	}
+
+	/// Wraps `tokens` with BOS/EOS according to the vocabulary flags.
+	pub(crate) fn add_special(&self, mut tokens: Vec<u32>) -> Vec<u32> {
+		if self.add_bos {
+			if let Some(bos) = self.bos {
+				tokens.insert(0, bos);
+			}
+		}
+		if self.add_eos {
+			tokens.extend(self.eos);
+		}
+		tokens
+	}
+}
+
+fn is_byte_token(t: &str) -> bool {
+	t.len() == 6 && t.starts_with("<0x") && t.ends_with('>') && u8::from_str_radix(&t[3..5], 16).is_ok()
+}
+
+pub(crate) enum Fragment<'a> {
+	Text(&'a str),
+	Token(u32),
+}
+
+/// Builds the tokenizer named by `tokenizer.ggml.model`.
+pub fn from_gguf(gguf: &GgufFile) -> Result<Arc<dyn Tokenizer>> {
+	match gguf.get_str("tokenizer.ggml.model") {
+		Some("gpt2") => Ok(Arc::new(BpeTokenizer::from_gguf(gguf)?)),
+		Some("llama") => Ok(Arc::new(SpmTokenizer::from_gguf(gguf, SpmModel::Merge)?)),
+		Some("t5") => Ok(Arc::new(SpmTokenizer::from_gguf(gguf, SpmModel::Unigram)?)),
+		Some(other) => Err(EngineError::LoadFailed(format!("unsupported tokenizer model {}", other))),
+		None => Err(EngineError::LoadFailed("missing tokenizer.ggml.model".into())),
+	}
+}
+
+/// Repeatedly joins the adjacent pair with the lowest `priority` (ties go to
+/// the leftmost pair) until no pair has one. `priority` sees the joined text
+/// and the byte offset where its two halves meet. Shared by BPE, which ranks pairs
+/// by merge order, and SentencePiece, which ranks them by token score.
+pub(crate) fn merge_symbols(text: &str, mut symbols: Vec<(usize, usize)>, priority: impl Fn(&str, usize) -> Option<f32>) -> Vec<(usize, usize)> {
+	#[derive(PartialEq)]
+	struct Candidate {
+		priority: f32,
+		left: usize,
+		right: usize,
+		len: usize,
+	}
+	impl Eq for Candidate {}
+	impl Ord for Candidate {
+		fn cmp(&self, other: &Self) -> Ordering {
+			// BinaryHeap is a max-heap: invert so the best pair pops first.
+			other
+				.priority
+				.total_cmp(&self.priority)
+				.then(other.left.cmp(&self.left))
+		}
+	}
+	impl PartialOrd for Candidate {
+		fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+			Some(self.cmp(other))
+		}
+	}
+
+	let n = symbols.len();
+	if n < 2 {
+		return symbols;
+	}
+	// Doubly linked list over the symbols; a merged-away symbol has len 0.
+	let mut prev: Vec<Option<usize>> = (0..n).map(|i| i.checked_sub(1)).collect();
+	let mut next: Vec<Option<usize>> = (0..n).map(|i| Some(i + 1).filter(|j| *j < n)).collect();
+	let mut heap = BinaryHeap::new();
+	let push = |heap: &mut BinaryHeap<Candidate>, symbols: &[(usize, usize)], left: usize, right: usize| {
+		let (start, len_l) = symbols[left];
+		let len = len_l + symbols[right].1;
+		if let Some(priority) = priority(&text[start..start + len], len_l) {
+			heap.push(Candidate {
+				priority,
+				left,
+				right,
+				len,
+			});
+		}
+	};
+	for i in 0..n - 1 {
+		push(&mut heap, &symbols, i, i + 1);
+	}
+
+	while let Some(c) = heap.pop() {
+		let (l, r) = (c.left, c.right);
+		// Skip pairs invalidated by an earlier merge.
+		if symbols[l].1 == 0 || symbols[r].1 == 0 || symbols[l].1 + symbols[r].1 != c.len || next[l] != Some(r) {
+			continue;
+		}
+		symbols[l].1 = c.len;
+		symbols[r].1 = 0;
+		next[l] = next[r];
+		if let Some(nr) = next[r] {
+			prev[nr] = Some(l);
+		}
+		if let Some(p) = prev[l] {
+			push(&mut heap, &symbols, p, l);
+		}
+		if let Some(nx) = next[l] {
+			push(&mut heap, &symbols, l, nx);
+		}
+	}
+	symbols.into_iter().filter(|s| s.1 > 0).collect()
+}
+
+/// One symbol per character of `text`, as (byte offset, byte length).
+pub(crate) fn char_symbols(text: &str) -> Vec<(usize, usize)> {
+	text.char_indices().map(|(i, c)| (i, c.len_utf8())).collect()
+}
@@ -0,0 +1,239 @@
+use crate::engine::{
+	gguf::GgufFile,
+	tokenizer::{char_symbols, merge_symbols, Fragment, TokenType, Tokenizer, Vocab},
+	Result,
+};
+
+const SPACE: char = '\u{2581}';
+
+/// How a SentencePiece vocabulary splits text into pieces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpmModel {
+	/// `tokenizer.ggml.model = "llama"`: greedily merge the highest-scoring
+	/// adjacent pair, the way llama.cpp does.
+	Merge,
+	/// `tokenizer.ggml.model = "t5"` (SentencePiece unigram): the split whose
+	/// piece log-probabilities sum highest, found by Viterbi. The normalizer
+	/// SentencePiece trains alongside (`precompiled_charsmap`) is not
+	/// applied, so text that needs NFKC folding may split differently.
+	Unigram,
+}
+
+/// SentencePiece vocabularies. Either way, pieces the vocabulary lacks fall
+/// back to `<0xXX>` byte tokens, or to the unknown token without those.
+pub struct SpmTokenizer {
+	vocab: Vocab,
+	add_space_prefix: bool,
+	model: SpmModel,
+	/// Longest piece in bytes, bounding how far Viterbi looks ahead.
+	max_piece_len: usize,
+}
+
+impl SpmTokenizer {
+	pub fn from_gguf(gguf: &GgufFile, model: SpmModel) -> Result<Self> {
+		let add_bos_default = model == SpmModel::Merge;
+		Ok(Self::new(
+			Vocab::from_gguf(gguf, add_bos_default)?,
+			gguf.get_bool("tokenizer.ggml.add_space_prefix").unwrap_or(true),
+		)
+		.with_model(model))
+	}
+
+	pub fn new(vocab: Vocab, add_space_prefix: bool) -> Self {
+		let max_piece_len = (0..vocab.len() as u32)
+			.filter_map(|id| vocab.token(id))
+			.map(str::len)
+			.max()
+			.unwrap_or(0);
+		Self {
+			vocab,
+			add_space_prefix,
+			model: SpmModel::Merge,
+			max_piece_len,
+		}
+	}
+
+	pub fn with_model(mut self, model: SpmModel) -> Self {
+		self.model = model;
+		self
+	}
+
+	fn encode_text(&self, text: &str, out: &mut Vec<u32>) {
+		let escaped = text.replace(' ', "\u{2581}");
+		let symbols = match self.model {
+			SpmModel::Merge => merge_symbols(&escaped, char_symbols(&escaped), |piece, _| {
+				// Higher scores merge first; the merge routine wants lowest first.
+				self.vocab.id(piece).map(|id| -self.vocab.score(id))
+			}),
+			SpmModel::Unigram => self.viterbi(&escaped),
+		};
+		for (start, len) in symbols {
+			let piece = &escaped[start..start + len];
+			if let Some(id) = self.vocab.id(piece) {
+				out.push(id);
+				continue;
+			}
+			for b in piece.bytes() {
+				match self.vocab.id(&format!("<0x{:02X}>", b)) {
+					Some(id) => out.push(id),
+					None => out.extend(self.vocab.unk),
+				}
+			}
+		}
+	}
+
+	/// Splits `text` into the pieces with the highest total score. A
+	/// character no piece covers costs well below the worst piece, as in
+	/// SentencePiece, so unknown text is only chosen when unavoidable.
+	fn viterbi(&self, text: &str) -> Vec<(usize, usize)> {
+		let unknown_score = (0..self.vocab.len() as u32).map(|id| self.vocab.score(id)).fold(0.0f32, f32::min) - 10.0;
+		// best[i]: highest score of any split of text[..i], and where its last piece starts.
+		let mut best: Vec<Option<(f32, usize)>> = vec![None; text.len() + 1];
+		best[0] = Some((0.0, 0));
+		for (start, c) in text.char_indices() {
+			let Some((base, _)) = best[start] else {
+				continue;
+			};
+			let mut relax = |end: usize, score: f32| {
+				if best[end].is_none_or(|(s, _)| base + score > s) {
+					best[end] = Some((base + score, start));
+				}
+			};
+			let mut covered = false;
+			let mut end = start;
+			for next in text[start..].chars() {
+				end += next.len_utf8();
+				if end - start > self.max_piece_len {
+					break;
+				}
+				if let Some(id) = self.vocab.id(&text[start..end]).filter(|id| self.is_piece(*id)) {
+					relax(end, self.vocab.score(id));
+					covered |= end == start + c.len_utf8();
+				}
+			}
+			if !covered {
+				relax(start + c.len_utf8(), unknown_score);
+			}
+		}
+
+		let mut pieces = vec![];
+		let mut end = text.len();
+		while end > 0 {
+			let (_, start) = best[end].expect("every character boundary is reachable");
+			pieces.push((start, end - start));
+			end = start;
+		}
+		pieces.reverse();
+		pieces
+	}
+
+	/// Tokens text may be split into; specials are matched separately.
+	fn is_piece(&self, id: u32) -> bool {
+		matches!(self.vocab.token_type(id), TokenType::Normal | TokenType::UserDefined)
+	}
+}
+
+impl Tokenizer for SpmTokenizer {
+	fn encode(&self, text: &str, add_special: bool) -> Vec<u32> {
+		let mut out = vec![];
+		// The space prefix goes on text that starts the input or follows a
+		// special token, matching how SentencePiece saw it during training.
+		let mut after_special = true;
+		for fragment in self.vocab.split_specials(text) {
+			match fragment {
+				Fragment::Token(id) => {
+					out.push(id);
+					after_special = true;
+				}
+				Fragment::Text(t) => {
+					if self.add_space_prefix && after_special {
+						self.encode_text(&format!(" {}", t), &mut out);
+					} else {
+						self.encode_text(t, &mut out);
+					}
+					after_special = false;
+				}
+			}
+		}
+		if add_special {
+			out = self.vocab.add_special(out);
+		}
+		out
+	}
+
+	fn decode_piece(&self, token: u32) -> Vec<u8> {
+		let Some(piece) = self.vocab.token(token) else {
+			return vec![];
+		};
+		match self.vocab.token_type(token) {
+			TokenType::Normal => piece.replace(SPACE, " ").into_bytes(),
+			TokenType::UserDefined => piece.as_bytes().to_vec(),
+			TokenType::Byte => piece
+				.strip_prefix("<0x")
+				.and_then(|p| p.strip_suffix('>'))
+				.and_then(|hex| u8::from_str_radix(hex, 16).ok())
+				.map(|b| vec![b])
+				.unwrap_or_default(),
+			TokenType::Unknown => "\u{2585}".as_bytes().to_vec(),
+			TokenType::Control | TokenType::Unused => vec![],
+		}
+	}
+
+	fn vocab(&self) -> &Vocab {
+		&self.vocab
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn tokenizer(model: SpmModel, pieces: &[(&str, f32)]) -> SpmTokenizer {
+		let mut tokens = vec!["<unk>".to_string(), "<0x21>".to_string()];
+		let mut types = vec![TokenType::Unknown, TokenType::Byte];
+		let mut scores = vec![0.0, 0.0];
+		for (piece, score) in pieces {
+			tokens.push(piece.to_string());
+			types.push(TokenType::Normal);
+			scores.push(*score);
+		}
+		let vocab = Vocab::new(tokens, types, scores).with_special_ids(None, None, None, Some(0));
+		SpmTokenizer::new(vocab, true).with_model(model)
+	}
+
+	fn pieces(t: &SpmTokenizer, text: &str) -> Vec<String> {
+		t.encode(text, false).iter().map(|id| t.vocab().token(*id).unwrap().to_string()).collect()
+	}
+
+	// "▁ab" + "c" scores -3.5, beating "▁a" + "bc" at -4, while pair merging
+	// joins "b" and "c" first because "bc" outscores "▁a".
+	const PIECES: [(&str, f32); 7] = [
+		("▁", -1.0),
+		("a", -2.0),
+		("b", -2.0),
+		("c", -2.0),
+		("▁a", -3.0),
+		("bc", -1.0),
+		("▁ab", -1.5),
+	];
+
+	#[test]
+	fn unigram_picks_the_best_scoring_split() {
+		let t = tokenizer(SpmModel::Unigram, &PIECES);
+		assert_eq!(pieces(&t, "abc"), vec!["▁ab", "c"]);
+	}
+
+	#[test]
+	fn merge_joins_the_best_pair_first() {
+		let t = tokenizer(SpmModel::Merge, &PIECES);
+		assert_eq!(pieces(&t, "abc"), vec!["▁a", "bc"]);
+	}
+
+	#[test]
+	fn unigram_falls_back_to_bytes_and_unknown() {
+		let t = tokenizer(SpmModel::Unigram, &PIECES);
+		assert_eq!(pieces(&t, "a!b"), vec!["▁a", "<0x21>", "b"]);
+		assert_eq!(pieces(&t, "é"), vec!["▁", "<unk>", "<unk>"]);
+		assert_eq!(t.decode(&t.encode("a!bc", false)), " a!bc");
+	}
+}
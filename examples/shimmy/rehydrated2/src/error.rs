@@ -0,0 +1,319 @@
+//! Request-facing errors. Backends fail with [`EngineError`], which only
+//! knows what went wrong inside a model and is serialized into cassettes,
+//! so it stays free of HTTP. [`Error`] adds what only the server can tell
+//! (unknown adapters, full queues, over-long prompts, cancelled requests)
+//! and decides how each API renders it.
+use axum::{
+	http::{header, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+use serde_json::{json, Value};
+
+use crate::{context_window::ContextExceeded, engine::EngineError, scheduler::{Abandoned, QueueFull}};
+
+/// Everything a request can fail with, whichever API it arrived through.
+/// [`Error::code`] is stable for clients to match on; the native, OpenAI and
+/// WebSocket APIs each render it in their own shape.
+#[derive(Debug, Clone)]
+pub enum Error {
+	ModelNotFound(String),
+	AdapterNotFound(String),
+	LoadFailed(String),
+	OutOfMemory(String),
+	ContextLengthExceeded(ContextExceeded),
+	InvalidRequest {
+		message: String,
+		/// The offending request field, when there is one.
+		param: Option<&'static str>,
+	},
+	Overloaded(QueueFull),
+	/// The request was abandoned before it produced a result: its queue
+	/// ticket was let go of, or the task generating it was aborted.
+	Cancelled,
+	GenerationFailed(String),
+	/// Finished output that does not satisfy the requested `response_format`.
+	InvalidOutput(String),
+}
+
+impl Error {
+	pub fn model_not_found(name: &str) -> Self {
+		Error::ModelNotFound(format!("Model not found: {}", name))
+	}
+
+	pub fn invalid_request(message: impl Into<String>) -> Self {
+		Error::InvalidRequest {
+			message: message.into(),
+			param: None,
+		}
+	}
+
+	pub fn invalid_param(param: &'static str, message: impl Into<String>) -> Self {
+		Error::InvalidRequest {
+			message: message.into(),
+			param: Some(param),
+		}
+	}
+
+	pub fn code(&self) -> &'static str {
+		match self {
+			Error::ModelNotFound(_) => "model_not_found",
+			Error::AdapterNotFound(_) => "adapter_not_found",
+			Error::LoadFailed(_) => "load_failed",
+			Error::OutOfMemory(_) => "out_of_memory",
+			Error::ContextLengthExceeded(_) => "context_length_exceeded",
+			Error::InvalidRequest { .. } => "invalid_request",
+			Error::Overloaded(_) => "overloaded",
+			Error::Cancelled => "cancelled",
+			Error::GenerationFailed(_) => "generation_failed",
+			Error::InvalidOutput(_) => "invalid_output",
+		}
+	}
+
+	pub fn status(&self) -> StatusCode {
+		match self {
+			Error::ModelNotFound(_) | Error::AdapterNotFound(_) => StatusCode::NOT_FOUND,
+			Error::ContextLengthExceeded(_) | Error::InvalidRequest { .. } => StatusCode::BAD_REQUEST,
+			Error::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+			Error::OutOfMemory(_) => StatusCode::SERVICE_UNAVAILABLE,
+			// nginx's "client closed request"; there is rarely anyone left to read it.
+			Error::Cancelled => StatusCode::from_u16(499).unwrap_or(StatusCode::BAD_REQUEST),
+			Error::InvalidOutput(_) => StatusCode::BAD_GATEWAY,
+			Error::LoadFailed(_) | Error::GenerationFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+		}
+	}
+
+	pub fn message(&self) -> String {
+		match self {
+			Error::ModelNotFound(m)
+			| Error::AdapterNotFound(m)
+			| Error::LoadFailed(m)
+			| Error::OutOfMemory(m)
+			| Error::GenerationFailed(m)
+			| Error::InvalidRequest { message: m, .. } => m.clone(),
+			Error::ContextLengthExceeded(e) => e.message(),
+			Error::Overloaded(full) => format!("Model queue is full; retry in {}s", full.retry_after_secs),
+			Error::Cancelled => "Request was cancelled".into(),
+			Error::InvalidOutput(reason) => format!("Model output does not match response_format: {}", reason),
+		}
+	}
+
+	pub fn param(&self) -> Option<&'static str> {
+		match self {
+			Error::InvalidRequest { param, .. } => *param,
+			_ => None,
+		}
+	}
+
+	/// The OpenAI `type`: the client's fault or the server's.
+	fn openai_type(&self) -> &'static str {
+		match self {
+			Error::ModelNotFound(_)
+			| Error::AdapterNotFound(_)
+			| Error::ContextLengthExceeded(_)
+			| Error::InvalidRequest { .. } => "invalid_request_error",
+			_ => "server_error",
+		}
+	}
+
+	/// `{"error": message, "code": code}`, as the native API returns it.
+	pub fn native_body(&self) -> Value {
+		let mut body = json!({"error": self.message(), "code": self.code()});
+		if let Some(param) = self.param() {
+			body["param"] = json!(param);
+		}
+		body
+	}
+
+	/// The OpenAI envelope, `{"error": {message, type, code, param}}`.
+	pub fn openai_body(&self) -> Value {
+		// OpenAI requests only ever overflow through their messages.
+		let param = match self {
+			Error::ContextLengthExceeded(_) => Some("messages"),
+			_ => self.param(),
+		};
+		json!({
+			"error": {
+				"message": self.message(),
+				"type": self.openai_type(),
+				"code": self.code(),
+				"param": param,
+			}
+		})
+	}
+
+	/// A WebSocket text frame, `{"error": code, "message": message}`.
+	pub fn ws_frame(&self) -> String {
+		let mut frame = json!({"error": self.code(), "message": self.message()});
+		if let Some(param) = self.param() {
+			frame["param"] = json!(param);
+		}
+		if let Error::Overloaded(full) = self {
+			frame["retry_after"] = json!(full.retry_after_secs);
+		}
+		frame.to_string()
+	}
+
+	pub fn openai_response(&self) -> Response {
+		self.respond(Json(self.openai_body()))
+	}
+
+	fn respond(&self, body: Json<Value>) -> Response {
+		match self {
+			Error::Overloaded(full) => {
+				(self.status(), [(header::RETRY_AFTER, full.retry_after_secs.to_string())], body).into_response()
+			}
+			_ => (self.status(), body).into_response(),
+		}
+	}
+}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.message())
+	}
+}
+
+impl std::error::Error for Error {}
+
+/// Renders the native API shape.
+impl IntoResponse for Error {
+	fn into_response(self) -> Response {
+		self.respond(Json(self.native_body()))
+	}
+}
+
+impl From<EngineError> for Error {
+	fn from(e: EngineError) -> Self {
+		let message = e.to_string();
+		match e {
+			EngineError::ModelNotFound(_) => Error::ModelNotFound(message),
+			EngineError::LoadFailed(_) => Error::LoadFailed(message),
+			EngineError::OutOfMemory { .. } => Error::OutOfMemory(message),
+			EngineError::GenerationFailed(_) => Error::GenerationFailed(message),
+		}
+	}
+}
+
+impl From<ContextExceeded> for Error {
+	fn from(e: ContextExceeded) -> Self {
+		Error::ContextLengthExceeded(e)
+	}
+}
+
+impl From<QueueFull> for Error {
+	fn from(full: QueueFull) -> Self {
+		Error::Overloaded(full)
+	}
+}
+
+impl From<Abandoned> for Error {
+	fn from(_: Abandoned) -> Self {
+		Error::Cancelled
+	}
+}
+
+impl From<tokio::task::JoinError> for Error {
+	fn from(e: tokio::task::JoinError) -> Self {
+		if e.is_cancelled() {
+			Error::Cancelled
+		} else {
+			Error::GenerationFailed(e.to_string())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn overloaded() -> Error {
+		Error::Overloaded(QueueFull { retry_after_secs: 7 })
+	}
+
+	#[test]
+	fn statuses() {
+		let cases = [
+			(Error::model_not_found("m"), 404),
+			(Error::AdapterNotFound("a".into()), 404),
+			(Error::invalid_param("temperature", "too hot"), 400),
+			(
+				Error::ContextLengthExceeded(ContextExceeded {
+					context_length: 8,
+					prompt_tokens: 10,
+					max_tokens: 1,
+				}),
+				400,
+			),
+			(overloaded(), 429),
+			(Error::OutOfMemory("oom".into()), 503),
+			(Error::Cancelled, 499),
+			(Error::InvalidOutput("not json".into()), 502),
+			(Error::LoadFailed("bad".into()), 500),
+			(Error::GenerationFailed("bad".into()), 500),
+		];
+		for (error, status) in cases {
+			assert_eq!(error.status().as_u16(), status, "{}", error.code());
+		}
+	}
+
+	#[test]
+	fn engine_errors_keep_their_kind() {
+		let oom: Error = EngineError::OutOfMemory {
+			required_bytes: 2048,
+			available_bytes: 1024,
+		}
+		.into();
+		assert_eq!(oom.code(), "out_of_memory");
+		assert!(oom.message().contains("2.0 KiB"), "{}", oom.message());
+		assert_eq!(Error::from(EngineError::ModelNotFound("m".into())).status(), StatusCode::NOT_FOUND);
+		assert_eq!(Error::from(Abandoned).code(), "cancelled");
+	}
+
+	#[test]
+	fn openai_body_carries_type_and_param() {
+		let body = Error::invalid_param("temperature", "too hot").openai_body();
+		assert_eq!(
+			body,
+			json!({"error": {
+				"message": "too hot",
+				"type": "invalid_request_error",
+				"code": "invalid_request",
+				"param": "temperature",
+			}})
+		);
+		let exceeded = Error::ContextLengthExceeded(ContextExceeded {
+			context_length: 8,
+			prompt_tokens: 10,
+			max_tokens: 1,
+		});
+		assert_eq!(exceeded.openai_body()["error"]["param"], "messages");
+		assert_eq!(exceeded.openai_body()["error"]["type"], "invalid_request_error");
+		let failed = Error::GenerationFailed("boom".into()).openai_body();
+		assert_eq!(failed["error"]["type"], "server_error");
+		assert!(failed["error"]["param"].is_null());
+	}
+
+	#[test]
+	fn native_body_and_ws_frame() {
+		let error = Error::invalid_param("n", "bad n");
+		assert_eq!(error.native_body(), json!({"error": "bad n", "code": "invalid_request", "param": "n"}));
+		let frame: Value = serde_json::from_str(&error.ws_frame()).unwrap();
+		assert_eq!(frame, json!({"error": "invalid_request", "message": "bad n", "param": "n"}));
+		let frame: Value = serde_json::from_str(&overloaded().ws_frame()).unwrap();
+		assert_eq!(frame["error"], "overloaded");
+		assert_eq!(frame["retry_after"], 7);
+		assert!(frame.get("param").is_none());
+	}
+
+	#[test]
+	fn only_overloaded_responses_carry_retry_after() {
+		for response in [overloaded().into_response(), overloaded().openai_response()] {
+			assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+			assert_eq!(response.headers()[header::RETRY_AFTER], "7");
+		}
+		let response = Error::Cancelled.into_response();
+		assert_eq!(response.status().as_u16(), 499);
+		assert!(response.headers().get(header::RETRY_AFTER).is_none());
+	}
+}
@@ -2,10 +2,16 @@ pub mod anthropic_compat;
 pub mod api;
 pub mod auto_discovery;
 pub mod cli;
+pub mod context_window;
 pub mod engine;
+pub mod error;
+pub mod model_pool;
 pub mod model_registry;
 pub mod openai_compat;
+pub mod scheduler;
 pub mod server;
 pub mod templates;
+#[cfg(test)]
+pub(crate) mod testing;
 
 pub use server::AppState;
@@ -1,13 +1,25 @@
-use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Instant};
+use std::{collections::HashMap, io::Write, net::SocketAddr, path::PathBuf, sync::Arc, time::Instant};
 
 use clap::Parser;
+use futures::StreamExt;
 
 use shimmy::{
 	auto_discovery::{filter_llm_only, ModelAutoDiscovery},
 	cli::{Cli, Command},
-	engine::{adapter::InferenceEngineAdapter, llama::{LlamaEngine, MoeConfig}, GenOptions, InferenceEngine},
+	context_window::ContextStrategy,
+	engine::{
+		adapter::InferenceEngineAdapter,
+		cassette::{RecordingEngine, ReplayEngine},
+		gguf::{format_parameter_count, GgufFile},
+		gpu::{GpuProbe, GpuReport},
+		llama::{LlamaEngine, MoeConfig, MoePlacement},
+		memory::{format_bytes, MemoryPlan},
+		collect_completion, GenOptions, InferenceEngine,
+	},
 	model_registry::{ModelEntry, Registry},
+	scheduler::{QueueOrdering, SchedulerConfig},
 	server::AppState,
+	templates::detect_template_from_chat_template,
 };
 
 #[tokio::main]
@@ -23,15 +35,52 @@ async fn main() -> anyhow::Result<()> {
 		registry.discovered_models = discovery.discover_models();
 	}
 
-	let engine: Box<dyn InferenceEngine> = if cfg!(feature = "llama") {
+	let engine: Box<dyn InferenceEngine> = if cfg!(feature = "cpu") {
+		// The adapter routes GGUF files to the CPU reference backend.
+		Box::new(InferenceEngineAdapter::new())
+	} else if cfg!(feature = "llama") {
 		let moe = MoeConfig::from_cli(cli.cpu_moe, cli.n_cpu_moe);
 		Box::new(LlamaEngine::new_with_moe(cli.gpu_backend.as_deref(), moe))
 	} else {
 		Box::new(InferenceEngineAdapter::new())
 	};
+	let engine: Box<dyn InferenceEngine> = if let Some(path) = &cli.replay {
+		let replay = ReplayEngine::open(path).map_err(|e| anyhow::anyhow!(e))?;
+		for (name, template) in replay.models() {
+			registry.register(ModelEntry {
+				name,
+				base_path: path.clone(),
+				lora_path: None,
+				template,
+				ctx_len: None,
+				n_threads: None,
+				draft_path: None,
+			});
+		}
+		Box::new(replay)
+	} else if let Some(path) = &cli.record {
+		let recorder = RecordingEngine::new(engine, path)
+			.map_err(|e| anyhow::anyhow!("Cannot open cassette {}: {}", path.display(), e))?;
+		Box::new(recorder)
+	} else {
+		engine
+	};
 
 	match cli.cmd {
-		Command::Serve { bind, model_path } => {
+		Command::Serve {
+			bind,
+			model_path,
+			max_model_memory_mb,
+			parallel,
+			model_parallel,
+			ctx_len,
+			model_ctx_len,
+			model_draft,
+			max_queue,
+			queue_policy,
+			context_strategy,
+			adapter_dir,
+		} => {
 			if let Some(p) = model_path {
 				let path = PathBuf::from(p);
 				let name = path
@@ -46,13 +95,60 @@ async fn main() -> anyhow::Result<()> {
 					template: None,
 					ctx_len: None,
 					n_threads: None,
+					draft_path: None,
 				});
 			}
 
 			registry.auto_register_discovered();
+			for item in model_draft {
+				let Some((name, path)) = item.split_once('=') else {
+					anyhow::bail!("Invalid --model-draft value: {} (expected NAME=PATH)", item);
+				};
+				let Some(entry) = registry.inner.get_mut(name) else {
+					anyhow::bail!("Unknown model for --model-draft: {}", name);
+				};
+				entry.draft_path = Some(PathBuf::from(path));
+			}
+			registry.default_ctx_len = ctx_len;
+			for item in model_ctx_len {
+				let parsed = item.split_once('=').and_then(|(name, n)| Some((name, n.parse::<usize>().ok()?)));
+				let Some((name, n)) = parsed else {
+					anyhow::bail!("Invalid --model-ctx-len value: {} (expected NAME=N)", item);
+				};
+				let Some(entry) = registry.inner.get_mut(name) else {
+					anyhow::bail!("Unknown model for --model-ctx-len: {}", name);
+				};
+				entry.ctx_len = Some(n);
+			}
 
 			let addr = parse_bind(&bind);
-			let state = Arc::new(AppState::new(engine, registry));
+			let budget = max_model_memory_mb.map(|mb| mb * 1024 * 1024);
+			let Some(ordering) = QueueOrdering::from_name(&queue_policy) else {
+				anyhow::bail!("Unknown queue policy: {} (expected fifo or priority)", queue_policy);
+			};
+			let Some(context_strategy) = ContextStrategy::from_name(&context_strategy) else {
+				anyhow::bail!(
+					"Unknown context strategy: {} (expected reject, drop_oldest or keep_ends)",
+					context_strategy
+				);
+			};
+			let mut per_model = HashMap::new();
+			for item in model_parallel {
+				let parsed = item.split_once('=').and_then(|(name, n)| Some((name.to_string(), n.parse::<usize>().ok()?)));
+				let Some((name, n)) = parsed else {
+					anyhow::bail!("Invalid --model-parallel value: {} (expected NAME=N)", item);
+				};
+				per_model.insert(name, n);
+			}
+			let scheduler = SchedulerConfig {
+				parallel,
+				max_queue,
+				ordering,
+				model_parallel: per_model,
+			};
+			let state = AppState::new_with_limits(engine, registry, budget, scheduler).with_context_strategy(context_strategy)
+				.with_adapter_dirs(adapter_dir);
+			let state = Arc::new(state);
 			shimmy::server::run(addr, state).await
 		}
 
@@ -94,8 +190,32 @@ async fn main() -> anyhow::Result<()> {
 			let Some(spec) = registry.to_spec(&name) else {
 				anyhow::bail!("Model not found: {}", name);
 			};
-			engine.load(&spec).await.map_err(|e| anyhow::anyhow!(e))?;
+			let gguf = GgufFile::open(&spec.base_path).ok();
+			let moe = MoeConfig::from_cli(cli.cpu_moe, cli.n_cpu_moe);
+			let placement = match &gguf {
+				Some(gguf) => moe.plan(gguf).map_err(|e| anyhow::anyhow!("{}: {}", name, e))?,
+				None => None,
+			};
+			let planned = engine.plan_memory(&spec);
+			let model = match engine.load(&spec).await {
+				Ok(model) => model,
+				Err(e) => {
+					if let Some(plan) = &planned {
+						print_memory_plan(plan);
+					}
+					anyhow::bail!(e);
+				}
+			};
 			println!("OK: loaded {}", name);
+			if let Some(gguf) = &gguf {
+				print_gguf_summary(gguf);
+			}
+			if let Some(placement) = &placement {
+				print_moe_placement(placement);
+			}
+			if let Some(plan) = model.memory_plan().or(planned) {
+				print_memory_plan(&plan);
+			}
 			Ok(())
 		}
 
@@ -105,43 +225,73 @@ async fn main() -> anyhow::Result<()> {
 			};
 			let model = engine.load(&spec).await.map_err(|e| anyhow::anyhow!(e))?;
 
-			let mut opts = GenOptions::default();
-			opts.max_tokens = max_tokens;
+			let opts = GenOptions {
+				max_tokens,
+				..Default::default()
+			};
 
 			let started = Instant::now();
-			let _ = model
-				.generate("bench", opts, None)
+			let completion = collect_completion(model.generate_until_stop("bench", opts))
 				.await
 				.map_err(|e| anyhow::anyhow!(e))?;
 			let elapsed = started.elapsed().as_secs_f64();
-			let tps = (max_tokens as f64) / elapsed.max(1e-9);
-			println!("Bench: {} tokens in {:.3}s ({:.1} tok/s)", max_tokens, elapsed, tps);
+			let tokens = completion.completion_tokens;
+			let tps = (tokens as f64) / elapsed.max(1e-9);
+			println!("Bench: {} tokens in {:.3}s ({:.1} tok/s)", tokens, elapsed, tps);
 			Ok(())
 		}
 
-		Command::Generate { name, prompt, max_tokens } => {
+		Command::Generate {
+			name,
+			prompt,
+			max_tokens,
+			temperature,
+			seed,
+		} => {
 			let Some(spec) = registry.to_spec(&name) else {
 				anyhow::bail!("Model not found: {}", name);
 			};
 			let model = engine.load(&spec).await.map_err(|e| anyhow::anyhow!(e))?;
-			let mut opts = GenOptions::default();
-			opts.max_tokens = max_tokens;
-			let text = model
-				.generate(&prompt, opts, None)
-				.await
-				.map_err(|e| anyhow::anyhow!(e))?;
-			println!("{}", text);
+			let defaults = GenOptions::default();
+			let opts = GenOptions {
+				max_tokens,
+				temperature: temperature.unwrap_or(defaults.temperature),
+				seed,
+				..defaults
+			};
+			let mut tokens = model.generate_until_stop(&prompt, opts);
+			let mut stdout = std::io::stdout();
+			while let Some(event) = tokens.next().await {
+				let event = event.map_err(|e| anyhow::anyhow!(e))?;
+				print!("{}", event.text);
+				stdout.flush()?;
+			}
+			println!();
 			Ok(())
 		}
 
-		Command::GpuInfo => {
+		Command::GpuInfo { name } => {
+			let moe = MoeConfig::from_cli(cli.cpu_moe, cli.n_cpu_moe);
 			if cfg!(feature = "llama") {
-				let moe = MoeConfig::from_cli(cli.cpu_moe, cli.n_cpu_moe);
-				let llama = LlamaEngine::new_with_moe(cli.gpu_backend.as_deref(), moe);
+				let llama = LlamaEngine::new_with_moe(cli.gpu_backend.as_deref(), moe.clone());
 				println!("Backend: {}", llama.get_backend_info());
 			} else {
 				println!("Backend: unavailable (llama feature disabled)");
 			}
+			print_gpu_report(&GpuProbe::new().detect());
+			if let Some(name) = name {
+				let Some(spec) = registry.to_spec(&name) else {
+					anyhow::bail!("Model not found: {}", name);
+				};
+				let gguf = GgufFile::open(&spec.base_path).map_err(|e| anyhow::anyhow!("{}: {}", name, e))?;
+				match moe.plan(&gguf).map_err(|e| anyhow::anyhow!("{}: {}", name, e))? {
+					Some(placement) => {
+						println!("{}:", name);
+						print_moe_placement(&placement);
+					}
+					None => println!("{}: dense model, no expert tensors to place", name),
+				}
+			}
 			Ok(())
 		}
 
@@ -153,6 +303,118 @@ async fn main() -> anyhow::Result<()> {
 	}
 }
 
+fn print_gguf_summary(gguf: &GgufFile) {
+	let or_unknown = |v: Option<String>| v.unwrap_or_else(|| "unknown".into());
+	let params = gguf.parameter_count();
+	println!("  GGUF version:   {}", gguf.version());
+	println!("  architecture:   {}", or_unknown(gguf.architecture().map(str::to_string)));
+	println!("  name:           {}", or_unknown(gguf.name().map(str::to_string)));
+	println!("  parameters:     {} ({})", format_parameter_count(params), params);
+	println!("  quantization:   {}", or_unknown(gguf.quantization()));
+	println!("  context length: {}", or_unknown(gguf.context_length().map(|n| n.to_string())));
+	println!("  layers:         {}", or_unknown(gguf.block_count().map(|n| n.to_string())));
+	println!("  tensors:        {}", gguf.tensors().len());
+	println!(
+		"  chat template:  {}",
+		or_unknown(gguf.chat_template().and_then(detect_template_from_chat_template).map(|f| f.name().to_string()))
+	);
+}
+
+fn print_memory_plan(plan: &MemoryPlan) {
+	let e = &plan.estimate;
+	println!("  memory:         {} estimated", format_bytes(e.total_bytes));
+	println!("    weights:      {}", format_bytes(e.weights_bytes));
+	println!(
+		"    KV cache:     {} ({} x {} tokens, {})",
+		format_bytes(e.kv_cache_bytes),
+		e.slots,
+		e.context_length,
+		e.kv_type.name()
+	);
+	println!("    scratch:      {}", format_bytes(e.scratch_bytes));
+	if e.cache_bytes > 0 {
+		println!("    caches:       {}", format_bytes(e.cache_bytes));
+	}
+	match plan.available_bytes {
+		Some(available) => println!(
+			"  available:      {} ({})",
+			format_bytes(available),
+			if plan.fits { "fits" } else { "does not fit" }
+		),
+		None => println!("  available:      unknown"),
+	}
+	if plan.downgraded() {
+		println!(
+			"  context:        reduced from {} to {} tokens to fit",
+			plan.requested_context_length, e.context_length
+		);
+	}
+}
+
+fn print_moe_placement(placement: &MoePlacement) {
+	match placement.expert_used_count {
+		Some(used) => println!("  experts:        {} ({} per token)", placement.expert_count, used),
+		None => println!("  experts:        {}", placement.expert_count),
+	}
+	println!(
+		"  MoE placement:  experts of {} of {} layers on CPU ({})",
+		placement.cpu_layers(),
+		placement.layers.len(),
+		format_bytes(placement.cpu_bytes())
+	);
+	for layer in &placement.layers {
+		let prefix = format!("blk.{}.", layer.layer);
+		let tensors: Vec<&str> = layer
+			.tensors
+			.iter()
+			.map(|t| t.strip_prefix(&prefix).unwrap_or(t).trim_end_matches(".weight"))
+			.collect();
+		println!(
+			"    layer {:>3}: {}  {} ({})",
+			layer.layer,
+			if layer.on_cpu { "cpu" } else { "gpu" },
+			tensors.join(", "),
+			format_bytes(layer.bytes)
+		);
+	}
+}
+
+fn print_gpu_report(report: &GpuReport) {
+	println!("Detected: {}", report.backend.as_str());
+	if report.devices.is_empty() {
+		println!("Devices: none found");
+	} else {
+		println!("Devices:");
+	}
+	for device in &report.devices {
+		let mark = if device.chosen { "*" } else { "-" };
+		println!("  {} {} [{}]", mark, device.name, device.source.display());
+		println!("      {}", device.reason);
+	}
+	println!("Libraries:");
+	for lib in &report.libraries {
+		match &lib.path {
+			Some(path) => println!("  {}: {}", lib.name, path.display()),
+			None => println!("  {}: not found", lib.name),
+		}
+	}
+	for (kind, manifests) in [("Vulkan ICDs", &report.vulkan_icds), ("OpenCL vendors", &report.opencl_vendors)] {
+		if manifests.is_empty() {
+			println!("{}: none", kind);
+			continue;
+		}
+		println!("{}:", kind);
+		for m in manifests {
+			let driver = match (&m.library, &m.resolved) {
+				(_, Some(path)) => path.display().to_string(),
+				(Some(library), None) => format!("{} (not found)", library),
+				(None, None) => "unreadable".into(),
+			};
+			println!("  {} -> {}", m.manifest.display(), driver);
+		}
+	}
+}
+
 fn parse_bind(bind: &str) -> SocketAddr {
 	if bind == "auto" {
 		return "127.0.0.1:0".parse().unwrap();
@@ -0,0 +1,496 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	future::Future,
+	sync::{Arc, Mutex, MutexGuard},
+	time::Instant,
+};
+
+use serde::Serialize;
+
+use crate::{
+	engine::{prefix_cache::PrefixCacheStats, InferenceEngine, LoadedModel, Result, SpeculativeStats},
+	model_registry::ModelSpec,
+};
+
+/// Keeps loaded models resident between requests, keyed by model name.
+///
+/// When `budget_bytes` is set, least-recently-used models are evicted once a
+/// new load would push the resident total over the budget. Evicted models stay
+/// alive until in-flight generations holding them finish.
+pub struct ModelPool {
+	budget_bytes: Option<u64>,
+	/// Never held across an await.
+	inner: Mutex<PoolInner>,
+	/// One lock per model name, held while that model loads so concurrent
+	/// requests for it never load its weights twice. Entries only live while
+	/// someone is loading or waiting.
+	loads: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+#[derive(Default)]
+struct PoolInner {
+	entries: HashMap<String, PoolEntry>,
+	/// Bytes claimed by loads still in progress.
+	reserved_bytes: u64,
+	clock: u64,
+	evictions: u64,
+	/// Prefix-cache counters of models that have left the pool.
+	retired_prefix_cache: PrefixCacheStats,
+	/// Draft acceptance totals of models that have left the pool.
+	retired_speculative: SpeculativeStats,
+}
+
+struct PoolEntry {
+	model: Arc<dyn LoadedModel>,
+	size_bytes: u64,
+	loaded_at: Instant,
+	last_used: Instant,
+	last_tick: u64,
+	uses: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PooledModelStatus {
+	pub model: String,
+	pub size_bytes: u64,
+	pub loaded_seconds: u64,
+	pub idle_seconds: u64,
+	pub uses: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStats {
+	pub loaded_count: usize,
+	pub resident_bytes: u64,
+	pub budget_bytes: Option<u64>,
+	pub evictions: u64,
+}
+
+impl ModelPool {
+	pub fn new(budget_bytes: Option<u64>) -> Self {
+		Self {
+			budget_bytes,
+			inner: Mutex::new(PoolInner::default()),
+			loads: Mutex::new(HashMap::new()),
+		}
+	}
+
+	pub fn budget_bytes(&self) -> Option<u64> {
+		self.budget_bytes
+	}
+
+	fn lock(&self) -> MutexGuard<'_, PoolInner> {
+		self.inner.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	/// Returns the resident model for `spec.name`, loading it through `engine`
+	/// on a miss, along with the names of any models evicted to make room.
+	/// `size_bytes` is only awaited on a miss. Only loads of the same model
+	/// wait for each other; the pool stays available to everything else
+	/// while weights are read.
+	pub async fn get_or_load(
+		&self,
+		engine: &dyn InferenceEngine,
+		spec: &ModelSpec,
+		size_bytes: impl Future<Output = u64>,
+	) -> Result<(Arc<dyn LoadedModel>, Vec<String>)> {
+		if let Some(model) = self.lock().touch(&spec.name) {
+			return Ok((model, vec![]));
+		}
+
+		let slot = LoadSlot::claim(self, &spec.name);
+		let _loading = slot.lock.lock().await;
+		if let Some(model) = self.lock().touch(&spec.name) {
+			return Ok((model, vec![]));
+		}
+
+		// Reserve the room so concurrent loads of other models account for it,
+		// but keep resident models until this one has loaded: a failed load
+		// then costs nothing.
+		let size_bytes = size_bytes.await;
+		let reservation = {
+			let mut inner = self.lock();
+			inner.reserved_bytes += size_bytes;
+			Reservation { pool: self, bytes: size_bytes }
+		};
+
+		let model: Arc<dyn LoadedModel> = Arc::from(engine.load(spec).await?);
+
+		let mut inner = self.lock();
+		reservation.settle(&mut inner);
+		let evicted = match self.budget_bytes {
+			Some(budget) => inner.evict_until_fits(budget, size_bytes),
+			None => vec![],
+		};
+		inner.clock += 1;
+		let tick = inner.clock;
+		let now = Instant::now();
+		inner.entries.insert(
+			spec.name.clone(),
+			PoolEntry {
+				model: model.clone(),
+				size_bytes,
+				loaded_at: now,
+				last_used: now,
+				last_tick: tick,
+				uses: 1,
+			},
+		);
+		Ok((model, evicted))
+	}
+
+	pub fn unload(&self, name: &str) -> bool {
+		self.lock().remove(name)
+	}
+
+	/// The resident model, if any, without loading it or counting a use.
+	pub fn get(&self, name: &str) -> Option<Arc<dyn LoadedModel>> {
+		self.lock().entries.get(name).map(|e| e.model.clone())
+	}
+
+	pub fn status(&self, name: &str) -> Option<PooledModelStatus> {
+		let inner = self.lock();
+		inner.entries.get(name).map(|e| e.status(name))
+	}
+
+	pub fn loaded(&self) -> Vec<PooledModelStatus> {
+		let inner = self.lock();
+		let mut out: Vec<PooledModelStatus> = inner.entries.iter().map(|(name, e)| e.status(name)).collect();
+		out.sort_by(|a, b| a.model.cmp(&b.model));
+		out
+	}
+
+	/// Prefix-cache counters of every resident model whose backend keeps one.
+	pub fn prefix_cache_stats(&self) -> BTreeMap<String, PrefixCacheStats> {
+		let inner = self.lock();
+		inner
+			.entries
+			.iter()
+			.filter_map(|(name, e)| e.model.prefix_cache_stats().map(|s| (name.clone(), s)))
+			.collect()
+	}
+
+	/// Prefix-cache counters since startup: those of models that have left
+	/// the pool plus the residents'. Entries and bytes only count residents.
+	pub fn prefix_cache_totals(&self) -> PrefixCacheStats {
+		let inner = self.lock();
+		let mut total = inner.retired_prefix_cache;
+		for stats in inner.entries.values().filter_map(|e| e.model.prefix_cache_stats()) {
+			total.merge(&stats);
+		}
+		total
+	}
+
+	/// Draft acceptance totals of every resident model decoding speculatively.
+	pub fn speculative_stats(&self) -> BTreeMap<String, SpeculativeStats> {
+		let inner = self.lock();
+		inner
+			.entries
+			.iter()
+			.filter_map(|(name, e)| e.model.speculative_stats().map(|s| (name.clone(), s)))
+			.collect()
+	}
+
+	/// Draft acceptance totals since startup, models that have left the
+	/// pool included.
+	pub fn speculative_totals(&self) -> SpeculativeStats {
+		let inner = self.lock();
+		let mut total = inner.retired_speculative;
+		for stats in inner.entries.values().filter_map(|e| e.model.speculative_stats()) {
+			total.merge(&stats);
+		}
+		total
+	}
+
+	pub fn stats(&self) -> PoolStats {
+		let inner = self.lock();
+		PoolStats {
+			loaded_count: inner.entries.len(),
+			resident_bytes: inner.resident_bytes(),
+			budget_bytes: self.budget_bytes,
+			evictions: inner.evictions,
+		}
+	}
+}
+
+impl PoolInner {
+	fn resident_bytes(&self) -> u64 {
+		self.entries.values().map(|e| e.size_bytes).sum()
+	}
+
+	/// Drops `name` from the pool, keeping its counters for the totals.
+	fn remove(&mut self, name: &str) -> bool {
+		let Some(entry) = self.entries.remove(name) else {
+			return false;
+		};
+		if let Some(stats) = entry.model.prefix_cache_stats() {
+			// Its cache goes with it; only the lookup counters carry over.
+			self.retired_prefix_cache.merge(&PrefixCacheStats {
+				entries: 0,
+				bytes: 0,
+				..stats
+			});
+		}
+		if let Some(stats) = entry.model.speculative_stats() {
+			self.retired_speculative.merge(&stats);
+		}
+		true
+	}
+
+	/// The resident model for `name`, counting a use.
+	fn touch(&mut self, name: &str) -> Option<Arc<dyn LoadedModel>> {
+		self.clock += 1;
+		let tick = self.clock;
+		let entry = self.entries.get_mut(name)?;
+		entry.last_used = Instant::now();
+		entry.last_tick = tick;
+		entry.uses += 1;
+		Some(entry.model.clone())
+	}
+
+	fn evict_until_fits(&mut self, budget: u64, incoming: u64) -> Vec<String> {
+		let mut evicted = vec![];
+		while !self.entries.is_empty() && self.resident_bytes() + self.reserved_bytes + incoming > budget {
+			let lru = self
+				.entries
+				.iter()
+				.min_by_key(|(_, e)| e.last_tick)
+				.map(|(name, _)| name.clone());
+			let Some(name) = lru else { break };
+			self.remove(&name);
+			self.evictions += 1;
+			evicted.push(name);
+		}
+		evicted
+	}
+}
+
+/// A claim on a model's load lock, dropping the lock from the pool once
+/// nobody else is waiting on it.
+struct LoadSlot<'a> {
+	pool: &'a ModelPool,
+	name: String,
+	lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl<'a> LoadSlot<'a> {
+	fn claim(pool: &'a ModelPool, name: &str) -> Self {
+		let mut loads = pool.loads.lock().unwrap_or_else(|e| e.into_inner());
+		let lock = loads.entry(name.to_string()).or_default().clone();
+		Self {
+			pool,
+			name: name.to_string(),
+			lock,
+		}
+	}
+}
+
+impl Drop for LoadSlot<'_> {
+	fn drop(&mut self) {
+		// Claims clone the lock under the same mutex, so two references mean
+		// the map's and ours.
+		let mut loads = self.pool.loads.lock().unwrap_or_else(|e| e.into_inner());
+		if Arc::strong_count(&self.lock) == 2 {
+			loads.remove(&self.name);
+		}
+	}
+}
+
+/// Room claimed for a load in progress, given back however the load ends.
+struct Reservation<'a> {
+	pool: &'a ModelPool,
+	bytes: u64,
+}
+
+impl Reservation<'_> {
+	/// Hands the room over to the loaded entry.
+	fn settle(mut self, inner: &mut PoolInner) {
+		inner.reserved_bytes -= self.bytes;
+		self.bytes = 0;
+	}
+}
+
+impl Drop for Reservation<'_> {
+	fn drop(&mut self) {
+		if self.bytes > 0 {
+			self.pool.lock().reserved_bytes -= self.bytes;
+		}
+	}
+}
+
+impl PoolEntry {
+	fn status(&self, name: &str) -> PooledModelStatus {
+		PooledModelStatus {
+			model: name.to_string(),
+			size_bytes: self.size_bytes,
+			loaded_seconds: self.loaded_at.elapsed().as_secs(),
+			idle_seconds: self.last_used.elapsed().as_secs(),
+			uses: self.uses,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use async_trait::async_trait;
+	use futures::StreamExt;
+
+	use super::*;
+	use crate::engine::{mock::ScriptedEngine, EngineError, GenOptions, TokenStream};
+
+	/// Loads models whose counters read the same however long they live.
+	struct CountingEngine;
+
+	struct CountingModel;
+
+	#[async_trait]
+	impl InferenceEngine for CountingEngine {
+		async fn load(&self, _spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+			Ok(Box::new(CountingModel))
+		}
+	}
+
+	impl LoadedModel for CountingModel {
+		fn generate_stream<'a>(&'a self, _prompt: &str, _opts: GenOptions) -> TokenStream<'a> {
+			futures::stream::empty().boxed()
+		}
+
+		fn prefix_cache_stats(&self) -> Option<PrefixCacheStats> {
+			Some(PrefixCacheStats {
+				hits: 2,
+				misses: 1,
+				reused_tokens: 40,
+				entries: 1,
+				bytes: 64,
+				..Default::default()
+			})
+		}
+
+		fn speculative_stats(&self) -> Option<SpeculativeStats> {
+			Some(SpeculativeStats { drafted: 8, accepted: 6 })
+		}
+	}
+
+	fn spec(name: &str) -> ModelSpec {
+		ModelSpec {
+			name: name.into(),
+			base_path: format!("/models/{}.gguf", name).into(),
+			lora_path: None,
+			template: None,
+			ctx_len: None,
+			n_threads: None,
+			draft_path: None,
+			adapters: Default::default(),
+			slots: 1,
+		}
+	}
+
+	async fn load(pool: &ModelPool, engine: &dyn InferenceEngine, name: &str, size: u64) -> Result<Vec<String>> {
+		pool.get_or_load(engine, &spec(name), async move { size }).await.map(|(_, evicted)| evicted)
+	}
+
+	fn resident(pool: &ModelPool) -> Vec<String> {
+		pool.loaded().into_iter().map(|m| m.model).collect()
+	}
+
+	#[tokio::test]
+	async fn hits_do_not_reload_or_size_the_model() {
+		let (pool, engine) = (ModelPool::new(None), ScriptedEngine::new());
+		load(&pool, &engine, "a", 10).await.unwrap();
+		let (model, evicted) = pool.get_or_load(&engine, &spec("a"), async { panic!("sized on a hit") }).await.unwrap();
+		assert!(evicted.is_empty());
+		assert!(Arc::ptr_eq(&model, &pool.get("a").unwrap()));
+		assert_eq!(engine.loads(), vec!["a"]);
+		assert_eq!(pool.status("a").unwrap().uses, 2);
+	}
+
+	#[tokio::test]
+	async fn the_least_recently_used_model_is_evicted_first() {
+		let (pool, engine) = (ModelPool::new(Some(100)), ScriptedEngine::new());
+		for name in ["a", "b", "c"] {
+			assert!(load(&pool, &engine, name, 30).await.unwrap().is_empty());
+		}
+		// Using "a" again leaves "b" as the oldest.
+		load(&pool, &engine, "a", 30).await.unwrap();
+		assert_eq!(load(&pool, &engine, "d", 30).await.unwrap(), vec!["b"]);
+		assert_eq!(resident(&pool), vec!["a", "c", "d"]);
+
+		// A large model evicts as many as it takes, oldest first.
+		assert_eq!(load(&pool, &engine, "e", 70).await.unwrap(), vec!["c", "a"]);
+		assert_eq!(resident(&pool), vec!["d", "e"]);
+		let stats = pool.stats();
+		assert_eq!((stats.loaded_count, stats.resident_bytes, stats.evictions), (2, 100, 3));
+	}
+
+	#[tokio::test]
+	async fn without_a_budget_nothing_is_evicted() {
+		let (pool, engine) = (ModelPool::new(None), ScriptedEngine::new());
+		for name in ["a", "b", "c"] {
+			assert!(load(&pool, &engine, name, u64::MAX / 4).await.unwrap().is_empty());
+		}
+		assert_eq!(resident(&pool).len(), 3);
+		assert!(pool.unload("b"));
+		assert!(!pool.unload("b"));
+		assert_eq!(resident(&pool), vec!["a", "c"]);
+	}
+
+	#[tokio::test]
+	async fn concurrent_loads_of_one_model_load_it_once() {
+		let (pool, engine) = (ModelPool::new(None), ScriptedEngine::new());
+		let slow = || async {
+			tokio::time::sleep(Duration::from_millis(20)).await;
+			10
+		};
+		let a = spec("a");
+		let (first, second) = tokio::join!(pool.get_or_load(&engine, &a, slow()), pool.get_or_load(&engine, &a, slow()));
+		assert!(Arc::ptr_eq(&first.unwrap().0, &second.unwrap().0));
+		assert_eq!(engine.loads(), vec!["a"]);
+		assert!(pool.loads.lock().unwrap().is_empty(), "load locks are dropped once idle");
+	}
+
+	#[tokio::test]
+	async fn a_failed_load_evicts_nothing() {
+		let engine = ScriptedEngine::new().fail_load("broken", EngineError::LoadFailed("bad file".into()));
+		let pool = ModelPool::new(Some(100));
+		load(&pool, &engine, "a", 60).await.unwrap();
+		let err = load(&pool, &engine, "broken", 60).await.unwrap_err();
+		assert!(matches!(err, EngineError::LoadFailed(_)));
+		assert_eq!(resident(&pool), vec!["a"]);
+		let stats = pool.stats();
+		assert_eq!((stats.evictions, pool.lock().reserved_bytes), (0, 0));
+		assert!(pool.loads.lock().unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn prefix_cache_totals_outlive_evicted_and_unloaded_models() {
+		let pool = ModelPool::new(Some(100));
+		load(&pool, &CountingEngine, "a", 60).await.unwrap();
+		assert_eq!(load(&pool, &CountingEngine, "b", 60).await.unwrap(), vec!["a"]);
+		let totals = pool.prefix_cache_totals();
+		assert_eq!((totals.hits, totals.misses, totals.reused_tokens), (4, 2, 80));
+		// Only the resident cache still holds anything.
+		assert_eq!((totals.entries, totals.bytes), (1, 64));
+
+		assert!(pool.unload("b"));
+		let totals = pool.prefix_cache_totals();
+		assert_eq!((totals.hits, totals.misses, totals.reused_tokens), (4, 2, 80));
+		assert_eq!((totals.entries, totals.bytes), (0, 0));
+		assert!(pool.prefix_cache_stats().is_empty());
+	}
+
+	#[tokio::test]
+	async fn speculative_totals_outlive_evicted_and_unloaded_models() {
+		let pool = ModelPool::new(Some(100));
+		load(&pool, &CountingEngine, "a", 60).await.unwrap();
+		assert_eq!(load(&pool, &CountingEngine, "b", 60).await.unwrap(), vec!["a"]);
+		assert_eq!(pool.speculative_totals(), SpeculativeStats { drafted: 16, accepted: 12 });
+
+		assert!(pool.unload("b"));
+		let totals = pool.speculative_totals();
+		assert_eq!(totals, SpeculativeStats { drafted: 16, accepted: 12 });
+		assert_eq!(totals.acceptance_rate(), Some(0.75));
+		assert!(pool.speculative_stats().is_empty());
+	}
+}
@@ -1,9 +1,12 @@
 use std::{
-	collections::{HashMap, HashSet},
-	path::PathBuf,
+	collections::{BTreeMap, HashMap, HashSet},
+	path::{Path, PathBuf},
 };
 
-use crate::auto_discovery::{DiscoveredModel, ModelAutoDiscovery};
+use crate::{
+	auto_discovery::{DiscoveredModel, ModelAutoDiscovery},
+	engine::gguf::GgufFile,
+};
 
 #[derive(Debug, Clone)]
 pub struct ModelEntry {
@@ -13,6 +16,8 @@ pub struct ModelEntry {
 	pub template: Option<String>,
 	pub ctx_len: Option<usize>,
 	pub n_threads: Option<i32>,
+	/// Smaller model with the same vocabulary, used for speculative decoding.
+	pub draft_path: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
@@ -23,12 +28,23 @@ pub struct ModelSpec {
 	pub template: Option<String>,
 	pub ctx_len: Option<usize>,
 	pub n_threads: Option<i32>,
+	/// Smaller model with the same vocabulary, used for speculative decoding.
+	pub draft_path: Option<PathBuf>,
+	/// LoRA adapters added at runtime, by name, on top of `lora_path`.
+	pub adapters: BTreeMap<String, PathBuf>,
+	/// Sequences decoded at once, each with its own KV cache.
+	pub slots: usize,
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct Registry {
 	pub inner: HashMap<String, ModelEntry>,
 	pub discovered_models: HashMap<String, DiscoveredModel>,
+	/// Runtime adapters per model, kept here so they come back whenever the
+	/// model is loaded again.
+	pub adapters: HashMap<String, BTreeMap<String, PathBuf>>,
+	/// Context window for models whose entry sets none.
+	pub default_ctx_len: Option<usize>,
 }
 
 impl Registry {
@@ -36,6 +52,8 @@ impl Registry {
 		Self {
 			inner: HashMap::new(),
 			discovered_models: HashMap::new(),
+			adapters: HashMap::new(),
+			default_ctx_len: None,
 		}
 	}
 
@@ -45,6 +63,8 @@ impl Registry {
 		Self {
 			inner: HashMap::new(),
 			discovered_models,
+			adapters: HashMap::new(),
+			default_ctx_len: None,
 		}
 	}
 
@@ -58,7 +78,7 @@ impl Registry {
 			if self.inner.contains_key(&name) {
 				continue;
 			}
-			let template = self.infer_template(&name);
+			let template = model.template.clone().or_else(|| self.infer_template(&name));
 			self.inner.insert(
 				name.clone(),
 				ModelEntry {
@@ -68,6 +88,7 @@ impl Registry {
 					template,
 					ctx_len: None,
 					n_threads: None,
+					draft_path: None,
 				},
 			);
 		}
@@ -84,8 +105,11 @@ impl Registry {
 				base_path: entry.base_path.clone(),
 				lora_path: entry.lora_path.clone(),
 				template: entry.template.clone(),
-				ctx_len: entry.ctx_len,
+				ctx_len: entry.ctx_len.or(self.default_ctx_len),
 				n_threads: entry.n_threads,
+				draft_path: entry.draft_path.clone(),
+				adapters: self.adapters.get(name).cloned().unwrap_or_default(),
+				slots: 1,
 			});
 		}
 
@@ -94,12 +118,31 @@ impl Registry {
 			name: discovered.name.clone(),
 			base_path: discovered.path.clone(),
 			lora_path: discovered.lora_path.clone(),
-			template: self.infer_template(name),
-			ctx_len: None,
+			template: discovered.template.clone().or_else(|| self.infer_template(name)),
+			ctx_len: self.default_ctx_len,
 			n_threads: None,
+			draft_path: None,
+			adapters: self.adapters.get(name).cloned().unwrap_or_default(),
+			slots: 1,
 		})
 	}
 
+	pub fn add_adapter(&mut self, model: &str, name: &str, path: PathBuf) {
+		self.adapters.entry(model.to_string()).or_default().insert(name.to_string(), path);
+	}
+
+	pub fn remove_adapter(&mut self, model: &str, name: &str) -> bool {
+		self.adapters.get_mut(model).is_some_and(|a| a.remove(name).is_some())
+	}
+
+	/// Bytes of weights `name` loads, or `None` when its files cannot be read.
+	pub fn size_bytes(&self, name: &str) -> Option<u64> {
+		if let Some(d) = self.discovered_models.get(name).filter(|d| d.size_bytes > 0) {
+			return Some(d.size_bytes);
+		}
+		weights_size_bytes(&self.to_spec(name)?.base_path)
+	}
+
 	pub fn list(&self) -> Vec<&ModelEntry> {
 		self.inner.values().collect()
 	}
@@ -140,6 +183,16 @@ impl Registry {
 	}
 }
 
+/// Bytes of weights in the file at `path`: its size on disk, or for a file
+/// that reports none, the tensor data its GGUF header lists.
+pub fn weights_size_bytes(path: &Path) -> Option<u64> {
+	std::fs::metadata(path)
+		.ok()
+		.map(|m| m.len())
+		.filter(|&n| n > 0)
+		.or_else(|| GgufFile::open(path).ok().map(|g| g.tensor_data_size()))
+}
+
 impl From<&ModelEntry> for ModelSpec {
 	fn from(entry: &ModelEntry) -> Self {
 		Self {
@@ -149,6 +202,9 @@ impl From<&ModelEntry> for ModelSpec {
 			template: entry.template.clone(),
 			ctx_len: entry.ctx_len,
 			n_threads: entry.n_threads,
+			draft_path: entry.draft_path.clone(),
+			adapters: BTreeMap::new(),
+			slots: 1,
 		}
 	}
 }
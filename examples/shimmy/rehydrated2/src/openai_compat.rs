@@ -2,20 +2,29 @@ use std::sync::Arc;
 
 use axum::{
 	extract::State,
-	http::StatusCode,
 	response::{sse::Event, IntoResponse, Sse},
 	Json,
 };
+use base64::Engine as _;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use futures::StreamExt;
+use serde_json::{json, Value};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use uuid::Uuid;
 
 use crate::{
-	engine::GenOptions,
+	api::{
+		adapter_options, cancel_on_drop, compile_grammar, context_length, logprob_options, template_from_spec,
+		AdapterSelection,
+	},
+	context_window::{ContextLimit, ContextReport, ContextStrategy},
+	engine::{
+		collect_completion, grammar::json_schema, EmbedOptions, EngineError, FinishReason, GenOptions, Pooling,
+		SpeculativeStats, TokenLogprobs,
+	},
+	error::Error,
 	server::AppState,
-	templates::{detect_template_family, TemplateFamily},
 };
 
 #[derive(Debug, Deserialize)]
@@ -26,9 +35,67 @@ pub struct ChatCompletionRequest {
 	pub temperature: Option<f32>,
 	pub max_tokens: Option<usize>,
 	pub top_p: Option<f32>,
+	pub frequency_penalty: Option<f32>,
+	pub presence_penalty: Option<f32>,
+	pub seed: Option<u64>,
 	pub stop: Option<StopTokens>,
+	/// Non-standard: `false` opts out of prompt-prefix cache reuse.
+	pub cache_prompt: Option<bool>,
+	pub response_format: Option<ResponseFormat>,
+	pub logprobs: Option<bool>,
+	pub top_logprobs: Option<usize>,
+	/// How many independent completions to sample from the one prompt.
+	pub n: Option<usize>,
+	/// Non-standard: LoRA adapter(s) to decode with.
+	pub adapter: Option<AdapterSelection>,
+	/// Non-standard: how to fit messages that overflow the context window.
+	pub context_strategy: Option<ContextStrategy>,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+	Text,
+	JsonObject,
+	JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JsonSchemaFormat {
+	pub name: Option<String>,
+	pub schema: Option<Value>,
+	/// Output is always held to the schema, so this is accepted but unused.
+	pub strict: Option<bool>,
+}
+
+impl ResponseFormat {
+	/// The GBNF constraining decoding and the schema the finished message
+	/// must satisfy, or `None` for free text.
+	fn compile(&self) -> Result<Option<(String, Value)>, String> {
+		match self {
+			ResponseFormat::Text => Ok(None),
+			ResponseFormat::JsonObject => Ok(Some((json_schema::json_object_gbnf(), json!({"type": "object"})))),
+			ResponseFormat::JsonSchema { json_schema: format } => {
+				let schema = format.schema.clone().unwrap_or_else(|| json!({}));
+				let gbnf = json_schema::schema_to_gbnf(&schema).map_err(|e| format!("invalid response_format schema: {}", e))?;
+				Ok(Some((gbnf, schema)))
+			}
+		}
+	}
+}
+
+/// Checks a finished structured-output message against its schema.
+fn check_output(schema: &Value, text: &str, finish_reason: FinishReason) -> Result<(), String> {
+	let value: Value = serde_json::from_str(text).map_err(|e| match finish_reason {
+		FinishReason::Length => "output reached max_tokens before the JSON document was complete".to_string(),
+		_ => format!("output is not valid JSON: {}", e),
+	})?;
+	json_schema::validate(schema, &value)
+}
+
+/// Upper bound on `n`; every choice holds its own KV cache.
+const MAX_CHOICES: usize = 16;
+
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 pub enum StopTokens {
@@ -53,20 +120,61 @@ pub struct ChatCompletionResponse {
 	pub model: String,
 	pub choices: Vec<Choice>,
 	pub usage: Usage,
+	/// Not part of the OpenAI schema: how the messages were fitted to the
+	/// context window.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub context: Option<ContextReport>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct Choice {
 	pub index: usize,
 	pub message: crate::api::ChatMessage,
+	pub logprobs: Option<ChoiceLogprobs>,
 	pub finish_reason: Option<String>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct ChoiceLogprobs {
+	pub content: Vec<TokenLogprobs>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct Usage {
 	pub prompt_tokens: usize,
 	pub completion_tokens: usize,
 	pub total_tokens: usize,
+	/// Not part of the OpenAI schema; present only for models with a draft.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub speculative: Option<SpeculativeStats>,
+}
+
+impl Usage {
+	pub fn new(prompt_tokens: usize, completion_tokens: usize) -> Self {
+		Self {
+			prompt_tokens,
+			completion_tokens,
+			total_tokens: prompt_tokens + completion_tokens,
+			speculative: None,
+		}
+	}
+}
+
+/// The OpenAI `finish_reason` for `reason`. OpenAI has none for a
+/// generation the client abandoned, so cancelled choices report `null`.
+fn finish_reason(reason: FinishReason) -> Option<String> {
+	match reason {
+		FinishReason::Stop | FinishReason::Length => Some(reason.as_str().into()),
+		FinishReason::Cancelled => None,
+	}
+}
+
+/// Sums the draft acceptance of every choice that reported any.
+fn merge_speculative(stats: impl IntoIterator<Item = Option<SpeculativeStats>>) -> Option<SpeculativeStats> {
+	stats.into_iter().flatten().reduce(|mut total, s| {
+		total.merge(&s);
+		total
+	})
 }
 
 #[derive(Debug, Serialize)]
@@ -82,6 +190,7 @@ pub struct ChatCompletionChunk {
 pub struct ChunkChoice {
 	pub index: usize,
 	pub delta: Delta,
+	pub logprobs: Option<ChoiceLogprobs>,
 	pub finish_reason: Option<String>,
 }
 
@@ -91,6 +200,73 @@ pub struct Delta {
 	pub content: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingRequest {
+	pub model: String,
+	pub input: EmbeddingInput,
+	pub encoding_format: Option<EncodingFormat>,
+	/// Non-standard: overrides the pooling the model declares.
+	pub pooling: Option<Pooling>,
+	/// Non-standard: `false` returns vectors as pooled, not unit length.
+	pub normalize: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+	Single(String),
+	Multiple(Vec<String>),
+}
+
+impl EmbeddingInput {
+	pub fn into_vec(self) -> Vec<String> {
+		match self {
+			EmbeddingInput::Single(s) => vec![s],
+			EmbeddingInput::Multiple(v) => v,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncodingFormat {
+	#[default]
+	Float,
+	/// Little-endian `f32`s, base64-encoded.
+	Base64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EmbeddingResponse {
+	pub object: String,
+	pub data: Vec<EmbeddingData>,
+	pub model: String,
+	pub usage: EmbeddingUsage,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EmbeddingData {
+	pub object: String,
+	pub index: usize,
+	pub embedding: EmbeddingVector,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+	Float(Vec<f32>),
+	Base64(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct EmbeddingUsage {
+	pub prompt_tokens: usize,
+	pub total_tokens: usize,
+}
+
+/// Upper bound on inputs per embeddings request.
+const MAX_EMBEDDING_INPUTS: usize = 2048;
+
 #[derive(Debug, Serialize)]
 pub struct ModelsResponse {
 	pub object: String,
@@ -106,56 +282,72 @@ pub struct ListModel {
 }
 
 pub async fn chat_completions(State(state): State<Arc<AppState>>, Json(req): Json<ChatCompletionRequest>) -> impl IntoResponse {
+	match chat_completions_inner(state, req).await {
+		Ok(resp) => resp,
+		Err(e) => e.openai_response(),
+	}
+}
+
+async fn chat_completions_inner(state: Arc<AppState>, req: ChatCompletionRequest) -> Result<axum::response::Response, Error> {
 	let spec = {
 		let reg = state.registry.read().await;
-		match reg.to_spec(&req.model) {
-			Some(s) => s,
-			None => {
-				return (
-					StatusCode::NOT_FOUND,
-					Json(json!({
-						"error": {
-							"message": format!("Model not found: {}", req.model),
-							"type": "invalid_request_error",
-							"code": "model_not_found"
-						}
-					})),
-				)
-					.into_response();
-			}
-		}
-	};
-
-	let loaded = match state.engine.load(&spec).await {
-		Ok(m) => m,
-		Err(e) => {
-			return (
-				StatusCode::BAD_GATEWAY,
-				Json(json!({"error": {"message": e.to_string(), "type": "server_error"}})),
-			)
-				.into_response();
-		}
+		reg.to_spec(&req.model).ok_or_else(|| Error::model_not_found(&req.model))?
 	};
 
-	let fam = match spec.template.as_deref() {
-		Some("chatml") => TemplateFamily::ChatML,
-		Some("llama3") | Some("llama-3") => TemplateFamily::Llama3,
-		_ => detect_template_family(&req.model),
-	};
+	// Queue before loading so a full queue is refused without touching the pool.
+	let ticket = state.scheduler.enqueue(&spec.name, 0)?;
+	let loaded = state.load_model(&spec).await?;
 
-	let (system, history, last_user) = crate::api::split_messages(None, &req.messages);
-	let prompt = fam.render(system.as_deref(), &history, last_user.as_deref());
+	let structured = req
+		.response_format
+		.as_ref()
+		.map(ResponseFormat::compile)
+		.transpose()
+		.map_err(|e| Error::invalid_param("response_format", e))?
+		.flatten();
+	let (grammar_src, schema) = structured.unzip();
+	let grammar =
+		compile_grammar(grammar_src.as_deref(), loaded.as_ref()).map_err(|e| Error::invalid_param("response_format", e))?;
+	let n = req.n.unwrap_or(1);
+	if !(1..=MAX_CHOICES).contains(&n) {
+		return Err(Error::invalid_param("n", format!("n must be between 1 and {}", MAX_CHOICES)));
+	}
+	let logprobs = logprob_options(req.logprobs, req.top_logprobs, loaded.as_ref())
+		.map_err(|e| Error::invalid_param("logprobs", e))?;
+	let adapters = adapter_options(req.adapter, loaded.as_ref()).map_err(|e| Error::invalid_param("adapter", e))?;
 
 	let mut opts = GenOptions::default();
 	if let Some(v) = req.max_tokens {
 		opts.max_tokens = v;
 	}
+
+	let fam = template_from_spec(&spec);
+	let (system, history, last_user) = crate::api::split_messages(None, &req.messages);
+	let count = |prompt: &str| loaded.count_prompt_tokens(prompt);
+	let limit = ContextLimit {
+		context_length: context_length(&spec, loaded.as_ref()),
+		max_tokens: opts.max_tokens,
+		count_tokens: &count,
+	};
+	let strategy = req.context_strategy.unwrap_or(state.context_strategy);
+	let (prompt, context) = limit.fit_chat(&fam, system.as_deref(), history, last_user.as_deref(), strategy)?;
 	if let Some(v) = req.temperature {
 		opts.temperature = v;
 	}
 	if let Some(v) = req.top_p {
 		opts.top_p = v;
 	}
+	if let Some(v) = req.frequency_penalty {
+		opts.frequency_penalty = v;
+	}
+	if let Some(v) = req.presence_penalty {
+		opts.presence_penalty = v;
+	}
+	opts.seed = req.seed;
+	opts.grammar = grammar;
+	opts.logprobs = logprobs;
+	opts.adapters = adapters;
+	opts.cache_prompt = req.cache_prompt.unwrap_or(true);
 	let stream = req.stream.unwrap_or(false);
 	opts.stream = stream;
 
@@ -167,85 +359,179 @@ pub async fn chat_completions(State(state): State<Arc<AppState>>, Json(req): Jso
 
 	let id = format!("chatcmpl-{}", Uuid::new_v4());
 	let created = chrono::Utc::now().timestamp() as u64;
+	// Each choice samples with its own seed so seeded requests stay
+	// reproducible without every choice coming out the same.
+	let choices: Vec<GenOptions> = (0..n)
+		.map(|i| GenOptions {
+			seed: opts.seed.map(|s| s.wrapping_add(i as u64)),
+			..opts.clone()
+		})
+		.collect();
+
+	let tracker = state.observability.track_generation();
 
 	if stream {
 		let (tx, rx) = mpsc::unbounded_channel::<Result<Event, std::convert::Infallible>>();
-		let prompt_clone = prompt.clone();
+		let stream = cancel_on_drop(UnboundedReceiverStream::new(rx), opts.cancel.clone());
+		let cancel = opts.cancel.clone();
 		let model_name = req.model.clone();
-		let id_clone = id.clone();
-		let tx_tokens = tx.clone();
-		let id_for_tokens = id_clone.clone();
-		let model_for_tokens = model_name.clone();
-
-		tokio::spawn(async move {
-			let init = ChatCompletionChunk {
-				id: id_clone.clone(),
+		let chunk = move |index: usize, delta: Delta, logprobs: Option<ChoiceLogprobs>, finish_reason: Option<String>| {
+			let chunk = ChatCompletionChunk {
+				id: id.clone(),
 				object: "chat.completion.chunk".into(),
 				created,
 				model: model_name.clone(),
 				choices: vec![ChunkChoice {
-					index: 0,
-					delta: Delta {
-						role: Some("assistant".into()),
-						content: None,
-					},
-					finish_reason: None,
+					index,
+					delta,
+					logprobs,
+					finish_reason,
 				}],
 			};
-			let _ = tx.send(Ok(Event::default().data(serde_json::to_string(&init).unwrap())));
-
-			let on_token = move |tok: String| {
-				let chunk = ChatCompletionChunk {
-					id: id_for_tokens.clone(),
-					object: "chat.completion.chunk".into(),
-					created,
-					model: model_for_tokens.clone(),
-					choices: vec![ChunkChoice {
-						index: 0,
-						delta: Delta {
-							role: None,
-							content: Some(tok),
-						},
-						finish_reason: None,
-					}],
-				};
-				let _ = tx_tokens.send(Ok(Event::default().data(serde_json::to_string(&chunk).unwrap())));
+			Ok(Event::default().data(serde_json::to_string(&chunk).unwrap()))
+		};
+
+		tokio::spawn(async move {
+			// Queue positions go out as SSE comments, which OpenAI clients ignore.
+			let queued = ticket.wait(|position| {
+				let _ = tx.send(Ok(Event::default().comment(format!("queue position {}", position))));
+			});
+			let _permit = tokio::select! {
+				permit = queued => match permit {
+					Ok(permit) => permit,
+					Err(e) => {
+						let _ = tx.send(Ok(Event::default().data(Error::from(e).openai_body().to_string())));
+						return;
+					}
+				},
+				_ = cancel.cancelled() => return,
 			};
+			if let Some(report) = &context {
+				let comment = format!("context {}", serde_json::to_string(report).unwrap_or_default());
+				let _ = tx.send(Ok(Event::default().comment(comment)));
+			}
 
-			let _ = loaded
-				.generate(&prompt_clone, opts, Some(Box::new(on_token)))
-				.await;
+			for index in 0..n {
+				let role = Delta {
+					role: Some("assistant".into()),
+					content: None,
+				};
+				let _ = tx.send(chunk(index, role, None, None));
+			}
 
-			let done_chunk = ChatCompletionChunk {
-				id: id_clone.clone(),
-				object: "chat.completion.chunk".into(),
-				created,
-				model: model_name.clone(),
-				choices: vec![ChunkChoice {
-					index: 0,
-					delta: Delta { role: None, content: None },
-					finish_reason: Some("stop".into()),
-				}],
-			};
-			let _ = tx.send(Ok(Event::default().data(serde_json::to_string(&done_chunk).unwrap())));
+			let mut finished: Vec<Option<FinishReason>> = vec![None; n];
+			let mut texts = vec![String::new(); n];
+			let mut speculative = vec![None; n];
+			let mut failed = vec![false; n];
+			let streams = state.scheduler.generate_many(&spec.name, &loaded, &prompt, choices);
+			let mut tokens = futures::stream::select_all(
+				streams.into_iter().enumerate().map(|(index, s)| s.map(move |event| (index, event))),
+			);
+			while let Some((index, event)) = tokens.next().await {
+				let ev = match event {
+					Ok(ev) => ev,
+					Err(e) => {
+						// Only this choice's stream has ended; the others keep going.
+						let mut err = Error::from(e).openai_body();
+						if n > 1 {
+							err["error"]["choice"] = index.into();
+						}
+						let _ = tx.send(Ok(Event::default().data(err.to_string())));
+						failed[index] = true;
+						continue;
+					}
+				};
+				if ev.speculative.is_some() {
+					speculative[index] = ev.speculative;
+				}
+				// A token that completes no character yet still reports its logprobs.
+				if !ev.text.is_empty() || ev.logprobs.is_some() {
+					if schema.is_some() {
+						texts[index].push_str(&ev.text);
+					}
+					let delta = Delta {
+						role: None,
+						content: Some(ev.text),
+					};
+					let logprobs = ev.logprobs.map(|lp| ChoiceLogprobs { content: vec![lp] });
+					if tx.send(chunk(index, delta, logprobs, None)).is_err() {
+						cancel.cancel();
+					}
+				}
+				let Some(reason) = ev.finish_reason else { continue };
+				// Content has already gone out, so a mismatch is reported after it.
+				if let Some(schema) = schema.as_ref().filter(|_| reason != FinishReason::Cancelled) {
+					if let Err(reason) = check_output(schema, &texts[index], reason) {
+						let err = Error::InvalidOutput(reason).openai_body();
+						let _ = tx.send(Ok(Event::default().data(err.to_string())));
+						failed[index] = true;
+					}
+				}
+				finished[index] = Some(reason);
+				let _ = tx.send(chunk(index, Delta { role: None, content: None }, None, finish_reason(reason)));
+			}
+			drop(tokens);
+			// A stream that ends with neither a finish reason nor an error was cut
+			// off, which is a failure too rather than a normal stop.
+			for index in 0..n {
+				if cancel.is_cancelled() {
+					break;
+				}
+				if finished[index].is_some() || failed[index] {
+					continue;
+				}
+				let mut err = Error::from(EngineError::GenerationFailed("generation ended unexpectedly".into())).openai_body();
+				if n > 1 {
+					err["error"]["choice"] = index.into();
+				}
+				let _ = tx.send(Ok(Event::default().data(err.to_string())));
+				failed[index] = true;
+			}
+			if failed.contains(&true) {
+				tracker.fail();
+			} else {
+				tracker.finish(finished[0].unwrap_or(FinishReason::Stop));
+			}
+			// Chunks have no place for it, so it goes out as a comment clients skip.
+			if let Some(stats) = merge_speculative(speculative) {
+				let comment = format!("speculative {}", serde_json::to_string(&stats).unwrap_or_default());
+				let _ = tx.send(Ok(Event::default().comment(comment)));
+			}
 			let _ = tx.send(Ok(Event::default().data("[DONE]")));
 		});
 
-		let stream = UnboundedReceiverStream::new(rx);
-		return Sse::new(stream)
-			.keep_alive(axum::response::sse::KeepAlive::default())
-			.into_response();
+		let sse = Sse::new(stream).keep_alive(axum::response::sse::KeepAlive::default());
+		return Ok(sse.into_response());
 	}
 
-	let text = match loaded.generate(&prompt, opts, None).await {
-		Ok(t) => t,
+	let _permit = ticket.wait(|_| {}).await?;
+	let streams = state.scheduler.generate_many(&spec.name, &loaded, &prompt, choices);
+	let collected = futures::future::join_all(streams.into_iter().map(collect_completion)).await;
+	let completions = match collected.into_iter().collect::<Result<Vec<_>, _>>() {
+		Ok(c) => c,
 		Err(e) => {
-			return (
-				StatusCode::BAD_GATEWAY,
-				Json(json!({"error": {"message": e.to_string(), "type": "server_error"}})),
-			)
-				.into_response();
+			tracker.fail();
+			return Err(e.into());
+		}
+	};
+
+	if let Some(schema) = &schema {
+		for (index, completion) in completions.iter().enumerate() {
+			if let Err(reason) = check_output(schema, &completion.text, completion.finish_reason) {
+				tracker.fail();
+				let reason = if n > 1 { format!("choice {}: {}", index, reason) } else { reason };
+				return Err(Error::InvalidOutput(reason));
+			}
 		}
+	}
+	tracker.finish(completions[0].finish_reason);
+	// Backends without a tokenizer cannot say how long the prompt was.
+	let prompt_tokens = loaded.count_prompt_tokens(&prompt).unwrap_or(0);
+	// The prompt is counted once, however many choices continue it.
+	let completion_tokens = completions.iter().map(|c| c.completion_tokens).sum();
+	let usage = Usage {
+		speculative: merge_speculative(completions.iter().map(|c| c.speculative)),
+		..Usage::new(prompt_tokens, completion_tokens)
 	};
 
 	let resp = ChatCompletionResponse {
@@ -253,22 +539,88 @@ pub async fn chat_completions(State(state): State<Arc<AppState>>, Json(req): Jso
 		object: "chat.completion".into(),
 		created,
 		model: req.model,
-		choices: vec![Choice {
-			index: 0,
-			message: crate::api::ChatMessage {
-				role: "assistant".into(),
-				content: text,
-			},
-			finish_reason: Some("stop".into()),
-		}],
-		usage: Usage {
-			prompt_tokens: 0,
-			completion_tokens: 0,
-			total_tokens: 0,
-		},
+		choices: completions
+			.into_iter()
+			.enumerate()
+			.map(|(index, completion)| Choice {
+				index,
+				message: crate::api::ChatMessage {
+					role: "assistant".into(),
+					content: completion.text,
+				},
+				logprobs: logprobs.map(|_| ChoiceLogprobs {
+					content: completion.logprobs,
+				}),
+				finish_reason: finish_reason(completion.finish_reason),
+			})
+			.collect(),
+		usage,
+		context,
 	};
 
-	Json(resp).into_response()
+	Ok(Json(resp).into_response())
+}
+
+pub async fn embeddings(State(state): State<Arc<AppState>>, Json(req): Json<EmbeddingRequest>) -> impl IntoResponse {
+	match embeddings_inner(state, req).await {
+		Ok(resp) => resp,
+		Err(e) => e.openai_response(),
+	}
+}
+
+async fn embeddings_inner(state: Arc<AppState>, req: EmbeddingRequest) -> Result<axum::response::Response, Error> {
+	let spec = {
+		let reg = state.registry.read().await;
+		reg.to_spec(&req.model).ok_or_else(|| Error::model_not_found(&req.model))?
+	};
+
+	let inputs = req.input.into_vec();
+	if inputs.is_empty() || inputs.len() > MAX_EMBEDDING_INPUTS {
+		let message = format!("input must hold between 1 and {} strings", MAX_EMBEDDING_INPUTS);
+		return Err(Error::invalid_param("input", message));
+	}
+
+	let ticket = state.scheduler.enqueue(&spec.name, 0)?;
+	let loaded = state.load_model(&spec).await?;
+	if !loaded.supports_embeddings() {
+		return Err(Error::invalid_param("model", "this model's backend does not compute embeddings"));
+	}
+
+	let _permit = ticket.wait(|_| {}).await?;
+	let opts = EmbedOptions {
+		pooling: req.pooling,
+		normalize: req.normalize.unwrap_or(true),
+	};
+	let embeddings = loaded.embed(&inputs, opts).await?;
+
+	let prompt_tokens = embeddings.iter().map(|e| e.tokens).sum();
+	let format = req.encoding_format.unwrap_or_default();
+	let data = embeddings
+		.into_iter()
+		.enumerate()
+		.map(|(index, e)| EmbeddingData {
+			object: "embedding".into(),
+			index,
+			embedding: match format {
+				EncodingFormat::Float => EmbeddingVector::Float(e.vector),
+				EncodingFormat::Base64 => {
+					let bytes: Vec<u8> = e.vector.iter().flat_map(|x| x.to_le_bytes()).collect();
+					EmbeddingVector::Base64(base64::engine::general_purpose::STANDARD.encode(bytes))
+				}
+			},
+		})
+		.collect();
+
+	Ok(Json(EmbeddingResponse {
+		object: "list".into(),
+		data,
+		model: req.model,
+		usage: EmbeddingUsage {
+			prompt_tokens,
+			total_tokens: prompt_tokens,
+		},
+	})
+	.into_response())
 }
 
 pub async fn models(State(state): State<Arc<AppState>>) -> impl IntoResponse {
@@ -290,3 +642,205 @@ pub async fn models(State(state): State<Arc<AppState>>) -> impl IntoResponse {
 		data,
 	})
 }
+
+#[cfg(all(test, feature = "cpu"))]
+mod tests {
+	use base64::Engine as _;
+	use serde_json::{json, Value};
+
+	use crate::{
+		engine::{
+			cpu::{
+				fixtures::{tiny_llama, tiny_llama_gguf, TempModel},
+				CpuEngine,
+			},
+			gguf::GgmlType,
+			tokenizer,
+		},
+		model_registry::{ModelEntry, Registry},
+		server::AppState,
+		testing::{self, TestServer},
+	};
+
+	async fn serve_entry(entry: ModelEntry) -> TestServer {
+		let mut registry = Registry::new();
+		registry.register(entry);
+		testing::serve(AppState::new(Box::new(CpuEngine::new()), registry)).await
+	}
+
+	async fn serve_tiny(file: &TempModel) -> TestServer {
+		serve_entry(testing::entry("tiny", file.path())).await
+	}
+
+	fn chat(top_logprobs: usize, stream: bool) -> Value {
+		json!({
+			"model": "tiny",
+			"messages": [{"role": "user", "content": "the cat sat on"}],
+			"max_tokens": 6,
+			"temperature": 0.0,
+			"logprobs": true,
+			"top_logprobs": top_logprobs,
+			"stream": stream,
+		})
+	}
+
+	/// Greedy decoding picks the most likely token, so each entry's own
+	/// logprob leads its alternatives, which form part of one distribution.
+	fn check_entry(entry: &Value, top_logprobs: usize) {
+		let alternatives = entry["top_logprobs"].as_array().unwrap();
+		assert_eq!(alternatives.len(), top_logprobs, "{}", entry);
+		let logprobs: Vec<f64> = alternatives.iter().map(|a| a["logprob"].as_f64().unwrap()).collect();
+		assert!(logprobs.windows(2).all(|w| w[0] >= w[1]), "{:?}", logprobs);
+		assert!(logprobs.iter().map(|lp| lp.exp()).sum::<f64>() <= 1.0 + 1e-4, "{:?}", logprobs);
+		let own = entry["logprob"].as_f64().unwrap();
+		assert!(own <= 0.0);
+		if let Some(best) = alternatives.first() {
+			assert_eq!(best["token"], entry["token"]);
+			assert!((best["logprob"].as_f64().unwrap() - own).abs() < 1e-5);
+		}
+	}
+
+	fn entry_bytes(content: &[Value]) -> Vec<u8> {
+		content
+			.iter()
+			.flat_map(|e| e["bytes"].as_array().unwrap().iter().map(|b| b.as_u64().unwrap() as u8))
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn chat_logprobs_cover_every_generated_token() {
+		let file = TempModel::write(&tiny_llama(3, GgmlType::F32));
+		let server = serve_tiny(&file).await;
+		for top_logprobs in [0, 1, 4] {
+			let resp = server.post("/v1/chat/completions", chat(top_logprobs, false)).await.json();
+			let choice = &resp["choices"][0];
+			let content = choice["logprobs"]["content"].as_array().unwrap();
+			assert!(!content.is_empty());
+			assert_eq!(content.len() as u64, resp["usage"]["completion_tokens"].as_u64().unwrap());
+			for entry in content {
+				check_entry(entry, top_logprobs);
+			}
+			let text = choice["message"]["content"].as_str().unwrap();
+			assert_eq!(String::from_utf8(entry_bytes(content)).unwrap(), text);
+		}
+	}
+
+	#[tokio::test]
+	async fn streamed_chat_logprobs_match_the_whole_response() {
+		let file = TempModel::write(&tiny_llama(3, GgmlType::F32));
+		let server = serve_tiny(&file).await;
+		let whole = server.post("/v1/chat/completions", chat(3, false)).await.json();
+		let streamed = server.post("/v1/chat/completions", chat(3, true)).await;
+		let chunks: Vec<Value> = streamed
+			.events()
+			.into_iter()
+			.filter(|e| *e != "[DONE]")
+			.map(|e| serde_json::from_str(e).unwrap())
+			.collect();
+
+		let mut content = vec![];
+		let mut text = String::new();
+		for chunk in &chunks {
+			let choice = &chunk["choices"][0];
+			text.push_str(choice["delta"]["content"].as_str().unwrap_or(""));
+			if let Some(entries) = choice["logprobs"]["content"].as_array() {
+				content.extend(entries.iter().cloned());
+			}
+		}
+		assert!(!content.is_empty());
+		for entry in &content {
+			check_entry(entry, 3);
+		}
+		assert_eq!(Value::from(content.clone()), whole["choices"][0]["logprobs"]["content"]);
+		assert_eq!(String::from_utf8(entry_bytes(&content)).unwrap(), text);
+	}
+
+	fn floats(embedding: &Value) -> Vec<f32> {
+		embedding.as_array().unwrap().iter().map(|x| x.as_f64().unwrap() as f32).collect()
+	}
+
+	#[tokio::test]
+	async fn embeddings_answer_each_input_in_order_as_floats_or_base64() {
+		let file = TempModel::write(&tiny_llama(3, GgmlType::F32));
+		let server = serve_tiny(&file).await;
+		let inputs = ["the cat sat", "a dog ran on the big red mat"];
+		let request = |format: &str| json!({"model": "tiny", "input": inputs, "encoding_format": format});
+
+		let float = server.post("/v1/embeddings", request("float")).await.json();
+		let base64 = server.post("/v1/embeddings", request("base64")).await.json();
+		for resp in [&float, &base64] {
+			let data = resp["data"].as_array().unwrap();
+			assert_eq!(data.iter().map(|d| d["index"].as_u64().unwrap()).collect::<Vec<_>>(), vec![0, 1]);
+		}
+		let vectors: Vec<Vec<f32>> = float["data"].as_array().unwrap().iter().map(|d| floats(&d["embedding"])).collect();
+		assert_ne!(vectors[0], vectors[1]);
+		for (d, vector) in base64["data"].as_array().unwrap().iter().zip(&vectors) {
+			let bytes = base64::engine::general_purpose::STANDARD.decode(d["embedding"].as_str().unwrap()).unwrap();
+			let decoded: Vec<f32> = bytes.chunks(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect();
+			assert_eq!(&decoded, vector);
+		}
+
+		// Usage counts every input's tokens, BOS included.
+		let tokenizer = tokenizer::from_gguf(&tiny_llama_gguf(3, GgmlType::F32)).unwrap();
+		let tokens: usize = inputs.iter().map(|i| tokenizer.encode(i, true).len()).sum();
+		assert_eq!(float["usage"], json!({"prompt_tokens": tokens, "total_tokens": tokens}));
+		assert_eq!(base64["usage"], float["usage"]);
+
+		let single = server.post("/v1/embeddings", json!({"model": "tiny", "input": inputs[1]})).await.json();
+		assert_eq!(floats(&single["data"][0]["embedding"]), vectors[1]);
+	}
+
+	/// Three exchanges and a question, well past the fixture's 64 tokens.
+	fn long_chat(strategy: &str) -> Value {
+		let mut messages = vec![];
+		for _ in 0..3 {
+			messages.push(json!({"role": "user", "content": "the cat sat on the mat"}));
+			messages.push(json!({"role": "assistant", "content": "the dog ran"}));
+		}
+		messages.push(json!({"role": "user", "content": "is the cat big"}));
+		json!({"model": "tiny", "messages": messages, "max_tokens": 4, "context_strategy": strategy})
+	}
+
+	#[tokio::test]
+	async fn over_long_chats_are_rejected_or_trimmed_to_the_context_window() {
+		let file = TempModel::write(&tiny_llama(3, GgmlType::F32));
+		let server = serve_tiny(&file).await;
+
+		let refused = server.post("/v1/chat/completions", long_chat("reject")).await;
+		assert_eq!(refused.status, 400);
+		let error = &refused.json()["error"];
+		assert_eq!((error["code"].as_str(), error["param"].as_str()), (Some("context_length_exceeded"), Some("messages")));
+
+		let trimmed = server.post("/v1/chat/completions", long_chat("drop_oldest")).await;
+		assert_eq!(trimmed.status, 200, "{}", trimmed.body);
+		let context = &trimmed.json()["context"];
+		assert_eq!(context["context_length"], 64);
+		assert!(context["dropped_turns"].as_u64().unwrap() > 0);
+		assert!(context["prompt_tokens"].as_u64().unwrap() + 4 <= 64);
+	}
+
+	#[tokio::test]
+	async fn a_configured_ctx_len_replaces_the_trained_window() {
+		let file = TempModel::write(&tiny_llama(3, GgmlType::F32));
+		let server = serve_entry(ModelEntry {
+			ctx_len: Some(512),
+			..testing::entry("tiny", file.path())
+		})
+		.await;
+		let resp = server.post("/v1/chat/completions", long_chat("reject")).await;
+		assert_eq!(resp.status, 200, "{}", resp.body);
+		let context = &resp.json()["context"];
+		assert_eq!((context["context_length"].as_u64(), context["dropped_turns"].as_u64()), (Some(512), Some(0)));
+
+		// `--ctx-len` sets it for every model that does not set its own.
+		let mut registry = Registry::new();
+		registry.register(testing::entry("tiny", file.path()));
+		registry.register(ModelEntry {
+			ctx_len: Some(128),
+			..testing::entry("own", file.path())
+		});
+		registry.default_ctx_len = Some(512);
+		assert_eq!(registry.to_spec("tiny").unwrap().ctx_len, Some(512));
+		assert_eq!(registry.to_spec("own").unwrap().ctx_len, Some(128));
+	}
+}
@@ -0,0 +1,573 @@
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex, Weak},
+	time::Instant,
+};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::{oneshot, watch};
+
+use crate::engine::{batch::BatchRunner, stop, GenOptions, LoadedModel, TokenStream};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueueOrdering {
+	#[default]
+	Fifo,
+	/// Higher `priority` first; FIFO among equal priorities.
+	Priority,
+}
+
+impl QueueOrdering {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name.to_ascii_lowercase().as_str() {
+			"fifo" => Some(QueueOrdering::Fifo),
+			"priority" => Some(QueueOrdering::Priority),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig {
+	/// Generations allowed to run at once per model.
+	pub parallel: usize,
+	/// Requests allowed to wait per model before new ones are rejected.
+	pub max_queue: usize,
+	pub ordering: QueueOrdering,
+	/// Per-model overrides of `parallel`.
+	pub model_parallel: HashMap<String, usize>,
+}
+
+impl Default for SchedulerConfig {
+	fn default() -> Self {
+		Self {
+			parallel: 1,
+			max_queue: 32,
+			ordering: QueueOrdering::Fifo,
+			model_parallel: HashMap::new(),
+		}
+	}
+}
+
+/// Returned when a model's queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull {
+	/// Suggested wait before retrying, from recent generation times.
+	pub retry_after_secs: u64,
+}
+
+/// Returned by [`Ticket::wait`] when the queue let go of a ticket without
+/// granting it a slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Abandoned;
+
+/// Admission control between the HTTP handlers and the loaded models: each
+/// model gets a fixed number of generation slots and a bounded wait queue.
+/// When a model has more than one slot and its backend can batch, admitted
+/// requests decode together in one shared batch.
+pub struct Scheduler {
+	config: SchedulerConfig,
+	queues: Mutex<HashMap<String, Arc<ModelQueue>>>,
+	batches: Mutex<HashMap<String, ModelBatch>>,
+}
+
+struct ModelBatch {
+	/// The loaded instance the runner decodes with; a reload gets a new runner.
+	model: Weak<dyn LoadedModel>,
+	runner: Arc<BatchRunner>,
+}
+
+impl Default for Scheduler {
+	fn default() -> Self {
+		Self::new(SchedulerConfig::default())
+	}
+}
+
+impl Scheduler {
+	pub fn new(config: SchedulerConfig) -> Self {
+		Self {
+			config,
+			queues: Mutex::new(HashMap::new()),
+			batches: Mutex::new(HashMap::new()),
+		}
+	}
+
+	pub fn config(&self) -> &SchedulerConfig {
+		&self.config
+	}
+
+	/// Generations `model` runs at once.
+	pub fn slots(&self, model: &str) -> usize {
+		self.config.model_parallel.get(model).copied().unwrap_or(self.config.parallel).max(1)
+	}
+
+	fn queue(&self, model: &str) -> Arc<ModelQueue> {
+		let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+		queues
+			.entry(model.to_string())
+			.or_insert_with(|| {
+				Arc::new(ModelQueue::new(self.slots(model), self.config.max_queue, self.config.ordering))
+			})
+			.clone()
+	}
+
+	/// Takes a slot for `model` if one is free, otherwise joins its queue.
+	/// `priority` only matters under [`QueueOrdering::Priority`].
+	pub fn enqueue(&self, model: &str, priority: i32) -> Result<Ticket, QueueFull> {
+		let queue = self.queue(model);
+		let (tx, rx) = oneshot::channel();
+		let seq = {
+			let mut st = queue.lock();
+			if st.active < queue.slots && st.waiting.is_empty() {
+				st.active += 1;
+				st.admitted += 1;
+				drop(st);
+				let permit = Permit::new(queue.clone());
+				return Ok(Ticket {
+					queue,
+					seq: 0,
+					state: TicketState::Ready(permit),
+				});
+			}
+			if st.waiting.len() >= queue.max_queue {
+				st.rejected += 1;
+				return Err(QueueFull {
+					retry_after_secs: st.retry_after_secs(queue.slots),
+				});
+			}
+			st.next_seq += 1;
+			let seq = st.next_seq;
+			let waiter = Waiter { seq, priority, grant: tx };
+			let at = match queue.ordering {
+				QueueOrdering::Fifo => st.waiting.len(),
+				QueueOrdering::Priority => st.waiting.iter().position(|w| w.priority < priority).unwrap_or(st.waiting.len()),
+			};
+			st.waiting.insert(at, waiter);
+			seq
+		};
+		queue.notify();
+		Ok(Ticket {
+			queue,
+			seq,
+			state: TicketState::Waiting(rx),
+		})
+	}
+
+	/// Streams an admitted request with stop sequences enforced. Requests for
+	/// a model with several slots share its batch decoder when it has one.
+	pub fn generate<'a>(&self, name: &str, model: &'a Arc<dyn LoadedModel>, prompt: &str, opts: GenOptions) -> TokenStream<'a> {
+		match self.batch_runner(name, model) {
+			Some(runner) => {
+				let stops = opts.stop_tokens.clone();
+				stop::apply_stops(runner.submit(prompt, opts), stops)
+			}
+			None => model.generate_until_stop(prompt, opts),
+		}
+	}
+
+	/// Streams `choices.len()` completions of one prompt, one stream per
+	/// choice. Backends with a batch decoder prefill the prompt once and fork
+	/// it through the model's runner, which is kept for this even when the
+	/// model has a single slot; others decode each choice on its own.
+	pub fn generate_many<'a>(
+		&self,
+		name: &str,
+		model: &'a Arc<dyn LoadedModel>,
+		prompt: &str,
+		choices: Vec<GenOptions>,
+	) -> Vec<TokenStream<'a>> {
+		match self.runner(name, model) {
+			Some(runner) => {
+				let stops: Vec<Vec<String>> = choices.iter().map(|o| o.stop_tokens.clone()).collect();
+				runner
+					.submit_many(prompt, choices)
+					.into_iter()
+					.zip(stops)
+					.map(|(stream, stops)| stop::apply_stops(stream, stops))
+					.collect()
+			}
+			None => choices.into_iter().map(|opts| model.generate_until_stop(prompt, opts)).collect(),
+		}
+	}
+
+	/// Drops the batch runner kept for `name`, so an unloaded or evicted
+	/// model's weights are freed once its last sequence finishes, and its
+	/// queue once nothing holds a ticket or slot in it.
+	pub fn forget(&self, name: &str) {
+		self.batches.lock().unwrap_or_else(|e| e.into_inner()).remove(name);
+		let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+		// Tickets and permits hold the queue, and new ones are only handed
+		// out under this lock, so a queue held by the map alone is idle.
+		if queues.get(name).is_some_and(|q| Arc::strong_count(q) == 1) {
+			queues.remove(name);
+		}
+	}
+
+	fn batch_runner(&self, name: &str, model: &Arc<dyn LoadedModel>) -> Option<Arc<BatchRunner>> {
+		if self.slots(name) < 2 {
+			return None;
+		}
+		self.runner(name, model)
+	}
+
+	/// The runner decoding for `model`, started on first use. `None` when the
+	/// backend cannot batch or its decode thread could not be started, in
+	/// which case callers decode without one.
+	fn runner(&self, name: &str, model: &Arc<dyn LoadedModel>) -> Option<Arc<BatchRunner>> {
+		let mut batches = self.batches.lock().unwrap_or_else(|e| e.into_inner());
+		// Runners of instances nobody holds any more only keep their weights alive.
+		batches.retain(|_, b| b.model.strong_count() > 0);
+		if let Some(batch) = batches.get(name).filter(|b| Weak::ptr_eq(&b.model, &Arc::downgrade(model))) {
+			return Some(batch.runner.clone());
+		}
+		let Some(runner) = model.batch_decoder().and_then(|decoder| BatchRunner::spawn(decoder).ok()) else {
+			batches.remove(name);
+			return None;
+		};
+		let runner = Arc::new(runner);
+		batches.insert(
+			name.to_string(),
+			ModelBatch {
+				model: Arc::downgrade(model),
+				runner: runner.clone(),
+			},
+		);
+		Some(runner)
+	}
+
+	pub fn stats(&self, model: &str) -> Option<QueueStats> {
+		let queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+		queues.get(model).map(|q| q.stats())
+	}
+
+	pub fn metrics(&self) -> Value {
+		let queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+		let models: serde_json::Map<String, Value> = queues
+			.iter()
+			.map(|(name, q)| (name.clone(), serde_json::to_value(q.stats()).unwrap_or(Value::Null)))
+			.collect();
+		let batched: Vec<String> = self.batches.lock().unwrap_or_else(|e| e.into_inner()).keys().cloned().collect();
+		json!({
+			"parallel": self.config.parallel,
+			"max_queue": self.config.max_queue,
+			"models": models,
+			"batched_models": batched,
+		})
+	}
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueStats {
+	pub slots: usize,
+	pub active: usize,
+	pub waiting: usize,
+	pub admitted: u64,
+	pub rejected: u64,
+	pub avg_generation_ms: Option<u64>,
+}
+
+struct Waiter {
+	seq: u64,
+	priority: i32,
+	grant: oneshot::Sender<Permit>,
+}
+
+#[derive(Default)]
+struct QueueState {
+	active: usize,
+	waiting: Vec<Waiter>,
+	next_seq: u64,
+	admitted: u64,
+	rejected: u64,
+	/// Exponential moving average of how long a slot is held, in seconds.
+	avg_service_secs: Option<f64>,
+}
+
+impl QueueState {
+	fn retry_after_secs(&self, slots: usize) -> u64 {
+		let per_request = self.avg_service_secs.unwrap_or(1.0);
+		let rounds = (self.waiting.len() + 1) as f64 / slots as f64;
+		(per_request * rounds).ceil().clamp(1.0, 300.0) as u64
+	}
+}
+
+struct ModelQueue {
+	slots: usize,
+	max_queue: usize,
+	ordering: QueueOrdering,
+	state: Mutex<QueueState>,
+	/// Bumped whenever the queue changes so waiters can re-check their position.
+	changed: watch::Sender<u64>,
+}
+
+impl ModelQueue {
+	fn new(slots: usize, max_queue: usize, ordering: QueueOrdering) -> Self {
+		Self {
+			slots,
+			max_queue,
+			ordering,
+			state: Mutex::new(QueueState::default()),
+			changed: watch::channel(0).0,
+		}
+	}
+
+	fn lock(&self) -> std::sync::MutexGuard<'_, QueueState> {
+		self.state.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	fn notify(&self) {
+		self.changed.send_modify(|v| *v = v.wrapping_add(1));
+	}
+
+	fn stats(&self) -> QueueStats {
+		let st = self.lock();
+		QueueStats {
+			slots: self.slots,
+			active: st.active,
+			waiting: st.waiting.len(),
+			admitted: st.admitted,
+			rejected: st.rejected,
+			avg_generation_ms: st.avg_service_secs.map(|s| (s * 1000.0) as u64),
+		}
+	}
+
+	/// Hands a finished slot to the next waiter, or frees it.
+	fn release(self: &Arc<Self>, held_secs: Option<f64>) {
+		let next = {
+			let mut st = self.lock();
+			if let Some(secs) = held_secs {
+				st.avg_service_secs = Some(match st.avg_service_secs {
+					Some(avg) => avg * 0.8 + secs * 0.2,
+					None => secs,
+				});
+			}
+			if st.waiting.is_empty() {
+				st.active -= 1;
+				None
+			} else {
+				st.admitted += 1;
+				Some(st.waiting.remove(0))
+			}
+		};
+		self.notify();
+		if let Some(waiter) = next {
+			// A waiter whose ticket was dropped mid-handoff returns the permit,
+			// whose drop passes the slot on again.
+			if let Err(mut permit) = waiter.grant.send(Permit::new(self.clone())) {
+				permit.started = None;
+			}
+		}
+	}
+}
+
+/// One generation slot. Dropping it lets the next queued request run.
+pub struct Permit {
+	queue: Arc<ModelQueue>,
+	started: Option<Instant>,
+}
+
+impl Permit {
+	fn new(queue: Arc<ModelQueue>) -> Self {
+		Self {
+			queue,
+			started: Some(Instant::now()),
+		}
+	}
+}
+
+impl Drop for Permit {
+	fn drop(&mut self) {
+		let held = self.started.map(|s| s.elapsed().as_secs_f64());
+		self.queue.release(held);
+	}
+}
+
+enum TicketState {
+	Ready(Permit),
+	Waiting(oneshot::Receiver<Permit>),
+	Done,
+}
+
+/// A place in a model's queue. Dropping a ticket that is still waiting
+/// leaves the queue.
+pub struct Ticket {
+	queue: Arc<ModelQueue>,
+	seq: u64,
+	state: TicketState,
+}
+
+impl Ticket {
+	/// 1-based place in line, or 0 once a slot is available.
+	pub fn position(&self) -> usize {
+		if !matches!(self.state, TicketState::Waiting(_)) {
+			return 0;
+		}
+		let st = self.queue.lock();
+		st.waiting.iter().position(|w| w.seq == self.seq).map(|i| i + 1).unwrap_or(0)
+	}
+
+	/// Waits for a slot, calling `on_position` with each new queue position.
+	pub async fn wait(mut self, mut on_position: impl FnMut(usize)) -> Result<Permit, Abandoned> {
+		let mut rx = match std::mem::replace(&mut self.state, TicketState::Done) {
+			TicketState::Ready(permit) => return Ok(permit),
+			TicketState::Waiting(rx) => rx,
+			TicketState::Done => return Err(Abandoned),
+		};
+		let mut changes = self.queue.changed.subscribe();
+		let mut last = None;
+		loop {
+			let position = {
+				let st = self.queue.lock();
+				st.waiting.iter().position(|w| w.seq == self.seq).map(|i| i + 1)
+			};
+			if let Some(p) = position.filter(|p| Some(*p) != last) {
+				on_position(p);
+				last = Some(p);
+			}
+			tokio::select! {
+				permit = &mut rx => return permit.map_err(|_| Abandoned),
+				_ = changes.changed() => {}
+			}
+		}
+	}
+}
+
+impl Drop for Ticket {
+	fn drop(&mut self) {
+		// Tickets admitted straight away never joined the queue.
+		if self.seq == 0 {
+			return;
+		}
+		let removed = {
+			let mut st = self.queue.lock();
+			let before = st.waiting.len();
+			st.waiting.retain(|w| w.seq != self.seq);
+			before != st.waiting.len()
+		};
+		if removed {
+			self.queue.notify();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn scheduler(max_queue: usize, ordering: QueueOrdering) -> Scheduler {
+		Scheduler::new(SchedulerConfig {
+			parallel: 1,
+			max_queue,
+			ordering,
+			model_parallel: HashMap::new(),
+		})
+	}
+
+	async fn admit(scheduler: &Scheduler) -> Permit {
+		scheduler.enqueue("m", 0).unwrap().wait(|_| {}).await.unwrap()
+	}
+
+	#[tokio::test]
+	async fn waiters_line_up_behind_the_running_request() {
+		let s = scheduler(8, QueueOrdering::Fifo);
+		let first = s.enqueue("m", 0).unwrap();
+		let second = s.enqueue("m", 0).unwrap();
+		let third = s.enqueue("m", 0).unwrap();
+		assert_eq!((first.position(), second.position(), third.position()), (0, 1, 2));
+
+		drop(first.wait(|_| {}).await.unwrap());
+		let _running = second.wait(|_| {}).await.unwrap();
+		assert_eq!(third.position(), 1);
+		let stats = s.stats("m").unwrap();
+		assert_eq!((stats.active, stats.waiting, stats.admitted), (1, 1, 2));
+	}
+
+	#[tokio::test]
+	async fn higher_priority_jumps_the_queue_under_priority_ordering() {
+		let s = scheduler(8, QueueOrdering::Priority);
+		let _running = admit(&s).await;
+		let low = s.enqueue("m", 0).unwrap();
+		let also_low = s.enqueue("m", 0).unwrap();
+		let high = s.enqueue("m", 5).unwrap();
+		assert_eq!((high.position(), low.position(), also_low.position()), (1, 2, 3));
+
+		let s = scheduler(8, QueueOrdering::Fifo);
+		let _running = admit(&s).await;
+		let low = s.enqueue("m", 0).unwrap();
+		let high = s.enqueue("m", 5).unwrap();
+		assert_eq!((low.position(), high.position()), (1, 2));
+	}
+
+	#[tokio::test]
+	async fn dropping_a_waiting_ticket_leaves_the_queue() {
+		let s = scheduler(8, QueueOrdering::Fifo);
+		let running = admit(&s).await;
+		let leaving = s.enqueue("m", 0).unwrap();
+		let staying = s.enqueue("m", 0).unwrap();
+		drop(leaving);
+		assert_eq!(staying.position(), 1);
+		assert_eq!(s.stats("m").unwrap().waiting, 1);
+
+		// The freed slot goes to the ticket still waiting, not the one that left.
+		drop(running);
+		let _running = staying.wait(|_| {}).await.unwrap();
+		let stats = s.stats("m").unwrap();
+		assert_eq!((stats.active, stats.waiting), (1, 0));
+	}
+
+	#[tokio::test]
+	async fn dropping_a_permit_hands_its_slot_to_the_next_waiter() {
+		let s = scheduler(8, QueueOrdering::Fifo);
+		let running = admit(&s).await;
+		let (positions, mut seen) = tokio::sync::mpsc::unbounded_channel();
+		let next = s.enqueue("m", 0).unwrap();
+		let _behind = s.enqueue("m", 0).unwrap();
+		let waiting = tokio::spawn(next.wait(move |p| {
+			let _ = positions.send(p);
+		}));
+		assert_eq!(seen.recv().await, Some(1));
+
+		drop(running);
+		let permit = waiting.await.unwrap().unwrap();
+		// The slot passed straight across: it was never free for a newcomer.
+		assert_eq!(s.stats("m").unwrap().active, 1);
+		assert_eq!(s.enqueue("m", 0).unwrap().position(), 2);
+		drop(permit);
+	}
+
+	#[tokio::test]
+	async fn a_full_queue_refuses_with_a_retry_hint() {
+		let s = scheduler(1, QueueOrdering::Fifo);
+		let _running = admit(&s).await;
+		let _waiting = s.enqueue("m", 0).unwrap();
+		let Err(full) = s.enqueue("m", 0) else {
+			panic!("queue should be full");
+		};
+		// No generation has finished yet, so each is assumed to take a second,
+		// and the refused request would have been second in line.
+		assert_eq!(full.retry_after_secs, 2);
+		assert_eq!(s.stats("m").unwrap().rejected, 1);
+	}
+
+	#[tokio::test]
+	async fn a_waiter_the_queue_lets_go_of_is_abandoned() {
+		let s = scheduler(8, QueueOrdering::Fifo);
+		let _running = admit(&s).await;
+		let ticket = s.enqueue("m", 0).unwrap();
+		ticket.queue.lock().waiting.clear();
+		assert_eq!(ticket.wait(|_| {}).await.err(), Some(Abandoned));
+	}
+
+	#[tokio::test]
+	async fn forgetting_a_model_drops_its_queue_once_idle() {
+		let s = scheduler(8, QueueOrdering::Fifo);
+		let running = admit(&s).await;
+		s.forget("m");
+		assert!(s.stats("m").is_some());
+
+		drop(running);
+		s.forget("m");
+		assert!(s.stats("m").is_none());
+	}
+}
@@ -1,4 +1,12 @@
-use std::{net::SocketAddr, sync::Arc, time::Instant};
+use std::{
+	net::SocketAddr,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	time::Instant,
+};
 
 use axum::{
 	extract::State,
@@ -6,24 +14,29 @@ use axum::{
 	http::{header, Method, Request, StatusCode},
 	middleware::Next,
 	response::{IntoResponse, Response},
-	routing::{get, post},
+	routing::{delete, get, post},
 	Json, Router,
 };
 use serde_json::{json, Value};
 
 use crate::{
-	engine::InferenceEngine,
-	model_registry::Registry,
+	context_window::ContextStrategy,
+	engine::{FinishReason, InferenceEngine, LoadedModel},
+	model_pool::ModelPool,
+	model_registry::{weights_size_bytes, ModelSpec, Registry},
+	scheduler::{Scheduler, SchedulerConfig},
 };
 
 pub struct ObservabilityManager {
 	started_at: Instant,
+	generations: Arc<GenerationCounters>,
 }
 
 impl Default for ObservabilityManager {
 	fn default() -> Self {
 		Self {
 			started_at: Instant::now(),
+			generations: Arc::new(GenerationCounters::default()),
 		}
 	}
 }
@@ -32,31 +45,172 @@ impl ObservabilityManager {
 	pub fn uptime_seconds(&self) -> u64 {
 		self.started_at.elapsed().as_secs()
 	}
+
+	pub fn track_generation(&self) -> GenerationTracker {
+		self.generations.started.fetch_add(1, Ordering::Relaxed);
+		GenerationTracker {
+			counters: self.generations.clone(),
+			finished: false,
+		}
+	}
+
+	pub fn generation_metrics(&self) -> Value {
+		let g = &self.generations;
+		json!({
+			"started": g.started.load(Ordering::Relaxed),
+			"completed": g.completed.load(Ordering::Relaxed),
+			"cancelled": g.cancelled.load(Ordering::Relaxed),
+			"failed": g.failed.load(Ordering::Relaxed),
+		})
+	}
+}
+
+#[derive(Default)]
+struct GenerationCounters {
+	started: AtomicU64,
+	completed: AtomicU64,
+	cancelled: AtomicU64,
+	failed: AtomicU64,
+}
+
+/// Records the outcome of one generation. Dropping it without calling
+/// `finish`/`fail` (e.g. because the request future was dropped on client
+/// disconnect) counts the generation as cancelled.
+pub struct GenerationTracker {
+	counters: Arc<GenerationCounters>,
+	finished: bool,
+}
+
+impl GenerationTracker {
+	pub fn finish(mut self, reason: FinishReason) {
+		self.finished = true;
+		let counter = match reason {
+			FinishReason::Cancelled => &self.counters.cancelled,
+			_ => &self.counters.completed,
+		};
+		counter.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn fail(mut self) {
+		self.finished = true;
+		self.counters.failed.fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+impl Drop for GenerationTracker {
+	fn drop(&mut self) {
+		if !self.finished {
+			self.counters.cancelled.fetch_add(1, Ordering::Relaxed);
+		}
+	}
 }
 
 #[derive(Default)]
 pub struct ResponseCache;
 
 pub struct AppState {
-	pub engine: Box<dyn InferenceEngine>,
+	pub engine: Arc<dyn InferenceEngine>,
 	pub registry: tokio::sync::RwLock<Registry>,
+	pub pool: ModelPool,
+	pub scheduler: Scheduler,
 	pub observability: ObservabilityManager,
 	pub response_cache: ResponseCache,
+	/// Applied to chats that do not pick a strategy themselves.
+	pub context_strategy: ContextStrategy,
+	/// Where the adapter API may load LoRA files from, besides the adapters
+	/// already registered for a model.
+	pub adapter_dirs: Vec<PathBuf>,
 }
 
 impl AppState {
 	pub fn new(engine: Box<dyn InferenceEngine>, registry: Registry) -> Self {
+		Self::new_with_pool_budget(engine, registry, None)
+	}
+
+	pub fn new_with_pool_budget(engine: Box<dyn InferenceEngine>, registry: Registry, budget_bytes: Option<u64>) -> Self {
+		Self::new_with_limits(engine, registry, budget_bytes, SchedulerConfig::default())
+	}
+
+	pub fn new_with_limits(
+		engine: Box<dyn InferenceEngine>,
+		registry: Registry,
+		budget_bytes: Option<u64>,
+		scheduler: SchedulerConfig,
+	) -> Self {
 		Self {
-			engine,
+			engine: Arc::from(engine),
 			registry: tokio::sync::RwLock::new(registry),
+			pool: ModelPool::new(budget_bytes),
+			scheduler: Scheduler::new(scheduler),
 			observability: ObservabilityManager::default(),
-			response_cache: ResponseCache::default(),
+			response_cache: ResponseCache,
+			context_strategy: ContextStrategy::default(),
+			adapter_dirs: vec![],
+		}
+	}
+
+	pub fn with_context_strategy(mut self, strategy: ContextStrategy) -> Self {
+		self.context_strategy = strategy;
+		self
+	}
+
+	pub fn with_adapter_dirs(mut self, dirs: Vec<PathBuf>) -> Self {
+		self.adapter_dirs = dirs;
+		self
+	}
+
+	/// Resolves `spec` through the model pool, loading (and evicting) as needed.
+	pub async fn load_model(&self, spec: &ModelSpec) -> crate::engine::Result<Arc<dyn LoadedModel>> {
+		let spec = self.spec_with_slots(spec);
+		// Budget with the backend's own estimate when it makes one, so KV
+		// caches count too; otherwise with the weights on disk. Both read
+		// the model file, so only a miss pays for it, off the runtime.
+		let size_bytes = {
+			let (engine, spec) = (self.engine.clone(), spec.clone());
+			async move {
+				let size = tokio::task::spawn_blocking(move || match engine.plan_memory(&spec) {
+					Some(plan) => Some(plan.estimate.total_bytes),
+					None => weights_size_bytes(&spec.base_path),
+				});
+				size.await.ok().flatten().unwrap_or(0)
+			}
+		};
+		let (model, evicted) = self.pool.get_or_load(self.engine.as_ref(), &spec, size_bytes).await?;
+		for name in evicted {
+			self.scheduler.forget(&name);
+		}
+		Ok(model)
+	}
+
+	/// `spec` with as many batch slots as the scheduler runs for it, so the
+	/// backend sizes its KV caches for them.
+	pub fn spec_with_slots(&self, spec: &ModelSpec) -> ModelSpec {
+		ModelSpec {
+			slots: self.scheduler.slots(&spec.name),
+			..spec.clone()
 		}
 	}
 }
 
 pub async fn run(addr: SocketAddr, state: Arc<AppState>) -> anyhow::Result<()> {
-	let app = Router::new()
+	let app = router(state);
+	let listener = tokio::net::TcpListener::bind(addr).await?;
+	let actual = listener.local_addr()?;
+	println!("✅ Ready to serve requests");
+	println!("   • POST /api/generate (streaming + non-streaming)");
+	println!("   • GET  /health (health check + metrics)");
+	println!("   • GET  /v1/models (OpenAI-compatible)");
+	println!("   • POST /v1/chat/completions (OpenAI-compatible)");
+	println!("Listening on http://{}", actual);
+
+	axum::serve(listener, app).await?;
+	Ok(())
+}
+
+/// Every HTTP and WebSocket route, for embedding shimmy or serving it from a
+/// test on a listener of the caller's choosing.
+pub fn router(state: Arc<AppState>) -> Router {
+	Router::new()
 		.route("/health", get(health_check))
 		.route("/metrics", get(metrics_endpoint))
 		.route("/diag", get(diag_handler))
@@ -67,6 +221,11 @@ pub async fn run(addr: SocketAddr, state: Arc<AppState>) -> anyhow::Result<()> {
 		.route("/api/models/:name/load", post(crate::api::load_model))
 		.route("/api/models/:name/unload", post(crate::api::unload_model))
 		.route("/api/models/:name/status", get(crate::api::model_status))
+		.route(
+			"/api/models/:name/adapters",
+			get(crate::api::list_adapters).post(crate::api::load_adapter),
+		)
+		.route("/api/models/:name/adapters/:adapter", delete(crate::api::unload_adapter))
 		.route("/api/tools", get(crate::api::list_tools))
 		.route("/api/tools/:name/execute", post(crate::api::execute_tool))
 		.route("/api/workflows/execute", post(crate::api::execute_workflow))
@@ -74,23 +233,12 @@ pub async fn run(addr: SocketAddr, state: Arc<AppState>) -> anyhow::Result<()> {
 		.route("/ws/generate", get(crate::api::ws_generate))
 		// OpenAI compatible
 		.route("/v1/chat/completions", post(crate::openai_compat::chat_completions))
+		.route("/v1/embeddings", post(crate::openai_compat::embeddings))
 		.route("/v1/models", get(crate::openai_compat::models))
 		// Anthropic compatible (stub)
 		.route("/v1/messages", post(crate::anthropic_compat::messages))
 		.with_state(state)
-		.layer(axum::middleware::from_fn(cors_layer));
-
-	let listener = tokio::net::TcpListener::bind(addr).await?;
-	let actual = listener.local_addr()?;
-	println!("✅ Ready to serve requests");
-	println!("   • POST /api/generate (streaming + non-streaming)");
-	println!("   • GET  /health (health check + metrics)");
-	println!("   • GET  /v1/models (OpenAI-compatible)");
-	println!("   • POST /v1/chat/completions (OpenAI-compatible)");
-	println!("Listening on http://{}", actual);
-
-	axum::serve(listener, app).await?;
-	Ok(())
+		.layer(axum::middleware::from_fn(cors_layer))
 }
 
 pub async fn cors_layer(req: Request<Body>, next: Next) -> Response {
@@ -159,12 +307,36 @@ pub async fn metrics_endpoint(State(state): State<Arc<AppState>>) -> Json<Value>
 		total_size_mb += (d.size_bytes as f64) / (1024.0 * 1024.0);
 	}
 
+	let pool = state.pool.stats();
+	let prefix_models = state.pool.prefix_cache_stats();
+	let prefix_total = state.pool.prefix_cache_totals();
+	let speculative_models = state.pool.speculative_stats();
+	let speculative_total = state.pool.speculative_totals();
+
 	Json(json!({
 		"models": {
 			"total_count": total_count,
 			"total_size_mb": total_size_mb,
 			"by_type": { "discovered": discovered, "manual": manual }
 		},
+		"pool": pool,
+		"scheduler": state.scheduler.metrics(),
+		"prefix_cache": {
+			"hits": prefix_total.hits,
+			"misses": prefix_total.misses,
+			"skipped": prefix_total.skipped,
+			"reused_tokens": prefix_total.reused_tokens,
+			"entries": prefix_total.entries,
+			"bytes": prefix_total.bytes,
+			"models": prefix_models,
+		},
+		"speculative": {
+			"drafted": speculative_total.drafted,
+			"accepted": speculative_total.accepted,
+			"acceptance_rate": speculative_total.acceptance_rate(),
+			"models": speculative_models,
+		},
+		"generations": state.observability.generation_metrics(),
 		"system": {
 			"memory_total_mb": 0,
 			"memory_free_mb": 0,
@@ -16,6 +16,25 @@ impl TemplateFamily {
 		}
 	}
 
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name.to_ascii_lowercase().as_str() {
+			"chatml" => Some(TemplateFamily::ChatML),
+			"llama3" | "llama-3" => Some(TemplateFamily::Llama3),
+			"openchat" => Some(TemplateFamily::OpenChat),
+			"mistral" => Some(TemplateFamily::Mistral),
+			_ => None,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			TemplateFamily::ChatML => "chatml",
+			TemplateFamily::Llama3 => "llama3",
+			TemplateFamily::OpenChat => "openchat",
+			TemplateFamily::Mistral => "mistral",
+		}
+	}
+
 	pub fn stop_tokens(&self) -> Vec<String> {
 		match self {
 			TemplateFamily::ChatML => stop_tokens_chatml(),
@@ -40,6 +59,23 @@ pub fn detect_template_family(model_name: &str) -> TemplateFamily {
 	TemplateFamily::OpenChat
 }
 
+/// Maps the Jinja `tokenizer.chat_template` stored in GGUF metadata onto a known family.
+pub fn detect_template_from_chat_template(template: &str) -> Option<TemplateFamily> {
+	if template.contains("<|im_start|>") {
+		return Some(TemplateFamily::ChatML);
+	}
+	if template.contains("<|start_header_id|>") {
+		return Some(TemplateFamily::Llama3);
+	}
+	if template.contains("GPT4 Correct") {
+		return Some(TemplateFamily::OpenChat);
+	}
+	if template.contains("[INST]") {
+		return Some(TemplateFamily::Mistral);
+	}
+	None
+}
+
 fn render_chatml(system: Option<&str>, history: &[(String, String)], user_input: Option<&str>) -> String {
 	let mut out = String::new();
 	if let Some(sys) = system {
@@ -0,0 +1,228 @@
+//! A real server on a loopback port for handler tests, driven over plain
+//! TCP so the tests see exactly what a client would, disconnects included.
+
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use serde_json::Value;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+};
+
+use crate::{
+	engine::InferenceEngine,
+	model_registry::{ModelEntry, Registry},
+	server::{router, AppState},
+};
+
+/// A registry of models that need no files, for engines that load anything.
+pub fn registry(models: &[&str]) -> Registry {
+	let mut registry = Registry::new();
+	for name in models {
+		registry.register(entry(name, format!("/nonexistent/{}.gguf", name)));
+	}
+	registry
+}
+
+/// A ChatML model entry for the weights at `path`.
+pub fn entry(name: &str, path: impl Into<PathBuf>) -> ModelEntry {
+	ModelEntry {
+		name: name.to_string(),
+		base_path: path.into(),
+		lora_path: None,
+		template: Some("chatml".into()),
+		ctx_len: None,
+		n_threads: None,
+		draft_path: None,
+	}
+}
+
+pub fn state(engine: impl InferenceEngine + 'static, models: &[&str]) -> AppState {
+	AppState::new(Box::new(engine), registry(models))
+}
+
+pub struct TestServer {
+	pub addr: SocketAddr,
+}
+
+pub async fn serve(state: AppState) -> TestServer {
+	let state = Arc::new(state);
+	let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let addr = listener.local_addr().unwrap();
+	tokio::spawn(async move { axum::serve(listener, router(state)).await });
+	TestServer { addr }
+}
+
+pub struct Response {
+	pub status: u16,
+	pub headers: Vec<(String, String)>,
+	pub body: String,
+}
+
+impl Response {
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+	}
+
+	pub fn json(&self) -> Value {
+		serde_json::from_str(&self.body).unwrap_or_else(|e| panic!("{}: {}", e, self.body))
+	}
+
+	/// The `data:` payloads of an SSE body, in order.
+	pub fn events(&self) -> Vec<&str> {
+		self.body.lines().filter_map(|l| l.strip_prefix("data: ")).collect()
+	}
+}
+
+impl TestServer {
+	/// Sends a request and returns the open connection, response unread.
+	pub async fn send(&self, method: &str, path: &str, body: Option<&Value>) -> TcpStream {
+		let body = body.map(Value::to_string).unwrap_or_default();
+		let mut stream = TcpStream::connect(self.addr).await.unwrap();
+		let head = format!(
+			"{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+			method,
+			path,
+			self.addr,
+			body.len()
+		);
+		stream.write_all(head.as_bytes()).await.unwrap();
+		stream.write_all(body.as_bytes()).await.unwrap();
+		stream
+	}
+
+	pub async fn request(&self, method: &str, path: &str, body: Option<&Value>) -> Response {
+		let mut stream = self.send(method, path, body).await;
+		let mut raw = vec![];
+		stream.read_to_end(&mut raw).await.unwrap();
+		parse_response(&raw)
+	}
+
+	pub async fn post(&self, path: &str, body: Value) -> Response {
+		self.request("POST", path, Some(&body)).await
+	}
+
+	pub async fn metrics(&self) -> Value {
+		self.request("GET", "/metrics", None).await.json()
+	}
+
+	/// Polls `/metrics` until `done` holds for it, for effects that land
+	/// after the response that caused them.
+	pub async fn wait_for_metrics(&self, done: impl Fn(&Value) -> bool) -> Value {
+		for _ in 0..200 {
+			let metrics = self.metrics().await;
+			if done(&metrics) {
+				return metrics;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		panic!("metrics never settled: {}", self.metrics().await);
+	}
+
+	/// Opens a WebSocket and completes the handshake.
+	pub async fn websocket(&self, path: &str) -> TcpStream {
+		let mut stream = TcpStream::connect(self.addr).await.unwrap();
+		let head = format!(
+			"GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+			path, self.addr
+		);
+		stream.write_all(head.as_bytes()).await.unwrap();
+		let mut head = vec![];
+		while !head.ends_with(b"\r\n\r\n") {
+			head.push(stream.read_u8().await.unwrap());
+		}
+		assert!(head.starts_with(b"HTTP/1.1 101"), "{}", String::from_utf8_lossy(&head));
+		stream
+	}
+}
+
+/// Reads from `stream` until `needle` has arrived, returning everything read.
+pub async fn read_until(stream: &mut TcpStream, needle: &str) -> String {
+	let mut raw = vec![];
+	let mut buf = [0u8; 1024];
+	while !String::from_utf8_lossy(&raw).contains(needle) {
+		let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap().unwrap();
+		assert!(n > 0, "connection closed before {:?}: {}", needle, String::from_utf8_lossy(&raw));
+		raw.extend_from_slice(&buf[..n]);
+	}
+	String::from_utf8_lossy(&raw).into_owned()
+}
+
+fn parse_response(raw: &[u8]) -> Response {
+	let split = raw.windows(4).position(|w| w == b"\r\n\r\n").expect("response head");
+	let head = String::from_utf8_lossy(&raw[..split]);
+	let mut lines = head.split("\r\n");
+	let status = lines.next().and_then(|l| l.split(' ').nth(1)).and_then(|s| s.parse().ok()).unwrap_or(0);
+	let headers: Vec<(String, String)> = lines
+		.filter_map(|l| l.split_once(':'))
+		.map(|(n, v)| (n.trim().to_string(), v.trim().to_string()))
+		.collect();
+	let mut body = raw[split + 4..].to_vec();
+	let chunked = headers.iter().any(|(n, v)| n.eq_ignore_ascii_case("transfer-encoding") && v.contains("chunked"));
+	if chunked {
+		body = dechunk(&body);
+	}
+	Response {
+		status,
+		headers,
+		body: String::from_utf8_lossy(&body).into_owned(),
+	}
+}
+
+fn dechunk(mut raw: &[u8]) -> Vec<u8> {
+	let mut out = vec![];
+	while let Some(end) = raw.windows(2).position(|w| w == b"\r\n") {
+		let size = usize::from_str_radix(String::from_utf8_lossy(&raw[..end]).trim(), 16).unwrap_or(0);
+		if size == 0 {
+			break;
+		}
+		out.extend_from_slice(&raw[end + 2..end + 2 + size]);
+		raw = &raw[end + 2 + size + 2..];
+	}
+	out
+}
+
+/// Sends one masked text frame, as clients must.
+pub async fn ws_send_text(stream: &mut TcpStream, text: &str) {
+	ws_send(stream, 0x1, text.as_bytes()).await;
+}
+
+/// Sends a close frame with no status.
+pub async fn ws_close(stream: &mut TcpStream) {
+	ws_send(stream, 0x8, &[]).await;
+}
+
+async fn ws_send(stream: &mut TcpStream, opcode: u8, payload: &[u8]) {
+	let mask = [0x12, 0x34, 0x56, 0x78];
+	let mut frame = vec![0x80 | opcode];
+	match payload.len() {
+		n if n < 126 => frame.push(0x80 | n as u8),
+		n => {
+			frame.push(0x80 | 126);
+			frame.extend_from_slice(&(n as u16).to_be_bytes());
+		}
+	}
+	frame.extend_from_slice(&mask);
+	frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+	stream.write_all(&frame).await.unwrap();
+}
+
+/// The next text frame's payload, or `None` once the server closes.
+pub async fn ws_recv_text(stream: &mut TcpStream) -> Option<String> {
+	loop {
+		let head = stream.read_u16().await.ok()?;
+		let opcode = (head >> 8) as u8 & 0x0f;
+		let len = match head & 0x7f {
+			126 => stream.read_u16().await.ok()? as usize,
+			127 => stream.read_u64().await.ok()? as usize,
+			n => n as usize,
+		};
+		let mut payload = vec![0; len];
+		stream.read_exact(&mut payload).await.ok()?;
+		match opcode {
+			0x1 => return String::from_utf8(payload).ok(),
+			0x8 => return None,
+			_ => continue,
+		}
+	}
+}